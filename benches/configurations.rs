@@ -0,0 +1,288 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Compare ciphers, user message hash, compression and serde formats on this machine.
+//! Run with `cargo bench --bench configurations`
+//! (add the `bin` and `json` features to compare serde formats).
+//!
+//! Numbers depend on the hardware: run the cases on the target machines before choosing a
+//! configuration.
+//! Message data are pseudo-random bytes of a fixed seed, so that all runs (and compression)
+//! work on the same data.
+
+use pkstl::{
+    EncryptAlgo, Error, IncomingBinaryMessage, MinimalSecureLayer, Result, SecureLayer,
+    SecureLayerConfig, Seed32,
+};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::io::{BufWriter, Write};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "ser")]
+use pkstl::{IncomingMessage, MessageFormat};
+
+/// Secure layer through which a benchmark case writes and reads its messages
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum BenchMode {
+    /// Minimal secure layer
+    Minimal,
+    /// Complete secure layer, binary messages (compression according to the configuration)
+    Complete,
+    #[cfg(feature = "ser")]
+    /// Complete secure layer, messages serialized in the configuration message format
+    Serialized,
+}
+
+/// Benchmark case
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct BenchCase {
+    /// Case label
+    label: &'static str,
+    /// Secure layer mode
+    mode: BenchMode,
+    /// Configuration of both peers
+    config: SecureLayerConfig,
+}
+
+/// Benchmark result
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct BenchResult {
+    /// Size of the message data in bytes
+    msg_size: usize,
+    /// Number of messages written and read
+    msgs_count: usize,
+    /// Throughput in MiB of message data per second
+    throughput: f64,
+    /// Median latency of a message (written by a peer then read by the other)
+    median_latency: Duration,
+    /// 99th percentile latency of a message
+    p99_latency: Duration,
+}
+
+/// Cases comparing the ciphers, the user message hash, the compression
+/// and the serde formats enabled by features
+fn default_cases() -> Vec<BenchCase> {
+    let encrypt_algos = [("chacha20-poly1305", EncryptAlgo::Chacha20Poly1305Aead)];
+    let mut cases = Vec::new();
+    for (label, encrypt_algo) in &encrypt_algos {
+        let config = SecureLayerConfig {
+            encrypt_algo: *encrypt_algo,
+            ..SecureLayerConfig::default()
+        };
+        cases.push(BenchCase {
+            label,
+            mode: BenchMode::Minimal,
+            config,
+        });
+        cases.push(BenchCase {
+            label: "no user message hash",
+            mode: BenchMode::Minimal,
+            config: SecureLayerConfig {
+                user_msg_hash: false,
+                ..config
+            },
+        });
+    }
+    cases.push(BenchCase {
+        label: "compression none",
+        mode: BenchMode::Complete,
+        config: SecureLayerConfig {
+            compression: flate2::Compression::none(),
+            ..SecureLayerConfig::default()
+        },
+    });
+    cases.push(BenchCase {
+        label: "compression fast",
+        mode: BenchMode::Complete,
+        config: SecureLayerConfig {
+            compression_min_size: 0,
+            ..SecureLayerConfig::default()
+        },
+    });
+    #[cfg(feature = "ser")]
+    {
+        let message_formats: &[(&'static str, MessageFormat)] = &[
+            #[cfg(feature = "bin")]
+            ("bincode", MessageFormat::Bincode),
+            #[cfg(feature = "cbor")]
+            ("cbor", MessageFormat::Cbor),
+            #[cfg(feature = "json")]
+            ("json", MessageFormat::Utf8Json),
+        ];
+        for (label, message_format) in message_formats {
+            cases.push(BenchCase {
+                label,
+                mode: BenchMode::Serialized,
+                config: SecureLayerConfig {
+                    message_format: *message_format,
+                    ..SecureLayerConfig::default()
+                },
+            });
+        }
+    }
+    cases
+}
+
+/// Write `msgs_count` messages of `msg_size` bytes from a peer to the other and read them
+fn run_case(case: &BenchCase, msg_size: usize, msgs_count: usize) -> Result<BenchResult> {
+    let data = bench_data(msg_size);
+    match case.mode {
+        BenchMode::Minimal => {
+            let (mut sender, mut receiver) = negotiate_minimal(case.config)?;
+            measure(msg_size, msgs_count, || {
+                let mut channel = BufWriter::new(Vec::with_capacity(msg_size + 100));
+                sender.write_message(&data, &mut channel)?;
+                channel.flush().map_err(|_| Error::BufferFlushError)?;
+                receiver.read(channel.get_ref())?;
+                Ok(())
+            })
+        }
+        BenchMode::Complete => {
+            let (mut sender, mut receiver) = negotiate_complete(case.config)?;
+            measure(msg_size, msgs_count, || {
+                let mut channel = BufWriter::new(Vec::with_capacity(msg_size + 100));
+                sender.write_bin(&data, &mut channel)?;
+                channel.flush().map_err(|_| Error::BufferFlushError)?;
+                receiver.read_bin(channel.get_ref())?;
+                Ok(())
+            })
+        }
+        #[cfg(feature = "ser")]
+        BenchMode::Serialized => {
+            let (mut sender, mut receiver) = negotiate_complete(case.config)?;
+            measure(msg_size, msgs_count, || {
+                let mut channel = BufWriter::new(Vec::with_capacity(msg_size + 100));
+                sender.write(&data, &mut channel)?;
+                channel.flush().map_err(|_| Error::BufferFlushError)?;
+                let _: Vec<IncomingMessage<Vec<u8>>> = receiver.read(channel.get_ref())?;
+                Ok(())
+            })
+        }
+    }
+}
+
+fn measure<F>(msg_size: usize, msgs_count: usize, mut round_trip: F) -> Result<BenchResult>
+where
+    F: FnMut() -> Result<()>,
+{
+    let mut latencies = Vec::with_capacity(msgs_count);
+    let start = Instant::now();
+    for _ in 0..msgs_count {
+        let msg_start = Instant::now();
+        round_trip()?;
+        latencies.push(msg_start.elapsed());
+    }
+    let elapsed = start.elapsed().as_micros() as f64 / 1_000_000.0;
+    latencies.sort();
+
+    let percentile = |p: usize| {
+        latencies
+            .get(latencies.len().saturating_sub(1) * p / 100)
+            .copied()
+            .unwrap_or_default()
+    };
+    Ok(BenchResult {
+        msg_size,
+        msgs_count,
+        throughput: (msg_size * msgs_count) as f64 / elapsed / 1_048_576.0,
+        median_latency: percentile(50),
+        p99_latency: percentile(99),
+    })
+}
+
+/// Pseudo-random bytes of a fixed seed
+fn bench_data(len: usize) -> Vec<u8> {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+fn negotiate_minimal(
+    config: SecureLayerConfig,
+) -> Result<(MinimalSecureLayer, MinimalSecureLayer)> {
+    let mut msl1 = MinimalSecureLayer::create(config, None)?;
+    let mut msl2 = MinimalSecureLayer::create(config, None)?;
+    let kp1 = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+        .map_err(|_| Error::FailtoGenSigKeyPair)?;
+    let kp2 = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+        .map_err(|_| Error::FailtoGenSigKeyPair)?;
+    let sign = |kp: &Ed25519KeyPair, mut msg: Vec<u8>| {
+        msg.extend_from_slice(kp.sign(&msg).as_ref());
+        msg
+    };
+
+    let connect_msg1 = sign(
+        &kp1,
+        msl1.create_connect_message(kp1.public_key().as_ref(), None)?,
+    );
+    let connect_msg2 = sign(
+        &kp2,
+        msl2.create_connect_message(kp2.public_key().as_ref(), None)?,
+    );
+    msl2.read(&connect_msg1)?;
+    msl1.read(&connect_msg2)?;
+    let ack_msg1 = sign(&kp1, msl1.create_ack_message(None)?);
+    let ack_msg2 = sign(&kp2, msl2.create_ack_message(None)?);
+    msl2.read(&ack_msg1)?;
+    msl1.read(&ack_msg2)?;
+
+    Ok((msl1, msl2))
+}
+
+fn negotiate_complete(config: SecureLayerConfig) -> Result<(SecureLayer, SecureLayer)> {
+    let mut sl1 = SecureLayer::create(config, None, None)?;
+    let mut sl2 = SecureLayer::create(config, None, None)?;
+    exchange(&mut sl1, &mut sl2, true)?;
+    exchange(&mut sl2, &mut sl1, true)?;
+    exchange(&mut sl1, &mut sl2, false)?;
+    exchange(&mut sl2, &mut sl1, false)?;
+
+    Ok((sl1, sl2))
+}
+
+fn exchange(sender: &mut SecureLayer, receiver: &mut SecureLayer, connect: bool) -> Result<()> {
+    let mut channel = BufWriter::new(Vec::new());
+    if connect {
+        sender.write_connect_msg_bin(None, &mut channel)?;
+    } else {
+        sender.write_ack_msg_bin(None, &mut channel)?;
+    }
+    let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+    let _: Vec<IncomingBinaryMessage> = receiver.read_bin(&channel)?;
+    Ok(())
+}
+
+const MSG_SIZES: &[usize] = &[64, 1_024, 65_536];
+const TOTAL_BYTES: usize = 64 * 1_024 * 1_024;
+
+fn main() -> Result<()> {
+    println!("case                 | msg size | MiB/s    | median latency | p99 latency");
+    for case in default_cases() {
+        for msg_size in MSG_SIZES {
+            let result = run_case(&case, *msg_size, TOTAL_BYTES / msg_size)?;
+            println!(
+                "{:<20} | {:>8} | {:>8.1} | {:>14?} | {:>11?}",
+                case.label, msg_size, result.throughput, result.median_latency, result.p99_latency
+            );
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,93 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Measure user messages throughput with and without Sha256 hash.
+//! Run with `cargo bench --bench user_msg_hash`.
+
+use pkstl::*;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::io::{BufWriter, Write};
+use std::time::Instant;
+
+const MSG_SIZES: &[usize] = &[64, 1_024, 65_536];
+const TOTAL_BYTES: usize = 64 * 1_024 * 1_024;
+
+fn negotiate(config: SecureLayerConfig) -> Result<(MinimalSecureLayer, MinimalSecureLayer)> {
+    let mut msl1 = MinimalSecureLayer::create(config, None)?;
+    let mut msl2 = MinimalSecureLayer::create(config, None)?;
+    let kp1 = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+        .map_err(|_| Error::FailtoGenSigKeyPair)?;
+    let kp2 = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+        .map_err(|_| Error::FailtoGenSigKeyPair)?;
+
+    let connect_msg1 = sign(
+        &kp1,
+        msl1.create_connect_message(kp1.public_key().as_ref(), None)?,
+    );
+    let connect_msg2 = sign(
+        &kp2,
+        msl2.create_connect_message(kp2.public_key().as_ref(), None)?,
+    );
+    msl2.read(&connect_msg1)?;
+    msl1.read(&connect_msg2)?;
+    let ack_msg1 = sign(&kp1, msl1.create_ack_message(None)?);
+    let ack_msg2 = sign(&kp2, msl2.create_ack_message(None)?);
+    msl2.read(&ack_msg1)?;
+    msl1.read(&ack_msg2)?;
+
+    Ok((msl1, msl2))
+}
+
+fn sign(kp: &Ed25519KeyPair, mut msg: Vec<u8>) -> Vec<u8> {
+    let sig = kp.sign(&msg);
+    msg.extend_from_slice(sig.as_ref());
+    msg
+}
+
+fn throughput(user_msg_hash: bool, msg_size: usize) -> Result<f64> {
+    let (mut sender, mut receiver) = negotiate(SecureLayerConfig {
+        user_msg_hash,
+        ..SecureLayerConfig::default()
+    })?;
+    let data = vec![42u8; msg_size];
+    let msgs_count = TOTAL_BYTES / msg_size;
+
+    let start = Instant::now();
+    for _ in 0..msgs_count {
+        let mut channel = BufWriter::new(Vec::with_capacity(msg_size + 100));
+        sender.write_message(&data, &mut channel)?;
+        channel.flush().map_err(|_| Error::BufferFlushError)?;
+        receiver.read(channel.get_ref())?;
+    }
+    let elapsed = start.elapsed().as_micros() as f64 / 1_000_000.0;
+
+    Ok((msgs_count * msg_size) as f64 / elapsed / 1_048_576.0)
+}
+
+fn main() -> Result<()> {
+    println!("msg size | with hash (MiB/s) | without hash (MiB/s) | gain");
+    for msg_size in MSG_SIZES {
+        let with_hash = throughput(true, *msg_size)?;
+        let without_hash = throughput(false, *msg_size)?;
+        println!(
+            "{:>8} | {:>17.1} | {:>20.1} | {:>+5.1}%",
+            msg_size,
+            with_hash,
+            without_hash,
+            (without_hash / with_hash - 1.0) * 100.0
+        );
+    }
+    Ok(())
+}
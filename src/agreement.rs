@@ -45,6 +45,16 @@ pub enum SharedSecret {
     B64(Seed64),
 }
 
+impl AsRef<[u8]> for SharedSecret {
+    fn as_ref(&self) -> &[u8] {
+        match self {
+            Self::B32(seed) => seed.as_ref(),
+            Self::B48(seed) => seed.as_ref(),
+            Self::B64(seed) => seed.as_ref(),
+        }
+    }
+}
+
 impl AsMut<[u8]> for SharedSecret {
     fn as_mut(&mut self) -> &mut [u8] {
         match self {
@@ -56,7 +66,7 @@ impl AsMut<[u8]> for SharedSecret {
 }
 
 impl SharedSecret {
-    fn new(len: SharedSecretLen) -> Self {
+    pub(crate) fn new(len: SharedSecretLen) -> Self {
         match len {
             SharedSecretLen::B32 => SharedSecret::B32(Seed32::default()),
             SharedSecretLen::B48 => SharedSecret::B48(Seed48::default()),
@@ -116,21 +126,22 @@ impl EphemeralKeyPair {
             self.privkey,
             &agreement::UnparsedPublicKey::new(&agreement::X25519, other_ephemeral_public_key),
             Error::FailToComputeAgreement,
-            |key_material| Ok(derive(key_material, salt, shared_secret_len)),
+            |key_material| derive(key_material, salt, shared_secret_len),
         )
     }
 }
 
-fn derive(seed: &[u8], salt: &[u8], shared_secret_len: SharedSecretLen) -> SharedSecret {
+fn derive(seed: &[u8], salt: &[u8], shared_secret_len: SharedSecretLen) -> Result<SharedSecret> {
+    let iterations = NonZeroU32::new(ITERATIONS).ok_or(Error::FailToComputeAgreement)?;
     let mut shared_secret = SharedSecret::new(shared_secret_len);
     pbkdf2::derive(
         shared_secret_len.algo(),
-        NonZeroU32::new(ITERATIONS).expect("ITERATIONS must be > 0"),
+        iterations,
         salt,
         seed,
         shared_secret.as_mut(),
     );
-    shared_secret
+    Ok(shared_secret)
 }
 
 #[cfg(test)]
@@ -0,0 +1,127 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage optional capabilities advertised in CONNECT messages.
+
+/// Optional capability of a peer
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Capability {
+    /// Reads compressed user data (secure layer, not minimal secure layer)
+    Compression,
+    /// Reads KEEPALIVE messages
+    Keepalive,
+    /// Reads COVER messages
+    CoverTraffic,
+    /// Reads EXPIRING USER messages
+    ExpiringMsgs,
+    /// Omits the hash of user messages, if the peer omits it too
+    OmitUserMsgHash,
+    /// Pads its frames (PADDING and PADDING_LEN fields of encrypted messages)
+    FramePadding,
+}
+
+impl Capability {
+    #[inline]
+    fn flag(self) -> u32 {
+        match self {
+            Self::Compression => 1,
+            Self::Keepalive => 1 << 1,
+            Self::CoverTraffic => 1 << 2,
+            Self::ExpiringMsgs => 1 << 3,
+            Self::OmitUserMsgHash => 1 << 4,
+            Self::FramePadding => 1 << 5,
+        }
+    }
+}
+
+/// Set of capabilities (CAPABILITIES field of CONNECT messages)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Empty set
+    pub fn empty() -> Self {
+        Capabilities(0)
+    }
+    /// Capabilities of a minimal secure layer
+    pub fn minimal() -> Self {
+        Self::empty()
+            .with(Capability::Keepalive)
+            .with(Capability::CoverTraffic)
+            .with(Capability::ExpiringMsgs)
+    }
+    /// Capabilities of a secure layer
+    pub fn complete() -> Self {
+        Self::minimal().with(Capability::Compression)
+    }
+    /// Add a capability to the set
+    pub fn with(self, capability: Capability) -> Self {
+        Capabilities(self.0 | capability.flag())
+    }
+    /// Remove a capability from the set
+    pub fn without(self, capability: Capability) -> Self {
+        Capabilities(self.0 & !capability.flag())
+    }
+    /// Check if the set contains a capability
+    pub fn contains(self, capability: Capability) -> bool {
+        self.0 & capability.flag() != 0
+    }
+    /// Check that the peer has a capability, or return `Error::PeerUnsupported`
+    pub(crate) fn require(self, capability: Capability) -> crate::Result<()> {
+        if self.contains(capability) {
+            Ok(())
+        } else {
+            Err(crate::Error::PeerUnsupported(capability))
+        }
+    }
+    #[inline]
+    pub(crate) fn from_bits(bits: u32) -> Self {
+        Capabilities(bits)
+    }
+    #[inline]
+    pub(crate) fn bits(self) -> u32 {
+        self.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = Capabilities::minimal();
+        assert!(capabilities.contains(Capability::Keepalive));
+        assert!(!capabilities.contains(Capability::Compression));
+        assert_eq!(
+            Capabilities::complete(),
+            capabilities.with(Capability::Compression)
+        );
+        assert_eq!(
+            capabilities,
+            Capabilities::complete().without(Capability::Compression)
+        );
+        assert_eq!(0b1110, capabilities.bits());
+        assert_eq!(capabilities, Capabilities::from_bits(0b1110));
+
+        if let Err(crate::Error::PeerUnsupported(Capability::Compression)) =
+            capabilities.require(Capability::Compression)
+        {
+        } else {
+            panic!("Expected error PeerUnsupported !")
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage time sources.
+
+use std::any::Any;
+use std::fmt::Debug;
+use std::time::SystemTime;
+
+/// Time source of expiry, timestamp and rate limit features,
+/// for example a simulated clock or the clock of a wasm host
+pub trait Clock: Any + Debug + Send + Sync {
+    /// Get current time
+    fn now(&self) -> SystemTime;
+}
+
+impl PartialEq for dyn Clock {
+    /// Clocks are equal if they are the same instance
+    fn eq(&self, other: &Self) -> bool {
+        let self_ptr: *const dyn Clock = self;
+        let other_ptr: *const dyn Clock = other;
+        self_ptr as *const u8 == other_ptr as *const u8 && self.type_id() == other.type_id()
+    }
+}
+
+/// Clock of the operating system
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[inline]
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// Default clock
+pub static SYSTEM_CLOCK: SystemClock = SystemClock;
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_clocks_eq() {
+        #[derive(Debug)]
+        struct FixedClock(u64);
+        impl Clock for FixedClock {
+            fn now(&self) -> SystemTime {
+                UNIX_EPOCH + Duration::from_secs(self.0)
+            }
+        }
+        static FIXED_CLOCK: FixedClock = FixedClock(42);
+
+        let clock: &dyn Clock = &SYSTEM_CLOCK;
+        let same_clock: &dyn Clock = &SYSTEM_CLOCK;
+        let other_clock: &dyn Clock = &FIXED_CLOCK;
+        assert!(clock == same_clock);
+        assert!(clock != other_clock);
+        assert_eq!(UNIX_EPOCH + Duration::from_secs(42), FIXED_CLOCK.now());
+    }
+}
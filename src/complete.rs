@@ -23,12 +23,23 @@ pub mod writer;
 #[cfg(feature = "ser")]
 pub use self::serde::IncomingMessage;
 
-use crate::{Error, Message, MinimalSecureLayer, Result, SecureLayerConfig, Seed32};
+use crate::constants::HASH_SIZE;
+use crate::message::UserMsgMeta;
+use crate::{
+    Capabilities, Capability, Error, HandshakeFailure, HandshakeTranscript, LossReport,
+    MemoryBudget, Message, MessageView, MinimalSecureLayer, NonceCheckpoint, PeerAuthPolicy,
+    PendingConnectVerification, PreparedConnect, Result, SecureLayerConfig, SecureLayerStatus,
+    Seed32, SessionStats, SlowConsumerWarning, VerifiedConnect,
+};
 use flate2::write::{DeflateDecoder, DeflateEncoder};
 use message::IncomingBinaryMessage;
 use ring::signature::Ed25519KeyPair;
+use std::collections::BTreeSet;
 use std::io::{BufWriter, Write};
+use std::time::SystemTime;
 
+#[cfg(feature = "ser")]
+use crate::MessageFormat;
 #[cfg(feature = "ser")]
 use ::serde::de::DeserializeOwned;
 #[cfg(feature = "ser")]
@@ -41,6 +52,7 @@ use std::fmt::Debug;
 pub struct SecureLayer {
     minimal_secure_layer: MinimalSecureLayer,
     sig_key_pair: Option<Ed25519KeyPair>,
+    co_signers_key_pairs: Vec<Ed25519KeyPair>,
 }
 
 impl SecureLayer {
@@ -51,8 +63,22 @@ impl SecureLayer {
         Ok(SecureLayer {
             minimal_secure_layer: msl_clone,
             sig_key_pair: None,
+            co_signers_key_pairs: Vec::new(),
         })
     }
+    /// Add a co-signer, that will co-sign the CONNECT message
+    pub fn add_connect_co_signer(&mut self, co_signer_key_pair_seed: Seed32) -> Result<()> {
+        self.co_signers_key_pairs.push(
+            Ed25519KeyPair::from_seed_unchecked(co_signer_key_pair_seed.as_ref())
+                .map_err(|_| Error::FailtoGenSigKeyPair)?,
+        );
+        Ok(())
+    }
+    /// Close secure layer: writing is no longer allowed, in-flight user messages can still be read
+    #[inline]
+    pub fn close(&mut self) -> Result<()> {
+        self.minimal_secure_layer.close()
+    }
     /// Change configuration
     #[inline]
     pub fn change_config(&mut self, new_config: SecureLayerConfig) -> Result<()> {
@@ -60,17 +86,23 @@ impl SecureLayer {
         Ok(())
     }
     fn compress(&self, bin_message: &[u8]) -> Result<Vec<u8>> {
+        self.minimal_secure_layer
+            .require_peer(Capability::Compression)?;
+        Self::deflate(bin_message, self.compression_level(bin_message.len()))
+    }
+    /// Compression level of a binary message of `len` bytes
+    #[inline]
+    fn compression_level(&self, len: usize) -> flate2::Compression {
+        if len < self.minimal_secure_layer.config.compression_min_size {
+            flate2::Compression::none()
+        } else {
+            self.minimal_secure_layer.config.compression
+        }
+    }
+    fn deflate(bin_message: &[u8], compression_level: flate2::Compression) -> Result<Vec<u8>> {
         // Create buffer
         let buffer = BufWriter::new(Vec::with_capacity(bin_message.len()));
 
-        // Determine compression level
-        let compression_level =
-            if bin_message.len() < self.minimal_secure_layer.config.compression_min_size {
-                flate2::Compression::none()
-            } else {
-                self.minimal_secure_layer.config.compression
-            };
-
         // Create compressor
         let mut deflate_encoder = DeflateEncoder::new(buffer, compression_level);
 
@@ -97,83 +129,262 @@ impl SecureLayer {
         sig_key_pair_seed: Option<Seed32>,
         expected_remote_sig_pubkey: Option<Vec<u8>>,
     ) -> Result<Self> {
-        let seed = sig_key_pair_seed.unwrap_or_else(Seed32::random);
+        let seed = match sig_key_pair_seed {
+            Some(seed) => seed,
+            None => Seed32::try_random()?,
+        };
+
+        let mut minimal_secure_layer =
+            MinimalSecureLayer::create(config, expected_remote_sig_pubkey)?;
+        minimal_secure_layer.local_capabilities = Capabilities::complete();
 
         let secure_layer = SecureLayer {
-            minimal_secure_layer: MinimalSecureLayer::create(config, expected_remote_sig_pubkey)?,
+            minimal_secure_layer,
             sig_key_pair: Some(
                 Ed25519KeyPair::from_seed_unchecked(seed.as_ref())
                     .map_err(|_| Error::FailtoGenSigKeyPair)?,
             ),
+            co_signers_key_pairs: Vec::new(),
         };
 
         Ok(secure_layer)
     }
+    /// Create secure layer whose CONNECT message is the prepared one (see `PreparedConnect`),
+    /// signed with the key pair of seed `sig_key_pair_seed`.
+    /// Writing the CONNECT message then re-emits the prepared frame, whatever its custom data.
+    pub fn create_with_prepared_connect(
+        prepared_connect: PreparedConnect,
+        sig_key_pair_seed: Seed32,
+        expected_remote_sig_pubkey: Option<Vec<u8>>,
+    ) -> Result<Self> {
+        Ok(SecureLayer {
+            minimal_secure_layer: MinimalSecureLayer::create_with_prepared_connect(
+                prepared_connect,
+                expected_remote_sig_pubkey,
+            )?,
+            sig_key_pair: Some(
+                Ed25519KeyPair::from_seed_unchecked(sig_key_pair_seed.as_ref())
+                    .map_err(|_| Error::FailtoGenSigKeyPair)?,
+            ),
+            co_signers_key_pairs: Vec::new(),
+        })
+    }
+    /// Recover the CONNECT frame written by this secure layer, to re-emit it in another
+    /// connection attempt (see `MinimalSecureLayer::into_prepared_connect`)
+    #[inline]
+    pub fn into_prepared_connect(self) -> Option<PreparedConnect> {
+        self.minimal_secure_layer.into_prepared_connect()
+    }
+    /// Get number of expired messages dropped
+    #[inline]
+    pub fn expired_msgs_count(&self) -> u64 {
+        self.minimal_secure_layer.expired_msgs_count()
+    }
+    /// Get nonces received ahead of the next expected nonce
+    #[inline]
+    pub fn pending_orphans(&self) -> &BTreeSet<u64> {
+        self.minimal_secure_layer.pending_orphans()
+    }
+    /// Get highest nonce received, if any
+    #[inline]
+    pub fn highest_nonce_received(&self) -> Option<u64> {
+        self.minimal_secure_layer.highest_nonce_received()
+    }
+    /// Get bytes held in buffers
+    #[inline]
+    pub fn buffered_bytes(&self) -> usize {
+        self.minimal_secure_layer.buffered_bytes()
+    }
+    /// Charge buffered bytes to a memory budget, usually shared with other secure layers
+    #[inline]
+    pub fn set_memory_budget(&mut self, memory_budget: MemoryBudget) {
+        self.minimal_secure_layer.set_memory_budget(memory_budget)
+    }
+    /// Get the message loss report, usually once the session is closed
+    #[inline]
+    pub fn loss_report(&self) -> LossReport {
+        self.minimal_secure_layer.loss_report()
+    }
+    /// Checkpoint nonce counters
+    #[inline]
+    pub fn nonce_checkpoint(&self) -> NonceCheckpoint {
+        self.minimal_secure_layer.nonce_checkpoint()
+    }
+    /// Restore nonce counters from a checkpoint, with a jump-ahead margin on the sent nonce
+    #[inline]
+    pub fn restore_nonce_checkpoint(
+        &mut self,
+        checkpoint: NonceCheckpoint,
+        margin: u64,
+    ) -> Result<()> {
+        self.minimal_secure_layer
+            .restore_nonce_checkpoint(checkpoint, margin)
+    }
+    #[cfg(feature = "keylog")]
+    /// Set a sink receiving the session secret in key log format, never enable it in production
+    #[inline]
+    pub fn set_key_log_sink<F>(&mut self, sink: F)
+    where
+        F: FnMut(&str) + Send + 'static,
+    {
+        self.minimal_secure_layer.set_key_log_sink(sink)
+    }
+    /// Set a hook called on each status change, with the old and the new status.
+    /// The hook is not inherited by clones.
+    #[inline]
+    pub fn on_state_change<F>(&mut self, hook: F)
+    where
+        F: FnMut(SecureLayerStatus, SecureLayerStatus) + Send + 'static,
+    {
+        self.minimal_secure_layer.on_state_change(hook)
+    }
+    /// Set a validator of the (uncompressed) custom data of peer handshake messages,
+    /// see `MinimalSecureLayer::set_custom_data_validator`.
+    /// Custom data that cannot be uncompressed is rejected.
+    pub fn set_custom_data_validator<F>(&mut self, mut validator: F)
+    where
+        F: FnMut(&MessageView<'_>, Option<&[u8; HASH_SIZE]>) -> bool + Send + 'static,
+    {
+        self.minimal_secure_layer
+            .set_custom_data_validator(move |message, session_fingerprint| {
+                match message.custom_data().map(Self::uncompress) {
+                    Some(Ok(custom_data)) => validator(
+                        &message.with_custom_data(Some(&custom_data)),
+                        session_fingerprint,
+                    ),
+                    Some(Err(_)) => false,
+                    None => validator(message, session_fingerprint),
+                }
+            })
+    }
+    /// Set the authentication policy of a peer whose signature public key is not expected
+    /// at creation (`PeerAuthPolicy::AcceptAny` without audit by default).
+    /// The policy is not inherited by clones.
+    #[inline]
+    pub fn set_peer_auth_policy(&mut self, policy: PeerAuthPolicy) {
+        self.minimal_secure_layer.set_peer_auth_policy(policy)
+    }
+    /// Set a hook called on each keepalive message received, with its payload.
+    /// The hook is not inherited by clones.
+    #[inline]
+    pub fn on_heartbeat<F>(&mut self, hook: F)
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        self.minimal_secure_layer.on_heartbeat(hook)
+    }
+    /// Set a hook called when a read leaves more than `buffered_bytes_threshold` bytes buffered
+    /// by the session, see `MinimalSecureLayer::on_slow_consumer`.
+    /// The hook is not inherited by clones.
+    #[inline]
+    pub fn on_slow_consumer<F>(&mut self, buffered_bytes_threshold: usize, hook: F)
+    where
+        F: FnMut(SlowConsumerWarning) + Send + 'static,
+    {
+        self.minimal_secure_layer
+            .on_slow_consumer(buffered_bytes_threshold, hook)
+    }
+    /// Set the payload of our next keepalive messages (not compressed)
+    #[inline]
+    pub fn set_heartbeat_payload(&mut self, payload: &[u8]) {
+        self.minimal_secure_layer.set_heartbeat_payload(payload)
+    }
+    /// Get capabilities advertised by the peer (none until its CONNECT message is received)
+    #[inline]
+    pub fn peer_capabilities(&self) -> Option<Capabilities> {
+        self.minimal_secure_layer.peer_capabilities()
+    }
+    /// Get peer signature public key (expected or received)
+    #[inline]
+    pub fn peer_sig_public_key(&self) -> Option<&[u8]> {
+        self.minimal_secure_layer.peer_sig_public_key()
+    }
+    /// Defer the verification of the peer CONNECT signatures
+    /// (see `MinimalSecureLayer::set_deferred_connect_verification`)
+    #[inline]
+    pub fn set_deferred_connect_verification(&mut self, deferred: bool) {
+        self.minimal_secure_layer
+            .set_deferred_connect_verification(deferred)
+    }
+    /// Take the peer CONNECT message waiting for its deferred verification
+    #[inline]
+    pub fn take_pending_connect_verification(&mut self) -> Option<PendingConnectVerification> {
+        self.minimal_secure_layer
+            .take_pending_connect_verification()
+    }
+    /// Complete the handshake with the result of a deferred CONNECT verification,
+    /// returns the peer CONNECT message and the messages it releases
+    pub fn complete_connect_verification(
+        &mut self,
+        verified_connect: VerifiedConnect,
+    ) -> Result<Vec<IncomingBinaryMessage>> {
+        let messages = self
+            .minimal_secure_layer
+            .complete_connect_verification_with_meta(verified_connect)?;
+        Self::into_bin_messages(messages)
+    }
     /// Read binary incoming data
     pub fn read_bin(&mut self, incoming_data: &[u8]) -> Result<Vec<IncomingBinaryMessage>> {
-        let mut messages = Vec::new();
-
-        let message_opt = self.minimal_secure_layer.read(incoming_data)?;
-
-        if let Some(message) = message_opt {
-            match message {
-                Message::Connect {
-                    custom_data,
-                    sig_pubkey,
-                    ..
-                } => {
-                    messages.push(IncomingBinaryMessage::Connect {
-                        custom_data: if let Some(custom_data) = custom_data {
-                            Some(Self::uncompress(&custom_data)?)
-                        } else {
-                            None
-                        },
-                        peer_sig_public_key: sig_pubkey,
-                    });
-                    if let Some(Message::Ack { custom_data }) =
-                        self.minimal_secure_layer.take_ack_msg_recv_too_early()?
-                    {
-                        messages.push(IncomingBinaryMessage::Ack {
-                            custom_data: if let Some(custom_data) = custom_data {
-                                Some(Self::uncompress(&custom_data)?)
-                            } else {
-                                None
-                            },
-                        });
-                    }
-                }
-                Message::Ack { custom_data } => {
-                    messages.push(IncomingBinaryMessage::Ack {
-                        custom_data: if let Some(custom_data) = custom_data {
-                            Some(Self::uncompress(&custom_data)?)
-                        } else {
-                            None
-                        },
-                    });
-                    for msg in self.minimal_secure_layer.drain_tmp_stack_user_msgs()? {
-                        if let Message::Message { custom_data } = msg {
-                            messages.push(IncomingBinaryMessage::Message {
-                                data: if let Some(custom_data) = custom_data {
-                                    Some(Self::uncompress(&custom_data)?)
-                                } else {
-                                    None
-                                },
-                            });
-                        }
-                    }
-                }
-                Message::Message { custom_data } => {
-                    messages.push(IncomingBinaryMessage::Message {
-                        data: if let Some(custom_data) = custom_data {
-                            Some(Self::uncompress(&custom_data)?)
-                        } else {
-                            None
-                        },
-                    })
+        let messages = self
+            .minimal_secure_layer
+            .read_all_with_meta(incoming_data)?;
+        Self::into_bin_messages(messages)
+    }
+    fn into_bin_messages(
+        messages: Vec<(Message, Option<UserMsgMeta>)>,
+    ) -> Result<Vec<IncomingBinaryMessage>> {
+        let mut bin_messages = Vec::with_capacity(messages.len());
+        for (message, meta) in messages {
+            bin_messages.push(match (message, meta) {
+                (
+                    Message::Connect {
+                        custom_data,
+                        sig_pubkey,
+                        ..
+                    },
+                    _,
+                ) => IncomingBinaryMessage::Connect {
+                    custom_data: if let Some(custom_data) = custom_data {
+                        Some(Self::uncompress(&custom_data)?)
+                    } else {
+                        None
+                    },
+                    peer_sig_public_key: sig_pubkey,
+                },
+                (Message::Ack { custom_data }, _) => IncomingBinaryMessage::Ack {
+                    custom_data: if let Some(custom_data) = custom_data {
+                        Some(Self::uncompress(&custom_data)?)
+                    } else {
+                        None
+                    },
+                },
+                (
+                    Message::Message { custom_data },
+                    Some(UserMsgMeta {
+                        nonce,
+                        received_at,
+                        size,
+                    }),
+                ) => IncomingBinaryMessage::Message {
+                    data: if let Some(custom_data) = custom_data {
+                        Some(Self::uncompress(&custom_data)?)
+                    } else {
+                        None
+                    },
+                    nonce,
+                    received_at,
+                    size,
+                },
+                // User messages are always read with their metadata
+                (Message::Message { .. }, None) => {
+                    return Err(Error::InternalError(
+                        "user message read without its metadata",
+                    ))
                 }
-            };
+            });
         }
-        Ok(messages)
+
+        Ok(bin_messages)
     }
     /// Read incoming data
     #[cfg(feature = "ser")]
@@ -184,6 +395,38 @@ impl SecureLayer {
     {
         self::serde::deserializer::read::<M>(self, incoming_data)
     }
+    /// Get the diagnostics of the first handshake failure, if the negotiation failed
+    #[inline]
+    pub fn handshake_failure(&self) -> Option<&HandshakeFailure> {
+        self.minimal_secure_layer.handshake_failure()
+    }
+    /// Take the VERSION REJECT message to send to the peer before closing the connection,
+    /// available after reading a handshake frame of an unsupported protocol version
+    #[inline]
+    pub fn take_version_reject_message(&mut self) -> Option<Vec<u8>> {
+        self.minimal_secure_layer.take_version_reject_message()
+    }
+    /// Get session statistics
+    #[inline]
+    pub fn stats(&self) -> SessionStats {
+        self.minimal_secure_layer.stats()
+    }
+    /// Get status
+    #[inline]
+    pub fn status(&self) -> SecureLayerStatus {
+        self.minimal_secure_layer.status()
+    }
+    /// Transcript of the handshake with the signatures of both peers, available once the
+    /// negotiation is successful
+    #[inline]
+    pub fn handshake_transcript(&self) -> Option<HandshakeTranscript> {
+        self.minimal_secure_layer.handshake_transcript()
+    }
+    /// Get session fingerprint (available as soon as the peer CONNECT message has been received)
+    #[inline]
+    pub fn session_fingerprint(&self) -> Option<[u8; HASH_SIZE]> {
+        self.minimal_secure_layer.session_fingerprint()
+    }
     fn uncompress(bin_zip_msg: &[u8]) -> Result<Vec<u8>> {
         let mut deflate_decoder = DeflateDecoder::new(Vec::with_capacity(bin_zip_msg.len() * 5));
         deflate_decoder
@@ -269,6 +512,67 @@ impl SecureLayer {
     {
         self::serde::serializer::write_message::<M, W>(self, message, writer)
     }
+    /// Write a message on the writer of each session, serializing and compressing it once
+    /// for all sessions sharing the same message format and compression level.
+    /// Returns the result of each session, in order.
+    #[cfg(feature = "ser")]
+    pub fn broadcast<'a, M, W, I>(message: &M, peers: I) -> Vec<Result<()>>
+    where
+        M: Serialize,
+        W: Write + 'a,
+        I: IntoIterator<Item = (&'a mut SecureLayer, &'a mut BufWriter<W>)>,
+    {
+        self::serde::serializer::broadcast_message::<M, W, I>(message, peers)
+    }
+    /// Write a message already serialized in `message_format` on a writer, without
+    /// serializing it again (the peer reads it as a message written by `write`)
+    #[cfg(feature = "ser")]
+    #[inline]
+    pub fn write_serialized<W: Write>(
+        &mut self,
+        message_format: MessageFormat,
+        serialized_message: &[u8],
+        writer: &mut BufWriter<W>,
+    ) -> Result<()> {
+        self::serde::serializer::write_serialized_message::<W>(
+            self,
+            message_format,
+            serialized_message,
+            writer,
+        )
+    }
+    /// Write binary message on a writer, the peer drops it if it reads it after `expiry`
+    pub fn write_bin_with_expiry<W>(
+        &mut self,
+        binary_message: &[u8],
+        expiry: SystemTime,
+        writer: &mut BufWriter<W>,
+    ) -> Result<()>
+    where
+        W: Write,
+    {
+        // Compress message
+        let bin_zip_msg = self.compress(binary_message)?;
+
+        self.minimal_secure_layer
+            .write_message_with_expiry(&bin_zip_msg, expiry, writer)
+    }
+    /// Write keepalive message on a writer
+    #[inline]
+    pub fn write_keepalive<W: Write>(&mut self, writer: &mut BufWriter<W>) -> Result<()> {
+        self.minimal_secure_layer.write_keepalive(writer)
+    }
+    /// Get the time at which `handle_timeout` must be called (none if nothing is scheduled)
+    #[inline]
+    pub fn poll_timeout(&self) -> Option<SystemTime> {
+        self.minimal_secure_layer.poll_timeout()
+    }
+    /// Run the tasks due at the current time (keepalive), returns `true` if something
+    /// has been written
+    #[inline]
+    pub fn handle_timeout<W: Write>(&mut self, writer: &mut BufWriter<W>) -> Result<bool> {
+        self.minimal_secure_layer.handle_timeout(writer)
+    }
     /// Write binary message on a writer
     pub fn write_bin<W>(&mut self, binary_message: &[u8], writer: &mut BufWriter<W>) -> Result<()>
     where
@@ -287,7 +591,9 @@ mod tests {
     use super::*;
     #[cfg(feature = "ser")]
     use crate::MessageFormat;
-    use crate::{EncryptAlgo, SecureLayerConfig};
+    use crate::{
+        EncryptAlgo, SecureLayerConfig, SigAlgos, SigRequirement, RING_DIGEST, SYSTEM_CLOCK,
+    };
 
     #[test]
     fn test_change_config() -> Result<()> {
@@ -297,7 +603,26 @@ mod tests {
             compression_min_size: 8_192,
             #[cfg(feature = "ser")]
             message_format: MessageFormat::RawBinary,
+            #[cfg(feature = "ser")]
+            canonical_serialization: false,
+            digest: &RING_DIGEST,
+            clock: &SYSTEM_CLOCK,
+            #[cfg(feature = "frame-allocator")]
+            frame_allocator: &crate::frame_allocator::GLOBAL_FRAME_ALLOCATOR,
             encrypt_algo: EncryptAlgo::default(),
+            user_msg_hash: true,
+            accepted_sig_algos: SigAlgos::default(),
+            connect_sig_policy: None,
+            local_sig_requirement: SigRequirement::ConnectAndAck,
+            peer_sig_requirement: SigRequirement::ConnectAndAck,
+            send_rate_limit: None,
+            keepalive_interval: None,
+            max_frame_len: None,
+            uniform_handshake_rejection: false,
+            small_msg_max_len: 512,
+            frame_padding: None,
+            cover_traffic: None,
+            key_context: "",
         })
         .expect("change config must be success");
         Ok(())
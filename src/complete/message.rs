@@ -16,6 +16,8 @@
 //! Manage complete Public Key Secure Transport Layer.
 //! Sub-module define incoming message format.
 
+use std::time::SystemTime;
+
 /// Incoming binary Message
 #[derive(Debug, PartialEq)]
 pub enum IncomingBinaryMessage {
@@ -35,5 +37,11 @@ pub enum IncomingBinaryMessage {
     Message {
         /// Message data (This is an option because it's possible to receive an empty message)
         data: Option<Vec<u8>>,
+        /// Nonce of the message
+        nonce: u64,
+        /// Time at which the message was received
+        received_at: SystemTime,
+        /// Size of the message data as received (compressed)
+        size: usize,
     },
 }
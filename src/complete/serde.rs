@@ -17,6 +17,7 @@
 
 use serde::de::DeserializeOwned;
 use std::fmt::Debug;
+use std::time::SystemTime;
 
 pub mod deserializer;
 pub mod serializer;
@@ -42,6 +43,12 @@ pub enum IncomingMessage<M: Debug + DeserializeOwned> {
     Message {
         /// Message data (This is an option because it's possible to receive an empty message)
         data: Option<M>,
+        /// Nonce of the message
+        nonce: u64,
+        /// Time at which the message was received
+        received_at: SystemTime,
+        /// Size of the message data as received (serialized and compressed)
+        size: usize,
     },
 }
 
@@ -56,6 +63,8 @@ pub enum SerdeError {
     #[cfg(feature = "json")]
     /// Json error
     JsonError(serde_json::Error),
+    /// Message format without serialization support
+    UnsupportedFormat(crate::format::MessageFormat),
     /// For the "raw binary" format, use the functions suffixed by _bin
     UseSuffixedBinFunctions,
     /// Not copyable error for linter
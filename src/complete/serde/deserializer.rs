@@ -23,10 +23,7 @@ use serde::de::DeserializeOwned;
 use std::convert::TryFrom;
 use std::fmt::Debug;
 
-pub(crate) fn read<M>(
-    sl: &mut SecureLayer,
-    incoming_data: &[u8],
-) -> Result<Vec<IncomingMessage<M>>>
+pub(crate) fn read<M>(sl: &mut SecureLayer, incoming_data: &[u8]) -> Result<Vec<IncomingMessage<M>>>
 where
     M: Debug + DeserializeOwned,
 {
@@ -54,12 +51,20 @@ where
                     None
                 },
             }),
-            IncomingBinaryMessage::Message { data } => msgs.push(IncomingMessage::Message {
+            IncomingBinaryMessage::Message {
+                data,
+                nonce,
+                received_at,
+                size,
+            } => msgs.push(IncomingMessage::Message {
                 data: if let Some(data) = data {
                     Some(deserialize(&data)?)
                 } else {
                     None
                 },
+                nonce,
+                received_at,
+                size,
             }),
         };
     }
@@ -101,6 +106,6 @@ where
         MessageFormat::Utf8Json => {
             Ok(serde_json::from_slice::<M>(binary_message).map_err(SerdeError::JsonError)?)
         }
-        _ => unimplemented!(),
+        _ => Err(SerdeError::UnsupportedFormat(message_format)),
     }
 }
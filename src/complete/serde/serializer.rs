@@ -15,9 +15,10 @@
 
 //! Define PKSTL serializer.
 
-use super::SerdeError;
+use super::{SerdeError, HEADER_FORMAT_LEN};
 use crate::format::MessageFormat;
-use crate::{Error, Result, SecureLayer};
+use crate::{Capability, Error, Result, SecureLayer};
+use flate2::Compression;
 use serde::Serialize;
 use std::io::{BufWriter, Write};
 
@@ -32,7 +33,11 @@ where
 {
     // Serialize and compress custom data
     let custom_data = if let Some(custom_data) = custom_data {
-        let bin_msg = serialize(custom_data, sl.minimal_secure_layer.config.message_format)?;
+        let bin_msg = serialize(
+            custom_data,
+            sl.minimal_secure_layer.config.message_format,
+            sl.minimal_secure_layer.config.canonical_serialization,
+        )?;
         Some(sl.compress(&bin_msg[..])?)
     } else {
         None
@@ -53,7 +58,11 @@ where
 {
     // Serialize and compress custom data
     let custom_data = if let Some(custom_data) = custom_data {
-        let bin_msg = serialize(custom_data, sl.minimal_secure_layer.config.message_format)?;
+        let bin_msg = serialize(
+            custom_data,
+            sl.minimal_secure_layer.config.message_format,
+            sl.minimal_secure_layer.config.canonical_serialization,
+        )?;
         Some(sl.compress(&bin_msg[..])?)
     } else {
         None
@@ -73,7 +82,11 @@ where
     W: Write,
 {
     // Serialize message
-    let bin_msg = serialize(message, sl.minimal_secure_layer.config.message_format)?;
+    let bin_msg = serialize(
+        message,
+        sl.minimal_secure_layer.config.message_format,
+        sl.minimal_secure_layer.config.canonical_serialization,
+    )?;
 
     // Compress message
     let bin_zip_msg = sl.compress(&bin_msg[..])?;
@@ -82,7 +95,88 @@ where
     crate::complete::writer::write_bin_message::<W>(sl, &bin_zip_msg, writer)
 }
 
-pub fn serialize<M>(message: &M, message_format: MessageFormat) -> Result<Vec<u8>>
+pub(crate) fn write_serialized_message<W>(
+    sl: &mut SecureLayer,
+    message_format: MessageFormat,
+    serialized_message: &[u8],
+    writer: &mut BufWriter<W>,
+) -> Result<()>
+where
+    W: Write,
+{
+    // Raw binary messages have no format header
+    if let MessageFormat::RawBinary = message_format {
+        return Err(Error::SerdeError(SerdeError::UseSuffixedBinFunctions));
+    }
+
+    // Prefix message with its format
+    let mut bin_msg = Vec::with_capacity(HEADER_FORMAT_LEN + serialized_message.len());
+    bin_msg.extend_from_slice(message_format.as_ref());
+    bin_msg.extend_from_slice(serialized_message);
+
+    // Compress message
+    let bin_zip_msg = sl.compress(&bin_msg[..])?;
+
+    // Write binary message on a writer
+    crate::complete::writer::write_bin_message::<W>(sl, &bin_zip_msg, writer)
+}
+
+pub(crate) fn broadcast_message<'a, M, W, I>(message: &M, peers: I) -> Vec<Result<()>>
+where
+    M: Serialize,
+    W: Write + 'a,
+    I: IntoIterator<Item = (&'a mut SecureLayer, &'a mut BufWriter<W>)>,
+{
+    // Messages serialized once per message format, then compressed once per compression level
+    let mut bin_msgs: Vec<(MessageFormat, bool, Vec<u8>)> = Vec::new();
+    let mut bin_zip_msgs: Vec<(MessageFormat, bool, Compression, Vec<u8>)> = Vec::new();
+
+    peers
+        .into_iter()
+        .map(|(sl, writer)| {
+            sl.minimal_secure_layer
+                .require_peer(Capability::Compression)?;
+            let message_format = sl.minimal_secure_layer.config.message_format;
+            let canonical = sl.minimal_secure_layer.config.canonical_serialization;
+
+            // Serialize message
+            let bin_msg_index = match bin_msgs
+                .iter()
+                .position(|(format, canon, _)| (*format, *canon) == (message_format, canonical))
+            {
+                Some(index) => index,
+                None => {
+                    let bin_msg = serialize(message, message_format, canonical)?;
+                    bin_msgs.push((message_format, canonical, bin_msg));
+                    bin_msgs.len() - 1
+                }
+            };
+            let bin_msg = &bin_msgs[bin_msg_index].2;
+
+            // Compress message
+            let level = sl.compression_level(bin_msg.len());
+            let bin_zip_msg_index = match bin_zip_msgs.iter().position(|(format, canon, lvl, _)| {
+                (*format, *canon, *lvl) == (message_format, canonical, level)
+            }) {
+                Some(index) => index,
+                None => {
+                    let bin_zip_msg = SecureLayer::deflate(bin_msg, level)?;
+                    bin_zip_msgs.push((message_format, canonical, level, bin_zip_msg));
+                    bin_zip_msgs.len() - 1
+                }
+            };
+
+            // Write binary message on a writer
+            crate::complete::writer::write_bin_message::<W>(
+                sl,
+                &bin_zip_msgs[bin_zip_msg_index].3,
+                writer,
+            )
+        })
+        .collect()
+}
+
+pub fn serialize<M>(message: &M, message_format: MessageFormat, canonical: bool) -> Result<Vec<u8>>
 where
     M: Serialize,
 {
@@ -90,10 +184,41 @@ where
     writer
         .write(message_format.as_ref())
         .map_err(Error::WriteError)?;
-    serialize_inner(message, message_format, &mut writer).map_err(Error::SerdeError)?;
+    if canonical {
+        serialize_canonical_inner(message, message_format, &mut writer)
+    } else {
+        serialize_inner(message, message_format, &mut writer)
+    }
+    .map_err(Error::SerdeError)?;
     writer.into_inner().map_err(|_| Error::BufferFlushError)
 }
 
+/// Serialize with a deterministic encoding: map keys are sorted (canonical CBOR ordering
+/// for CBOR, lexicographic ordering for JSON). Bincode is already deterministic.
+pub fn serialize_canonical_inner<M, W>(
+    message: &M,
+    message_format: MessageFormat,
+    writer: &mut W,
+) -> std::result::Result<(), SerdeError>
+where
+    M: Serialize,
+    W: Write,
+{
+    match message_format {
+        #[cfg(feature = "cbor")]
+        MessageFormat::Cbor => {
+            let value = serde_cbor::value::to_value(message).map_err(SerdeError::CborError)?;
+            Ok(serde_cbor::to_writer(writer, &value).map_err(SerdeError::CborError)?)
+        }
+        #[cfg(feature = "json")]
+        MessageFormat::Utf8Json => {
+            let value = serde_json::to_value(message).map_err(SerdeError::JsonError)?;
+            Ok(serde_json::to_writer(writer, &value).map_err(SerdeError::JsonError)?)
+        }
+        _ => serialize_inner(message, message_format, writer),
+    }
+}
+
 pub fn serialize_inner<M, W>(
     message: &M,
     message_format: MessageFormat,
@@ -116,6 +241,42 @@ where
         MessageFormat::Utf8Json => {
             Ok(serde_json::to_writer(writer, message).map_err(SerdeError::JsonError)?)
         }
-        _ => unimplemented!(),
+        _ => Err(SerdeError::UnsupportedFormat(message_format)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    #[cfg(feature = "json")]
+    use std::collections::HashMap;
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn test_serialize_canonical_json() -> Result<()> {
+        let mut message = HashMap::new();
+        for key in &["delta", "alpha", "charlie", "bravo", "echo"] {
+            message.insert(key.to_string(), 1u8);
+        }
+
+        let bin_msg = serialize(&message, MessageFormat::Utf8Json, true)?;
+
+        assert_eq!(MessageFormat::Utf8Json.as_ref(), &bin_msg[..4]);
+        assert_eq!(
+            &b"{\"alpha\":1,\"bravo\":1,\"charlie\":1,\"delta\":1,\"echo\":1}"[..],
+            &bin_msg[4..]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_serialize_canonical_raw_binary() {
+        if let Err(Error::SerdeError(SerdeError::UseSuffixedBinFunctions)) =
+            serialize(&0u8, MessageFormat::RawBinary, true)
+        {
+        } else {
+            panic!("unexpected result")
+        }
     }
 }
@@ -17,8 +17,10 @@
 //! Sub-module define write operations.
 
 use super::SecureLayer;
+use crate::signature::SigRequirement;
+use crate::transcript::TranscriptMsg;
 use crate::{Error, Result};
-use ring::signature::{Ed25519KeyPair, KeyPair};
+use ring::signature::KeyPair;
 use std::io::{BufWriter, Write};
 
 #[inline]
@@ -31,8 +33,16 @@ where
     W: Write,
 {
     if let Some(ref sig_key_pair) = sl.sig_key_pair {
+        // Re-emit prepared connect frame
+        if let Some(frame) = sl.minimal_secure_layer.prepared_connect_frame() {
+            return writer
+                .write(frame)
+                .map(|_| ())
+                .map_err(|_| Error::BufferFlushError);
+        }
+
         // Create connect message
-        let bin_connect_msg = sl.minimal_secure_layer.create_connect_message(
+        let mut frame = sl.minimal_secure_layer.create_connect_message(
             sig_key_pair.public_key().as_ref(),
             match custom_data {
                 Some(ref d) => Some(&d[..]),
@@ -40,13 +50,23 @@ where
             },
         )?;
 
-        // Write connect message
-        writer
-            .write(&bin_connect_msg)
-            .map_err(|_| Error::BufferFlushError)?;
+        if sl.minimal_secure_layer.config.local_sig_requirement == SigRequirement::ConnectAndAck {
+            // Sign message and append co-signatures
+            let bin_connect_msg_len = frame.len();
+            for key_pair in std::iter::once(sig_key_pair).chain(&sl.co_signers_key_pairs) {
+                let sig = key_pair.sign(&frame[..bin_connect_msg_len]);
+                frame.extend_from_slice(sig.as_ref());
+            }
+            sl.minimal_secure_layer
+                .transcript
+                .record_sigs(TranscriptMsg::LocalConnect, &frame[bin_connect_msg_len..]);
+        }
 
-        // Sign message and write signature
-        sign_bin_msg_and_write_sig(sig_key_pair, &bin_connect_msg, writer)
+        // Write connect message, and keep it to re-emit it in another attempt
+        writer.write(&frame).map_err(|_| Error::BufferFlushError)?;
+        let now = sl.minimal_secure_layer.config.clock.now();
+        sl.minimal_secure_layer.prepared_connect_frame = Some((frame, now));
+        Ok(())
     } else {
         Err(Error::ConnectMsgAlreadyWritten)
     }
@@ -75,28 +95,24 @@ where
             .write(&bin_connect_msg)
             .map_err(|_| Error::BufferFlushError)?;
 
-        // Sign message and write signature
-        sign_bin_msg_and_write_sig(sig_key_pair, &bin_connect_msg, writer)
+        if sl.minimal_secure_layer.config.local_sig_requirement == SigRequirement::Anonymous {
+            Ok(())
+        } else {
+            // Sign message and write signature
+            let sig = sig_key_pair.sign(&bin_connect_msg);
+            sl.minimal_secure_layer
+                .transcript
+                .record_sigs(TranscriptMsg::LocalAck, sig.as_ref());
+            writer
+                .write(sig.as_ref())
+                .map(|_| ())
+                .map_err(|_| Error::BufferFlushError)
+        }
     } else {
         Err(Error::ConnectMsgAlreadyWritten)
     }
 }
 
-#[inline]
-fn sign_bin_msg_and_write_sig<W>(
-    sig_key_pair: &Ed25519KeyPair,
-    bin_msg: &[u8],
-    writer: &mut BufWriter<W>,
-) -> Result<()>
-where
-    W: Write,
-{
-    writer
-        .write(sig_key_pair.sign(bin_msg).as_ref())
-        .map(|_| ())
-        .map_err(|_| Error::BufferFlushError)
-}
-
 #[inline]
 pub fn write_bin_message<W>(
     sl: &mut SecureLayer,
@@ -15,10 +15,22 @@
 
 //! Manage PKSTL configuration.
 
+use crate::capabilities::{Capabilities, Capability};
+use crate::clock::{Clock, SYSTEM_CLOCK};
+use crate::constants::{EXPIRY_SIZE, HASH_SIZE, PADDING_LEN_SIZE, SMALL_MSG_BUFFER_SIZE};
+use crate::cover_traffic::CoverTraffic;
+use crate::digest::{Digest, RING_DIGEST};
 use crate::encryption::EncryptAlgo;
+use crate::frame_allocator::{FrameAllocator, GLOBAL_FRAME_ALLOCATOR};
+use crate::rate_limit::SendRateLimit;
+use crate::reader::USER_MSG_MIN_LEN;
+use crate::signature::{ConnectSigPolicy, SigAlgos, SigRequirement};
+use crate::{Error, Result};
+use std::time::Duration;
 
 #[cfg(feature = "zip-sign")]
 const DEFAULT_COMPRESSION_MIN_SIZE: usize = 8_192;
+const DEFAULT_SMALL_MSG_MAX_LEN: usize = 512;
 
 #[cfg(feature = "ser")]
 use crate::format::MessageFormat;
@@ -36,8 +48,59 @@ pub struct SecureLayerConfig {
     #[cfg(feature = "ser")]
     /// Message format
     pub message_format: MessageFormat,
+    #[cfg(feature = "ser")]
+    /// Use canonical encoding (canonical CBOR, sorted-key JSON) when serializing messages,
+    /// so that signatures over serialized structures are reproducible across platforms.
+    pub canonical_serialization: bool,
+    /// Digest backend
+    pub digest: &'static dyn Digest,
+    /// Time source
+    pub clock: &'static dyn Clock,
+    #[cfg(feature = "frame-allocator")]
+    /// Allocator of the buffers of the encrypt and decrypt path
+    /// (stack buffers are used for small messages)
+    pub frame_allocator: &'static dyn FrameAllocator,
     /// Encryption algorithm
     pub encrypt_algo: EncryptAlgo,
+    /// Hash user messages with Sha256 before encryption.
+    /// Redundant with the AEAD tag, it is omitted if the peer has disabled it too
+    /// (advertised in CONNECT messages).
+    pub user_msg_hash: bool,
+    /// Signature algorithms accepted for the peer
+    pub accepted_sig_algos: SigAlgos,
+    /// Policy required on the co-signatures of peer CONNECT message (co-signatures are ignored if none).
+    /// Co-signatures follow the peer signature: it requires `SigRequirement::ConnectAndAck` for the peer.
+    pub connect_sig_policy: Option<&'static ConnectSigPolicy>,
+    /// Handshake messages that we sign
+    pub local_sig_requirement: SigRequirement,
+    /// Handshake messages that the peer must sign
+    /// (for example an authenticated server accepting anonymous clients)
+    pub peer_sig_requirement: SigRequirement,
+    /// Rate limit on the frames of outgoing user messages (unlimited if none)
+    pub send_rate_limit: Option<SendRateLimit>,
+    /// Send a keepalive message when nothing has been sent for this duration (never if none),
+    /// see `poll_timeout`
+    pub keepalive_interval: Option<Duration>,
+    /// Maximum length of incoming frames in bytes (unlimited if none)
+    pub max_frame_len: Option<usize>,
+    /// Reject all invalid handshake frames the same way: after a signature verification,
+    /// failing the secure layer, with `Error::HandshakeRejected`.
+    /// The detailed reason remains available locally in the failure status.
+    pub uniform_handshake_rejection: bool,
+    /// User messages up to this length are encapsulated and encrypted in stack buffers,
+    /// then written at once, without heap allocation (0 to disable).
+    /// Limited by `SMALL_MSG_BUFFER_SIZE`, minus the frame overhead.
+    pub small_msg_max_len: usize,
+    /// Pad encrypted frames to a multiple of this length in bytes (no padding if none),
+    /// to hide the exact length of user messages (advertised in CONNECT messages).
+    pub frame_padding: Option<u16>,
+    /// Send dummy encrypted frames at random intervals, dropped by the peer (never if none),
+    /// see `poll_timeout`
+    pub cover_traffic: Option<CoverTraffic>,
+    /// Application context mixed into the derivation of the encryption keys
+    /// (for example `"currency:g1, api:ws2p-v2"`), it must be the same on both sides:
+    /// otherwise, the first encrypted message cannot be decrypted by the peer.
+    pub key_context: &'static str,
 }
 
 impl Default for SecureLayerConfig {
@@ -49,8 +112,92 @@ impl Default for SecureLayerConfig {
             compression_min_size: DEFAULT_COMPRESSION_MIN_SIZE,
             #[cfg(feature = "ser")]
             message_format: MessageFormat::default(),
+            #[cfg(feature = "ser")]
+            canonical_serialization: false,
+            digest: &RING_DIGEST,
+            clock: &SYSTEM_CLOCK,
+            #[cfg(feature = "frame-allocator")]
+            frame_allocator: &GLOBAL_FRAME_ALLOCATOR,
             encrypt_algo: EncryptAlgo::default(),
+            user_msg_hash: true,
+            accepted_sig_algos: SigAlgos::default(),
+            connect_sig_policy: None,
+            local_sig_requirement: SigRequirement::ConnectAndAck,
+            peer_sig_requirement: SigRequirement::ConnectAndAck,
+            send_rate_limit: None,
+            keepalive_interval: None,
+            max_frame_len: None,
+            uniform_handshake_rejection: false,
+            small_msg_max_len: DEFAULT_SMALL_MSG_MAX_LEN,
+            frame_padding: None,
+            cover_traffic: None,
+            key_context: "",
+        }
+    }
+}
+
+impl SecureLayerConfig {
+    /// Capabilities to advertise in our CONNECT message: `capabilities` of the secure layer
+    /// and the options of this configuration
+    pub(crate) fn advertised_capabilities(&self, capabilities: Capabilities) -> Capabilities {
+        let capabilities = if self.user_msg_hash {
+            capabilities.without(Capability::OmitUserMsgHash)
+        } else {
+            capabilities.with(Capability::OmitUserMsgHash)
+        };
+        if self.frame_padding.is_some() {
+            capabilities.with(Capability::FramePadding)
+        } else {
+            capabilities.without(Capability::FramePadding)
+        }
+    }
+    /// Check that the options of the configuration are consistent
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.connect_sig_policy.is_some()
+            && self.peer_sig_requirement != SigRequirement::ConnectAndAck
+        {
+            return Err(Error::InvalidConfig(
+                "connect_sig_policy requires peer_sig_requirement ConnectAndAck",
+            ));
         }
+        Ok(())
+    }
+    /// Bytes added to the data of a user message by its frame: headers, hash and encryption tag
+    /// (8 more bytes for a message with expiry, compression and padding bytes are not accounted)
+    pub fn frame_overhead(&self) -> usize {
+        let hash_len = if self.user_msg_hash { HASH_SIZE } else { 0 };
+        let padding_len_size = if self.frame_padding.is_some() {
+            PADDING_LEN_SIZE
+        } else {
+            0
+        };
+        USER_MSG_MIN_LEN + hash_len + padding_len_size + self.encrypt_algo.tag_len()
+    }
+    /// Maximum length of the data of a user message whose frame fits in `frame_len` bytes
+    pub fn max_plaintext_for_frame(&self, frame_len: usize) -> usize {
+        (frame_len - frame_len % self.padding_block_len()).saturating_sub(self.frame_overhead())
+    }
+    /// Length whose frames are a multiple of (1 without padding)
+    pub(crate) fn padding_block_len(&self) -> usize {
+        self.frame_padding
+            .map_or(1, |block_len| usize::from(block_len).max(1))
+    }
+    /// Allocator of frame buffers (the global allocator without the `frame-allocator` feature)
+    #[inline]
+    pub(crate) fn frame_allocator(&self) -> &'static dyn FrameAllocator {
+        #[cfg(feature = "frame-allocator")]
+        {
+            self.frame_allocator
+        }
+        #[cfg(not(feature = "frame-allocator"))]
+        {
+            &GLOBAL_FRAME_ALLOCATOR
+        }
+    }
+    /// Whether a user message of `data_len` bytes is written without heap allocation
+    pub(crate) fn is_small_msg(&self, data_len: usize) -> bool {
+        data_len <= self.small_msg_max_len
+            && data_len + EXPIRY_SIZE <= self.max_plaintext_for_frame(SMALL_MSG_BUFFER_SIZE)
     }
 }
 
@@ -67,7 +214,26 @@ mod tests {
                 compression_min_size: DEFAULT_COMPRESSION_MIN_SIZE,
                 #[cfg(feature = "ser")]
                 message_format: MessageFormat::default(),
+                #[cfg(feature = "ser")]
+                canonical_serialization: false,
+                digest: &RING_DIGEST,
+                clock: &SYSTEM_CLOCK,
+                #[cfg(feature = "frame-allocator")]
+                frame_allocator: &GLOBAL_FRAME_ALLOCATOR,
                 encrypt_algo: EncryptAlgo::default(),
+                user_msg_hash: true,
+                accepted_sig_algos: SigAlgos::default(),
+                connect_sig_policy: None,
+                local_sig_requirement: SigRequirement::ConnectAndAck,
+                peer_sig_requirement: SigRequirement::ConnectAndAck,
+                send_rate_limit: None,
+                keepalive_interval: None,
+                max_frame_len: None,
+                uniform_handshake_rejection: false,
+                small_msg_max_len: DEFAULT_SMALL_MSG_MAX_LEN,
+                frame_padding: None,
+                cover_traffic: None,
+                key_context: "",
             },
             SecureLayerConfig::default()
         )
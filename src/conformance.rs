@@ -0,0 +1,478 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage wire format conformance vectors and their runner.
+//!
+//! Frame vectors are byte-for-byte expectations on clear frames (CONNECT and ACK messages),
+//! so that other implementations (and refactors of this one) can be validated against them.
+//! Signatures are Ed25519 with seed `[0x2A; 32]` (co-signature with seed `[0x2B; 32]`),
+//! ACK challenges are the Sha256 of the ephemeral public key `[0x22; 32]`.
+
+use crate::constants::*;
+use crate::encoding::from_base16;
+use crate::errors::IncomingMsgErr;
+use crate::message::{EncapsuledMessage, MsgTypeHeaders};
+use crate::reader::{self, DecryptedIncomingData};
+use crate::Error;
+
+/// Expected decoding of a frame vector (binary fields are lowercase hexadecimal)
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExpectedFrame {
+    /// Valid CONNECT frame
+    Connect {
+        /// Ephemeral public key
+        epk: &'static str,
+        /// Signature algorithm
+        sig_algo: u32,
+        /// Signature public key
+        sig_pubkey: &'static str,
+        /// Capabilities
+        capabilities: u32,
+        /// Custom data
+        custom_data: &'static str,
+        /// Signature, followed by co-signatures if any
+        signature: &'static str,
+    },
+    /// Valid ACK frame
+    Ack {
+        /// Challenge
+        challenge: &'static str,
+        /// Custom data
+        custom_data: &'static str,
+        /// Signature
+        signature: &'static str,
+    },
+    /// Frame that must be rejected
+    Rejected(IncomingMsgErr),
+}
+
+/// Frame vector
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FrameVector {
+    /// Vector name
+    pub name: &'static str,
+    /// Frame bytes (lowercase hexadecimal)
+    pub frame: &'static str,
+    /// Expected decoding
+    pub expected: ExpectedFrame,
+}
+
+/// Decoded clear frame
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DecodedFrame {
+    /// CONNECT frame
+    Connect {
+        /// Ephemeral public key
+        epk: Vec<u8>,
+        /// Signature algorithm
+        sig_algo: u32,
+        /// Signature public key
+        sig_pubkey: Vec<u8>,
+        /// Capabilities
+        capabilities: u32,
+        /// Custom data
+        custom_data: Vec<u8>,
+        /// Signature, followed by co-signatures if any
+        signature: Vec<u8>,
+    },
+    /// ACK frame
+    Ack {
+        /// Challenge
+        challenge: Vec<u8>,
+        /// Custom data
+        custom_data: Vec<u8>,
+        /// Signature
+        signature: Vec<u8>,
+    },
+}
+
+/// PKSTL implementation under test
+pub trait PkstlImpl {
+    /// Decode a clear frame
+    fn decode(&mut self, frame: &[u8]) -> Result<DecodedFrame, IncomingMsgErr>;
+    /// Encode a clear frame
+    fn encode(&mut self, decoded_frame: &DecodedFrame) -> Vec<u8>;
+}
+
+/// Conformance failure
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConformanceFailure {
+    /// Name of the failed vector
+    pub vector: &'static str,
+    /// Failure description
+    pub reason: String,
+}
+
+/// Reference implementation (this crate)
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReferenceImpl;
+
+impl PkstlImpl for ReferenceImpl {
+    fn decode(&mut self, frame: &[u8]) -> Result<DecodedFrame, IncomingMsgErr> {
+        let DecryptedIncomingData {
+            data,
+            user_msg_begin,
+            user_msg_end,
+            msg_type_headers,
+        } = reader::read(None, frame, true).map_err(|e| match e {
+            Error::RecvInvalidMsg(e) => e,
+            _ => IncomingMsgErr::UnknownMessageFormat,
+        })?;
+        let custom_data = data[user_msg_begin..user_msg_end].to_vec();
+        let signature = data[user_msg_end..].to_vec();
+
+        match msg_type_headers {
+            MsgTypeHeaders::Connect {
+                peer_ephemeral_pk,
+                sig_algo,
+                sig_pubkey,
+                capabilities,
+            } => Ok(DecodedFrame::Connect {
+                epk: peer_ephemeral_pk.to_vec(),
+                sig_algo: u32::from_be_bytes(sig_algo.id()),
+                sig_pubkey,
+                capabilities: capabilities.bits(),
+                custom_data,
+                signature,
+            }),
+            MsgTypeHeaders::Ack { challenge } => Ok(DecodedFrame::Ack {
+                challenge: challenge.to_vec(),
+                custom_data,
+                signature,
+            }),
+            MsgTypeHeaders::UserMsg { .. } => Err(IncomingMsgErr::UnexpectedEncryptionState),
+        }
+    }
+    fn encode(&mut self, decoded_frame: &DecodedFrame) -> Vec<u8> {
+        let (type_msg_headers, custom_data, signature) = match decoded_frame {
+            DecodedFrame::Connect {
+                epk,
+                sig_algo,
+                sig_pubkey,
+                capabilities,
+                custom_data,
+                signature,
+            } => {
+                let mut type_msg_headers = CONNECT_MSG_TYPE.to_vec();
+                type_msg_headers.extend_from_slice(epk);
+                type_msg_headers.extend_from_slice(&sig_algo.to_be_bytes());
+                type_msg_headers.extend_from_slice(sig_pubkey);
+                type_msg_headers.extend_from_slice(&capabilities.to_be_bytes());
+                (type_msg_headers, custom_data, signature)
+            }
+            DecodedFrame::Ack {
+                challenge,
+                custom_data,
+                signature,
+            } => {
+                let mut type_msg_headers = ACK_MSG_TYPE.to_vec();
+                type_msg_headers.extend_from_slice(challenge);
+                (type_msg_headers, custom_data, signature)
+            }
+        };
+        let custom_data = if custom_data.is_empty() {
+            None
+        } else {
+            Some(&custom_data[..])
+        };
+
+        match EncapsuledMessage::new(&type_msg_headers, custom_data) {
+            Ok(encapsuled_msg) => {
+                let mut frame = encapsuled_msg.data;
+                frame.extend_from_slice(signature);
+                frame
+            }
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+/// Run all frame vectors against an implementation
+pub fn run_conformance<T: PkstlImpl>(
+    implementation: &mut T,
+) -> Result<(), Vec<ConformanceFailure>> {
+    let failures = FRAME_VECTORS
+        .iter()
+        .filter_map(|vector| {
+            run_vector(implementation, vector)
+                .err()
+                .map(|reason| ConformanceFailure {
+                    vector: vector.name,
+                    reason,
+                })
+        })
+        .collect::<Vec<_>>();
+
+    if failures.is_empty() {
+        Ok(())
+    } else {
+        Err(failures)
+    }
+}
+
+fn run_vector<T: PkstlImpl>(implementation: &mut T, vector: &FrameVector) -> Result<(), String> {
+    let frame = hex(vector.frame)?;
+    let decoding_result = implementation.decode(&frame);
+
+    let expected_decoded_frame = match vector.expected {
+        ExpectedFrame::Rejected(expected_err) => {
+            return match decoding_result {
+                Err(err) if err == expected_err => Ok(()),
+                result => Err(format!("expected {:?}, got {:?}", expected_err, result)),
+            };
+        }
+        ExpectedFrame::Connect {
+            epk,
+            sig_algo,
+            sig_pubkey,
+            capabilities,
+            custom_data,
+            signature,
+        } => DecodedFrame::Connect {
+            epk: hex(epk)?,
+            sig_algo,
+            sig_pubkey: hex(sig_pubkey)?,
+            capabilities,
+            custom_data: hex(custom_data)?,
+            signature: hex(signature)?,
+        },
+        ExpectedFrame::Ack {
+            challenge,
+            custom_data,
+            signature,
+        } => DecodedFrame::Ack {
+            challenge: hex(challenge)?,
+            custom_data: hex(custom_data)?,
+            signature: hex(signature)?,
+        },
+    };
+
+    // Decoding
+    match decoding_result {
+        Ok(ref decoded_frame) if *decoded_frame == expected_decoded_frame => {}
+        result => {
+            return Err(format!(
+                "expected {:?}, got {:?}",
+                expected_decoded_frame, result
+            ))
+        }
+    }
+
+    // Encoding
+    let encoded_frame = implementation.encode(&expected_decoded_frame);
+    if encoded_frame == frame {
+        Ok(())
+    } else {
+        Err(format!("wrong encoding {:?}", encoded_frame))
+    }
+}
+
+#[inline]
+fn hex(hex_str: &str) -> Result<Vec<u8>, String> {
+    from_base16(hex_str).map_err(|_| format!("invalid hexadecimal string {}", hex_str))
+}
+
+/// Frame vectors
+pub const FRAME_VECTORS: &[FrameVector] = &[
+    FrameVector {
+        name: "connect",
+        frame: concat!(
+            "e2c2e2d200000002000000000000004a",
+            "0001",
+            "1111111111111111111111111111111111111111111111111111111111111111",
+            "00000000",
+            "197f6b23e16c8532c6abc838facd5ea789be0c76b2920334039bfa8b3d368d61",
+            "0000000e",
+            "b92f4c712fb581e7c7f852bdd1452c0e4f68ab0587ef80428da0e1a0bafe870f",
+            "816e65f9b765afe5da37808bec958147330ab9fc2738119806c0d57e950d410b",
+        ),
+        expected: ExpectedFrame::Connect {
+            epk: "1111111111111111111111111111111111111111111111111111111111111111",
+            sig_algo: 0,
+            sig_pubkey: "197f6b23e16c8532c6abc838facd5ea789be0c76b2920334039bfa8b3d368d61",
+            capabilities: 0x0e,
+            custom_data: "",
+            signature: concat!(
+                "b92f4c712fb581e7c7f852bdd1452c0e4f68ab0587ef80428da0e1a0bafe870f",
+                "816e65f9b765afe5da37808bec958147330ab9fc2738119806c0d57e950d410b",
+            ),
+        },
+    },
+    FrameVector {
+        name: "connect_with_custom_data",
+        frame: concat!(
+            "e2c2e2d200000002000000000000004f",
+            "0001",
+            "1111111111111111111111111111111111111111111111111111111111111111",
+            "00000000",
+            "197f6b23e16c8532c6abc838facd5ea789be0c76b2920334039bfa8b3d368d61",
+            "0000000e",
+            "68656c6c6f",
+            "48031787e1a7cc3e2077d76162ea3637e146db4abba42fe55adebafbcfe42d1c",
+            "71e5ff286c1659a02a651ece5589e1ff71ada72357b0f5b618706c268dd6620f",
+        ),
+        expected: ExpectedFrame::Connect {
+            epk: "1111111111111111111111111111111111111111111111111111111111111111",
+            sig_algo: 0,
+            sig_pubkey: "197f6b23e16c8532c6abc838facd5ea789be0c76b2920334039bfa8b3d368d61",
+            capabilities: 0x0e,
+            custom_data: "68656c6c6f",
+            signature: concat!(
+                "48031787e1a7cc3e2077d76162ea3637e146db4abba42fe55adebafbcfe42d1c",
+                "71e5ff286c1659a02a651ece5589e1ff71ada72357b0f5b618706c268dd6620f",
+            ),
+        },
+    },
+    FrameVector {
+        name: "connect_with_co_signature",
+        frame: concat!(
+            "e2c2e2d200000002000000000000004a",
+            "0001",
+            "1111111111111111111111111111111111111111111111111111111111111111",
+            "00000000",
+            "197f6b23e16c8532c6abc838facd5ea789be0c76b2920334039bfa8b3d368d61",
+            "0000000e",
+            "b92f4c712fb581e7c7f852bdd1452c0e4f68ab0587ef80428da0e1a0bafe870f",
+            "816e65f9b765afe5da37808bec958147330ab9fc2738119806c0d57e950d410b",
+            "6ddd386ea6f4ee463bf28df772853893b0143a43a2508a16e4b5d08bea9ad0eb",
+            "a91889d142b03b4e3025404eec3febebcf1ab11fc23ad5e0e2e635c1708c380e",
+        ),
+        expected: ExpectedFrame::Connect {
+            epk: "1111111111111111111111111111111111111111111111111111111111111111",
+            sig_algo: 0,
+            sig_pubkey: "197f6b23e16c8532c6abc838facd5ea789be0c76b2920334039bfa8b3d368d61",
+            capabilities: 0x0e,
+            custom_data: "",
+            signature: concat!(
+                "b92f4c712fb581e7c7f852bdd1452c0e4f68ab0587ef80428da0e1a0bafe870f",
+                "816e65f9b765afe5da37808bec958147330ab9fc2738119806c0d57e950d410b",
+                "6ddd386ea6f4ee463bf28df772853893b0143a43a2508a16e4b5d08bea9ad0eb",
+                "a91889d142b03b4e3025404eec3febebcf1ab11fc23ad5e0e2e635c1708c380e",
+            ),
+        },
+    },
+    FrameVector {
+        name: "ack",
+        frame: concat!(
+            "e2c2e2d2000000020000000000000022",
+            "0002",
+            "9f72ea0cf49536e3c66c787f705186df9a4378083753ae9536d65b3ad7fcddc4",
+            "2bbef5b28b35a10c210c1369842bc76e3de850e4069714114e42cb8ddb21c6d9",
+            "8ba8e9b37affceb0ad9ccfaf6c3d39526f6d69fa637f7ba22e7d26dfc7152b0b",
+        ),
+        expected: ExpectedFrame::Ack {
+            challenge: "9f72ea0cf49536e3c66c787f705186df9a4378083753ae9536d65b3ad7fcddc4",
+            custom_data: "",
+            signature: concat!(
+                "2bbef5b28b35a10c210c1369842bc76e3de850e4069714114e42cb8ddb21c6d9",
+                "8ba8e9b37affceb0ad9ccfaf6c3d39526f6d69fa637f7ba22e7d26dfc7152b0b",
+            ),
+        },
+    },
+    FrameVector {
+        name: "ack_with_custom_data",
+        frame: concat!(
+            "e2c2e2d2000000020000000000000027",
+            "0002",
+            "9f72ea0cf49536e3c66c787f705186df9a4378083753ae9536d65b3ad7fcddc4",
+            "68656c6c6f",
+            "df82b94c5425ee95f40a7ac6498b70fd05352bbbbda435f24cef8a3234b02b00",
+            "b991f64811241deb6b3490d84ad5a7ef9fd76570714f7f7a46cea0716a22cb0a",
+        ),
+        expected: ExpectedFrame::Ack {
+            challenge: "9f72ea0cf49536e3c66c787f705186df9a4378083753ae9536d65b3ad7fcddc4",
+            custom_data: "68656c6c6f",
+            signature: concat!(
+                "df82b94c5425ee95f40a7ac6498b70fd05352bbbbda435f24cef8a3234b02b00",
+                "b991f64811241deb6b3490d84ad5a7ef9fd76570714f7f7a46cea0716a22cb0a",
+            ),
+        },
+    },
+    // CONNECT message of version 1 (without CAPABILITIES field)
+    FrameVector {
+        name: "unsupported_version",
+        frame: concat!(
+            "e2c2e2d2000000010000000000000046",
+            "0001",
+            "1111111111111111111111111111111111111111111111111111111111111111",
+            "00000000",
+            "197f6b23e16c8532c6abc838facd5ea789be0c76b2920334039bfa8b3d368d61",
+            "85897b55ec34e04750675f4e539ffe4e6f4dbd1e4d107622e25d5fd77c582ae1",
+            "304c2b156319a05e94a15c9cd5f3ab387f52fcc86f33660d111fb103454ade0d",
+        ),
+        expected: ExpectedFrame::Rejected(IncomingMsgErr::UnsupportedVersion),
+    },
+    FrameVector {
+        name: "unsupported_sig_algo",
+        frame: concat!(
+            "e2c2e2d200000002000000000000004a",
+            "0001",
+            "1111111111111111111111111111111111111111111111111111111111111111",
+            "00000001",
+            "197f6b23e16c8532c6abc838facd5ea789be0c76b2920334039bfa8b3d368d61",
+            "0000000e",
+            "1d8fab6a2334f3bbc042abb365dc1f42e5c49355e21923ad677c63090b2cf774",
+            "c54db25fcf6bc908aa7ac996897dde8435b9993ad95c97904e2e062e5784f006",
+        ),
+        expected: ExpectedFrame::Rejected(IncomingMsgErr::UnsupportedSigAlgo),
+    },
+    FrameVector {
+        name: "unknown_message_type",
+        frame: concat!("e2c2e2d2000000020000000000000002", "0009"),
+        expected: ExpectedFrame::Rejected(IncomingMsgErr::UnknownMessageType),
+    },
+];
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::digest::{Digest, RING_DIGEST};
+    use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+    #[test]
+    fn test_reference_impl_conformance() {
+        assert_eq!(Ok(()), run_conformance(&mut ReferenceImpl));
+    }
+
+    #[test]
+    fn test_vectors_signatures() -> Result<(), String> {
+        let sig_pubkey = Ed25519KeyPair::from_seed_unchecked(&[0x2A; 32])
+            .map_err(|_| "fail to gen sig keypair".to_owned())?
+            .public_key()
+            .as_ref()
+            .to_vec();
+        for vector in FRAME_VECTORS {
+            let frame = hex(vector.frame)?;
+            let signature = match vector.expected {
+                ExpectedFrame::Connect { signature, .. } => hex(signature)?,
+                ExpectedFrame::Ack {
+                    challenge,
+                    signature,
+                    ..
+                } => {
+                    assert_eq!(&RING_DIGEST.sha256(&[0x22; 32])[..], &hex(challenge)?[..]);
+                    hex(signature)?
+                }
+                ExpectedFrame::Rejected(_) => continue,
+            };
+            let signed_data = &frame[..frame.len() - signature.len()];
+            UnparsedPublicKey::new(&ED25519, &sig_pubkey)
+                .verify(signed_data, &signature[..64])
+                .map_err(|_| format!("invalid signature in vector {}", vector.name))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,562 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage connections racing several peer endpoints (happy eyeballs, see RFC 8305)
+//! or retrying a single endpoint with exponential backoff.
+
+use crate::complete::SecureLayer;
+use crate::transport::{StreamTransport, TcpTransportDriver, TransportDriver};
+use crate::{Error, Result};
+use ring::rand::{SecureRandom, SystemRandom};
+use std::convert::TryFrom;
+use std::io::ErrorKind;
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+// Not Copy with the socks5 feature, which must not change the API of other variants
+#[allow(missing_copy_implementations)]
+/// Peer endpoint
+pub enum Endpoint {
+    /// Direct TCP endpoint (IPv4 or IPv6)
+    Tcp(SocketAddr),
+    #[cfg(feature = "socks5")]
+    /// Endpoint reached through a SOCKS5 proxy (e.g. onion address through Tor)
+    Socks5 {
+        /// Proxy address
+        proxy_addr: SocketAddr,
+        /// Peer host name, resolved by the proxy
+        host: String,
+        /// Peer port
+        port: u16,
+    },
+}
+
+impl Endpoint {
+    fn connect(&self) -> std::io::Result<TcpStream> {
+        match self {
+            Self::Tcp(addr) => TcpStream::connect(addr),
+            #[cfg(feature = "socks5")]
+            Self::Socks5 {
+                proxy_addr,
+                host,
+                port,
+            } => crate::socks5::connect_socks5(proxy_addr, host, *port),
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Session established on the first responsive endpoint
+pub struct RaceWinner {
+    /// Index of the winning endpoint
+    pub endpoint_index: usize,
+    /// Driver of the established session
+    pub driver: TcpTransportDriver,
+    /// Custom data of the peer CONNECT message
+    pub peer_connect_custom_data: Option<Vec<u8>>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Retry policy of `connect_with_retry`
+pub struct RetryPolicy {
+    /// Maximum number of attempts
+    pub max_attempts: usize,
+    /// Delay after the first failed attempt
+    pub initial_backoff: Duration,
+    /// Maximum delay between two attempts
+    pub max_backoff: Duration,
+    /// Factor applied to the delay after each failed attempt
+    pub backoff_multiplier: u32,
+    /// Pick each delay randomly between half and all of the backoff
+    pub jitter: bool,
+    /// Maximum duration of the handshake of each attempt
+    pub handshake_timeout: Option<Duration>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            backoff_multiplier: 2,
+            jitter: true,
+            handshake_timeout: Some(Duration::from_secs(10)),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Backoff after the failed attempt number `attempt_index` (starting at 0), without jitter
+    fn backoff(&self, attempt_index: usize) -> Duration {
+        let mut backoff = self.initial_backoff;
+        for _ in 0..attempt_index {
+            if backoff >= self.max_backoff {
+                break;
+            }
+            backoff = backoff
+                .checked_mul(self.backoff_multiplier)
+                .unwrap_or(self.max_backoff);
+        }
+        std::cmp::min(backoff, self.max_backoff)
+    }
+}
+
+#[derive(Debug)]
+/// Failed attempt of `connect_with_retry`
+pub struct FailedAttempt {
+    /// Duration of the attempt
+    pub elapsed: Duration,
+    /// Error of the attempt
+    pub error: Error,
+    /// Delay waited before the next attempt (none if there was no next attempt)
+    pub backoff: Option<Duration>,
+}
+
+#[derive(Debug)]
+/// Session established by `connect_with_retry`
+pub struct RetryWinner {
+    /// Driver of the established session
+    pub driver: TcpTransportDriver,
+    /// Custom data of the peer CONNECT message
+    pub peer_connect_custom_data: Option<Vec<u8>>,
+    /// Attempts failed before the session was established
+    pub failed_attempts: Vec<FailedAttempt>,
+}
+
+type AttemptResult = (usize, Result<(TcpTransportDriver, Option<Vec<u8>>)>);
+
+/// Attempts shared state, used to cancel the losing attempts
+#[derive(Default)]
+struct Race {
+    over: bool,
+    streams: Vec<(usize, TcpStream)>,
+}
+
+/// Register the stream of an attempt, fail if the race is already over
+fn register(race: &Mutex<Race>, endpoint_index: usize, stream: &TcpStream) -> Result<()> {
+    let stream_clone = stream.try_clone().map_err(Error::TransportError)?;
+    match race.lock() {
+        Ok(ref mut race) if !race.over => {
+            race.streams.push((endpoint_index, stream_clone));
+            Ok(())
+        }
+        _ => {
+            let _ = stream.shutdown(Shutdown::Both);
+            Err(Error::TransportError(ErrorKind::Interrupted.into()))
+        }
+    }
+}
+
+/// End the race, cancelling all attempts but the winner one
+fn finish(race: &Mutex<Race>, winner_index: Option<usize>) {
+    if let Ok(mut race) = race.lock() {
+        race.over = true;
+        for (endpoint_index, stream) in race.streams.drain(..) {
+            if Some(endpoint_index) != winner_index {
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+        }
+    }
+}
+
+/// Connect to the first responsive endpoint and run the negotiation on it.
+///
+/// Attempts are started in order, each one `attempt_delay` after the previous one
+/// (or as soon as the previous one fails). The first established session wins and the
+/// other attempts are cancelled. If all attempts fail, the error of the last one is returned.
+/// `create_secure_layer` is called once per attempt.
+pub fn connect_racing<F>(
+    endpoints: &[Endpoint],
+    attempt_delay: Duration,
+    max_frame_len: usize,
+    connect_custom_data: Option<&[u8]>,
+    create_secure_layer: F,
+) -> Result<RaceWinner>
+where
+    F: Fn() -> Result<SecureLayer> + Send + Sync + 'static,
+{
+    let race = Arc::new(Mutex::new(Race::default()));
+    let create_secure_layer = Arc::new(create_secure_layer);
+    let connect_custom_data = connect_custom_data.map(<[u8]>::to_vec);
+    let (sender, receiver) = channel();
+
+    let mut next_attempt = 0;
+    let mut pending_attempts = 0;
+    let mut last_error = Error::TransportError(ErrorKind::NotFound.into());
+    loop {
+        if next_attempt < endpoints.len() {
+            start_attempt(
+                next_attempt,
+                endpoints[next_attempt].clone(),
+                max_frame_len,
+                connect_custom_data.clone(),
+                create_secure_layer.clone(),
+                race.clone(),
+                sender.clone(),
+            );
+            next_attempt += 1;
+            pending_attempts += 1;
+        } else if pending_attempts == 0 {
+            finish(&race, None);
+            return Err(last_error);
+        }
+
+        let attempt_result = if next_attempt < endpoints.len() {
+            receiver.recv_timeout(attempt_delay).ok()
+        } else {
+            receiver.recv().ok()
+        };
+        if let Some((endpoint_index, result)) = attempt_result {
+            pending_attempts -= 1;
+            match result {
+                Ok((driver, peer_connect_custom_data)) => {
+                    finish(&race, Some(endpoint_index));
+                    return Ok(RaceWinner {
+                        endpoint_index,
+                        driver,
+                        peer_connect_custom_data,
+                    });
+                }
+                Err(e) => last_error = e,
+            }
+        }
+    }
+}
+
+/// Connect to an endpoint and run the negotiation on it, retrying on transient failures.
+///
+/// Transport errors (connection refused or reset, handshake timeout, etc) are retried
+/// after an exponential backoff, up to `policy.max_attempts` attempts. Any other error
+/// (invalid peer message, etc) stops the retries. On failure, `Error::ConnectAttemptsFailed`
+/// reports every attempt. `create_secure_layer` is called once per attempt.
+pub fn connect_with_retry<F>(
+    endpoint: &Endpoint,
+    policy: RetryPolicy,
+    max_frame_len: usize,
+    connect_custom_data: Option<&[u8]>,
+    create_secure_layer: F,
+) -> Result<RetryWinner>
+where
+    F: Fn() -> Result<SecureLayer>,
+{
+    let rng = SystemRandom::new();
+    let mut failed_attempts = Vec::new();
+    for attempt_index in 0..policy.max_attempts {
+        let begin = Instant::now();
+        let result = (|| {
+            let secure_layer = create_secure_layer()?;
+            let stream = endpoint.connect().map_err(Error::TransportError)?;
+            stream
+                .set_read_timeout(policy.handshake_timeout)
+                .and_then(|()| stream.set_write_timeout(policy.handshake_timeout))
+                .map_err(Error::TransportError)?;
+            let mut driver =
+                TransportDriver::new(secure_layer, StreamTransport::new(stream, max_frame_len));
+            let peer_connect_custom_data = driver.handshake(connect_custom_data, None)?;
+            let stream = driver.transport().stream();
+            stream
+                .set_read_timeout(None)
+                .and_then(|()| stream.set_write_timeout(None))
+                .map_err(Error::TransportError)?;
+            Ok((driver, peer_connect_custom_data))
+        })();
+        match result {
+            Ok((driver, peer_connect_custom_data)) => {
+                return Ok(RetryWinner {
+                    driver,
+                    peer_connect_custom_data,
+                    failed_attempts,
+                })
+            }
+            Err(error) => {
+                let retry = if let Error::TransportError(_) = error {
+                    attempt_index + 1 < policy.max_attempts
+                } else {
+                    false
+                };
+                let backoff = if retry {
+                    Some(jittered(&rng, policy.backoff(attempt_index), policy.jitter))
+                } else {
+                    None
+                };
+                failed_attempts.push(FailedAttempt {
+                    elapsed: begin.elapsed(),
+                    error,
+                    backoff,
+                });
+                if let Some(backoff) = backoff {
+                    std::thread::sleep(backoff);
+                } else {
+                    break;
+                }
+            }
+        }
+    }
+    Err(Error::ConnectAttemptsFailed(failed_attempts))
+}
+
+/// Pick a delay between half and all of the backoff
+fn jittered(rng: &SystemRandom, backoff: Duration, jitter: bool) -> Duration {
+    let mut random = [0u8; 4];
+    if !jitter || rng.fill(&mut random).is_err() {
+        return backoff;
+    }
+    let half = backoff / 2;
+    let extra_nanos = (half.as_nanos() * u128::from(u32::from_be_bytes(random))) >> 32;
+    half + Duration::from_nanos(u64::try_from(extra_nanos).unwrap_or(!0))
+}
+
+fn start_attempt<F>(
+    endpoint_index: usize,
+    endpoint: Endpoint,
+    max_frame_len: usize,
+    connect_custom_data: Option<Vec<u8>>,
+    create_secure_layer: Arc<F>,
+    race: Arc<Mutex<Race>>,
+    sender: Sender<AttemptResult>,
+) where
+    F: Fn() -> Result<SecureLayer> + Send + Sync + 'static,
+{
+    std::thread::spawn(move || {
+        let result = (|| {
+            let secure_layer = create_secure_layer()?;
+            let stream = endpoint.connect().map_err(Error::TransportError)?;
+            register(&race, endpoint_index, &stream)?;
+            let mut driver =
+                TransportDriver::new(secure_layer, StreamTransport::new(stream, max_frame_len));
+            let peer_connect_custom_data =
+                driver.handshake(connect_custom_data.as_ref().map(|d| &d[..]), None)?;
+            Ok((driver, peer_connect_custom_data))
+        })();
+        // The race may be over, the receiver is then dropped
+        let _ = sender.send((endpoint_index, result));
+    });
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{SecureLayerConfig, SecureLayerStatus};
+    use std::net::TcpListener;
+
+    fn create_secure_layer() -> Result<SecureLayer> {
+        SecureLayer::create(SecureLayerConfig::default(), None, None)
+    }
+
+    /// Peer accepting one connection and establishing a session on it
+    fn spawn_peer(listener: TcpListener) -> std::thread::JoinHandle<Result<Option<Vec<u8>>>> {
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().map_err(Error::TransportError)?;
+            let mut peer =
+                TransportDriver::new(create_secure_layer()?, StreamTransport::new(stream, 1_024));
+            peer.handshake(None, None)?;
+            peer.recv()
+        })
+    }
+
+    fn refused_endpoint() -> std::io::Result<Endpoint> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        Ok(Endpoint::Tcp(listener.local_addr()?))
+    }
+
+    #[test]
+    fn test_connect_racing_skips_failed_endpoints() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(Error::TransportError)?;
+        let endpoints = vec![
+            refused_endpoint().map_err(Error::TransportError)?,
+            Endpoint::Tcp(listener.local_addr().map_err(Error::TransportError)?),
+        ];
+        let peer_thread = spawn_peer(listener);
+
+        let mut winner = connect_racing(
+            &endpoints,
+            Duration::from_secs(10),
+            1_024,
+            None,
+            create_secure_layer,
+        )?;
+        assert_eq!(1, winner.endpoint_index);
+        assert_eq!(
+            SecureLayerStatus::Established,
+            winner.driver.secure_layer().status()
+        );
+        winner.driver.send(&[1, 2, 3])?;
+        assert_eq!(
+            Some(vec![1, 2, 3]),
+            peer_thread.join().expect("peer thread panicked")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_connect_racing_cancels_unresponsive_endpoint() -> Result<()> {
+        // Accept connections but never answer
+        let unresponsive_listener =
+            TcpListener::bind("127.0.0.1:0").map_err(Error::TransportError)?;
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(Error::TransportError)?;
+        let endpoints = vec![
+            Endpoint::Tcp(
+                unresponsive_listener
+                    .local_addr()
+                    .map_err(Error::TransportError)?,
+            ),
+            Endpoint::Tcp(listener.local_addr().map_err(Error::TransportError)?),
+        ];
+        let peer_thread = spawn_peer(listener);
+
+        let mut winner = connect_racing(
+            &endpoints,
+            Duration::from_millis(50),
+            1_024,
+            None,
+            create_secure_layer,
+        )?;
+        assert_eq!(1, winner.endpoint_index);
+        winner.driver.send(&[9])?;
+        assert_eq!(
+            Some(vec![9]),
+            peer_thread.join().expect("peer thread panicked")?
+        );
+
+        // The unresponsive attempt has been cancelled
+        let (mut stream, _) = unresponsive_listener
+            .accept()
+            .map_err(Error::TransportError)?;
+        let mut buffer = Vec::new();
+        std::io::Read::read_to_end(&mut stream, &mut buffer).map_err(Error::TransportError)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_retry_policy_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(500),
+            backoff_multiplier: 2,
+            jitter: false,
+            handshake_timeout: None,
+        };
+        let backoffs: Vec<u64> = (0..5)
+            .map(|i| policy.backoff(i).as_millis() as u64)
+            .collect();
+        assert_eq!(vec![100, 200, 400, 500, 500], backoffs);
+
+        let rng = SystemRandom::new();
+        let backoff = Duration::from_millis(100);
+        assert_eq!(backoff, jittered(&rng, backoff, false));
+        for _ in 0..10 {
+            let delay = jittered(&rng, backoff, true);
+            assert!(delay >= backoff / 2 && delay <= backoff);
+        }
+    }
+
+    #[test]
+    fn test_connect_with_retry_until_peer_listens() -> Result<()> {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .map_err(Error::TransportError)?;
+        let endpoint = Endpoint::Tcp(addr);
+        let peer_thread = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(150));
+            let listener = TcpListener::bind(addr).map_err(Error::TransportError)?;
+            spawn_peer(listener).join().expect("peer thread panicked")
+        });
+
+        let policy = RetryPolicy {
+            max_attempts: 50,
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(50),
+            ..RetryPolicy::default()
+        };
+        let mut winner = connect_with_retry(&endpoint, policy, 1_024, None, create_secure_layer)?;
+        assert!(!winner.failed_attempts.is_empty());
+        for failed_attempt in &winner.failed_attempts {
+            assert!(failed_attempt.backoff.is_some());
+            if let Error::TransportError(ref e) = failed_attempt.error {
+                assert_eq!(ErrorKind::ConnectionRefused, e.kind());
+            } else {
+                panic!("unexpected error");
+            }
+        }
+        winner.driver.send(&[1, 2, 3])?;
+        assert_eq!(
+            Some(vec![1, 2, 3]),
+            peer_thread.join().expect("peer thread panicked")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_connect_with_retry_handshake_timeout() -> Result<()> {
+        // Accept connections but never answer
+        let unresponsive_listener =
+            TcpListener::bind("127.0.0.1:0").map_err(Error::TransportError)?;
+        let endpoint = Endpoint::Tcp(
+            unresponsive_listener
+                .local_addr()
+                .map_err(Error::TransportError)?,
+        );
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            initial_backoff: Duration::from_millis(10),
+            handshake_timeout: Some(Duration::from_millis(50)),
+            ..RetryPolicy::default()
+        };
+        let result = connect_with_retry(&endpoint, policy, 1_024, None, create_secure_layer);
+        if let Err(Error::ConnectAttemptsFailed(failed_attempts)) = result {
+            assert_eq!(2, failed_attempts.len());
+            assert!(failed_attempts[0].backoff.is_some());
+            assert!(failed_attempts[1].backoff.is_none());
+            for failed_attempt in failed_attempts {
+                assert!(failed_attempt.elapsed >= Duration::from_millis(50));
+                if let Error::TransportError(e) = failed_attempt.error {
+                    assert!(e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut);
+                } else {
+                    panic!("unexpected error");
+                }
+            }
+        } else {
+            panic!("unexpected result");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_connect_racing_all_failed() -> Result<()> {
+        let endpoints = vec![
+            refused_endpoint().map_err(Error::TransportError)?,
+            refused_endpoint().map_err(Error::TransportError)?,
+        ];
+        let result = connect_racing(
+            &endpoints,
+            Duration::from_secs(10),
+            1_024,
+            None,
+            create_secure_layer,
+        );
+        if let Err(Error::TransportError(e)) = result {
+            assert_eq!(ErrorKind::ConnectionRefused, e.kind());
+        } else {
+            panic!("unexpected result");
+        }
+        Ok(())
+    }
+}
@@ -18,8 +18,11 @@
 /// Sig algo length
 pub const SIG_ALGO_LEN: usize = 4;
 
-/// Current version
-pub(crate) const CURRENT_VERSION: [u8; 4] = [0, 0, 0, 1];
+/// Protocol version spoken by this implementation
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// Current version (VERSION field of the frames we write)
+pub(crate) const CURRENT_VERSION: [u8; 4] = [0, 0, 0, 2];
 
 /// Challenge size
 pub(crate) const CHALLENGE_SIZE: usize = 32;
@@ -45,8 +48,50 @@ pub(crate) const CONNECT_MSG_TYPE: &[u8] = &[0, 1];
 /// Ack message type
 pub(crate) const ACK_MSG_TYPE: &[u8] = &[0, 2];
 
+/// Expiring user message type
+pub(crate) const EXPIRING_USER_MSG_TYPE: &[u8] = &[0, 3];
+
+/// Keepalive message type
+pub(crate) const KEEPALIVE_MSG_TYPE: &[u8] = &[0, 4];
+
+/// Version reject message type
+pub(crate) const VERSION_REJECT_MSG_TYPE: &[u8] = &[0, 5];
+
+/// Cover message type (dummy frame, dropped on receive)
+pub(crate) const COVER_MSG_TYPE: &[u8] = &[0, 6];
+
+/// Version size
+pub(crate) const VERSION_SIZE: usize = 4;
+
+/// Size of the additional authenticated data of encrypted messages
+pub(crate) const AAD_SIZE: usize = VERSION_SIZE + 1;
+
+/// Expiry size (milliseconds since UNIX epoch)
+pub(crate) const EXPIRY_SIZE: usize = 8;
+
+/// Size of the padding length, at the end of the plaintext of padded frames
+pub(crate) const PADDING_LEN_SIZE: usize = 2;
+
+/// Capabilities size
+pub(crate) const CAPABILITIES_SIZE: usize = 4;
+
 /// Sig pubkey begin
 pub(crate) const SIG_PUBKEY_BEGIN: usize = MSG_TYPE_LEN + EPK_SIZE + SIG_ALGO_LEN;
 
+/// Label of the derivation of the encryption key of each direction
+pub(crate) const DIRECTION_KEY_LABEL: &[u8] = b"PKSTL_DIRECTION_KEY";
+
+/// Label of the derivation of the nonce of each direction
+pub(crate) const DIRECTION_NONCE_LABEL: &[u8] = b"PKSTL_DIRECTION_NONCE";
+
+/// Label of session secret lines in key log
+pub(crate) const SESSION_SECRET_LABEL: &str = "PKSTL_SESSION_SECRET";
+
 /// Maximum amount of orphan nonces
 pub(crate) const MAX_ORPHAN_NONCES: usize = 10_000;
+
+/// Default jump-ahead margin applied to the sent nonce when restoring a nonce checkpoint
+pub const DEFAULT_NONCE_CHECKPOINT_MARGIN: u64 = 1_000;
+
+/// Size of the stack buffers of small user messages, see `SecureLayerConfig::small_msg_max_len`
+pub const SMALL_MSG_BUFFER_SIZE: usize = 1_024;
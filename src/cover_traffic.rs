@@ -0,0 +1,70 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage cover traffic of low-volume links.
+
+use ring::rand::{SecureRandom, SystemRandom};
+use std::convert::TryFrom;
+use std::time::Duration;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Cover traffic: dummy encrypted frames sent at random intervals and dropped by the peer,
+/// to hide when the application is silent
+pub struct CoverTraffic {
+    /// Minimal interval between two cover frames
+    pub min_interval: Duration,
+    /// Maximal interval between two cover frames
+    pub max_interval: Duration,
+}
+
+impl CoverTraffic {
+    /// Pick the interval until the next cover frame, uniformly between min and max
+    pub(crate) fn next_interval(self) -> Duration {
+        let mut random = [0u8; 4];
+        if self.max_interval <= self.min_interval || SystemRandom::new().fill(&mut random).is_err()
+        {
+            return self.min_interval;
+        }
+        let spread = self.max_interval - self.min_interval;
+        let extra_nanos = (spread.as_nanos() * u128::from(u32::from_be_bytes(random))) >> 32;
+        self.min_interval + Duration::from_nanos(u64::try_from(extra_nanos).unwrap_or(!0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_next_interval() {
+        let cover_traffic = CoverTraffic {
+            min_interval: Duration::from_secs(1),
+            max_interval: Duration::from_secs(3),
+        };
+        for _ in 0..100 {
+            let interval = cover_traffic.next_interval();
+            assert!(interval >= cover_traffic.min_interval);
+            assert!(interval <= cover_traffic.max_interval);
+        }
+
+        // Fixed interval
+        let cover_traffic = CoverTraffic {
+            min_interval: Duration::from_secs(2),
+            max_interval: Duration::from_secs(1),
+        };
+        assert_eq!(Duration::from_secs(2), cover_traffic.next_interval());
+    }
+}
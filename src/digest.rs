@@ -15,6 +15,72 @@
 
 //! Manage cryptographic digest operations.
 
-pub(crate) fn sha256(datas: &[u8]) -> impl AsRef<[u8]> {
-    ring::digest::digest(&ring::digest::SHA256, datas)
+use crate::constants::HASH_SIZE;
+use std::any::Any;
+use std::fmt::Debug;
+
+/// Digest backend (Sha256), for example a hardware SHA engine
+pub trait Digest: Any + Debug + Send + Sync {
+    /// Compute Sha256 hash
+    fn sha256(&self, datas: &[u8]) -> [u8; HASH_SIZE];
+}
+
+impl PartialEq for dyn Digest {
+    /// Digest backends are equal if they are the same instance
+    fn eq(&self, other: &Self) -> bool {
+        let self_ptr: *const dyn Digest = self;
+        let other_ptr: *const dyn Digest = other;
+        self_ptr as *const u8 == other_ptr as *const u8 && self.type_id() == other.type_id()
+    }
+}
+
+/// Digest backend of ring crate
+#[derive(Clone, Copy, Debug, Default)]
+pub struct RingDigest;
+
+impl Digest for RingDigest {
+    fn sha256(&self, datas: &[u8]) -> [u8; HASH_SIZE] {
+        let mut hash = [0u8; HASH_SIZE];
+        hash.copy_from_slice(ring::digest::digest(&ring::digest::SHA256, datas).as_ref());
+        hash
+    }
+}
+
+/// Default digest backend
+pub static RING_DIGEST: RingDigest = RingDigest;
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_ring_digest() {
+        assert_eq!(
+            [
+                0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae,
+                0x22, 0x23, 0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61,
+                0xf2, 0x00, 0x15, 0xad,
+            ],
+            RING_DIGEST.sha256(b"abc")
+        );
+    }
+
+    #[test]
+    fn test_digest_backends_eq() {
+        #[derive(Debug)]
+        struct OtherDigest(u8);
+        impl Digest for OtherDigest {
+            fn sha256(&self, datas: &[u8]) -> [u8; HASH_SIZE] {
+                RING_DIGEST.sha256(datas)
+            }
+        }
+        static OTHER_DIGEST: OtherDigest = OtherDigest(0);
+
+        let digest: &dyn Digest = &RING_DIGEST;
+        let same_digest: &dyn Digest = &RING_DIGEST;
+        let other_digest: &dyn Digest = &OTHER_DIGEST;
+        assert!(digest == same_digest);
+        assert!(digest != other_digest);
+    }
 }
@@ -0,0 +1,223 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage base58 and multibase encodings of keys and fingerprints.
+
+use crate::{Error, Result};
+
+/// Base58 alphabet (same as Bitcoin and Duniter)
+const BASE58_ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// Base16 alphabet (lowercase)
+const BASE16_ALPHABET: &[u8; 16] = b"0123456789abcdef";
+
+/// Multibase prefix of base16 (lowercase) encoding
+const MULTIBASE_BASE16_PREFIX: char = 'f';
+
+/// Multibase prefix of base58btc encoding
+const MULTIBASE_BASE58BTC_PREFIX: char = 'z';
+
+/// Multibase encoding
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MultibaseEncoding {
+    /// Hexadecimal lowercase (prefix `f`)
+    Base16,
+    /// Base58 with Bitcoin alphabet (prefix `z`)
+    Base58Btc,
+}
+
+/// Encode bytes in base58
+pub fn to_base58(bytes: &[u8]) -> String {
+    let zeros = bytes.iter().take_while(|b| **b == 0).count();
+
+    // Base58 digits in little endian order
+    let mut digits: Vec<u8> = Vec::with_capacity(bytes.len() * 138 / 100 + 1);
+    for byte in &bytes[zeros..] {
+        let mut carry = u32::from(*byte);
+        for digit in digits.iter_mut() {
+            carry += u32::from(*digit) << 8;
+            *digit = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry > 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+
+    let mut encoded = String::with_capacity(zeros + digits.len());
+    for _ in 0..zeros {
+        encoded.push(BASE58_ALPHABET[0] as char);
+    }
+    for digit in digits.iter().rev() {
+        encoded.push(BASE58_ALPHABET[*digit as usize] as char);
+    }
+    encoded
+}
+
+/// Decode base58 string
+pub fn from_base58(encoded: &str) -> Result<Vec<u8>> {
+    let zeros = encoded
+        .bytes()
+        .take_while(|c| *c == BASE58_ALPHABET[0])
+        .count();
+
+    // Bytes in little endian order
+    let mut bytes: Vec<u8> = Vec::with_capacity(encoded.len());
+    for c in encoded.bytes().skip(zeros) {
+        let mut carry = BASE58_ALPHABET
+            .iter()
+            .position(|a| *a == c)
+            .ok_or(Error::InvalidBase58String)? as u32;
+        for byte in bytes.iter_mut() {
+            carry += u32::from(*byte) * 58;
+            *byte = (carry & 0xFF) as u8;
+            carry >>= 8;
+        }
+        while carry > 0 {
+            bytes.push((carry & 0xFF) as u8);
+            carry >>= 8;
+        }
+    }
+
+    let mut decoded = vec![0u8; zeros];
+    decoded.extend(bytes.iter().rev());
+    Ok(decoded)
+}
+
+/// Encode bytes in multibase
+pub fn to_multibase(bytes: &[u8], encoding: MultibaseEncoding) -> String {
+    match encoding {
+        MultibaseEncoding::Base16 => {
+            let mut encoded = String::with_capacity(1 + bytes.len() * 2);
+            encoded.push(MULTIBASE_BASE16_PREFIX);
+            encoded.push_str(&to_base16(bytes));
+            encoded
+        }
+        MultibaseEncoding::Base58Btc => {
+            let mut encoded = String::with_capacity(1 + bytes.len() * 138 / 100 + 1);
+            encoded.push(MULTIBASE_BASE58BTC_PREFIX);
+            encoded.push_str(&to_base58(bytes));
+            encoded
+        }
+    }
+}
+
+/// Decode multibase string
+pub fn from_multibase(encoded: &str) -> Result<(Vec<u8>, MultibaseEncoding)> {
+    let mut chars = encoded.chars();
+    match chars.next() {
+        Some(MULTIBASE_BASE16_PREFIX) => {
+            Ok((from_base16(chars.as_str())?, MultibaseEncoding::Base16))
+        }
+        Some(MULTIBASE_BASE58BTC_PREFIX) => {
+            Ok((from_base58(chars.as_str())?, MultibaseEncoding::Base58Btc))
+        }
+        _ => Err(Error::UnsupportedMultibaseEncoding),
+    }
+}
+
+pub(crate) fn to_base16(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        encoded.push(BASE16_ALPHABET[(byte >> 4) as usize] as char);
+        encoded.push(BASE16_ALPHABET[(byte & 0x0F) as usize] as char);
+    }
+    encoded
+}
+
+pub(crate) fn from_base16(encoded: &str) -> Result<Vec<u8>> {
+    let encoded = encoded.as_bytes();
+    if encoded.len() % 2 == 1 {
+        return Err(Error::InvalidBase16String);
+    }
+
+    let mut decoded = Vec::with_capacity(encoded.len() / 2);
+    for pair in encoded.chunks(2) {
+        let high = base16_value(pair[0])?;
+        let low = base16_value(pair[1])?;
+        decoded.push((high << 4) | low);
+    }
+    Ok(decoded)
+}
+
+#[inline]
+fn base16_value(c: u8) -> Result<u8> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        _ => Err(Error::InvalidBase16String),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_base58() -> Result<()> {
+        assert_eq!("", to_base58(&[]));
+        assert_eq!("11", to_base58(&[0, 0]));
+        assert_eq!("5S", to_base58(&[1, 1]));
+        assert_eq!("StV1DL6CwTryKyV", to_base58(b"hello world"));
+        assert_eq!("1112", to_base58(&[0, 0, 0, 1]));
+
+        assert_eq!(b"hello world".to_vec(), from_base58("StV1DL6CwTryKyV")?);
+        assert_eq!(vec![0, 0, 0, 1], from_base58("1112")?);
+        assert_eq!(Vec::<u8>::new(), from_base58("")?);
+
+        let bytes = [42u8; 32];
+        assert_eq!(bytes.to_vec(), from_base58(&to_base58(&bytes))?);
+
+        // 0, O, I and l are not in the alphabet
+        if let Err(Error::InvalidBase58String) = from_base58("0OIl") {
+            Ok(())
+        } else {
+            panic!("unexpected result")
+        }
+    }
+
+    #[test]
+    fn test_multibase() -> Result<()> {
+        assert_eq!(
+            "f00ff10",
+            to_multibase(&[0, 255, 16], MultibaseEncoding::Base16)
+        );
+        assert_eq!(
+            "zStV1DL6CwTryKyV",
+            to_multibase(b"hello world", MultibaseEncoding::Base58Btc)
+        );
+
+        assert_eq!(
+            (vec![0, 255, 16], MultibaseEncoding::Base16),
+            from_multibase("f00ff10")?
+        );
+        assert_eq!(
+            (b"hello world".to_vec(), MultibaseEncoding::Base58Btc),
+            from_multibase("zStV1DL6CwTryKyV")?
+        );
+
+        if let Err(Error::InvalidBase16String) = from_multibase("f0") {
+        } else {
+            panic!("unexpected result")
+        }
+        if let Err(Error::UnsupportedMultibaseEncoding) = from_multibase("mAP8Q") {
+            Ok(())
+        } else {
+            panic!("unexpected result")
+        }
+    }
+}
@@ -18,8 +18,11 @@
 mod chacha20_poly1305_aead;
 
 use crate::agreement::{SharedSecret, SharedSecretLen};
-use crate::Result;
-use std::io::{BufWriter, Read, Write};
+use crate::constants::{AAD_SIZE, CURRENT_VERSION, VERSION_SIZE};
+use crate::{Error, Result};
+use std::io::Write;
+#[cfg(test)]
+use std::io::{BufWriter, Read};
 
 /// Encryption algorithm
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -35,55 +38,176 @@ impl Default for EncryptAlgo {
 }
 
 impl EncryptAlgo {
+    /// All encryption algorithms supported by this implementation
+    pub const SUPPORTED: &'static [EncryptAlgo] = &[EncryptAlgo::Chacha20Poly1305Aead];
+
     pub(crate) fn shared_secret_len(self) -> SharedSecretLen {
         match self {
             Self::Chacha20Poly1305Aead => SharedSecretLen::B48,
         }
     }
+    #[inline]
+    pub(crate) fn tag_len(self) -> usize {
+        match self {
+            Self::Chacha20Poly1305Aead => chacha20_poly1305_aead::CHACHA20_TAG_SIZE,
+        }
+    }
+    /// Known-answer test of the algorithm, returns the failed check
+    pub(crate) fn known_answer_test(self) -> std::result::Result<(), &'static str> {
+        match self {
+            Self::Chacha20Poly1305Aead => chacha20_poly1305_aead::known_answer_test(),
+        }
+    }
+}
+
+/// Direction of an encrypted frame, given by the role of its sender: each peer sends both
+/// CONNECT and ACK messages, so roles are given by the order of the ephemeral public keys.
+/// Each direction has its own encryption key and is authenticated by the AAD.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Direction {
+    /// Sent by the peer with the lowest ephemeral public key
+    FromLowestEpk,
+    /// Sent by the peer with the highest ephemeral public key
+    FromHighestEpk,
+}
+
+impl Direction {
+    /// Direction of the frames sent by `sender_epk` to `receiver_epk`
+    pub(crate) fn new(sender_epk: &[u8], receiver_epk: &[u8]) -> Self {
+        if sender_epk < receiver_epk {
+            Direction::FromLowestEpk
+        } else {
+            Direction::FromHighestEpk
+        }
+    }
+    #[inline]
+    fn flag(self) -> u8 {
+        match self {
+            Direction::FromLowestEpk => 0,
+            Direction::FromHighestEpk => 1,
+        }
+    }
+    #[inline]
+    fn reverse(self) -> Self {
+        match self {
+            Direction::FromLowestEpk => Direction::FromHighestEpk,
+            Direction::FromHighestEpk => Direction::FromLowestEpk,
+        }
+    }
+}
+
+/// Additional authenticated data of an encrypted frame: protocol version and direction flag
+/// (since version 2). The message type is authenticated as part of the encrypted data.
+fn aad(direction: Direction) -> [u8; AAD_SIZE] {
+    let mut aad = [0u8; AAD_SIZE];
+    aad[..VERSION_SIZE].copy_from_slice(&CURRENT_VERSION);
+    aad[VERSION_SIZE] = direction.flag();
+    aad
 }
 
 #[derive(Clone, Debug)]
-pub enum EncryptAlgoWithSecretKey {
+pub struct EncryptAlgoWithSecretKey {
+    /// Direction of the frames we encrypt, the frames we decrypt have the opposite one
+    pub(crate) outgoing_direction: Direction,
+    /// Secret keys of the frames sent from the lowest and the highest ephemeral public key
+    secret_keys: [SecretKey; 2],
+}
+
+#[derive(Clone, Debug)]
+enum SecretKey {
     Chacha20Poly1305Aead(chacha20_poly1305_aead::SecretKey),
 }
 
 impl EncryptAlgoWithSecretKey {
-    pub fn build(encrypt_algo: EncryptAlgo, shared_secret: SharedSecret) -> Self {
-        match encrypt_algo {
+    #[inline]
+    pub(crate) fn tag_len(&self) -> usize {
+        match self.secret_keys[0] {
+            SecretKey::Chacha20Poly1305Aead(_) => chacha20_poly1305_aead::CHACHA20_TAG_SIZE,
+        }
+    }
+    #[inline]
+    fn secret_key(&self, direction: Direction) -> &SecretKey {
+        &self.secret_keys[direction.flag() as usize]
+    }
+    pub(crate) fn build(
+        encrypt_algo: EncryptAlgo,
+        shared_secret: SharedSecret,
+        outgoing_direction: Direction,
+        key_context: &[u8],
+    ) -> Result<Self> {
+        let secret_keys = match encrypt_algo {
             EncryptAlgo::Chacha20Poly1305Aead => {
                 if let SharedSecret::B48(seed) = shared_secret {
-                    Self::Chacha20Poly1305Aead(chacha20_poly1305_aead::SecretKey::new(&seed))
+                    let secret_key = |direction: Direction| {
+                        SecretKey::Chacha20Poly1305Aead(chacha20_poly1305_aead::SecretKey::new(
+                            &seed,
+                            direction.flag(),
+                            key_context,
+                        ))
+                    };
+                    [
+                        secret_key(Direction::FromLowestEpk),
+                        secret_key(Direction::FromHighestEpk),
+                    ]
                 } else {
-                    panic!("dev error: EncryptAlgo::Chacha20Poly1305Aead must request shared secret of 48 bytes!")
+                    return Err(Error::InternalError(
+                        "EncryptAlgo::Chacha20Poly1305Aead must request shared secret of 48 bytes",
+                    ));
                 }
             }
-        }
+        };
+        Ok(EncryptAlgoWithSecretKey {
+            outgoing_direction,
+            secret_keys,
+        })
     }
 }
 
-#[inline]
+/// Decrypt incoming data
 pub(crate) fn decrypt<W: Write>(
     encrypted_data: &[u8],
     algo_with_secret_key: &EncryptAlgoWithSecretKey,
-    writer: &mut BufWriter<W>,
+    writer: &mut W,
 ) -> Result<()> {
-    match algo_with_secret_key {
-        EncryptAlgoWithSecretKey::Chacha20Poly1305Aead(secret_key) => {
-            chacha20_poly1305_aead::decrypt(encrypted_data, secret_key, writer)
+    let direction = algo_with_secret_key.outgoing_direction.reverse();
+    let aad = aad(direction);
+    match algo_with_secret_key.secret_key(direction) {
+        SecretKey::Chacha20Poly1305Aead(secret_key) => {
+            chacha20_poly1305_aead::decrypt(encrypted_data, secret_key, &aad, writer)
         }
     }
 }
 
-/// Encrypt data
+/// Encrypt outgoing data
+#[cfg(test)]
 #[inline]
 pub(crate) fn encrypt<R: Read, W: Write>(
     reader: &mut R,
     algo_with_secret_key: &EncryptAlgoWithSecretKey,
     writer: &mut BufWriter<W>,
 ) -> Result<()> {
-    match algo_with_secret_key {
-        EncryptAlgoWithSecretKey::Chacha20Poly1305Aead(secret_key) => {
-            chacha20_poly1305_aead::encrypt(reader, secret_key, writer)
+    let direction = algo_with_secret_key.outgoing_direction;
+    let aad = aad(direction);
+    match algo_with_secret_key.secret_key(direction) {
+        SecretKey::Chacha20Poly1305Aead(secret_key) => {
+            chacha20_poly1305_aead::encrypt(reader, secret_key, &aad, writer)
+        }
+    }
+}
+
+/// Encrypt outgoing data into `output`, without allocation.
+/// Returns the written length.
+#[inline]
+pub(crate) fn encrypt_into(
+    data: &[u8],
+    algo_with_secret_key: &EncryptAlgoWithSecretKey,
+    output: &mut [u8],
+) -> Result<usize> {
+    let direction = algo_with_secret_key.outgoing_direction;
+    let aad = aad(direction);
+    match algo_with_secret_key.secret_key(direction) {
+        SecretKey::Chacha20Poly1305Aead(secret_key) => {
+            chacha20_poly1305_aead::encrypt_into(data, secret_key, &aad, output)
         }
     }
 }
@@ -96,7 +220,35 @@ pub mod tests {
 
     pub fn gen_random_encrypt_algo_with_secret() -> EncryptAlgoWithSecretKey {
         let random_shared_secret = SharedSecret::B48(random_seed_48());
-        EncryptAlgoWithSecretKey::build(EncryptAlgo::Chacha20Poly1305Aead, random_shared_secret)
+        EncryptAlgoWithSecretKey::build(
+            EncryptAlgo::Chacha20Poly1305Aead,
+            random_shared_secret,
+            Direction::FromLowestEpk,
+            b"",
+        )
+        .expect("48 bytes shared secret")
+    }
+
+    /// Same secret key, seen by the peer
+    pub fn peer_encrypt_algo_with_secret(
+        encrypt_algo_with_secret: &EncryptAlgoWithSecretKey,
+    ) -> EncryptAlgoWithSecretKey {
+        let mut peer_encrypt_algo_with_secret = encrypt_algo_with_secret.clone();
+        peer_encrypt_algo_with_secret.outgoing_direction =
+            encrypt_algo_with_secret.outgoing_direction.reverse();
+        peer_encrypt_algo_with_secret
+    }
+
+    #[test]
+    fn test_direction() {
+        assert_eq!(Direction::FromLowestEpk, Direction::new(&[0, 1], &[1, 0]));
+        assert_eq!(Direction::FromHighestEpk, Direction::new(&[1, 0], &[0, 1]));
+    }
+
+    #[test]
+    fn test_aad() {
+        assert_eq!([0, 0, 0, 2, 1], aad(Direction::FromHighestEpk));
+        assert_eq!([0, 0, 0, 2, 0], aad(Direction::FromLowestEpk));
     }
 
     #[test]
@@ -105,15 +257,20 @@ pub mod tests {
     }
 
     #[test]
-    #[should_panic(
-        expected = "dev error: EncryptAlgo::Chacha20Poly1305Aead must request shared secret of 48 bytes!"
-    )]
     fn test_encryption_with_wrong_shared_secret_len() {
         let shared_secret = SharedSecret::B32(Seed32::new([
             0u8, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
             24, 25, 26, 27, 28, 29, 30, 31,
         ]));
-        EncryptAlgoWithSecretKey::build(EncryptAlgo::Chacha20Poly1305Aead, shared_secret);
+        if let Err(Error::InternalError(_)) = EncryptAlgoWithSecretKey::build(
+            EncryptAlgo::Chacha20Poly1305Aead,
+            shared_secret,
+            Direction::FromLowestEpk,
+            b"",
+        ) {
+        } else {
+            panic!("Expected error InternalError !")
+        }
     }
 
     #[test]
@@ -125,8 +282,14 @@ pub mod tests {
             24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45,
             46, 47,
         ]));
-        let encrypt_algo_with_secret_key =
-            EncryptAlgoWithSecretKey::build(EncryptAlgo::Chacha20Poly1305Aead, shared_secret);
+        let encrypt_algo_with_secret_key = EncryptAlgoWithSecretKey::build(
+            EncryptAlgo::Chacha20Poly1305Aead,
+            shared_secret,
+            Direction::FromLowestEpk,
+            b"",
+        )?;
+        let peer_encrypt_algo_with_secret_key =
+            peer_encrypt_algo_with_secret(&encrypt_algo_with_secret_key);
 
         let mut encrypted_data = BufWriter::new(Vec::with_capacity(data.len()));
 
@@ -139,11 +302,22 @@ pub mod tests {
             .into_inner()
             .expect("fail to flush encrypt buffer");
 
+        // A reflected frame is rejected
         let mut decrypted_data = BufWriter::new(Vec::with_capacity(data.len()));
-        decrypt(
+        if let Err(Error::FailToDecryptData(_)) = decrypt(
             &encrypted_data,
             &encrypt_algo_with_secret_key,
             &mut decrypted_data,
+        ) {
+        } else {
+            panic!("Expected error FailToDecryptData !")
+        }
+
+        let mut decrypted_data = BufWriter::new(Vec::with_capacity(data.len()));
+        decrypt(
+            &encrypted_data,
+            &peer_encrypt_algo_with_secret_key,
+            &mut decrypted_data,
         )?;
         let decrypted_data = decrypted_data
             .into_inner()
@@ -15,12 +15,16 @@
 
 //! Manage cryptographic encryption operations with Chacha20Poly1305Aead algorithm.
 
+use crate::constants::{DIRECTION_KEY_LABEL, DIRECTION_NONCE_LABEL};
 use crate::seeds::Seed48;
 use crate::{Error, Result};
-use std::io::{BufWriter, Read, Write};
+use ring::hmac;
+use std::io::Write;
+#[cfg(test)]
+use std::io::{BufWriter, Read};
 use zeroize::Zeroize;
 
-const CHACHA20_TAG_SIZE: usize = 16;
+pub(crate) const CHACHA20_TAG_SIZE: usize = 16;
 
 #[derive(Clone, Debug, Default, Zeroize)]
 #[zeroize(drop)]
@@ -28,17 +32,29 @@ const CHACHA20_TAG_SIZE: usize = 16;
 pub struct SecretKey {
     key: [u8; 32],
     nonce: [u8; 12],
-    aad: [u8; 4],
 }
 
 impl SecretKey {
-    /// Create new secret key
-    pub fn new(seed: &Seed48) -> SecretKey {
+    /// Create new secret key of the frames sent in direction `direction_flag`,
+    /// bound to the application context `key_context` (key schedule of protocol version 2)
+    pub fn new(seed: &Seed48, direction_flag: u8, key_context: &[u8]) -> SecretKey {
         let mut secret_key = SecretKey::default();
 
-        secret_key.key.copy_from_slice(&seed.as_ref()[0..32]);
-        secret_key.nonce.copy_from_slice(&seed.as_ref()[32..44]);
-        secret_key.aad.copy_from_slice(&seed.as_ref()[44..48]);
+        // Each direction has its own key and nonce, so a frame cannot be reflected to its sender
+        let hmac_key = hmac::Key::new(hmac::HMAC_SHA256, &seed.as_ref()[0..32]);
+        let derive = |label: &[u8]| {
+            let mut hmac_ctx = hmac::Context::with_key(&hmac_key);
+            hmac_ctx.update(label);
+            hmac_ctx.update(&[direction_flag]);
+            hmac_ctx.update(key_context);
+            hmac_ctx.sign()
+        };
+        secret_key
+            .key
+            .copy_from_slice(derive(DIRECTION_KEY_LABEL).as_ref());
+        secret_key
+            .nonce
+            .copy_from_slice(&derive(DIRECTION_NONCE_LABEL).as_ref()[..12]);
 
         secret_key
     }
@@ -48,14 +64,20 @@ impl SecretKey {
 pub fn decrypt<W: Write>(
     encrypted_data: &[u8],
     secret_key: &SecretKey,
-    writer: &mut BufWriter<W>,
+    aad: &[u8],
+    writer: &mut W,
 ) -> Result<()> {
+    if encrypted_data.len() < CHACHA20_TAG_SIZE {
+        return Err(Error::FailToDecryptData(
+            chacha20_poly1305_aead::DecryptError::TagMismatch,
+        ));
+    }
     let payload_len = encrypted_data.len() - CHACHA20_TAG_SIZE;
 
     chacha20_poly1305_aead::decrypt(
         &secret_key.key,
         &secret_key.nonce,
-        &secret_key.aad,
+        aad,
         &encrypted_data[0..payload_len],
         &encrypted_data[payload_len..],
         writer,
@@ -66,15 +88,17 @@ pub fn decrypt<W: Write>(
 }
 
 /// Encrypt data
+#[cfg(test)]
 pub fn encrypt<R: Read, W: Write>(
     reader: &mut R,
     secret_key: &SecretKey,
+    aad: &[u8],
     writer: &mut BufWriter<W>,
 ) -> Result<()> {
     let tag = chacha20_poly1305_aead::encrypt_read(
         &secret_key.key,
         &secret_key.nonce,
-        &secret_key.aad,
+        aad,
         reader,
         writer,
     )
@@ -87,6 +111,91 @@ pub fn encrypt<R: Read, W: Write>(
     Ok(())
 }
 
+/// Encrypt data into `output`, followed by the tag, returns the written length
+pub fn encrypt_into(
+    data: &[u8],
+    secret_key: &SecretKey,
+    aad: &[u8],
+    output: &mut [u8],
+) -> Result<usize> {
+    let encrypted_len = data.len() + CHACHA20_TAG_SIZE;
+    if output.len() < encrypted_len {
+        return Err(Error::FailToEncryptData(
+            std::io::ErrorKind::WriteZero.into(),
+        ));
+    }
+    let (ciphertext, tag_output) = output[..encrypted_len].split_at_mut(data.len());
+
+    let tag = chacha20_poly1305_aead::encrypt(
+        &secret_key.key,
+        &secret_key.nonce,
+        aad,
+        data,
+        &mut &mut ciphertext[..],
+    )
+    .map_err(Error::FailToEncryptData)?;
+    tag_output.copy_from_slice(&tag);
+
+    Ok(encrypted_len)
+}
+
+/// Key of the known-answer test (RFC 7539 section 2.8.2)
+const KAT_KEY: [u8; 32] = [
+    0x80, 0x81, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x8b, 0x8c, 0x8d, 0x8e, 0x8f,
+    0x90, 0x91, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98, 0x99, 0x9a, 0x9b, 0x9c, 0x9d, 0x9e, 0x9f,
+];
+/// Nonce of the known-answer test (constant `07 00 00 00` followed by the IV)
+const KAT_NONCE: [u8; 12] = [
+    0x07, 0x00, 0x00, 0x00, 0x40, 0x41, 0x42, 0x43, 0x44, 0x45, 0x46, 0x47,
+];
+/// AAD of the known-answer test
+const KAT_AAD: [u8; 12] = [
+    0x50, 0x51, 0x52, 0x53, 0xc0, 0xc1, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+];
+/// Plaintext of the known-answer test
+const KAT_PLAINTEXT: &[u8] = b"Ladies and Gentlemen of the class of '99: \
+If I could offer you only one tip for the future, sunscreen would be it.";
+/// Ciphertext followed by the tag of the known-answer test
+const KAT_CIPHERTEXT: [u8; 130] = [
+    0xd3, 0x1a, 0x8d, 0x34, 0x64, 0x8e, 0x60, 0xdb, 0x7b, 0x86, 0xaf, 0xbc, 0x53, 0xef, 0x7e, 0xc2,
+    0xa4, 0xad, 0xed, 0x51, 0x29, 0x6e, 0x08, 0xfe, 0xa9, 0xe2, 0xb5, 0xa7, 0x36, 0xee, 0x62, 0xd6,
+    0x3d, 0xbe, 0xa4, 0x5e, 0x8c, 0xa9, 0x67, 0x12, 0x82, 0xfa, 0xfb, 0x69, 0xda, 0x92, 0x72, 0x8b,
+    0x1a, 0x71, 0xde, 0x0a, 0x9e, 0x06, 0x0b, 0x29, 0x05, 0xd6, 0xa5, 0xb6, 0x7e, 0xcd, 0x3b, 0x36,
+    0x92, 0xdd, 0xbd, 0x7f, 0x2d, 0x77, 0x8b, 0x8c, 0x98, 0x03, 0xae, 0xe3, 0x28, 0x09, 0x1b, 0x58,
+    0xfa, 0xb3, 0x24, 0xe4, 0xfa, 0xd6, 0x75, 0x94, 0x55, 0x85, 0x80, 0x8b, 0x48, 0x31, 0xd7, 0xbc,
+    0x3f, 0xf4, 0xde, 0xf0, 0x8e, 0x4b, 0x7a, 0x9d, 0xe5, 0x76, 0xd2, 0x65, 0x86, 0xce, 0xc6, 0x4b,
+    0x61, 0x16, 0x1a, 0xe1, 0x0b, 0x59, 0x4f, 0x09, 0xe2, 0x6a, 0x7e, 0x90, 0x2e, 0xcb, 0xd0, 0x60,
+    0x06, 0x91,
+];
+
+/// Known-answer test: encrypt and decrypt the RFC 7539 test vector,
+/// and reject it once tampered. Returns the failed check.
+pub(crate) fn known_answer_test() -> std::result::Result<(), &'static str> {
+    let secret_key = SecretKey {
+        key: KAT_KEY,
+        nonce: KAT_NONCE,
+    };
+
+    let mut encrypted_data = [0u8; 130];
+    match encrypt_into(KAT_PLAINTEXT, &secret_key, &KAT_AAD, &mut encrypted_data) {
+        Ok(encrypted_len) if encrypted_data[..encrypted_len] == KAT_CIPHERTEXT[..] => {}
+        _ => return Err("unexpected ciphertext"),
+    }
+
+    let mut decrypted_data = Vec::with_capacity(KAT_PLAINTEXT.len());
+    match decrypt(&KAT_CIPHERTEXT, &secret_key, &KAT_AAD, &mut decrypted_data) {
+        Ok(()) if decrypted_data == KAT_PLAINTEXT => {}
+        _ => return Err("unexpected plaintext"),
+    }
+
+    let mut tampered_data = KAT_CIPHERTEXT;
+    tampered_data[0] ^= 1;
+    if decrypt(&tampered_data, &secret_key, &KAT_AAD, &mut Vec::new()).is_ok() {
+        return Err("tampered ciphertext accepted");
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -97,21 +206,22 @@ mod tests {
     fn test_encryption() -> Result<()> {
         let data = b"My secret data".to_vec();
 
-        let secret_key = SecretKey::new(&Seed48::new([
+        let seed = Seed48::new([
             0u8, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
             24, 25, 26, 27, 28, 29, 30, 31, 32, 33, 34, 35, 36, 37, 38, 39, 40, 41, 42, 43, 44, 45,
             46, 47,
-        ]));
+        ]);
+        let secret_key = SecretKey::new(&seed, 0, b"");
 
         let mut encrypted_data = BufWriter::new(Vec::with_capacity(data.len()));
 
-        encrypt(&mut &data[..], &secret_key, &mut encrypted_data)?;
+        encrypt(&mut &data[..], &secret_key, &[0, 1], &mut encrypted_data)?;
         let encrypted_data = encrypted_data
             .into_inner()
             .expect("fail to flush encrypt buffer");
 
         let mut decrypted_data = BufWriter::new(Vec::with_capacity(data.len()));
-        decrypt(&encrypted_data, &secret_key, &mut decrypted_data)?;
+        decrypt(&encrypted_data, &secret_key, &[0, 1], &mut decrypted_data)?;
         let decrypted_data = decrypted_data
             .into_inner()
             .expect("fail to flush decrypt buffer");
@@ -121,6 +231,70 @@ mod tests {
 
         assert_eq!(data, decrypted_data);
 
+        // The key and the nonce of the other direction are different
+        let other_direction_key = SecretKey::new(&seed, 1, b"");
+        assert_ne!(secret_key.key, other_direction_key.key);
+        assert_ne!(secret_key.nonce, other_direction_key.nonce);
+        let mut decrypted_data = BufWriter::new(Vec::with_capacity(data.len()));
+        if let Err(Error::FailToDecryptData(_)) = decrypt(
+            &encrypted_data,
+            &other_direction_key,
+            &[0, 1],
+            &mut decrypted_data,
+        ) {
+        } else {
+            panic!("Expected error FailToDecryptData !")
+        }
+
+        // The key of another application context is different
+        let other_context_key = SecretKey::new(&seed, 0, b"currency:g1");
+        let mut decrypted_data = BufWriter::new(Vec::with_capacity(data.len()));
+        if let Err(Error::FailToDecryptData(_)) = decrypt(
+            &encrypted_data,
+            &other_context_key,
+            &[0, 1],
+            &mut decrypted_data,
+        ) {
+        } else {
+            panic!("Expected error FailToDecryptData !")
+        }
+
+        // The additional authenticated data must match
+        let mut decrypted_data = BufWriter::new(Vec::with_capacity(data.len()));
+        if let Err(Error::FailToDecryptData(_)) =
+            decrypt(&encrypted_data, &secret_key, &[0, 2], &mut decrypted_data)
+        {
+        } else {
+            panic!("Expected error FailToDecryptData !")
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_encryption_into_slice() -> Result<()> {
+        let data = b"My secret data".to_vec();
+        let secret_key = SecretKey::new(&Seed48::new([7u8; 48]), 0, b"");
+
+        let mut encrypted_data = BufWriter::new(Vec::with_capacity(data.len()));
+        encrypt(&mut &data[..], &secret_key, &[0, 1], &mut encrypted_data)?;
+        let encrypted_data = encrypted_data
+            .into_inner()
+            .expect("fail to flush encrypt buffer");
+
+        // Same output as the streaming encryption
+        let mut output = [0u8; 64];
+        let len = encrypt_into(&data, &secret_key, &[0, 1], &mut output)?;
+        assert_eq!(&encrypted_data[..], &output[..len]);
+
+        // Output too short for the tag
+        if let Err(Error::FailToEncryptData(_)) =
+            encrypt_into(&data, &secret_key, &[0, 1], &mut output[..len - 1])
+        {
+        } else {
+            panic!("Expected error FailToEncryptData !")
+        }
+
         Ok(())
     }
 }
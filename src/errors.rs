@@ -24,6 +24,9 @@ pub enum Error {
     ConnectionHadFail,
     /// Connect msg already written
     ConnectMsgAlreadyWritten,
+    #[cfg(feature = "zip-sign")]
+    /// Connection attempts failed, the last attempt holds the final error
+    ConnectAttemptsFailed(Vec<crate::connector::FailedAttempt>),
     /// Fail to compute agreement
     FailToComputeAgreement,
     /// Fail to decrypt data
@@ -34,21 +37,64 @@ pub enum Error {
     FailToGenEphemerKeyPair,
     /// Fail to generate ephemeral public key
     FailToGenEphemerPubKey,
+    /// Fail to generate random seed
+    FailToGenRandomSeed,
     /// Fail to generate signature key pair
     FailtoGenSigKeyPair,
+    /// Frame larger than the maximum frame length
+    FrameTooLarge {
+        /// Maximum frame length
+        max: usize,
+        /// Frame length
+        got: usize,
+    },
+    /// Frame shorter than the length announced by its header (or than the smallest frame)
+    FrameTruncated {
+        /// Expected minimal length
+        expected: usize,
+        /// Frame length
+        got: usize,
+    },
     /// Forbidden to change the configuration after the security layer has been cloned
     ForbidChangeConfAfterClone,
     /// Forbidden to write the ACK message now
     ForbidWriteAckMsgNow,
+    /// Forbidden to write a message after the secure layer has been closed
+    ForbidWriteAfterClose,
+    /// Handshake frame rejected (the detailed reason is only available in the failure status)
+    HandshakeRejected,
+    /// Inconsistent internal state (bug in the secure layer, which must be dropped)
+    InternalError(&'static str),
+    /// Invalid base16 string
+    InvalidBase16String,
+    /// Invalid base58 string
+    InvalidBase58String,
+    /// Inconsistent options in the configuration
+    InvalidConfig(&'static str),
+    /// Invalid session secret or key log line
+    InvalidSessionSecret,
+    /// Memory budget exceeded, the incoming message has been dropped
+    MemoryBudgetExceeded,
     /// Message must be signed
     MessageMustBeSigned,
     /// The negotiation must have been successful
     NegoMustHaveBeenSuccessful,
+    /// All reserved nonces have been used
+    NoncesExhausted,
+    /// The peer does not have the capability required by this operation
+    PeerUnsupported(crate::capabilities::Capability),
+    /// Outgoing rate limit exceeded, the message can be written from `ready_at`
+    RateLimited {
+        /// Time from which the message can be written
+        ready_at: std::time::SystemTime,
+    },
     #[cfg(feature = "ser")]
     /// Error in serialization/deserialization
     SerdeError(crate::complete::serde::SerdeError),
     /// Serialization error
     SerializationError(std::io::Error),
+    /// Error on session store
+    StoreError(std::io::Error),
     /// Try to generate connect message too late
     TryToGenConnectMsgTooLate,
     /// Try to write a message when the negotiation is not successful
@@ -57,8 +103,19 @@ pub enum Error {
     RecvInvalidMsg(IncomingMsgErr),
     /// Received too many unordered messages; possibly due to an attack
     TooManyUnorderedMsgs,
+    /// Error on transport
+    TransportError(std::io::Error),
     /// Unexpected remote signature public key
     UnexpectedRemoteSigPubKey,
+    /// Unsupported multibase encoding
+    UnsupportedMultibaseEncoding,
+    /// The peer does not support our protocol version
+    VersionRejected {
+        /// Minimal version supported by the peer
+        min_version: u32,
+        /// Maximal version supported by the peer
+        max_version: u32,
+    },
     /// Error on writer
     WriteError(std::io::Error),
     /// Written length error
@@ -92,6 +149,14 @@ pub enum IncomingMsgErr {
     InvalidNonce,
     /// Message too short
     MessageTooShort,
+    /// Not enough valid co-signatures to satisfy the connect signature policy
+    NotEnoughCoSignatures,
+    /// Connect message carrying our own ephemeral public key (our connect message reflected to us)
+    ReflectedConnectMsg,
+    /// Custom data of a handshake message rejected by the application validator
+    RejectedCustomData,
+    /// More co-signatures than authorized co-signers
+    TooManyCoSignatures,
     /// Unexpected ack message
     UnexpectedAckMsg,
     /// Unexpected connect message
@@ -0,0 +1,162 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage the allocation of frame buffers.
+
+use std::any::Any;
+use std::fmt::Debug;
+
+#[cfg(feature = "frame-allocator")]
+use std::sync::Mutex;
+#[cfg(feature = "frame-allocator")]
+use zeroize::Zeroize;
+
+/// Allocator of the buffers of the encrypt and decrypt path, for example an arena
+/// or a pool of preallocated buffers.
+///
+/// Each buffer is released once its frame is processed, except the decrypted buffers handed
+/// to the application as message data, and the buffers dropped by some read errors.
+pub trait FrameAllocator: Any + Debug + Send + Sync {
+    /// Allocate an empty buffer of at least `capacity` bytes
+    fn alloc(&self, capacity: usize) -> Vec<u8>;
+    /// Release a buffer no longer used
+    fn release(&self, buffer: Vec<u8>);
+}
+
+#[cfg(feature = "frame-allocator")]
+impl PartialEq for dyn FrameAllocator {
+    /// Frame allocators are equal if they are the same instance
+    fn eq(&self, other: &Self) -> bool {
+        let self_ptr: *const dyn FrameAllocator = self;
+        let other_ptr: *const dyn FrameAllocator = other;
+        self_ptr as *const u8 == other_ptr as *const u8 && self.type_id() == other.type_id()
+    }
+}
+
+/// Frame allocator of the global allocator
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GlobalFrameAllocator;
+
+impl FrameAllocator for GlobalFrameAllocator {
+    #[inline]
+    fn alloc(&self, capacity: usize) -> Vec<u8> {
+        Vec::with_capacity(capacity)
+    }
+    #[inline]
+    fn release(&self, _buffer: Vec<u8>) {}
+}
+
+/// Default frame allocator
+pub static GLOBAL_FRAME_ALLOCATOR: GlobalFrameAllocator = GlobalFrameAllocator;
+
+#[cfg(feature = "frame-allocator")]
+/// Pool of frame buffers allocated at creation and reused by all frames: no allocation occurs
+/// while the pool holds a buffer large enough, otherwise the global allocator is used.
+/// Released buffers are zeroized, they may have held decrypted data.
+#[derive(Debug)]
+pub struct FrameBufferPool {
+    buffers: Mutex<Vec<Vec<u8>>>,
+    count: usize,
+}
+
+#[cfg(feature = "frame-allocator")]
+impl FrameBufferPool {
+    /// Create a pool of `count` buffers of `buffer_len` bytes
+    pub fn new(count: usize, buffer_len: usize) -> Self {
+        FrameBufferPool {
+            buffers: Mutex::new((0..count).map(|_| Vec::with_capacity(buffer_len)).collect()),
+            count,
+        }
+    }
+    /// Number of buffers available in the pool
+    pub fn available(&self) -> usize {
+        self.buffers
+            .lock()
+            .map(|buffers| buffers.len())
+            .unwrap_or(0)
+    }
+}
+
+#[cfg(feature = "frame-allocator")]
+impl FrameAllocator for FrameBufferPool {
+    fn alloc(&self, capacity: usize) -> Vec<u8> {
+        // A pool poisoned by a panic of another thread falls back to the global allocator
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if let Some(index) = buffers
+                .iter()
+                .position(|buffer| buffer.capacity() >= capacity)
+            {
+                return buffers.swap_remove(index);
+            }
+        }
+        Vec::with_capacity(capacity)
+    }
+    fn release(&self, mut buffer: Vec<u8>) {
+        buffer.zeroize();
+        if let Ok(mut buffers) = self.buffers.lock() {
+            if buffers.len() < self.count {
+                buffers.push(buffer);
+            }
+        }
+    }
+}
+
+#[cfg(all(test, feature = "frame-allocator"))]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_frame_buffer_pool() {
+        let pool = FrameBufferPool::new(2, 64);
+
+        let mut buffer = pool.alloc(32);
+        assert!(buffer.capacity() >= 64);
+        assert_eq!(1, pool.available());
+        buffer.extend_from_slice(&[1, 2, 3]);
+        pool.release(buffer);
+        assert_eq!(2, pool.available());
+
+        // Reused buffers are empty
+        let buffer = pool.alloc(64);
+        assert!(buffer.is_empty());
+        pool.release(buffer);
+
+        // Larger buffers are allocated by the global allocator, the pool does not grow
+        let buffer = pool.alloc(128);
+        assert_eq!(2, pool.available());
+        pool.release(buffer);
+        assert_eq!(2, pool.available());
+    }
+
+    #[test]
+    fn test_frame_allocators_eq() {
+        #[derive(Debug)]
+        struct OtherAllocator(u8);
+        impl FrameAllocator for OtherAllocator {
+            fn alloc(&self, capacity: usize) -> Vec<u8> {
+                GLOBAL_FRAME_ALLOCATOR.alloc(capacity)
+            }
+            fn release(&self, _buffer: Vec<u8>) {}
+        }
+        static OTHER_ALLOCATOR: OtherAllocator = OtherAllocator(0);
+
+        let allocator: &dyn FrameAllocator = &GLOBAL_FRAME_ALLOCATOR;
+        let same_allocator: &dyn FrameAllocator = &GLOBAL_FRAME_ALLOCATOR;
+        let other_allocator: &dyn FrameAllocator = &OTHER_ALLOCATOR;
+        assert!(allocator == same_allocator);
+        assert!(allocator != other_allocator);
+    }
+}
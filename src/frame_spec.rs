@@ -0,0 +1,315 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Describe frames layout (offsets, field names), for dissectors and captures analysis.
+
+use crate::constants::*;
+use crate::reader::ENCAPSULED_MSG_BEGIN;
+use std::ops::Range;
+
+/// Field offset in frame
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FieldOffset {
+    /// Fixed offset from the beginning of the frame
+    Fixed(usize),
+    /// Just after the encapsuled message (`16 + ENCAPSULED_MSG_LEN`)
+    AfterEncapsuledMsg,
+}
+
+/// Field length
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FieldLen {
+    /// Fixed length
+    Fixed(usize),
+    /// Up to the end of the encapsuled message
+    ToEncapsuledMsgEnd,
+    /// Up to the end of the frame
+    ToFrameEnd,
+}
+
+/// Field specification
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FieldSpec {
+    /// Field name (as in README)
+    pub name: &'static str,
+    /// Field offset
+    pub offset: FieldOffset,
+    /// Field length
+    pub len: FieldLen,
+}
+
+/// Frame specification
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FrameSpec {
+    /// Message type name (as in README)
+    pub name: &'static str,
+    /// MSG_TYPE field value
+    pub msg_type: u16,
+    /// The frame is encrypted, fields are those of the decrypted frame
+    pub encrypted: bool,
+    /// Fields in frame order
+    pub fields: &'static [FieldSpec],
+}
+
+const fn field(name: &'static str, offset: usize, len: usize) -> FieldSpec {
+    FieldSpec {
+        name,
+        offset: FieldOffset::Fixed(offset),
+        len: FieldLen::Fixed(len),
+    }
+}
+
+const MAGIC_VALUE_FIELD: FieldSpec = field("MAGIC_VALUE", 0, 4);
+const VERSION_FIELD: FieldSpec = field("VERSION", 4, 4);
+const ENCAPSULED_MSG_LEN_FIELD: FieldSpec = field("ENCAPSULED_MSG_LEN", 8, 8);
+const MSG_TYPE_FIELD: FieldSpec = field("MSG_TYPE", ENCAPSULED_MSG_BEGIN, MSG_TYPE_LEN);
+const MSG_CONTENT_BEGIN: usize = ENCAPSULED_MSG_BEGIN + MSG_TYPE_LEN;
+
+const fn custom_data_field(offset: usize) -> FieldSpec {
+    FieldSpec {
+        name: "CUSTOM_DATA",
+        offset: FieldOffset::Fixed(offset),
+        len: FieldLen::ToEncapsuledMsgEnd,
+    }
+}
+
+const fn footer_field(name: &'static str) -> FieldSpec {
+    FieldSpec {
+        name,
+        offset: FieldOffset::AfterEncapsuledMsg,
+        len: FieldLen::ToFrameEnd,
+    }
+}
+
+/// Frames specifications of the current protocol version (`PROTOCOL_VERSION`)
+pub const FRAME_SPECS: &[FrameSpec] = &[
+    FrameSpec {
+        name: "USER",
+        msg_type: 0,
+        encrypted: true,
+        fields: &[
+            MAGIC_VALUE_FIELD,
+            VERSION_FIELD,
+            ENCAPSULED_MSG_LEN_FIELD,
+            MSG_TYPE_FIELD,
+            field("NONCE", MSG_CONTENT_BEGIN, 8),
+            custom_data_field(MSG_CONTENT_BEGIN + 8),
+            footer_field("HASH"),
+        ],
+    },
+    FrameSpec {
+        name: "CONNECT",
+        msg_type: 1,
+        encrypted: false,
+        fields: &[
+            MAGIC_VALUE_FIELD,
+            VERSION_FIELD,
+            ENCAPSULED_MSG_LEN_FIELD,
+            MSG_TYPE_FIELD,
+            field("EPK", MSG_CONTENT_BEGIN, EPK_SIZE),
+            field("SIG_ALGO", MSG_CONTENT_BEGIN + EPK_SIZE, SIG_ALGO_LEN),
+            // Ed25519 public key, the only supported algorithm
+            field("SIG_PUBKEY", ENCAPSULED_MSG_BEGIN + SIG_PUBKEY_BEGIN, 32),
+            field(
+                "CAPABILITIES",
+                ENCAPSULED_MSG_BEGIN + SIG_PUBKEY_BEGIN + 32,
+                CAPABILITIES_SIZE,
+            ),
+            custom_data_field(ENCAPSULED_MSG_BEGIN + SIG_PUBKEY_BEGIN + 32 + CAPABILITIES_SIZE),
+            footer_field("SIGNATURE"),
+        ],
+    },
+    FrameSpec {
+        name: "ACK",
+        msg_type: 2,
+        encrypted: false,
+        fields: &[
+            MAGIC_VALUE_FIELD,
+            VERSION_FIELD,
+            ENCAPSULED_MSG_LEN_FIELD,
+            MSG_TYPE_FIELD,
+            field("CHALLENGE", MSG_CONTENT_BEGIN, CHALLENGE_SIZE),
+            custom_data_field(MSG_CONTENT_BEGIN + CHALLENGE_SIZE),
+            footer_field("SIGNATURE"),
+        ],
+    },
+    FrameSpec {
+        name: "EXPIRING USER",
+        msg_type: 3,
+        encrypted: true,
+        fields: &[
+            MAGIC_VALUE_FIELD,
+            VERSION_FIELD,
+            ENCAPSULED_MSG_LEN_FIELD,
+            MSG_TYPE_FIELD,
+            field("NONCE", MSG_CONTENT_BEGIN, 8),
+            field("EXPIRY", MSG_CONTENT_BEGIN + 8, EXPIRY_SIZE),
+            custom_data_field(MSG_CONTENT_BEGIN + 8 + EXPIRY_SIZE),
+            footer_field("HASH"),
+        ],
+    },
+    FrameSpec {
+        name: "KEEPALIVE",
+        msg_type: 4,
+        encrypted: true,
+        fields: &[
+            MAGIC_VALUE_FIELD,
+            VERSION_FIELD,
+            ENCAPSULED_MSG_LEN_FIELD,
+            MSG_TYPE_FIELD,
+            field("NONCE", MSG_CONTENT_BEGIN, 8),
+            custom_data_field(MSG_CONTENT_BEGIN + 8),
+            footer_field("HASH"),
+        ],
+    },
+    FrameSpec {
+        name: "VERSION REJECT",
+        msg_type: 5,
+        encrypted: false,
+        fields: &[
+            MAGIC_VALUE_FIELD,
+            VERSION_FIELD,
+            ENCAPSULED_MSG_LEN_FIELD,
+            MSG_TYPE_FIELD,
+            field("MIN_VERSION", MSG_CONTENT_BEGIN, 4),
+            field("MAX_VERSION", MSG_CONTENT_BEGIN + 4, 4),
+        ],
+    },
+    FrameSpec {
+        name: "COVER",
+        msg_type: 6,
+        encrypted: true,
+        fields: &[
+            MAGIC_VALUE_FIELD,
+            VERSION_FIELD,
+            ENCAPSULED_MSG_LEN_FIELD,
+            MSG_TYPE_FIELD,
+            field("NONCE", MSG_CONTENT_BEGIN, 8),
+            custom_data_field(MSG_CONTENT_BEGIN + 8),
+            footer_field("HASH"),
+        ],
+    },
+];
+
+/// Get frame specification of a message type
+pub fn frame_spec(msg_type: u16) -> Option<&'static FrameSpec> {
+    FRAME_SPECS.iter().find(|spec| spec.msg_type == msg_type)
+}
+
+impl FrameSpec {
+    /// Locate a field in a clear (or decrypted) frame, returns `None` if the field does not
+    /// exist or if the frame is too short
+    pub fn field_range(&self, name: &str, frame: &[u8]) -> Option<Range<usize>> {
+        let field = self.fields.iter().find(|field| field.name == name)?;
+
+        if frame.len() < ENCAPSULED_MSG_BEGIN {
+            return None;
+        }
+        let mut encapsuled_msg_len = [0u8; 8];
+        encapsuled_msg_len.copy_from_slice(&frame[8..ENCAPSULED_MSG_BEGIN]);
+        let encapsuled_msg_end =
+            (u64::from_be_bytes(encapsuled_msg_len) as usize).checked_add(ENCAPSULED_MSG_BEGIN)?;
+
+        let begin = match field.offset {
+            FieldOffset::Fixed(offset) => offset,
+            FieldOffset::AfterEncapsuledMsg => encapsuled_msg_end,
+        };
+        let end = match field.len {
+            FieldLen::Fixed(len) => begin + len,
+            FieldLen::ToEncapsuledMsgEnd => encapsuled_msg_end,
+            FieldLen::ToFrameEnd => frame.len(),
+        };
+
+        if begin <= end && end <= frame.len() {
+            Some(begin..end)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::capabilities::Capabilities;
+    use crate::digest::RING_DIGEST;
+    use crate::message::MessageRef;
+    use crate::Result;
+
+    #[test]
+    fn test_frame_specs() -> Result<()> {
+        let epk = [1u8; EPK_SIZE];
+        let mut frame = MessageRef::Connect {
+            sig_algo: [0, 0, 0, 0],
+            sig_pubkey: vec![2u8; 32],
+            capabilities: Capabilities::minimal(),
+            custom_data: Some(&[3, 3, 3]),
+        }
+        .to_bytes(&epk, None, &RING_DIGEST)?
+        .data;
+        frame.extend_from_slice(&[4u8; 64]);
+
+        let spec = frame_spec(1).expect("connect frame spec must exist");
+        assert_eq!("CONNECT", spec.name);
+        let field = |name| spec.field_range(name, &frame).map(|range| &frame[range]);
+        assert_eq!(Some(&MAGIC_VALUE[..]), field("MAGIC_VALUE"));
+        assert_eq!(Some(&CURRENT_VERSION[..]), field("VERSION"));
+        assert_eq!(Some(&CONNECT_MSG_TYPE[..]), field("MSG_TYPE"));
+        assert_eq!(Some(&epk[..]), field("EPK"));
+        assert_eq!(Some(&[0u8, 0, 0, 0][..]), field("SIG_ALGO"));
+        assert_eq!(Some(&[2u8; 32][..]), field("SIG_PUBKEY"));
+        assert_eq!(Some(&[0u8, 0, 0, 14][..]), field("CAPABILITIES"));
+        assert_eq!(Some(&[3u8, 3, 3][..]), field("CUSTOM_DATA"));
+        assert_eq!(Some(&[4u8; 64][..]), field("SIGNATURE"));
+        assert_eq!(None, field("NONCE"));
+        assert_eq!(None, spec.field_range("EPK", &frame[..20]));
+
+        // Expiring user message
+        let frame = MessageRef::Message {
+            custom_data: Some(&[5, 5]),
+            nonce: 42,
+            expiry: Some(1_000),
+            keepalive: false,
+            cover: false,
+        }
+        .to_bytes(&epk, None, &RING_DIGEST)?
+        .data;
+        let spec = frame_spec(3).expect("expiring user frame spec must exist");
+        let field = |name| spec.field_range(name, &frame).map(|range| &frame[range]);
+        assert_eq!(Some(&42u64.to_be_bytes()[..]), field("NONCE"));
+        assert_eq!(Some(&1_000u64.to_be_bytes()[..]), field("EXPIRY"));
+        assert_eq!(Some(&[5u8, 5][..]), field("CUSTOM_DATA"));
+        assert_eq!(Some(&[][..]), field("HASH"));
+
+        // Keepalive message
+        let frame = MessageRef::Message {
+            custom_data: None,
+            nonce: 43,
+            expiry: None,
+            keepalive: true,
+            cover: false,
+        }
+        .to_bytes(&epk, None, &RING_DIGEST)?
+        .data;
+        let spec = frame_spec(4).expect("keepalive frame spec must exist");
+        let field = |name| spec.field_range(name, &frame).map(|range| &frame[range]);
+        assert_eq!(Some(&[0u8, 4][..]), field("MSG_TYPE"));
+        assert_eq!(Some(&43u64.to_be_bytes()[..]), field("NONCE"));
+        assert_eq!(Some(&[][..]), field("CUSTOM_DATA"));
+
+        Ok(())
+    }
+}
@@ -0,0 +1,130 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage handshake failure diagnostics.
+
+use crate::constants::{
+    ACK_MSG_TYPE, CONNECT_MSG_TYPE, EPK_SIZE, MAGIC_VALUE, MSG_TYPE_LEN, SIG_PUBKEY_BEGIN,
+};
+use crate::errors::IncomingMsgErr;
+use crate::reader::ENCAPSULED_MSG_BEGIN;
+use crate::signature::SigAlgo;
+use crate::status::FailReason;
+use crate::Error;
+
+/// Length of the raw header snapshot: magic value, version, length and message type
+const HEADER_SNAPSHOT_LEN: usize = ENCAPSULED_MSG_BEGIN + MSG_TYPE_LEN;
+
+/// Handshake phase in which the negotiation failed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HandshakePhase {
+    /// Reading a peer CONNECT message
+    Connect,
+    /// Reading a peer ACK message
+    Ack,
+    /// Reading a frame whose message type is unreadable or is not a handshake one
+    Unknown,
+}
+
+/// Handshake frame field at fault
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HandshakeField {
+    /// Magic value
+    Magic,
+    /// Protocol version
+    Version,
+    /// Signature algorithm
+    SigAlgo,
+    /// Signature public key
+    SigPubkey,
+    /// Signature or co-signatures
+    Sig,
+    /// ACK challenge
+    Challenge,
+    /// Custom data
+    CustomData,
+}
+
+/// Diagnostics of a failed negotiation, to debug interop issues from logs
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HandshakeFailure {
+    /// Handshake phase
+    pub phase: HandshakePhase,
+    /// Field at fault, if the failure is tied to one
+    pub field: Option<HandshakeField>,
+    /// Failure reason
+    pub reason: FailReason,
+    /// Signature public key claimed by the peer, not necessarily authenticated
+    pub peer_sig_pubkey: Option<Vec<u8>>,
+    /// Raw header of the frame (magic value, version, length and message type),
+    /// truncated to the frame length
+    pub header: Vec<u8>,
+}
+
+impl HandshakeFailure {
+    /// `known_peer_sig_pubkey` is the expected or received peer signature public key,
+    /// used when the frame does not claim one (ACK message).
+    pub(crate) fn new(error: &Error, frame: &[u8], known_peer_sig_pubkey: Option<&[u8]>) -> Self {
+        let clear = frame.get(..MAGIC_VALUE.len()) == Some(&MAGIC_VALUE[..]);
+        let msg_type = if clear {
+            frame.get(ENCAPSULED_MSG_BEGIN..HEADER_SNAPSHOT_LEN)
+        } else {
+            None
+        };
+        let (phase, claimed_sig_pubkey) = match msg_type {
+            Some(CONNECT_MSG_TYPE) => (HandshakePhase::Connect, claimed_sig_pubkey(frame)),
+            Some(ACK_MSG_TYPE) => (HandshakePhase::Ack, None),
+            _ => (HandshakePhase::Unknown, None),
+        };
+
+        HandshakeFailure {
+            phase,
+            field: field_at_fault(error, clear),
+            reason: FailReason::from(error),
+            peer_sig_pubkey: claimed_sig_pubkey
+                .or(known_peer_sig_pubkey)
+                .map(<[u8]>::to_vec),
+            header: frame[..std::cmp::min(frame.len(), HEADER_SNAPSHOT_LEN)].to_vec(),
+        }
+    }
+}
+
+/// Signature public key claimed by a clear CONNECT frame
+fn claimed_sig_pubkey(frame: &[u8]) -> Option<&[u8]> {
+    let type_headers = frame.get(ENCAPSULED_MSG_BEGIN..)?;
+    let sig_algo = SigAlgo::from_id(type_headers.get(MSG_TYPE_LEN + EPK_SIZE..SIG_PUBKEY_BEGIN)?)?;
+    type_headers.get(SIG_PUBKEY_BEGIN..SIG_PUBKEY_BEGIN + sig_algo.pubkey_len())
+}
+
+fn field_at_fault(error: &Error, clear: bool) -> Option<HandshakeField> {
+    match error {
+        // Without the shared secret, a frame without magic value is taken for an encrypted one
+        Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedMessage) if !clear => {
+            Some(HandshakeField::Magic)
+        }
+        Error::RecvInvalidMsg(IncomingMsgErr::InvalidMagicValue) => Some(HandshakeField::Magic),
+        Error::RecvInvalidMsg(IncomingMsgErr::UnsupportedVersion) => Some(HandshakeField::Version),
+        Error::RecvInvalidMsg(IncomingMsgErr::UnsupportedSigAlgo) => Some(HandshakeField::SigAlgo),
+        Error::UnexpectedRemoteSigPubKey => Some(HandshakeField::SigPubkey),
+        Error::RecvInvalidMsg(IncomingMsgErr::InvalidHashOrSig)
+        | Error::RecvInvalidMsg(IncomingMsgErr::NotEnoughCoSignatures)
+        | Error::RecvInvalidMsg(IncomingMsgErr::TooManyCoSignatures) => Some(HandshakeField::Sig),
+        Error::RecvInvalidMsg(IncomingMsgErr::InvalidChallenge) => Some(HandshakeField::Challenge),
+        Error::RecvInvalidMsg(IncomingMsgErr::RejectedCustomData) => {
+            Some(HandshakeField::CustomData)
+        }
+        _ => None,
+    }
+}
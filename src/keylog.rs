@@ -0,0 +1,73 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage logging of session secrets, to decrypt its own captures during development.
+//!
+//! Each session secret is logged in one line:
+//!
+//! `PKSTL_SESSION_SECRET <SESSION_FINGERPRINT> <SHARED_SECRET>`
+//!
+//! Both fields are in lowercase hexadecimal. The session fingerprint is the Sha256 hash of the
+//! two ephemeral public keys (smallest first), so it can be computed from the captured CONNECT
+//! messages. The shared secret is the seed of the encryption algorithm (see README).
+
+use crate::constants::SESSION_SECRET_LABEL;
+use crate::encoding::to_base16;
+use std::fmt::{Debug, Formatter};
+
+/// Sink receiving key log lines
+pub(crate) struct KeyLogSink(pub(crate) Box<dyn FnMut(&str) + Send>);
+
+impl Debug for KeyLogSink {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "KeyLogSink")
+    }
+}
+
+impl KeyLogSink {
+    pub(crate) fn log_session_secret(&mut self, session_fingerprint: &[u8], shared_secret: &[u8]) {
+        (self.0)(&format!(
+            "{} {} {}",
+            SESSION_SECRET_LABEL,
+            to_base16(session_fingerprint),
+            to_base16(shared_secret)
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn test_log_session_secret() {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let lines_clone = lines.clone();
+        let mut sink = KeyLogSink(Box::new(move |line| {
+            lines_clone
+                .lock()
+                .expect("poisoned lock")
+                .push(line.to_owned())
+        }));
+
+        sink.log_session_secret(&[0, 1, 0xab], &[0xff, 2]);
+        assert_eq!(
+            vec!["PKSTL_SESSION_SECRET 0001ab ff02".to_owned()],
+            *lines.lock().expect("poisoned lock")
+        );
+    }
+}
@@ -0,0 +1,256 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage known peers file, pinning the signature public keys of peers (like SSH known_hosts).
+//!
+//! Each line contains a peer identifier and its signature public key in base58, separated by
+//! a space. Empty lines and lines starting with `#` are ignored.
+//!
+//! ```text
+//! # PKSTL known peers
+//! example.org:10900 BSWsyt1HfgfRzLY9yqEYiGNWf1qJfMNvbEcP3Ch5n73H
+//! ```
+
+use crate::encoding::{from_base58, to_base58};
+use crate::session_store::{MemorySessionStore, SessionStore};
+use crate::{Error, Result};
+use ring::constant_time::verify_slices_are_equal;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+/// Handling of peers missing from the known peers file
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KnownPeersMode {
+    /// Reject unknown peers, the file is only modified explicitly
+    Strict,
+    /// Trust unknown peers on first use and pin them
+    Loose,
+}
+
+/// Known peers file.
+///
+/// Implements `SessionStore`: pinned peers are persisted in the file at each change,
+/// resumption tickets and prekeys are only kept in memory.
+/// A peer whose public key differs from the pinned one is always rejected,
+/// use `update` after a legitimate key change.
+#[derive(Debug)]
+pub struct KnownPeers {
+    mode: KnownPeersMode,
+    path: PathBuf,
+    peers: Mutex<BTreeMap<String, Vec<u8>>>,
+    session_data: MemorySessionStore,
+}
+
+#[inline]
+fn invalid_data(line_number: usize, reason: &str) -> Error {
+    Error::StoreError(io::Error::new(
+        ErrorKind::InvalidData,
+        format!("known peers line {}: {}", line_number, reason),
+    ))
+}
+
+fn parse(content: &str) -> Result<BTreeMap<String, Vec<u8>>> {
+    let mut peers = BTreeMap::new();
+    for (i, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 2 {
+            return Err(invalid_data(
+                i + 1,
+                "expected a peer identifier and a public key",
+            ));
+        }
+        let sig_pubkey =
+            from_base58(fields[1]).map_err(|_| invalid_data(i + 1, "invalid public key"))?;
+        if peers.insert(fields[0].to_owned(), sig_pubkey).is_some() {
+            return Err(invalid_data(i + 1, "duplicated peer identifier"));
+        }
+    }
+    Ok(peers)
+}
+
+impl KnownPeers {
+    /// Load known peers file, a missing file is created on the first change
+    pub fn load<P: AsRef<Path>>(path: P, mode: KnownPeersMode) -> Result<Self> {
+        let peers = match fs::read_to_string(path.as_ref()) {
+            Ok(content) => parse(&content)?,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(Error::StoreError(e)),
+        };
+
+        Ok(KnownPeers {
+            mode,
+            path: path.as_ref().to_path_buf(),
+            peers: Mutex::new(peers),
+            session_data: MemorySessionStore::new(),
+        })
+    }
+    /// Handling of unknown peers
+    pub fn mode(&self) -> KnownPeersMode {
+        self.mode
+    }
+    /// Save known peers file (written in a temporary file, then renamed)
+    pub fn save(&self) -> Result<()> {
+        let peers = lock(&self.peers)?;
+        let mut content = String::from("# PKSTL known peers\n");
+        for (peer_id, sig_pubkey) in peers.iter() {
+            content.push_str(&format!("{} {}\n", peer_id, to_base58(sig_pubkey)));
+        }
+
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        fs::write(&tmp_path, content).map_err(Error::StoreError)?;
+        fs::rename(&tmp_path, &self.path).map_err(Error::StoreError)
+    }
+    /// Pin the signature public key of a peer, replacing the previous one, and save the file
+    pub fn update(&self, peer_id: &str, sig_pubkey: &[u8]) -> Result<()> {
+        if peer_id.is_empty() || peer_id.starts_with('#') || peer_id.contains(char::is_whitespace) {
+            return Err(Error::StoreError(io::Error::new(
+                ErrorKind::InvalidInput,
+                "invalid peer identifier",
+            )));
+        }
+        lock(&self.peers)?.insert(peer_id.to_owned(), sig_pubkey.to_vec());
+        self.save()
+    }
+    /// Remove a peer and save the file, returns false if the peer is unknown
+    pub fn remove(&self, peer_id: &str) -> Result<bool> {
+        if lock(&self.peers)?.remove(peer_id).is_some() {
+            self.save()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+#[inline]
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>> {
+    // Poisoned by a panic of another thread
+    mutex
+        .lock()
+        .map_err(|_| Error::StoreError(ErrorKind::Other.into()))
+}
+
+impl SessionStore for KnownPeers {
+    fn save_ticket(&self, peer_id: &str, ticket: &[u8]) -> Result<()> {
+        self.session_data.save_ticket(peer_id, ticket)
+    }
+    fn load_ticket(&self, peer_id: &str) -> Result<Option<Vec<u8>>> {
+        self.session_data.load_ticket(peer_id)
+    }
+    fn remove_ticket(&self, peer_id: &str) -> Result<()> {
+        self.session_data.remove_ticket(peer_id)
+    }
+    fn save_prekey(&self, prekey_id: u64, prekey: &[u8]) -> Result<()> {
+        self.session_data.save_prekey(prekey_id, prekey)
+    }
+    fn take_prekey(&self, prekey_id: u64) -> Result<Option<Vec<u8>>> {
+        self.session_data.take_prekey(prekey_id)
+    }
+    fn pin_peer(&self, peer_id: &str, sig_pubkey: &[u8]) -> Result<()> {
+        self.update(peer_id, sig_pubkey)
+    }
+    fn pinned_peer(&self, peer_id: &str) -> Result<Option<Vec<u8>>> {
+        Ok(lock(&self.peers)?.get(peer_id).cloned())
+    }
+    fn trust_on_first_use(&self, peer_id: &str, sig_pubkey: &[u8]) -> Result<bool> {
+        if let Some(pinned_sig_pubkey) = self.pinned_peer(peer_id)? {
+            Ok(verify_slices_are_equal(&pinned_sig_pubkey, sig_pubkey).is_ok())
+        } else if self.mode == KnownPeersMode::Loose {
+            self.pin_peer(peer_id, sig_pubkey)?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn tmp_path() -> PathBuf {
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        std::env::temp_dir().join(format!(
+            "pkstl_known_peers_{}_{}",
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::SeqCst)
+        ))
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        let peers = parse("# comment\n\npeer1 11111111111111111111111111111112\n  peer2 2  \n")?;
+        let mut pubkey1 = vec![0u8; 32];
+        pubkey1[31] = 1;
+        assert_eq!(Some(&pubkey1), peers.get("peer1"));
+        assert_eq!(Some(&vec![1u8]), peers.get("peer2"));
+
+        if let Err(Error::StoreError(e)) = parse("peer1 2\npeer1 3\n") {
+            assert_eq!(ErrorKind::InvalidData, e.kind());
+            assert_eq!(
+                "known peers line 2: duplicated peer identifier",
+                e.to_string()
+            );
+        } else {
+            panic!("duplicated peer must be rejected")
+        }
+        assert!(parse("peer1\n").is_err());
+        assert!(parse("peer1 0OIl\n").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_known_peers_modes() -> Result<()> {
+        let path = tmp_path();
+
+        // Loose mode pins unknown peers in the file
+        let known_peers = KnownPeers::load(&path, KnownPeersMode::Loose)?;
+        assert!(known_peers.trust_on_first_use("peer1", &[1; 32])?);
+        assert!(known_peers.trust_on_first_use("peer1", &[1; 32])?);
+        assert!(!known_peers.trust_on_first_use("peer1", &[2; 32])?);
+
+        // Strict mode rejects unknown peers and changed keys
+        let known_peers = KnownPeers::load(&path, KnownPeersMode::Strict)?;
+        assert_eq!(KnownPeersMode::Strict, known_peers.mode());
+        assert_eq!(Some(vec![1; 32]), known_peers.pinned_peer("peer1")?);
+        assert!(known_peers.trust_on_first_use("peer1", &[1; 32])?);
+        assert!(!known_peers.trust_on_first_use("peer1", &[2; 32])?);
+        assert!(!known_peers.trust_on_first_use("peer2", &[2; 32])?);
+        assert_eq!(None, known_peers.pinned_peer("peer2")?);
+
+        // Explicit changes are persisted
+        known_peers.update("peer1", &[2; 32])?;
+        assert!(known_peers.update("peer 2", &[2; 32]).is_err());
+        let known_peers = KnownPeers::load(&path, KnownPeersMode::Strict)?;
+        assert!(known_peers.trust_on_first_use("peer1", &[2; 32])?);
+        assert!(known_peers.remove("peer1")?);
+        assert!(!known_peers.remove("peer1")?);
+        let known_peers = KnownPeers::load(&path, KnownPeersMode::Strict)?;
+        assert_eq!(None, known_peers.pinned_peer("peer1")?);
+
+        fs::remove_file(&path).map_err(Error::StoreError)?;
+        Ok(())
+    }
+}
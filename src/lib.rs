@@ -27,32 +27,100 @@
     unused_import_braces,
     unused_qualifications
 )]
+// The `no-panic` feature is checked by clippy, not enforced by the compiler: a plain build
+// does not run these lints, so check it with `cargo clippy --features no-panic`. It also
+// removes the panicking `Seed32::random`. Indexing, arithmetic and dependencies may still panic.
+#![cfg_attr(
+    feature = "no-panic",
+    deny(
+        clippy::expect_used,
+        clippy::panic,
+        clippy::todo,
+        clippy::unimplemented,
+        clippy::unreachable
+    )
+)]
 
 mod agreement;
+mod capabilities;
+mod clock;
 #[cfg(feature = "zip-sign")]
 mod complete;
 mod config;
+#[cfg(feature = "conformance")]
+pub mod conformance;
+#[cfg(feature = "zip-sign")]
+mod connector;
 mod constants;
+mod cover_traffic;
 mod digest;
+mod encoding;
 mod encryption;
 mod errors;
 #[cfg(feature = "ser")]
 mod format;
+mod frame_allocator;
+mod frame_spec;
+mod handshake_failure;
+#[cfg(feature = "keylog")]
+mod keylog;
+mod known_peers;
+mod memory_budget;
 mod message;
 mod minimal;
+mod peer_auth;
+mod rate_limit;
 mod reader;
 mod seeds;
+mod self_test;
+mod session_store;
 mod signature;
+#[cfg(feature = "socks5")]
+mod socks5;
+mod stats;
 mod status;
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+mod transcript;
+#[cfg(feature = "zip-sign")]
+mod transport;
+mod verifier;
 
 pub use agreement::EphemeralPublicKey;
+pub use capabilities::{Capabilities, Capability};
+pub use clock::{Clock, SystemClock, SYSTEM_CLOCK};
 pub use config::SecureLayerConfig;
+pub use constants::{DEFAULT_NONCE_CHECKPOINT_MARGIN, PROTOCOL_VERSION, SMALL_MSG_BUFFER_SIZE};
+pub use cover_traffic::CoverTraffic;
+pub use digest::{Digest, RingDigest, RING_DIGEST};
+pub use encoding::{from_base58, from_multibase, to_base58, to_multibase, MultibaseEncoding};
 pub use encryption::EncryptAlgo;
-pub use errors::Error;
-pub use message::{EncapsuledMessage, Message};
-pub use minimal::MinimalSecureLayer;
+pub use errors::{Error, IncomingMsgErr};
+pub use frame_spec::{frame_spec, FieldLen, FieldOffset, FieldSpec, FrameSpec, FRAME_SPECS};
+pub use handshake_failure::{HandshakeFailure, HandshakeField, HandshakePhase};
+pub use known_peers::{KnownPeers, KnownPeersMode};
+pub use memory_budget::MemoryBudget;
+pub use message::{EncapsuledMessage, Message, MessageView};
+pub use minimal::{
+    LossReport, MinimalSecureLayer, NonceCheckpoint, PendingConnectVerification, PreparedConnect,
+    ReservedNonces, VerifiedConnect,
+};
+pub use peer_auth::{PeerAuthAudit, PeerAuthPolicy};
+pub use rate_limit::SendRateLimit;
+pub use reader::{parse_untrusted, peek_frame_version, version_reject_frame};
 pub use seeds::Seed32;
-pub use signature::{SIG_ALGO_ED25519, SIG_ALGO_ED25519_ARRAY};
+pub use self_test::{
+    self_test, self_test_with_digest, SelfTestAlgo, SelfTestReport, SelfTestResult,
+};
+pub use session_store::{MemorySessionStore, SessionStore};
+pub use signature::{
+    verify_batch, ConnectSigPolicy, SigAlgo, SigAlgos, SigRequirement, SigToVerify,
+    SIG_ALGO_ED25519, SIG_ALGO_ED25519_ARRAY,
+};
+pub use stats::{SessionStats, SizeHistogram, SlowConsumerWarning, SIZE_HISTOGRAM_BUCKETS};
+pub use status::{FailReason, SecureLayerStatus};
+pub use transcript::{HandshakeTranscript, SignedHandshakeMsg};
+pub use verifier::{CaptureSide, FrameReport, OfflineVerifier, VerificationReport, VerifiedFrame};
 
 #[cfg(feature = "ser")]
 pub use complete::IncomingMessage;
@@ -63,6 +131,23 @@ pub use format::MessageFormat;
 pub use complete::message::IncomingBinaryMessage;
 #[cfg(feature = "zip-sign")]
 pub use complete::SecureLayer;
+#[cfg(feature = "zip-sign")]
+pub use connector::{
+    connect_racing, connect_with_retry, Endpoint, FailedAttempt, RaceWinner, RetryPolicy,
+    RetryWinner,
+};
+#[cfg(feature = "frame-allocator")]
+pub use frame_allocator::{
+    FrameAllocator, FrameBufferPool, GlobalFrameAllocator, GLOBAL_FRAME_ALLOCATOR,
+};
+#[cfg(feature = "socks5")]
+pub use socks5::{connect_socks5, handshake_over_socks5};
+#[cfg(feature = "prometheus")]
+pub use stats::format_prometheus;
+#[cfg(feature = "zip-sign")]
+pub use transport::{
+    ChecksummedTransport, StreamTransport, TcpTransportDriver, Transport, TransportDriver,
+};
 
 /// PKSTL Result
 pub type Result<T> = std::result::Result<T, Error>;
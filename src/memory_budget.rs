@@ -0,0 +1,101 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage process-wide memory budget of secure layers.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+#[derive(Debug)]
+struct MemoryBudgetState {
+    cap: usize,
+    used: AtomicUsize,
+}
+
+#[derive(Clone, Debug)]
+/// Memory budget shared by secure layers: caps the bytes they collectively hold in their
+/// buffers (messages received too early, orphan nonces, retransmission detection, etc).
+/// Clones share the same budget.
+pub struct MemoryBudget(Arc<MemoryBudgetState>);
+
+impl MemoryBudget {
+    /// Create memory budget of `cap` bytes
+    pub fn new(cap: usize) -> Self {
+        MemoryBudget(Arc::new(MemoryBudgetState {
+            cap,
+            used: AtomicUsize::new(0),
+        }))
+    }
+    /// Get cap in bytes
+    #[inline]
+    pub fn cap(&self) -> usize {
+        self.0.cap
+    }
+    /// Get bytes used by all secure layers sharing this budget
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.0.used.load(Ordering::SeqCst)
+    }
+    /// Reserve bytes, fail if the cap would be exceeded
+    pub(crate) fn try_reserve(&self, bytes: usize) -> bool {
+        let mut used = self.used();
+        loop {
+            let new_used = match used.checked_add(bytes) {
+                Some(new_used) if new_used <= self.0.cap => new_used,
+                _ => return false,
+            };
+            match self
+                .0
+                .used
+                .compare_exchange(used, new_used, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return true,
+                Err(current_used) => used = current_used,
+            }
+        }
+    }
+    /// Reserve bytes even if the cap is exceeded
+    pub(crate) fn force_reserve(&self, bytes: usize) {
+        self.0.used.fetch_add(bytes, Ordering::SeqCst);
+    }
+    /// Release bytes
+    pub(crate) fn release(&self, bytes: usize) {
+        self.0.used.fetch_sub(bytes, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_memory_budget() {
+        let budget = MemoryBudget::new(100);
+        let shared_budget = budget.clone();
+
+        assert!(budget.try_reserve(60));
+        assert!(!shared_budget.try_reserve(41));
+        assert!(shared_budget.try_reserve(40));
+        assert_eq!(100, budget.used());
+        assert!(!budget.try_reserve(usize::max_value()));
+
+        budget.release(60);
+        assert_eq!(40, shared_budget.used());
+        shared_budget.force_reserve(80);
+        assert_eq!(120, budget.used());
+        assert_eq!(100, budget.cap());
+    }
+}
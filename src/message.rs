@@ -15,12 +15,17 @@
 
 //! Manage PKSTL messages.
 
+use crate::capabilities::Capabilities;
 use crate::constants::*;
-use crate::digest::sha256;
+use crate::digest::Digest;
+use crate::reader::ENCAPSULED_MSG_BEGIN;
+use crate::signature::SigAlgo;
 use crate::{Error, Result};
+use std::fmt::{Debug, Formatter};
 use std::io::{BufWriter, Write};
+use std::time::SystemTime;
 
-const CONNECT_MSG_TYPE_HEADERS_SIZE: usize = 70;
+const CONNECT_MSG_TYPE_HEADERS_SIZE: usize = 74;
 const ACK_MSG_TYPE_HEADERS_SIZE: usize = 34;
 const USER_MSG_TYPE_HEADERS_SIZE: usize = 8;
 
@@ -59,6 +64,8 @@ pub enum MessageRef<'a> {
         sig_algo: [u8; SIG_ALGO_LEN],
         /// Signature public key
         sig_pubkey: Vec<u8>,
+        /// Capabilities advertised to the peer
+        capabilities: Capabilities,
         /// Custom data
         custom_data: Option<&'a [u8]>,
     },
@@ -66,6 +73,8 @@ pub enum MessageRef<'a> {
     Ack {
         /// Custom data
         custom_data: Option<&'a [u8]>,
+        /// Only the ACK message is signed, its challenge also covers our ephemeral public key
+        ack_only_sig: bool,
     },
     /// User Message
     Message {
@@ -73,9 +82,133 @@ pub enum MessageRef<'a> {
         custom_data: Option<&'a [u8]>,
         /// Nonce
         nonce: u64,
+        /// Expiry (milliseconds since UNIX epoch)
+        expiry: Option<u64>,
+        /// Keepalive message, not delivered to the peer application
+        keepalive: bool,
+        /// Cover message, dropped by the peer
+        cover: bool,
     },
 }
 
+/// Message borrowing the decrypted data of the secure layer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MessageView<'a> {
+    /// Connect message
+    Connect {
+        /// Signature algorithm
+        sig_algo: [u8; SIG_ALGO_LEN],
+        /// Signature public key
+        sig_pubkey: &'a [u8],
+        /// Custom data
+        custom_data: Option<&'a [u8]>,
+    },
+    /// Ack Message
+    Ack {
+        /// Custom data
+        custom_data: Option<&'a [u8]>,
+    },
+    /// User Message
+    Message {
+        /// Custom data
+        custom_data: Option<&'a [u8]>,
+    },
+}
+
+impl<'a> MessageView<'a> {
+    pub(crate) fn new(msg_bytes: &'a [u8], msg_type_headers: &'a MsgTypeHeaders) -> Self {
+        let custom_data = if !msg_bytes.is_empty() {
+            Some(msg_bytes)
+        } else {
+            None
+        };
+
+        match msg_type_headers {
+            MsgTypeHeaders::UserMsg { .. } => MessageView::Message { custom_data },
+            MsgTypeHeaders::Connect {
+                sig_algo,
+                sig_pubkey,
+                ..
+            } => MessageView::Connect {
+                sig_algo: sig_algo.id(),
+                sig_pubkey,
+                custom_data,
+            },
+            MsgTypeHeaders::Ack { .. } => MessageView::Ack { custom_data },
+        }
+    }
+    /// Custom data
+    pub fn custom_data(&self) -> Option<&'a [u8]> {
+        match self {
+            MessageView::Connect { custom_data, .. }
+            | MessageView::Ack { custom_data }
+            | MessageView::Message { custom_data } => *custom_data,
+        }
+    }
+    /// Same message with other custom data
+    pub(crate) fn with_custom_data<'b>(&self, custom_data: Option<&'b [u8]>) -> MessageView<'b>
+    where
+        'a: 'b,
+    {
+        match *self {
+            MessageView::Connect {
+                sig_algo,
+                sig_pubkey,
+                ..
+            } => MessageView::Connect {
+                sig_algo,
+                sig_pubkey,
+                custom_data,
+            },
+            MessageView::Ack { .. } => MessageView::Ack { custom_data },
+            MessageView::Message { .. } => MessageView::Message { custom_data },
+        }
+    }
+    /// Copy into an owned message
+    pub fn to_message(&self) -> Message {
+        let to_vec = |custom_data: &Option<&[u8]>| custom_data.map(<[u8]>::to_vec);
+        match self {
+            MessageView::Connect {
+                sig_algo,
+                sig_pubkey,
+                custom_data,
+            } => Message::Connect {
+                sig_algo: *sig_algo,
+                sig_pubkey: sig_pubkey.to_vec(),
+                custom_data: to_vec(custom_data),
+            },
+            MessageView::Ack { custom_data } => Message::Ack {
+                custom_data: to_vec(custom_data),
+            },
+            MessageView::Message { custom_data } => Message::Message {
+                custom_data: to_vec(custom_data),
+            },
+        }
+    }
+}
+
+type CustomDataValidatorFn = dyn FnMut(&MessageView<'_>, Option<&[u8; HASH_SIZE]>) -> bool + Send;
+
+/// Validator of the custom data of peer handshake messages
+pub(crate) struct CustomDataValidator(pub(crate) Box<CustomDataValidatorFn>);
+
+impl Debug for CustomDataValidator {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "CustomDataValidator")
+    }
+}
+
+type HeartbeatHookFn = dyn FnMut(&[u8]) + Send;
+
+/// Hook receiving the payload of peer keepalive messages
+pub(crate) struct HeartbeatHook(pub(crate) Box<HeartbeatHookFn>);
+
+impl Debug for HeartbeatHook {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "HeartbeatHook")
+    }
+}
+
 /// Encapsuled message
 #[derive(Debug, PartialEq)]
 pub struct EncapsuledMessage {
@@ -88,21 +221,86 @@ impl AsRef<[u8]> for EncapsuledMessage {
     }
 }
 
+impl EncapsuledMessage {
+    /// Encapsule message type headers and user message
+    pub(crate) fn new(type_msg_headers: &[u8], bin_user_msg: Option<&[u8]>) -> Result<Self> {
+        let bin_user_msg_len = bin_user_msg.unwrap_or(&[]).len();
+
+        // Create temporary write buffer for data that will then be signed or hashed
+        let mut bytes_will_signed_or_hashed = BufWriter::new(Vec::with_capacity(
+            bin_user_msg_len + HEADERS_AND_FOOTERS_MAX_SIZE,
+        ));
+
+        // Write MAGIC_VALUE
+        bytes_will_signed_or_hashed
+            .write(&MAGIC_VALUE)
+            .map_err(Error::WriteError)?;
+
+        // Write VERSION
+        bytes_will_signed_or_hashed
+            .write(&CURRENT_VERSION)
+            .map_err(Error::WriteError)?;
+
+        // Write ENCAPSULED_MSG_SIZE
+        let encapsuled_msg_size = type_msg_headers.len() + bin_user_msg.unwrap_or(&[]).len();
+        bytes_will_signed_or_hashed
+            .write(&(encapsuled_msg_size as u64).to_be_bytes())
+            .map_err(Error::WriteError)?;
+
+        // Write type message headers
+        bytes_will_signed_or_hashed
+            .write(type_msg_headers)
+            .map_err(Error::WriteError)?;
+
+        // Write user message
+        if let Some(bin_user_msg) = bin_user_msg {
+            bytes_will_signed_or_hashed
+                .write(bin_user_msg)
+                .map_err(Error::WriteError)?;
+        }
+
+        // Flush bytes_will_signed buffer
+        let bytes_will_signed_or_hashed = bytes_will_signed_or_hashed
+            .into_inner()
+            .map_err(|_| Error::BufferFlushError)?;
+
+        // Return the bytes will signed
+        Ok(EncapsuledMessage {
+            data: bytes_will_signed_or_hashed,
+        })
+    }
+}
+
 #[derive(Debug, Eq, PartialEq)]
 pub(crate) enum MsgTypeHeaders {
     Connect {
         peer_ephemeral_pk: [u8; EPK_SIZE],
-        sig_algo: [u8; SIG_ALGO_LEN],
+        sig_algo: SigAlgo,
         sig_pubkey: Vec<u8>,
+        capabilities: Capabilities,
     },
     Ack {
         challenge: [u8; CHALLENGE_SIZE],
     },
     UserMsg {
         nonce: u64,
+        expiry: Option<u64>,
+        keepalive: bool,
+        cover: bool,
     },
 }
 
+/// Metadata of a received user message
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct UserMsgMeta {
+    /// Nonce of the message
+    pub(crate) nonce: u64,
+    /// Time at which the message was received
+    pub(crate) received_at: SystemTime,
+    /// Size of the message data, as received
+    pub(crate) size: usize,
+}
+
 impl MsgTypeHeaders {
     pub(crate) fn must_be_encrypted(&self) -> bool {
         if let MsgTypeHeaders::UserMsg { .. } = self {
@@ -135,7 +333,7 @@ impl Message {
                 sig_pubkey,
                 ..
             } => Ok(Message::Connect {
-                sig_algo,
+                sig_algo: sig_algo.id(),
                 sig_pubkey,
                 custom_data,
             }),
@@ -150,11 +348,13 @@ impl<'a> MessageRef<'a> {
         &self,
         self_epk: &[u8],
         peer_epk: Option<&Vec<u8>>,
+        digest: &dyn Digest,
     ) -> Result<InnerPreparedMsg<'a>> {
         match self {
             Self::Connect {
                 sig_algo,
                 sig_pubkey,
+                capabilities,
                 custom_data,
             } => {
                 // type message headers
@@ -171,13 +371,19 @@ impl<'a> MessageRef<'a> {
                 type_msg_headers
                     .write(sig_pubkey)
                     .map_err(Error::WriteError)?;
+                type_msg_headers
+                    .write(&capabilities.bits().to_be_bytes())
+                    .map_err(Error::WriteError)?;
 
                 Ok(InnerPreparedMsg {
                     bin_user_msg: *custom_data,
                     type_msg_headers,
                 })
             }
-            Self::Ack { custom_data } => {
+            Self::Ack {
+                custom_data,
+                ack_only_sig,
+            } => {
                 // type message headers
                 let mut type_msg_headers = Vec::with_capacity(ACK_MSG_TYPE_HEADERS_SIZE);
                 type_msg_headers
@@ -185,9 +391,17 @@ impl<'a> MessageRef<'a> {
                     .map_err(Error::WriteError)?;
                 // write challenge
                 if let Some(peer_epk) = peer_epk {
-                    Self::write_challenge(peer_epk, &mut type_msg_headers)?;
+                    type_msg_headers
+                        .write(&ack_challenge(
+                            digest,
+                            peer_epk,
+                            if *ack_only_sig { Some(self_epk) } else { None },
+                        ))
+                        .map_err(Error::WriteError)?;
                 } else {
-                    panic!("Dev error: try to write ack message before knowing peer epk.");
+                    return Err(Error::InternalError(
+                        "try to write ack message before knowing peer epk",
+                    ));
                 }
 
                 Ok(InnerPreparedMsg {
@@ -198,15 +412,32 @@ impl<'a> MessageRef<'a> {
             Self::Message {
                 custom_data,
                 nonce,
+                expiry,
+                keepalive,
+                cover,
             } => {
                 // type message headers
-                let mut type_msg_headers = Vec::with_capacity(USER_MSG_TYPE_HEADERS_SIZE);
+                let mut type_msg_headers =
+                    Vec::with_capacity(USER_MSG_TYPE_HEADERS_SIZE + EXPIRY_SIZE);
                 type_msg_headers
-                    .write(USER_MSG_TYPE)
+                    .write(if *cover {
+                        COVER_MSG_TYPE
+                    } else if *keepalive {
+                        KEEPALIVE_MSG_TYPE
+                    } else if expiry.is_some() {
+                        EXPIRING_USER_MSG_TYPE
+                    } else {
+                        USER_MSG_TYPE
+                    })
                     .map_err(Error::WriteError)?;
                 type_msg_headers
                     .write(&nonce.to_be_bytes())
                     .map_err(Error::WriteError)?;
+                if let (Some(expiry), false, false) = (expiry, keepalive, cover) {
+                    type_msg_headers
+                        .write(&expiry.to_be_bytes())
+                        .map_err(Error::WriteError)?;
+                }
 
                 Ok(InnerPreparedMsg {
                     bin_user_msg: *custom_data,
@@ -215,68 +446,72 @@ impl<'a> MessageRef<'a> {
             }
         }
     }
+    /// Encapsulate a user message into `buffer` without allocation, returns its length.
+    /// Returns `None` for a handshake message or if the buffer is too short.
+    pub(crate) fn encapsulate_user_msg_into(&self, buffer: &mut [u8]) -> Option<usize> {
+        if let Self::Message {
+            custom_data,
+            nonce,
+            expiry,
+            keepalive,
+            cover,
+        } = self
+        {
+            let bin_user_msg = custom_data.unwrap_or(&[]);
+            let (msg_type, expiry) = match (expiry, keepalive, cover) {
+                (_, _, true) => (COVER_MSG_TYPE, None),
+                (_, true, false) => (KEEPALIVE_MSG_TYPE, None),
+                (Some(expiry), false, false) => (EXPIRING_USER_MSG_TYPE, Some(expiry)),
+                (None, false, false) => (USER_MSG_TYPE, None),
+            };
+            let expiry_len = if expiry.is_some() { EXPIRY_SIZE } else { 0 };
+            let encapsuled_msg_size =
+                MSG_TYPE_LEN + USER_MSG_TYPE_HEADERS_SIZE + expiry_len + bin_user_msg.len();
+            let len = ENCAPSULED_MSG_BEGIN + encapsuled_msg_size;
+
+            let mut cursor = buffer.get_mut(..len)?;
+            cursor.write_all(&MAGIC_VALUE).ok()?;
+            cursor.write_all(&CURRENT_VERSION).ok()?;
+            cursor
+                .write_all(&(encapsuled_msg_size as u64).to_be_bytes())
+                .ok()?;
+            cursor.write_all(msg_type).ok()?;
+            cursor.write_all(&nonce.to_be_bytes()).ok()?;
+            if let Some(expiry) = expiry {
+                cursor.write_all(&expiry.to_be_bytes()).ok()?;
+            }
+            cursor.write_all(bin_user_msg).ok()?;
+            Some(len)
+        } else {
+            None
+        }
+    }
     /// Convert message to bytes
     pub(crate) fn to_bytes(
         &self,
         self_epk: &[u8],
         peer_epk: Option<&Vec<u8>>,
+        digest: &dyn Digest,
     ) -> Result<EncapsuledMessage> {
         let InnerPreparedMsg {
             bin_user_msg,
             type_msg_headers,
-        } = self.prepare_message(self_epk, peer_epk)?;
-
-        let bin_user_msg_len = bin_user_msg.unwrap_or(&[]).len();
-
-        // Create temporary write buffer for data that will then be signed or hashed
-        let mut bytes_will_signed_or_hashed = BufWriter::new(Vec::with_capacity(
-            bin_user_msg_len + HEADERS_AND_FOOTERS_MAX_SIZE,
-        ));
-
-        // Write MAGIC_VALUE
-        bytes_will_signed_or_hashed
-            .write(&MAGIC_VALUE)
-            .map_err(Error::WriteError)?;
-
-        // Write VERSION
-        bytes_will_signed_or_hashed
-            .write(&CURRENT_VERSION)
-            .map_err(Error::WriteError)?;
+        } = self.prepare_message(self_epk, peer_epk, digest)?;
 
-        // Write ENCAPSULED_MSG_SIZE
-        let encapsuled_msg_size = type_msg_headers.len() + bin_user_msg.unwrap_or(&[]).len();
-        bytes_will_signed_or_hashed
-            .write(&(encapsuled_msg_size as u64).to_be_bytes())
-            .map_err(Error::WriteError)?;
-
-        // Write type message headers
-        bytes_will_signed_or_hashed
-            .write(&type_msg_headers)
-            .map_err(Error::WriteError)?;
-
-        // Write user message
-        if let Some(bin_user_msg) = bin_user_msg {
-            bytes_will_signed_or_hashed
-                .write(bin_user_msg)
-                .map_err(Error::WriteError)?;
-        }
-
-        // Flush bytes_will_signed buffer
-        let bytes_will_signed_or_hashed = bytes_will_signed_or_hashed
-            .into_inner()
-            .map_err(|_| Error::BufferFlushError)?;
-
-        // Return the bytes will signed
-        Ok(EncapsuledMessage {
-            data: bytes_will_signed_or_hashed,
-        })
+        EncapsuledMessage::new(&type_msg_headers, bin_user_msg)
     }
-    #[inline]
-    fn write_challenge<W: Write>(ephem_pk: &[u8], writer: &mut W) -> Result<()> {
-        writer
-            .write(sha256(ephem_pk).as_ref())
-            .map_err(Error::WriteError)?;
-        Ok(())
+}
+
+/// Compute the challenge of an ACK message: Sha256 of the receiver ephemeral public key,
+/// followed by the signer one if the signer signs only its ACK message
+pub(crate) fn ack_challenge(
+    digest: &dyn Digest,
+    receiver_epk: &[u8],
+    signer_epk: Option<&[u8]>,
+) -> [u8; CHALLENGE_SIZE] {
+    match signer_epk {
+        Some(signer_epk) => digest.sha256(&[receiver_epk, signer_epk].concat()),
+        None => digest.sha256(receiver_epk),
     }
 }
 
@@ -284,6 +519,7 @@ impl<'a> MessageRef<'a> {
 mod tests {
 
     use super::*;
+    use crate::digest::RING_DIGEST;
     use pretty_assertions::assert_eq;
 
     #[test]
@@ -307,23 +543,25 @@ mod tests {
                 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9,
                 10, 11, 12, 13, 14, 15,
             ],
+            capabilities: Capabilities::minimal(),
         };
         assert_eq!(
             EncapsuledMessage {
                 data: vec![
                     226, 194, 226, 210, // MAGIC_VALUE
-                    0, 0, 0, 1, // VERSION
-                    0, 0, 0, 0, 0, 0, 0, 74, // ENCAPSULED_MSG_LEN
+                    0, 0, 0, 2, // VERSION
+                    0, 0, 0, 0, 0, 0, 0, 78, // ENCAPSULED_MSG_LEN
                     0, 1, // CONNECT_MSG_TYPE
                     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
                     0, 0, 0, 0, 0, 0, // fake EPK (32 bytes)
                     0, 0, 0, 0, // SIG_ALGO
                     0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 0, 1, 2, 3, 4, 5, 6, 7,
                     8, 9, 10, 11, 12, 13, 14, 15, // fake SIG_PK (32 bytes)
+                    0, 0, 0, 14, // CAPABILITIES
                     5, 4, 4, 5 // custom data
                 ],
             },
-            message.to_bytes(fake_epk, None)?
+            message.to_bytes(fake_epk, None, &RING_DIGEST)?
         );
 
         // Test connect message without custom data
@@ -334,23 +572,25 @@ mod tests {
                 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9,
                 10, 11, 12, 13, 14, 15,
             ],
+            capabilities: Capabilities::minimal(),
         };
 
         assert_eq!(
             EncapsuledMessage {
                 data: vec![
                     226, 194, 226, 210, // MAGIC_VALUE
-                    0, 0, 0, 1, // VERSION
-                    0, 0, 0, 0, 0, 0, 0, 70, // ENCAPSULED_MSG_LEN
+                    0, 0, 0, 2, // VERSION
+                    0, 0, 0, 0, 0, 0, 0, 74, // ENCAPSULED_MSG_LEN
                     0, 1, // CONNECT_MSG_TYPE
                     0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
                     0, 0, 0, 0, 0, 0, // fake EPK (32 bytes)
                     0, 0, 0, 0, // SIG_ALGO
                     0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 0, 1, 2, 3, 4, 5, 6, 7,
                     8, 9, 10, 11, 12, 13, 14, 15, // fake SIG_PK (32 bytes)
+                    0, 0, 0, 14, // CAPABILITIES
                 ],
             },
-            message.to_bytes(fake_epk, None,)?
+            message.to_bytes(fake_epk, None, &RING_DIGEST)?
         );
 
         Ok(())
@@ -363,12 +603,13 @@ mod tests {
         // Test ack message with custom data
         let message = MessageRef::Ack {
             custom_data: Some(&[5, 4, 4, 5]),
+            ack_only_sig: false,
         };
         assert_eq!(
             EncapsuledMessage {
                 data: vec![
                     226, 194, 226, 210, // MAGIC_VALUE
-                    0, 0, 0, 1, // VERSION
+                    0, 0, 0, 2, // VERSION
                     0, 0, 0, 0, 0, 0, 0, 38, // ENCAPSULED_MSG_LEN
                     0, 2, // ACK_MSG_TYPE
                     102, 104, 122, 173, 248, 98, 189, 119, 108, 143, 193, 139, 142, 159, 142, 32,
@@ -377,16 +618,19 @@ mod tests {
                     5, 4, 4, 5 // custom data
                 ],
             },
-            message.to_bytes(fake_epk, Some(&fake_epk.to_vec()),)?
+            message.to_bytes(fake_epk, Some(&fake_epk.to_vec()), &RING_DIGEST)?
         );
 
         // Test ack message without custom data
-        let message = MessageRef::Ack { custom_data: None };
+        let message = MessageRef::Ack {
+            custom_data: None,
+            ack_only_sig: false,
+        };
         assert_eq!(
             EncapsuledMessage {
                 data: vec![
                     226, 194, 226, 210, // MAGIC_VALUE
-                    0, 0, 0, 1, // VERSION
+                    0, 0, 0, 2, // VERSION
                     0, 0, 0, 0, 0, 0, 0, 34, // ENCAPSULED_MSG_LEN
                     0, 2, // ACK_MSG_TYPE
                     102, 104, 122, 173, 248, 98, 189, 119, 108, 143, 193, 139, 142, 159, 142, 32,
@@ -394,20 +638,37 @@ mod tests {
                     37, // CHALLENGE (hash sha256)
                 ],
             },
-            message.to_bytes(fake_epk, Some(&fake_epk.to_vec()),)?
+            message.to_bytes(fake_epk, Some(&fake_epk.to_vec()), &RING_DIGEST)?
+        );
+
+        // Test ack message signed alone, the challenge also covers our epk
+        let message = MessageRef::Ack {
+            custom_data: None,
+            ack_only_sig: true,
+        };
+        assert_eq!(
+            &RING_DIGEST.sha256(&[0u8; 64])[..],
+            &message
+                .to_bytes(fake_epk, Some(&fake_epk.to_vec()), &RING_DIGEST)?
+                .data[18..]
         );
 
         Ok(())
     }
 
     #[test]
-    #[should_panic(expected = "Dev error: try to write ack message before knowing peer epk.")]
     fn test_ack_message_to_bytes_before_recv_connect_msg() {
         let fake_epk = &[0u8; 32];
 
         // Test ack message without custom data
-        let message = MessageRef::Ack { custom_data: None };
-        let _ = message.to_bytes(fake_epk, None);
+        let message = MessageRef::Ack {
+            custom_data: None,
+            ack_only_sig: false,
+        };
+        if let Err(Error::InternalError(_)) = message.to_bytes(fake_epk, None, &RING_DIGEST) {
+        } else {
+            panic!("Expected error InternalError !")
+        }
     }
 
     #[test]
@@ -418,42 +679,91 @@ mod tests {
         let empty_user_message = MessageRef::Message {
             nonce: 123_456,
             custom_data: Some(&[5, 4, 4, 5]),
+            expiry: None,
+            keepalive: false,
+            cover: false,
         };
         assert_eq!(
             EncapsuledMessage {
                 data: vec![
                     226, 194, 226, 210, // MAGIC_VALUE
-                    0, 0, 0, 1, // VERSION
+                    0, 0, 0, 2, // VERSION
                     0, 0, 0, 0, 0, 0, 0, 14, // ENCAPSULED_MSG_LEN
                     0, 0, // USER_MSG_TYPE
                     0, 0, 0, 0, 0, 1, 226, 64, // NONCE
                     5, 4, 4, 5 // custom data
                 ],
             },
-            empty_user_message.to_bytes(fake_epk, None)?
+            empty_user_message.to_bytes(fake_epk, None, &RING_DIGEST)?
         );
 
         // Test empty user message
         let empty_user_message = MessageRef::Message {
             nonce: 0,
             custom_data: None,
+            expiry: None,
+            keepalive: false,
+            cover: false,
         };
         assert_eq!(
             EncapsuledMessage {
                 data: vec![
                     226, 194, 226, 210, // MAGIC_VALUE
-                    0, 0, 0, 1, // VERSION
+                    0, 0, 0, 2, // VERSION
                     0, 0, 0, 0, 0, 0, 0, 10, // ENCAPSULED_MSG_LEN
                     0, 0, // USER_MSG_TYPE
                     0, 0, 0, 0, 0, 0, 0, 0, // NONCE
                 ],
             },
-            empty_user_message.to_bytes(fake_epk, None)?
+            empty_user_message.to_bytes(fake_epk, None, &RING_DIGEST)?
         );
 
         Ok(())
     }
 
+    #[test]
+    fn test_encapsulate_user_msg_into() -> Result<()> {
+        let fake_epk = &[0u8; 32];
+        let mut buffer = [0u8; 64];
+
+        // Same bytes as the allocating encapsulation, whatever the message type
+        for (expiry, keepalive) in &[(None, false), (Some(42), false), (Some(42), true)] {
+            let message = MessageRef::Message {
+                nonce: 123_456,
+                custom_data: Some(&[5, 4, 4, 5]),
+                expiry: *expiry,
+                keepalive: *keepalive,
+                cover: false,
+            };
+            let len = message
+                .encapsulate_user_msg_into(&mut buffer)
+                .expect("buffer must be long enough");
+            assert_eq!(
+                message.to_bytes(fake_epk, None, &RING_DIGEST)?.as_ref(),
+                &buffer[..len]
+            );
+        }
+
+        // Buffer too short
+        let message = MessageRef::Message {
+            nonce: 0,
+            custom_data: Some(&[0u8; 64]),
+            expiry: None,
+            keepalive: false,
+            cover: false,
+        };
+        assert_eq!(None, message.encapsulate_user_msg_into(&mut buffer));
+
+        // Handshake message
+        let message = MessageRef::Ack {
+            custom_data: None,
+            ack_only_sig: false,
+        };
+        assert_eq!(None, message.encapsulate_user_msg_into(&mut buffer));
+
+        Ok(())
+    }
+
     #[test]
     fn test_message_from_bytes() -> Result<()> {
         // Define message
@@ -466,7 +776,15 @@ mod tests {
             Message::Message {
                 custom_data: Some(vec![3, 3, 3, 3]),
             },
-            Message::from_bytes(msg_bytes.clone(), MsgTypeHeaders::UserMsg { nonce: 123_456 })?
+            Message::from_bytes(
+                msg_bytes.clone(),
+                MsgTypeHeaders::UserMsg {
+                    nonce: 123_456,
+                    expiry: None,
+                    keepalive: false,
+                    cover: false,
+                }
+            )?
         );
 
         // Ack message
@@ -493,8 +811,9 @@ mod tests {
                 msg_bytes.clone(),
                 MsgTypeHeaders::Connect {
                     peer_ephemeral_pk: [0u8; EPK_SIZE],
-                    sig_algo: [0u8; SIG_ALGO_LEN],
+                    sig_algo: SigAlgo::Ed25519,
                     sig_pubkey: (0..31).collect(),
+                    capabilities: Capabilities::minimal(),
                 }
             )?
         );
@@ -506,7 +825,12 @@ mod tests {
             },
             Message::from_bytes(
                 msg_bytes.drain(..2).collect(),
-                MsgTypeHeaders::UserMsg { nonce: 123_456 }
+                MsgTypeHeaders::UserMsg {
+                    nonce: 123_456,
+                    expiry: None,
+                    keepalive: false,
+                    cover: false,
+                }
             )?,
         );
 
@@ -514,7 +838,15 @@ mod tests {
         let empty_msg_bytes = vec![];
         assert_eq!(
             Message::Message { custom_data: None },
-            Message::from_bytes(empty_msg_bytes, MsgTypeHeaders::UserMsg { nonce: 123_456 })?
+            Message::from_bytes(
+                empty_msg_bytes,
+                MsgTypeHeaders::UserMsg {
+                    nonce: 123_456,
+                    expiry: None,
+                    keepalive: false,
+                    cover: false,
+                }
+            )?
         );
 
         Ok(())
@@ -16,18 +16,226 @@
 //! Manage minimal secure and decentralized transport layer.
 
 use crate::agreement::{EphemeralKeyPair, EphemeralPublicKey};
+use crate::capabilities::{Capabilities, Capability};
 use crate::config::SecureLayerConfig;
 use crate::constants::*;
-use crate::digest::sha256;
-use crate::encryption::{encrypt, EncryptAlgoWithSecretKey};
+use crate::digest::Digest;
+use crate::encryption::{encrypt_into, Direction, EncryptAlgoWithSecretKey};
 use crate::errors::IncomingMsgErr;
-use crate::message::{EncapsuledMessage, Message, MessageRef, MsgTypeHeaders};
+use crate::handshake_failure::HandshakeFailure;
+#[cfg(feature = "keylog")]
+use crate::keylog::KeyLogSink;
+use crate::memory_budget::MemoryBudget;
+use crate::message::{
+    ack_challenge, CustomDataValidator, EncapsuledMessage, HeartbeatHook, Message, MessageRef,
+    MessageView, MsgTypeHeaders, UserMsgMeta,
+};
+use crate::peer_auth::PeerAuthPolicy;
+use crate::rate_limit::TokenBucket;
 use crate::reader::{self, DecryptedIncomingData};
-use crate::signature::{self, SIG_ALGO_ED25519_ARRAY};
-use crate::status::SecureLayerStatus;
+use crate::signature::{SigAlgo, SigRequirement};
+use crate::stats::{SessionStats, SlowConsumerHook, SlowConsumerWarning, TrafficCounters};
+use crate::status::{FailReason, SecureLayerStatus, StateChangeHook, StatusMachine};
+use crate::transcript::{HandshakeTranscript, TranscriptMsg, TranscriptRecorder};
 use crate::{Action, ActionSideEffects, Error, MsgType, Result};
+use ring::constant_time::verify_slices_are_equal;
 use std::collections::BTreeSet;
-use std::io::{BufReader, BufWriter, Write};
+use std::io::{BufWriter, Write};
+use std::ops::Range;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Nonce counters checkpoint, to be persisted by the application
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct NonceCheckpoint {
+    /// Nonce for the next message to be sent
+    pub next_nonce_sent: u64,
+    /// Minimal expected nonce in the next received message
+    pub next_nonce_expected: u64,
+}
+
+/// Message loss report of a session, see `loss_report`.
+///
+/// There is no acknowledgment of user messages: the sent messages that never arrived are known
+/// by comparing our report with the one of the peer, exchanged on reconnect.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct LossReport {
+    /// Nonces consumed by sent messages: from 0 to `nonces_sent` excluded
+    pub nonces_sent: u64,
+    /// Expected nonces that never arrived, below the highest nonce received
+    pub missing_nonces: Vec<Range<u64>>,
+    /// Nonce following the highest nonce received
+    pub received_end: u64,
+}
+
+impl LossReport {
+    /// Sent nonces that never arrived according to the peer report:
+    /// those missing on the peer side and those after the highest nonce it received
+    pub fn unacknowledged(&self, peer_report: &LossReport) -> Vec<Range<u64>> {
+        let mut unacknowledged = peer_report
+            .missing_nonces
+            .iter()
+            .filter(|nonces| nonces.start < self.nonces_sent)
+            .map(|nonces| nonces.start..std::cmp::min(nonces.end, self.nonces_sent))
+            .collect::<Vec<_>>();
+        if peer_report.received_end < self.nonces_sent {
+            unacknowledged.push(peer_report.received_end..self.nonces_sent);
+        }
+        unacknowledged
+    }
+}
+
+/// Nonces reserved for user messages written concurrently, see `reserve_nonces`.
+///
+/// It holds its own copy of the session key, so that each writer task can encrypt without
+/// borrowing the secure layer. Its messages are neither rate limited nor counted in statistics,
+/// and can be emitted in any order (the peer accepts up to 10 000 pending unordered messages).
+#[derive(Clone, Debug)]
+pub struct ReservedNonces {
+    config: SecureLayerConfig,
+    encrypt_algo_with_secret: EncryptAlgoWithSecretKey,
+    nonces: Range<u64>,
+}
+
+impl ReservedNonces {
+    /// Reserved nonces not used yet
+    pub fn remaining(&self) -> Range<u64> {
+        self.nonces.clone()
+    }
+    /// Write user message with the next reserved nonce, returns this nonce
+    pub fn write_message<W: Write>(
+        &mut self,
+        data: &[u8],
+        writer: &mut BufWriter<W>,
+    ) -> Result<u64> {
+        let nonce = self.nonces.next().ok_or(Error::NoncesExhausted)?;
+        let encapsuled_msg = MessageRef::Message {
+            nonce,
+            custom_data: Some(data),
+            expiry: None,
+            keepalive: false,
+            cover: false,
+        }
+        .to_bytes(&[], None, self.config.digest)?;
+        encrypt_and_write(
+            &self.config,
+            &self.encrypt_algo_with_secret,
+            encapsuled_msg.as_ref(),
+            false,
+            writer,
+        )?;
+        Ok(nonce)
+    }
+}
+
+/// Peer CONNECT message whose signatures are not verified yet,
+/// see `MinimalSecureLayer::set_deferred_connect_verification`.
+///
+/// Its verification is CPU bound: run `verify` on any thread (an executor or a thread pool),
+/// then give the result back with `MinimalSecureLayer::complete_connect_verification`.
+#[derive(Debug)]
+pub struct PendingConnectVerification {
+    config: SecureLayerConfig,
+    frame: Vec<u8>,
+    sig_algo: SigAlgo,
+    sig_pubkey: Vec<u8>,
+    user_msg_end: usize,
+}
+
+impl PendingConnectVerification {
+    /// Verify the signature and co-signatures of the CONNECT message
+    pub fn verify(self) -> VerifiedConnect {
+        let result = verify_connect_sigs(
+            &self.config,
+            self.sig_algo,
+            &self.frame,
+            &self.sig_pubkey,
+            self.user_msg_end,
+        );
+        VerifiedConnect {
+            frame: self.frame,
+            result,
+        }
+    }
+}
+
+/// Result of a deferred CONNECT verification
+#[derive(Debug)]
+pub struct VerifiedConnect {
+    frame: Vec<u8>,
+    result: Result<()>,
+}
+
+impl VerifiedConnect {
+    /// The signature and co-signatures of the CONNECT message are valid
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+/// Signed CONNECT frame, built once for its ephemeral key pair and re-emitted across
+/// connection attempts to the same peer, to avoid signing it again on every retry.
+///
+/// It can be reused until an attempt receives the peer CONNECT message: its ephemeral key pair
+/// is then used to compute the shared secret. Re-emit it only within a short window,
+/// see `prepared_at`.
+#[derive(Debug)]
+pub struct PreparedConnect {
+    capabilities: Capabilities,
+    config: SecureLayerConfig,
+    ephemeral_kp: EphemeralKeyPair,
+    frame: Vec<u8>,
+    prepared_at: SystemTime,
+}
+
+impl PreparedConnect {
+    /// Prepare a CONNECT frame, `sign` returns the signature of the CONNECT message
+    /// (followed by its co-signatures if any, empty if the CONNECT message is not signed).
+    /// `capabilities` are those of the secure layer that will re-emit it
+    /// (`Capabilities::minimal()` or `Capabilities::complete()`).
+    pub fn new<F>(
+        config: SecureLayerConfig,
+        sig_algo: SigAlgo,
+        public_key: &[u8],
+        capabilities: Capabilities,
+        custom_data: Option<&[u8]>,
+        sign: F,
+    ) -> Result<Self>
+    where
+        F: FnOnce(&[u8]) -> Vec<u8>,
+    {
+        let ephemeral_kp = EphemeralKeyPair::generate()?;
+        let capabilities = config.advertised_capabilities(capabilities);
+        let mut frame = MessageRef::Connect {
+            sig_algo: sig_algo.id(),
+            sig_pubkey: public_key.to_vec(),
+            capabilities,
+            custom_data,
+        }
+        .to_bytes(ephemeral_kp.public_key().as_ref(), None, config.digest)?
+        .data;
+        let sigs = sign(&frame);
+        frame.extend_from_slice(&sigs);
+
+        Ok(PreparedConnect {
+            capabilities,
+            config,
+            ephemeral_kp,
+            frame,
+            prepared_at: config.clock.now(),
+        })
+    }
+    /// Signed CONNECT frame
+    #[inline]
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+    /// Time of preparation, according to the clock of the configuration
+    #[inline]
+    pub fn prepared_at(&self) -> SystemTime {
+        self.prepared_at
+    }
+}
 
 /// Minimal secure layer
 #[derive(Debug)]
@@ -35,47 +243,304 @@ pub struct MinimalSecureLayer {
     ack_msg_recv_too_early: Option<Vec<u8>>,
     cloned: bool,
     pub(crate) config: SecureLayerConfig,
+    custom_data_validator: Option<CustomDataValidator>,
+    /// Defer the verification of the peer CONNECT signatures to the caller
+    deferred_connect_verification: bool,
     pub(crate) encrypt_algo_with_secret: Option<EncryptAlgoWithSecretKey>,
     ephemeral_kp: Option<EphemeralKeyPair>,
     pub(crate) ephemeral_pubkey: EphemeralPublicKey,
+    #[cfg(feature = "keylog")]
+    key_log_sink: Option<KeyLogSink>,
+    /// Number of expired messages dropped
+    expired_msgs_count: u64,
+    /// Diagnostics of the first handshake failure
+    handshake_failure: Option<HandshakeFailure>,
+    heartbeat_hook: Option<HeartbeatHook>,
+    /// Payload of our keepalive messages
+    heartbeat_payload: Vec<u8>,
+    /// Time of the last read
+    last_read_at: Option<SystemTime>,
+    /// Time of the last message sent
+    last_sent_at: Option<SystemTime>,
+    /// Capabilities advertised in our CONNECT message
+    pub(crate) local_capabilities: Capabilities,
+    /// Longest time between two reads
+    max_read_interval: Option<Duration>,
+    /// Budget charged for buffered bytes
+    memory_budget: Option<MemoryBudget>,
+    /// Bytes charged to the memory budget
+    memory_charged: usize,
+    /// Time of the next cover frame
+    next_cover_at: Option<SystemTime>,
     /// Minimal expected nonce in the next received message
     next_nonce_expected: u64,
     /// Nonce for the next message to be sent
     next_nonce_sent: u64,
     /// List of orphan nonces (greater than next_nonce_expected)
     orphan_nonce_list: BTreeSet<u64>,
+    /// Capabilities advertised in the peer CONNECT message
+    peer_capabilities: Option<Capabilities>,
+    /// Authentication of a peer whose signature public key is not expected
+    peer_auth_policy: PeerAuthPolicy,
+    /// CONNECT message accepted from the peer, to ignore its retransmissions
+    peer_connect_msg: Option<Vec<u8>>,
     peer_epk: Option<Vec<u8>>,
+    peer_sig_algo: SigAlgo,
     peer_sig_pubkey: Option<Vec<u8>>,
-    pub(crate) status: SecureLayerStatus,
-    tmp_stack_user_msgs: Vec<Vec<u8>>,
+    /// Peer CONNECT message waiting for its deferred verification
+    pending_connect_verification: Option<PendingConnectVerification>,
+    /// Our signed CONNECT frame and its preparation time, to re-emit it in another attempt
+    pub(crate) prepared_connect_frame: Option<(Vec<u8>, SystemTime)>,
+    /// Decrypted data of the last message read with `read_view`
+    read_view_data: Option<DecryptedIncomingData>,
+    send_token_bucket: TokenBucket,
+    session_fingerprint: Option<[u8; HASH_SIZE]>,
+    slow_consumer_hook: Option<SlowConsumerHook>,
+    state_change_hook: Option<StateChangeHook>,
+    pub(crate) status: StatusMachine,
+    /// User messages received too early, with their time of reception
+    tmp_stack_user_msgs: Vec<(Vec<u8>, SystemTime)>,
+    traffic_counters: TrafficCounters,
+    /// Handshake messages, to export the handshake transcript
+    pub(crate) transcript: TranscriptRecorder,
+    /// Peer sig pubkey to authenticate once the peer ACK signature is verified
+    unauthenticated_peer_sig_pubkey: bool,
+    /// Peer CONNECT frame whose deferred verification succeeded, while it is read again
+    verified_connect_frame: Option<Vec<u8>>,
+    /// VERSION REJECT message to send to the peer
+    version_reject_msg: Option<Vec<u8>>,
+}
+
+/// Kind of the user messages we write
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum OutgoingMsgKind {
+    User,
+    Keepalive,
+    Cover,
+}
+
+/// Bytes charged for each orphan nonce
+const ORPHAN_NONCE_SIZE: usize = 8;
+
+/// Milliseconds since UNIX epoch (0 for earlier times)
+#[inline]
+fn unix_timestamp_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// Drop the metadata of user messages
+#[inline]
+fn without_meta(msgs: Vec<(Message, Option<UserMsgMeta>)>) -> Vec<Message> {
+    msgs.into_iter().map(|(message, _)| message).collect()
+}
+
+/// Verify the signature and the co-signatures of a CONNECT message
+fn verify_connect_sigs(
+    config: &SecureLayerConfig,
+    sig_algo: SigAlgo,
+    data: &[u8],
+    sig_pubkey: &[u8],
+    user_msg_end: usize,
+) -> Result<()> {
+    let data_signed = &data[..user_msg_end];
+    let sig_end = user_msg_end + sig_algo.sig_len();
+    if data.len() < sig_end {
+        sig_algo.dummy_verify(data_signed);
+        return Err(IncomingMsgErr::InvalidHashOrSig.into());
+    }
+    if !sig_algo.verify(sig_pubkey, data_signed, &data[user_msg_end..sig_end]) {
+        return Err(IncomingMsgErr::InvalidHashOrSig.into());
+    }
+
+    // Co-signatures follow the peer signature
+    if let Some(connect_sig_policy) = config.connect_sig_policy {
+        connect_sig_policy.verify(sig_algo, data_signed, &data[sig_end..])?;
+    }
+    Ok(())
+}
+
+/// Hash (if configured), encrypt and write message on a writer
+fn encrypt_and_write<W: Write>(
+    config: &SecureLayerConfig,
+    encrypt_algo_with_secret: &EncryptAlgoWithSecretKey,
+    encapsuled_message: &[u8],
+    small_msg: bool,
+    writer: &mut BufWriter<W>,
+) -> Result<()> {
+    let hash_len = if config.user_msg_hash { HASH_SIZE } else { 0 };
+    let hashed_msg_len = encapsuled_message.len() + hash_len;
+
+    // Padding bytes (zeros) then their count complete the frame to a multiple of the block length
+    let padding_trailer = if config.frame_padding.is_some() {
+        let block_len = config.padding_block_len();
+        let unpadded_frame_len =
+            hashed_msg_len + PADDING_LEN_SIZE + encrypt_algo_with_secret.tag_len();
+        let padding_len = (block_len - unpadded_frame_len % block_len) % block_len;
+        Some(padding_len as u16)
+    } else {
+        None
+    };
+    let data_will_encrypted_len = hashed_msg_len
+        + padding_trailer.map_or(0, |padding_len| padding_len as usize + PADDING_LEN_SIZE);
+
+    // Encrypt a small message in stack buffers and write it at once, without allocation
+    if small_msg
+        && data_will_encrypted_len + encrypt_algo_with_secret.tag_len() <= SMALL_MSG_BUFFER_SIZE
+    {
+        let buffer_too_short = || Error::InternalError("small message larger than its buffer");
+        let mut data_will_encrypted = [0u8; SMALL_MSG_BUFFER_SIZE];
+        data_will_encrypted
+            .get_mut(..encapsuled_message.len())
+            .ok_or_else(buffer_too_short)?
+            .copy_from_slice(encapsuled_message);
+        if config.user_msg_hash {
+            data_will_encrypted
+                .get_mut(encapsuled_message.len()..hashed_msg_len)
+                .ok_or_else(buffer_too_short)?
+                .copy_from_slice(&config.digest.sha256(encapsuled_message));
+        }
+        if let Some(padding_len) = padding_trailer {
+            // Padding bytes are already zeros
+            data_will_encrypted
+                .get_mut(data_will_encrypted_len - PADDING_LEN_SIZE..data_will_encrypted_len)
+                .ok_or_else(buffer_too_short)?
+                .copy_from_slice(&padding_len.to_be_bytes());
+        }
+        let mut encrypted_data = [0u8; SMALL_MSG_BUFFER_SIZE];
+        let encrypted_len = encrypt_into(
+            data_will_encrypted
+                .get(..data_will_encrypted_len)
+                .ok_or_else(buffer_too_short)?,
+            encrypt_algo_with_secret,
+            &mut encrypted_data,
+        )?;
+        return writer
+            .write_all(
+                encrypted_data
+                    .get(..encrypted_len)
+                    .ok_or_else(buffer_too_short)?,
+            )
+            .map_err(Error::WriteError);
+    }
+
+    // Other messages are encrypted in buffers of the frame allocator
+    let frame_allocator = config.frame_allocator();
+    let mut data_will_encrypted = frame_allocator.alloc(data_will_encrypted_len);
+
+    // Write encapsuled message
+    data_will_encrypted.extend_from_slice(encapsuled_message);
+    // Write encapsuled message hash
+    if config.user_msg_hash {
+        data_will_encrypted.extend_from_slice(&config.digest.sha256(encapsuled_message));
+    }
+    // Write padding
+    if let Some(padding_len) = padding_trailer {
+        data_will_encrypted.resize(data_will_encrypted_len - PADDING_LEN_SIZE, 0);
+        data_will_encrypted.extend_from_slice(&padding_len.to_be_bytes());
+    }
+
+    // Encrypt
+    let encrypted_len = data_will_encrypted_len + encrypt_algo_with_secret.tag_len();
+    let mut encrypted_data = frame_allocator.alloc(encrypted_len);
+    encrypted_data.resize(encrypted_len, 0);
+    let result = encrypt_into(
+        &data_will_encrypted,
+        encrypt_algo_with_secret,
+        &mut encrypted_data,
+    )
+    .and_then(|_| writer.write_all(&encrypted_data).map_err(Error::WriteError));
+    frame_allocator.release(data_will_encrypted);
+    frame_allocator.release(encrypted_data);
+    result
+}
+
+impl Drop for MinimalSecureLayer {
+    fn drop(&mut self) {
+        if let Some(ref memory_budget) = self.memory_budget {
+            memory_budget.release(self.memory_charged);
+        }
+    }
 }
 
 impl MinimalSecureLayer {
     /// Try to clone, The negotiation must have been successful
     pub fn try_clone(&mut self) -> Result<Self> {
-        if self.status == SecureLayerStatus::NegotiationSuccessful {
+        if self.status == StatusMachine::NegotiationSuccessful {
             self.cloned = true;
-            Ok(MinimalSecureLayer {
+            let mut clone = MinimalSecureLayer {
                 ack_msg_recv_too_early: None,
                 cloned: true,
                 config: self.config,
+                custom_data_validator: None,
+                deferred_connect_verification: self.deferred_connect_verification,
                 encrypt_algo_with_secret: self.encrypt_algo_with_secret.clone(),
                 ephemeral_kp: None,
                 ephemeral_pubkey: self.ephemeral_pubkey.clone(),
+                #[cfg(feature = "keylog")]
+                key_log_sink: None,
+                expired_msgs_count: self.expired_msgs_count,
+                handshake_failure: None,
+                heartbeat_hook: None,
+                heartbeat_payload: self.heartbeat_payload.clone(),
+                last_read_at: self.last_read_at,
+                last_sent_at: self.last_sent_at,
+                local_capabilities: self.local_capabilities,
+                max_read_interval: self.max_read_interval,
+                memory_budget: None,
+                memory_charged: 0,
+                next_cover_at: self.next_cover_at,
                 orphan_nonce_list: self.orphan_nonce_list.clone(),
+                peer_capabilities: self.peer_capabilities,
+                peer_auth_policy: PeerAuthPolicy::default(),
+                peer_connect_msg: self.peer_connect_msg.clone(),
                 peer_epk: None,
-                peer_sig_pubkey: None,
+                peer_sig_algo: self.peer_sig_algo,
+                peer_sig_pubkey: self.peer_sig_pubkey.clone(),
+                pending_connect_verification: None,
+                prepared_connect_frame: None,
+                read_view_data: None,
+                send_token_bucket: self.send_token_bucket,
                 next_nonce_expected: self.next_nonce_expected,
                 next_nonce_sent: self.next_nonce_sent,
-                status: SecureLayerStatus::NegotiationSuccessful,
+                session_fingerprint: self.session_fingerprint,
+                slow_consumer_hook: None,
+                state_change_hook: None,
+                status: StatusMachine::NegotiationSuccessful,
                 tmp_stack_user_msgs: self.tmp_stack_user_msgs.clone(),
-            })
+                traffic_counters: self.traffic_counters,
+                transcript: self.transcript.clone(),
+                unauthenticated_peer_sig_pubkey: false,
+                verified_connect_frame: None,
+                version_reject_msg: None,
+            };
+            if let Some(ref memory_budget) = self.memory_budget {
+                clone.set_memory_budget(memory_budget.clone());
+            }
+            Ok(clone)
         } else {
             Err(Error::NegoMustHaveBeenSuccessful)
         }
     }
+    fn apply_action(&mut self, action: Action) -> Result<Option<ActionSideEffects>> {
+        let old_status = self.status();
+        let result = self.status.apply_action(action);
+        self.notify_state_change(old_status);
+        // Schedule the first cover frame once the session is established
+        if old_status != SecureLayerStatus::Established
+            && self.status() == SecureLayerStatus::Established
+        {
+            self.next_cover_at = self
+                .config
+                .cover_traffic
+                .map(|cover_traffic| self.config.clock.now() + cover_traffic.next_interval());
+        }
+        result
+    }
     /// Change configuration
     pub fn change_config(&mut self, new_config: SecureLayerConfig) -> Result<()> {
+        new_config.validate()?;
         if !self.cloned {
             self.config = new_config;
             Ok(())
@@ -83,31 +548,136 @@ impl MinimalSecureLayer {
             Err(Error::ForbidChangeConfAfterClone)
         }
     }
+    /// Close secure layer: writing is no longer allowed, in-flight user messages can still be read
+    pub fn close(&mut self) -> Result<()> {
+        match self.status {
+            StatusMachine::Fail(_) => Err(Error::ConnectionHadFail),
+            StatusMachine::Closing => Ok(()),
+            _ => {
+                let old_status = self.status();
+                self.status = StatusMachine::Closing;
+                self.notify_state_change(old_status);
+                Ok(())
+            }
+        }
+    }
     /// Create minimal secure layer
     pub fn create(
         config: SecureLayerConfig,
         expected_remote_sig_public_key: Option<Vec<u8>>,
     ) -> Result<Self> {
-        let ephemeral_kp = EphemeralKeyPair::generate()?;
+        config.validate()?;
+        Ok(Self::create_with_ephemeral_kp(
+            config,
+            expected_remote_sig_public_key,
+            EphemeralKeyPair::generate()?,
+        ))
+    }
+    /// Create minimal secure layer whose CONNECT message is the prepared one:
+    /// write `prepared_connect_frame()` instead of creating a CONNECT message
+    pub fn create_with_prepared_connect(
+        prepared_connect: PreparedConnect,
+        expected_remote_sig_public_key: Option<Vec<u8>>,
+    ) -> Result<Self> {
+        let PreparedConnect {
+            capabilities,
+            config,
+            ephemeral_kp,
+            frame,
+            prepared_at,
+        } = prepared_connect;
+        config.validate()?;
+        let mut secure_layer =
+            Self::create_with_ephemeral_kp(config, expected_remote_sig_public_key, ephemeral_kp);
+        secure_layer.local_capabilities = capabilities;
+        secure_layer.apply_action(Action::Create(MsgType::Connect))?;
+        let signed_len = reader::read(None, &frame, false)?.user_msg_end;
+        secure_layer.transcript.record(
+            TranscriptMsg::LocalConnect,
+            &frame[..signed_len],
+            &frame[signed_len..],
+        );
+        secure_layer.prepared_connect_frame = Some((frame, prepared_at));
+
+        Ok(secure_layer)
+    }
+    /// Signed CONNECT frame to re-emit, if created with a prepared CONNECT frame
+    #[inline]
+    pub fn prepared_connect_frame(&self) -> Option<&[u8]> {
+        self.prepared_connect_frame
+            .as_ref()
+            .map(|(frame, _)| &frame[..])
+    }
+    /// Recover the prepared CONNECT frame, to re-emit it in another connection attempt.
+    /// Returns `None` if the peer CONNECT message has been received (the ephemeral key pair
+    /// is then used) or if the signed CONNECT frame is unknown (created by
+    /// `create_connect_message`, to be signed by the caller).
+    pub fn into_prepared_connect(mut self) -> Option<PreparedConnect> {
+        match (self.ephemeral_kp.take(), self.prepared_connect_frame.take()) {
+            (Some(ephemeral_kp), Some((frame, prepared_at))) => Some(PreparedConnect {
+                capabilities: self.local_capabilities,
+                config: self.config,
+                ephemeral_kp,
+                frame,
+                prepared_at,
+            }),
+            _ => None,
+        }
+    }
+    fn create_with_ephemeral_kp(
+        config: SecureLayerConfig,
+        expected_remote_sig_public_key: Option<Vec<u8>>,
+        ephemeral_kp: EphemeralKeyPair,
+    ) -> Self {
         let ephemeral_pubkey = ephemeral_kp.public_key().clone();
 
-        let secure_layer = MinimalSecureLayer {
+        MinimalSecureLayer {
             ack_msg_recv_too_early: None,
             cloned: false,
             config,
+            custom_data_validator: None,
+            deferred_connect_verification: false,
             encrypt_algo_with_secret: None,
             ephemeral_pubkey,
             ephemeral_kp: Some(ephemeral_kp),
+            #[cfg(feature = "keylog")]
+            key_log_sink: None,
+            expired_msgs_count: 0,
+            handshake_failure: None,
+            heartbeat_hook: None,
+            heartbeat_payload: Vec::new(),
+            last_read_at: None,
+            last_sent_at: None,
+            local_capabilities: Capabilities::minimal(),
+            max_read_interval: None,
+            memory_budget: None,
+            memory_charged: 0,
+            next_cover_at: None,
             orphan_nonce_list: BTreeSet::new(),
+            peer_capabilities: None,
+            peer_auth_policy: PeerAuthPolicy::default(),
+            peer_connect_msg: None,
             peer_epk: None,
+            // An expected remote public key is necessarily an Ed25519 key
+            peer_sig_algo: SigAlgo::Ed25519,
             peer_sig_pubkey: expected_remote_sig_public_key,
+            pending_connect_verification: None,
+            prepared_connect_frame: None,
+            read_view_data: None,
+            send_token_bucket: TokenBucket::new(),
             next_nonce_expected: 0,
             next_nonce_sent: 0,
-            status: SecureLayerStatus::init(),
+            session_fingerprint: None,
+            slow_consumer_hook: None,
+            state_change_hook: None,
+            status: StatusMachine::init(),
             tmp_stack_user_msgs: Vec::new(),
-        };
-
-        Ok(secure_layer)
+            traffic_counters: TrafficCounters::default(),
+            transcript: TranscriptRecorder::default(),
+            unauthenticated_peer_sig_pubkey: false,
+            verified_connect_frame: None,
+            version_reject_msg: None,
+        }
     }
     pub(crate) fn compute_shared_secret(&mut self, peer_ephemeral_public_key: &[u8]) -> Result<()> {
         let encrypt_algo = self.config.encrypt_algo;
@@ -118,34 +688,174 @@ impl MinimalSecureLayer {
                 encrypt_algo.shared_secret_len(),
             )?;
 
-            self.encrypt_algo_with_secret =
-                Some(EncryptAlgoWithSecretKey::build(encrypt_algo, shared_secret));
+            let session_fingerprint = Self::compute_session_fingerprint(
+                self.config.digest,
+                self.ephemeral_pubkey.as_ref(),
+                peer_ephemeral_public_key,
+            );
+            #[cfg(feature = "keylog")]
+            {
+                if let Some(ref mut key_log_sink) = self.key_log_sink {
+                    key_log_sink.log_session_secret(&session_fingerprint, shared_secret.as_ref());
+                }
+            }
+
+            self.encrypt_algo_with_secret = Some(EncryptAlgoWithSecretKey::build(
+                encrypt_algo,
+                shared_secret,
+                Direction::new(self.ephemeral_pubkey.as_ref(), peer_ephemeral_public_key),
+                self.config.key_context.as_bytes(),
+            )?);
+            self.session_fingerprint = Some(session_fingerprint);
 
             Ok(())
         } else if self.encrypt_algo_with_secret.is_some() {
             // Shared secret already computed, do nothing
             Ok(())
         } else {
-            unreachable!("dev error: fisrt call of compute_shared_secret() without ephemeral_kp!")
+            Err(Error::InternalError(
+                "first call of compute_shared_secret() without ephemeral_kp",
+            ))
         }
     }
-    /// Drain temporary stack of remote messages
+    pub(crate) fn compute_session_fingerprint(
+        digest: &dyn Digest,
+        self_epk: &[u8],
+        peer_epk: &[u8],
+    ) -> [u8; HASH_SIZE] {
+        let (min_epk, max_epk) = if self_epk < peer_epk {
+            (self_epk, peer_epk)
+        } else {
+            (peer_epk, self_epk)
+        };
+        let mut epks = Vec::with_capacity(min_epk.len() + max_epk.len());
+        epks.extend_from_slice(min_epk);
+        epks.extend_from_slice(max_epk);
+
+        digest.sha256(&epks)
+    }
+    /// Drain temporary stack of remote messages, the user messages received too early.
+    /// Not needed when reading with `read_all`, which drains it.
+    #[inline]
     pub fn drain_tmp_stack_user_msgs(&mut self) -> Result<Vec<Message>> {
-        let bin_msgs: Vec<Vec<u8>> = self.tmp_stack_user_msgs.drain(..).collect();
+        Ok(without_meta(self.drain_tmp_stack_user_msgs_with_meta()?))
+    }
+    fn drain_tmp_stack_user_msgs_with_meta(
+        &mut self,
+    ) -> Result<Vec<(Message, Option<UserMsgMeta>)>> {
+        let bin_msgs: Vec<(Vec<u8>, SystemTime)> = self.tmp_stack_user_msgs.drain(..).collect();
         let mut msgs = Vec::with_capacity(bin_msgs.len());
-        for bin_msg in bin_msgs {
-            if let Some(msg) = self.read_inner(&bin_msg, false)? {
-                msgs.push(msg);
+        let mut result = Ok(());
+        for (bin_msg, received_at) in bin_msgs {
+            match self.read_inner(&bin_msg, false) {
+                Ok(Some(decrypted_incoming_data)) => {
+                    let meta = decrypted_incoming_data.user_msg_meta(received_at);
+                    msgs.push((decrypted_incoming_data.into_message()?, meta))
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
             }
         }
-        Ok(msgs)
+        self.release_unused_memory();
+        result.map(|()| msgs)
     }
     #[inline]
     /// Encapsulate message
     fn encapsulate_message(&mut self, message: &MessageRef) -> Result<EncapsuledMessage> {
-        message.to_bytes(&self.ephemeral_pubkey.as_ref(), self.peer_epk.as_ref())
+        message.to_bytes(
+            &self.ephemeral_pubkey.as_ref(),
+            self.peer_epk.as_ref(),
+            self.config.digest,
+        )
+    }
+    fn fail(&mut self, error: Error) -> Error {
+        // Keep the first failure reason
+        if let StatusMachine::Fail(_) = self.status {
+        } else {
+            let old_status = self.status();
+            self.status = StatusMachine::Fail(FailReason::from(&error));
+            self.notify_state_change(old_status);
+        }
+        error
     }
-    /// Take ACK message received too early
+    fn notify_state_change(&mut self, old_status: SecureLayerStatus) {
+        let new_status = self.status();
+        if new_status != old_status {
+            if let Some(StateChangeHook(ref mut hook)) = self.state_change_hook {
+                hook(old_status, new_status);
+            }
+        }
+    }
+    #[cfg(feature = "keylog")]
+    /// Set a sink receiving the session secret in key log format
+    /// (`PKSTL_SESSION_SECRET <SESSION_FINGERPRINT> <SHARED_SECRET>` in lowercase hexadecimal),
+    /// to decrypt its own captures during development. Never enable it in production.
+    /// The sink is not inherited by clones.
+    pub fn set_key_log_sink<F>(&mut self, sink: F)
+    where
+        F: FnMut(&str) + Send + 'static,
+    {
+        self.key_log_sink = Some(KeyLogSink(Box::new(sink)));
+    }
+    /// Set the authentication policy of a peer whose signature public key is not expected
+    /// at creation (`PeerAuthPolicy::AcceptAny` without audit by default).
+    /// The policy is not inherited by clones.
+    pub fn set_peer_auth_policy(&mut self, policy: PeerAuthPolicy) {
+        self.peer_auth_policy = policy;
+    }
+    /// Set a hook called on each status change, with the old and the new status.
+    /// The hook is not inherited by clones.
+    pub fn on_state_change<F>(&mut self, hook: F)
+    where
+        F: FnMut(SecureLayerStatus, SecureLayerStatus) + Send + 'static,
+    {
+        self.state_change_hook = Some(StateChangeHook(Box::new(hook)));
+    }
+    /// Set a validator of the custom data of peer handshake messages (for example an API token
+    /// or a signed invitation), called once their signature is verified and before the
+    /// negotiation can succeed. The session fingerprint, unknown for an ACK message received
+    /// before the CONNECT message, allows to check a response bound to this session.
+    /// Rejected custom data fails the secure layer with `IncomingMsgErr::RejectedCustomData`.
+    /// The validator is not inherited by clones.
+    pub fn set_custom_data_validator<F>(&mut self, validator: F)
+    where
+        F: FnMut(&MessageView<'_>, Option<&[u8; HASH_SIZE]>) -> bool + Send + 'static,
+    {
+        self.custom_data_validator = Some(CustomDataValidator(Box::new(validator)));
+    }
+    /// Set a hook called on each keepalive message received, with its payload
+    /// (application liveness data, empty if none).
+    /// The hook is not inherited by clones.
+    pub fn on_heartbeat<F>(&mut self, hook: F)
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        self.heartbeat_hook = Some(HeartbeatHook(Box::new(hook)));
+    }
+    /// Set a hook called when a read leaves more than `buffered_bytes_threshold` bytes buffered
+    /// by the session, a sign that the consumer lags. It is called again only once the
+    /// buffered bytes have fallen below the threshold.
+    /// The hook is not inherited by clones.
+    pub fn on_slow_consumer<F>(&mut self, buffered_bytes_threshold: usize, hook: F)
+    where
+        F: FnMut(SlowConsumerWarning) + Send + 'static,
+    {
+        self.slow_consumer_hook = Some(SlowConsumerHook {
+            threshold: buffered_bytes_threshold,
+            lagging: false,
+            hook: Box::new(hook),
+        });
+    }
+    /// Set the payload of our next keepalive messages, a small application liveness data
+    /// (for example the current block height)
+    pub fn set_heartbeat_payload(&mut self, payload: &[u8]) {
+        self.heartbeat_payload = payload.to_vec();
+    }
+    /// Take ACK message received too early.
+    /// Not needed when reading with `read_all`, which takes it.
     #[inline]
     pub fn take_ack_msg_recv_too_early(&mut self) -> Result<Option<Message>> {
         if let Some(bin_ack_msg) = self.ack_msg_recv_too_early.take() {
@@ -154,31 +864,390 @@ impl MinimalSecureLayer {
             Ok(None)
         }
     }
+    /// Defer the verification of the peer CONNECT signatures, to keep the accept loop latency
+    /// low during connection storms: reading the peer CONNECT message then returns `None`, and
+    /// its verification must be taken with `take_pending_connect_verification`.
+    #[inline]
+    pub fn set_deferred_connect_verification(&mut self, deferred: bool) {
+        self.deferred_connect_verification = deferred;
+    }
+    /// Take the peer CONNECT message waiting for its deferred verification
     #[inline]
-    /// Read incoming data
+    pub fn take_pending_connect_verification(&mut self) -> Option<PendingConnectVerification> {
+        self.pending_connect_verification.take()
+    }
+    /// Complete the handshake with the result of a deferred CONNECT verification,
+    /// returns the peer CONNECT message and the messages it releases (see `read_all`)
+    #[inline]
+    pub fn complete_connect_verification(
+        &mut self,
+        verified_connect: VerifiedConnect,
+    ) -> Result<Vec<Message>> {
+        Ok(without_meta(self.complete_connect_verification_with_meta(
+            verified_connect,
+        )?))
+    }
+    pub(crate) fn complete_connect_verification_with_meta(
+        &mut self,
+        verified_connect: VerifiedConnect,
+    ) -> Result<Vec<(Message, Option<UserMsgMeta>)>> {
+        let VerifiedConnect { frame, result } = verified_connect;
+        if let Err(e) = result {
+            return Err(self.reject_handshake(e, &frame, true));
+        }
+        self.verified_connect_frame = Some(frame.clone());
+        let result = self.read_all_with_meta(&frame);
+        self.verified_connect_frame = None;
+        result
+    }
+    /// Get the diagnostics of the first handshake failure, if the negotiation failed
+    #[inline]
+    pub fn handshake_failure(&self) -> Option<&HandshakeFailure> {
+        self.handshake_failure.as_ref()
+    }
+    /// Take the VERSION REJECT message to send to the peer before closing the connection,
+    /// available after reading a handshake frame of an unsupported protocol version.
+    /// It tells the peer which versions we support.
+    pub fn take_version_reject_message(&mut self) -> Option<Vec<u8>> {
+        self.version_reject_msg.take()
+    }
+    /// Checkpoint nonce counters.
+    /// Orphan nonces are not persisted, so the expected nonce is set after the highest nonce
+    /// received: pending messages will be rejected rather than risking to accept a replay.
+    pub fn nonce_checkpoint(&self) -> NonceCheckpoint {
+        let next_nonce_expected = match self.highest_nonce_received() {
+            Some(highest_nonce) => highest_nonce + 1,
+            None => self.next_nonce_expected,
+        };
+        NonceCheckpoint {
+            next_nonce_sent: self.next_nonce_sent,
+            next_nonce_expected,
+        }
+    }
+    /// Get the message loss report, usually once the session is closed.
+    /// Messages expected after the highest nonce received can only be known from the peer
+    /// report (see `LossReport::unacknowledged`).
+    pub fn loss_report(&self) -> LossReport {
+        let mut missing_nonces = Vec::new();
+        let mut next_nonce = self.next_nonce_expected;
+        for orphan_nonce in &self.orphan_nonce_list {
+            if *orphan_nonce > next_nonce {
+                missing_nonces.push(next_nonce..*orphan_nonce);
+            }
+            next_nonce = orphan_nonce.saturating_add(1);
+        }
+        LossReport {
+            nonces_sent: self.next_nonce_sent,
+            missing_nonces,
+            received_end: next_nonce,
+        }
+    }
+    /// Reserve the `n` next nonces, for user messages written concurrently by several tasks
+    /// (see `ReservedNonces`). The negotiation must have been successful.
+    pub fn reserve_nonces(&mut self, n: u64) -> Result<ReservedNonces> {
+        if self.status != StatusMachine::NegotiationSuccessful {
+            return Err(Error::NegoMustHaveBeenSuccessful);
+        }
+        let encrypt_algo_with_secret = match self.encrypt_algo_with_secret {
+            Some(ref encrypt_algo_with_secret) => encrypt_algo_with_secret.clone(),
+            None => return Err(Error::NegoMustHaveBeenSuccessful),
+        };
+        let end = self
+            .next_nonce_sent
+            .checked_add(n)
+            .ok_or(Error::NoncesExhausted)?;
+
+        let nonces = self.next_nonce_sent..end;
+        self.next_nonce_sent = end;
+        Ok(ReservedNonces {
+            config: self.frame_config(),
+            encrypt_algo_with_secret,
+            nonces,
+        })
+    }
+    /// Restore nonce counters from a checkpoint.
+    /// The nonce of the next message sent jumps `margin` nonces ahead, to cover the messages
+    /// that may have been sent after the checkpoint was persisted. Counters never go backward.
+    pub fn restore_nonce_checkpoint(
+        &mut self,
+        checkpoint: NonceCheckpoint,
+        margin: u64,
+    ) -> Result<()> {
+        if let StatusMachine::Fail(_) = self.status {
+            return Err(Error::ConnectionHadFail);
+        }
+
+        let next_nonce_sent = checkpoint.next_nonce_sent.saturating_add(margin);
+        if next_nonce_sent > self.next_nonce_sent {
+            self.next_nonce_sent = next_nonce_sent;
+        }
+        if checkpoint.next_nonce_expected > self.next_nonce_expected {
+            self.next_nonce_expected = checkpoint.next_nonce_expected;
+            self.orphan_nonce_list = self.orphan_nonce_list.split_off(&self.next_nonce_expected);
+            self.release_unused_memory();
+        }
+        Ok(())
+    }
+    /// Get nonces received ahead of the next expected nonce (the missing nonces below them
+    /// are reordered or lost messages)
+    #[inline]
+    pub fn pending_orphans(&self) -> &BTreeSet<u64> {
+        &self.orphan_nonce_list
+    }
+    /// Get highest nonce received, if any
+    #[inline]
+    pub fn highest_nonce_received(&self) -> Option<u64> {
+        match self.orphan_nonce_list.iter().next_back() {
+            Some(max_orphan_nonce) => Some(*max_orphan_nonce),
+            None => self.next_nonce_expected.checked_sub(1),
+        }
+    }
+    /// Get bytes held in buffers (messages received too early, orphan nonces,
+    /// accepted CONNECT message)
+    pub fn buffered_bytes(&self) -> usize {
+        self.tmp_stack_user_msgs
+            .iter()
+            .map(|(msg, _)| msg.len())
+            .sum::<usize>()
+            + self.ack_msg_recv_too_early.as_ref().map_or(0, Vec::len)
+            + self.peer_connect_msg.as_ref().map_or(0, Vec::len)
+            + self.orphan_nonce_list.len() * ORPHAN_NONCE_SIZE
+    }
+    /// Charge buffered bytes to a memory budget, usually shared with other secure layers.
+    /// An incoming message that would exceed the budget cap is dropped with
+    /// `Error::MemoryBudgetExceeded`, without failing the secure layer.
+    pub fn set_memory_budget(&mut self, memory_budget: MemoryBudget) {
+        if let Some(ref old_memory_budget) = self.memory_budget {
+            old_memory_budget.release(self.memory_charged);
+        }
+        self.memory_charged = self.buffered_bytes();
+        memory_budget.force_reserve(self.memory_charged);
+        self.memory_budget = Some(memory_budget);
+    }
+    fn reserve_memory(&mut self, bytes: usize) -> Result<()> {
+        if let Some(ref memory_budget) = self.memory_budget {
+            if !memory_budget.try_reserve(bytes) {
+                return Err(Error::MemoryBudgetExceeded);
+            }
+            self.memory_charged += bytes;
+        }
+        Ok(())
+    }
+    /// Release the memory charged beyond buffered bytes
+    fn release_unused_memory(&mut self) {
+        if let Some(ref memory_budget) = self.memory_budget {
+            let buffered_bytes = self.buffered_bytes();
+            if self.memory_charged > buffered_bytes {
+                memory_budget.release(self.memory_charged - buffered_bytes);
+                self.memory_charged = buffered_bytes;
+            }
+        }
+    }
+    /// Measure the time between reads and warn the slow consumer hook
+    fn watch_consumer(&mut self) {
+        let now = self.config.clock.now();
+        let read_interval = self
+            .last_read_at
+            .and_then(|last_read_at| now.duration_since(last_read_at).ok());
+        self.last_read_at = Some(now);
+        if read_interval > self.max_read_interval {
+            self.max_read_interval = read_interval;
+        }
+
+        let buffered_bytes = self.buffered_bytes();
+        if let Some(ref mut slow_consumer_hook) = self.slow_consumer_hook {
+            let lagging = buffered_bytes > slow_consumer_hook.threshold;
+            if lagging && !slow_consumer_hook.lagging {
+                (slow_consumer_hook.hook)(SlowConsumerWarning {
+                    buffered_bytes,
+                    read_interval,
+                });
+            }
+            slow_consumer_hook.lagging = lagging;
+        }
+    }
+    /// Get capabilities advertised by the peer (none until its CONNECT message is received)
+    #[inline]
+    pub fn peer_capabilities(&self) -> Option<Capabilities> {
+        self.peer_capabilities
+    }
+    /// Check that the peer supports `capability` (assumed until its CONNECT message is received)
+    #[inline]
+    fn peer_supports(&self, capability: Capability) -> bool {
+        match self.peer_capabilities {
+            Some(peer_capabilities) => peer_capabilities.contains(capability),
+            None => true,
+        }
+    }
+    /// Check that both peers advertise `capability` (false until the peer CONNECT message is received)
+    #[inline]
+    fn negotiated(&self, capability: Capability) -> bool {
+        match self.peer_capabilities {
+            Some(peer_capabilities) => {
+                self.local_capabilities.contains(capability)
+                    && peer_capabilities.contains(capability)
+            }
+            None => false,
+        }
+    }
+    /// Configuration of our user message frames, with the options negotiated with the peer
+    /// (our frames are padded if we have advertised it)
+    fn frame_config(&self) -> SecureLayerConfig {
+        let frame_padding = if self.local_capabilities.contains(Capability::FramePadding) {
+            Some(self.config.frame_padding.unwrap_or(1))
+        } else {
+            None
+        };
+        SecureLayerConfig {
+            user_msg_hash: !self.negotiated(Capability::OmitUserMsgHash),
+            frame_padding,
+            ..self.config
+        }
+    }
+    /// Fail with `Error::PeerUnsupported` if the peer does not support `capability`
+    #[inline]
+    pub(crate) fn require_peer(&self, capability: Capability) -> Result<()> {
+        match self.peer_capabilities {
+            Some(peer_capabilities) => peer_capabilities.require(capability),
+            None => Ok(()),
+        }
+    }
+    /// Get peer signature public key (expected or received)
+    #[inline]
+    pub fn peer_sig_public_key(&self) -> Option<&[u8]> {
+        self.peer_sig_pubkey.as_ref().map(|pubkey| &pubkey[..])
+    }
+    #[inline]
+    /// Read incoming data.
+    ///
+    /// Messages received too early are set aside and not returned by later calls: they must be
+    /// taken with `take_ack_msg_recv_too_early` and `drain_tmp_stack_user_msgs`.
+    /// Draining is opt-in in minimal mode, use `read_all` to get them back automatically
+    /// (`SecureLayer` always drains them).
     pub fn read(&mut self, incoming_data: &[u8]) -> Result<Option<Message>> {
-        self.read_inner(incoming_data, true)
+        Ok(self
+            .read_with_meta(incoming_data)?
+            .map(|(message, _)| message))
+    }
+    fn read_with_meta(
+        &mut self,
+        incoming_data: &[u8],
+    ) -> Result<Option<(Message, Option<UserMsgMeta>)>> {
+        let received_at = self.config.clock.now();
+        let result = self.read_inner(incoming_data, true);
+        self.release_unused_memory();
+        self.watch_consumer();
+        match result? {
+            Some(decrypted_incoming_data) => {
+                let meta = decrypted_incoming_data.user_msg_meta(received_at);
+                Ok(Some((decrypted_incoming_data.into_message()?, meta)))
+            }
+            None => Ok(None),
+        }
+    }
+    /// Read incoming data, the message borrows the decrypted data instead of copying it.
+    /// The decrypted data is kept until the next call.
+    /// Messages received too early are set aside, as with `read`.
+    pub fn read_view(&mut self, incoming_data: &[u8]) -> Result<Option<MessageView<'_>>> {
+        if let Some(read_view_data) = self.read_view_data.take() {
+            self.config.frame_allocator().release(read_view_data.data);
+        }
+        let result = self.read_inner(incoming_data, true);
+        self.release_unused_memory();
+        self.watch_consumer();
+        self.read_view_data = result?;
+        Ok(self
+            .read_view_data
+            .as_ref()
+            .map(DecryptedIncomingData::view))
+    }
+    /// Read incoming data and the messages it releases: the ACK message received too early
+    /// after a CONNECT message, and the user messages received too early after an ACK message.
+    #[inline]
+    pub fn read_all(&mut self, incoming_data: &[u8]) -> Result<Vec<Message>> {
+        Ok(without_meta(self.read_all_with_meta(incoming_data)?))
+    }
+    /// Read incoming data and the messages it releases (see `read_all`),
+    /// with the metadata of user messages
+    pub(crate) fn read_all_with_meta(
+        &mut self,
+        incoming_data: &[u8],
+    ) -> Result<Vec<(Message, Option<UserMsgMeta>)>> {
+        let mut msgs = Vec::new();
+        if let Some(msg) = self.read_with_meta(incoming_data)? {
+            let (connect_received, mut ack_received) = match msg.0 {
+                Message::Connect { .. } => (true, false),
+                Message::Ack { .. } => (false, true),
+                Message::Message { .. } => (false, false),
+            };
+            msgs.push(msg);
+
+            if connect_received {
+                if let Some(ack_msg) = self.take_ack_msg_recv_too_early()? {
+                    msgs.push((ack_msg, None));
+                    ack_received = true;
+                }
+            }
+            if ack_received {
+                msgs.append(&mut self.drain_tmp_stack_user_msgs_with_meta()?);
+            }
+        }
+        Ok(msgs)
     }
     fn read_inner(
         &mut self,
         incoming_data: &[u8],
         check_encrypt_state: bool,
-    ) -> Result<Option<Message>> {
+    ) -> Result<Option<DecryptedIncomingData>> {
+        // Reject oversized frames before any crypto work
+        if let Some(max_frame_len) = self.config.max_frame_len {
+            if incoming_data.len() > max_frame_len {
+                return Err(Error::FrameTooLarge {
+                    max: max_frame_len,
+                    got: incoming_data.len(),
+                });
+            }
+        }
+
+        // Ignore retransmission of the accepted CONNECT message
+        if let Some(ref peer_connect_msg) = self.peer_connect_msg {
+            if incoming_data == &peer_connect_msg[..] {
+                return Ok(None);
+            }
+        }
+
         // Decrypt incoming messsage and parse headers
         let DecryptedIncomingData {
-            mut data,
+            data,
             user_msg_begin,
             user_msg_end,
             msg_type_headers,
-        } = match reader::read(
+        } = match reader::read_with_allocator(
+            self.config.frame_allocator(),
             self.encrypt_algo_with_secret.as_ref(),
             incoming_data,
             check_encrypt_state,
         ) {
             Ok(decrypted_incoming_data) => decrypted_incoming_data,
+            // A truncated frame is not authenticated, it must not break the session
+            Err(e @ Error::FrameTruncated { .. }) => return Err(e),
+            // The peer does not speak our version, there is nothing to hide
+            Err(e @ Error::VersionRejected { .. }) => return Err(self.fail(e)),
             Err(e) => {
-                self.status = SecureLayerStatus::Fail;
-                return Err(e);
+                let status = self.status();
+                let negotiating = status == SecureLayerStatus::AwaitingConnect
+                    || status == SecureLayerStatus::AwaitingAck;
+                if let (true, Error::RecvInvalidMsg(IncomingMsgErr::UnsupportedVersion)) =
+                    (negotiating, &e)
+                {
+                    self.version_reject_msg = reader::create_version_reject_msg().ok();
+                }
+                let e = self.fail(e);
+                return Err(if negotiating {
+                    self.reject_handshake(e, incoming_data, false)
+                } else {
+                    e
+                });
             }
         };
 
@@ -186,78 +1255,237 @@ impl MinimalSecureLayer {
         match msg_type_headers {
             MsgTypeHeaders::Connect {
                 peer_ephemeral_pk,
+                sig_algo,
                 ref sig_pubkey,
-                ..
+                capabilities,
             } => {
-                // Verify (or get) peer sig pubkey
-                if let Some(ref peer_sig_pubkey) = self.peer_sig_pubkey {
-                    if sig_pubkey != peer_sig_pubkey {
-                        return Err(Error::UnexpectedRemoteSigPubKey);
+                // Both directions would share the same key with our own ephemeral public key
+                if verify_slices_are_equal(&peer_ephemeral_pk[..], self.ephemeral_pubkey.as_ref())
+                    .is_ok()
+                {
+                    let e = self.fail(IncomingMsgErr::ReflectedConnectMsg.into());
+                    return Err(self.reject_handshake(e, incoming_data, true));
+                }
+                // Run all checks before rejecting anything, so that all causes take the same time
+                let sig_algo_accepted = self.config.accepted_sig_algos.contains(sig_algo);
+                let sig_pubkey_expected = match self.peer_sig_pubkey {
+                    Some(ref peer_sig_pubkey) => {
+                        verify_slices_are_equal(sig_pubkey, peer_sig_pubkey).is_ok()
                     }
+                    None => true,
+                };
+                let sigs_verified = self.config.peer_sig_requirement
+                    != SigRequirement::ConnectAndAck
+                    || self.verified_connect_frame.as_ref().map(|frame| &frame[..])
+                        == Some(incoming_data);
+                let defer_verification = !sigs_verified && self.deferred_connect_verification;
+                let sigs_verification = if sigs_verified || defer_verification {
+                    Ok(())
                 } else {
-                    self.peer_sig_pubkey = Some(sig_pubkey.to_vec());
-                }
+                    verify_connect_sigs(&self.config, sig_algo, &data, sig_pubkey, user_msg_end)
+                };
 
-                // Verify sig
-                // The reader has already made sure that the signature algorithm is supported,
-                // as we only support the Ed25519 algorithm, we know that it is necessarily this one.
-                if !self.verify_sig(&data, sig_pubkey, user_msg_end) {
-                    return Err(IncomingMsgErr::InvalidHashOrSig.into());
+                // Verify that the peer signature algorithm is accepted
+                if !sig_algo_accepted {
+                    let e = self.fail(IncomingMsgErr::UnsupportedSigAlgo.into());
+                    return Err(self.reject_handshake(e, incoming_data, true));
+                }
+                // Verify peer sig pubkey
+                if !sig_pubkey_expected {
+                    return Err(self.reject_handshake(
+                        Error::UnexpectedRemoteSigPubKey,
+                        incoming_data,
+                        true,
+                    ));
                 }
+                // Verify sig and co-signatures, or let the caller verify them
+                if let Err(e) = sigs_verification {
+                    return Err(self.reject_handshake(e, incoming_data, true));
+                }
+                if defer_verification {
+                    self.pending_connect_verification = Some(PendingConnectVerification {
+                        config: self.config,
+                        frame: incoming_data.to_vec(),
+                        sig_algo,
+                        sig_pubkey: sig_pubkey.to_vec(),
+                        user_msg_end,
+                    });
+                    return Ok(None);
+                }
+                // Authenticate an unexpected peer sig pubkey once its signature is verified,
+                // the public key of an anonymous peer is not authenticated
+                if self.peer_sig_pubkey.is_none() {
+                    match self.config.peer_sig_requirement {
+                        SigRequirement::ConnectAndAck => {
+                            if let Err(e) = self.peer_auth_policy.authenticate(sig_pubkey) {
+                                return Err(self.reject_handshake(e, incoming_data, true));
+                            }
+                            self.peer_sig_pubkey = Some(sig_pubkey.to_vec());
+                        }
+                        SigRequirement::AckOnly => {
+                            self.peer_sig_pubkey = Some(sig_pubkey.to_vec());
+                            self.unauthenticated_peer_sig_pubkey = true;
+                        }
+                        SigRequirement::Anonymous => {}
+                    }
+                }
+                self.peer_sig_algo = sig_algo;
+                self.peer_capabilities = Some(capabilities);
 
                 // Update status
-                self.status
-                    .apply_action(Action::Receive(MsgType::Connect))?;
+                if let Err(e) = self.apply_action(Action::Receive(MsgType::Connect)) {
+                    return Err(self.reject_handshake(e, incoming_data, true));
+                }
+                if self.reserve_memory(incoming_data.len()).is_ok() {
+                    self.peer_connect_msg = Some(incoming_data.to_vec());
+                }
 
                 // Get peeer EPK and compute shared secret
                 self.peer_epk = Some(peer_ephemeral_pk.to_vec());
                 self.compute_shared_secret(&peer_ephemeral_pk[..])?;
+
+                // Validate custom data
+                if !self
+                    .validate_custom_data(&data[user_msg_begin..user_msg_end], &msg_type_headers)
+                {
+                    let e = self.fail(IncomingMsgErr::RejectedCustomData.into());
+                    return Err(self.reject_handshake(e, incoming_data, true));
+                }
+                self.transcript.record(
+                    TranscriptMsg::PeerConnect,
+                    &data[..user_msg_end],
+                    &data[user_msg_end..],
+                );
             }
             MsgTypeHeaders::Ack { challenge } => {
+                // Run all checks before rejecting anything, so that all causes take the same time
+                let expected_challenge = match self.config.peer_sig_requirement {
+                    SigRequirement::AckOnly => self.peer_epk.as_ref().map(|peer_epk| {
+                        ack_challenge(
+                            self.config.digest,
+                            self.ephemeral_pubkey.as_ref(),
+                            Some(peer_epk),
+                        )
+                    }),
+                    _ => Some(ack_challenge(
+                        self.config.digest,
+                        self.ephemeral_pubkey.as_ref(),
+                        None,
+                    )),
+                };
+                let challenge_valid = match expected_challenge {
+                    Some(ref expected_challenge) => {
+                        verify_slices_are_equal(&challenge, expected_challenge).is_ok()
+                    }
+                    None => true,
+                };
+                let sig_valid_opt = match (expected_challenge, self.config.peer_sig_requirement) {
+                    // The challenge is known only after the peer CONNECT message
+                    (None, _) => None,
+                    (Some(_), SigRequirement::Anonymous) => Some(true),
+                    (Some(_), _) => self.peer_sig_pubkey.as_ref().map(|peer_sig_pubkey| {
+                        self.verify_sig(&data, peer_sig_pubkey, user_msg_end)
+                    }),
+                };
+
                 // Verify challenge
-                if challenge != sha256(self.ephemeral_pubkey.as_ref()).as_ref() {
-                    return Err(IncomingMsgErr::InvalidChallenge.into());
+                if !challenge_valid {
+                    return Err(self.reject_handshake(
+                        IncomingMsgErr::InvalidChallenge.into(),
+                        incoming_data,
+                        sig_valid_opt.is_some(),
+                    ));
                 }
 
-                let peer_sig_pubkey = if let Some(ref peer_sig_pubkey) = self.peer_sig_pubkey {
-                    peer_sig_pubkey
-                } else if self.ack_msg_recv_too_early.is_none() {
-                    self.ack_msg_recv_too_early = Some(incoming_data.to_vec());
-                    return Ok(None);
-                } else {
-                    self.status = SecureLayerStatus::Fail;
-                    return Err(IncomingMsgErr::UnexpectedAckMsg.into());
-                };
-
                 // Verify sig
-                // The reader has already made sure that the signature algorithm is supported,
-                // as we only support the Ed25519 algorithm, we know that it is necessarily this one.
-                if !self.verify_sig(&data, peer_sig_pubkey, user_msg_end) {
-                    return Err(IncomingMsgErr::InvalidHashOrSig.into());
+                match sig_valid_opt {
+                    Some(true) => {
+                        // Authenticate the peer sig pubkey, verified by the ACK signature alone
+                        if self.unauthenticated_peer_sig_pubkey {
+                            let auth_result = match self.peer_sig_pubkey {
+                                Some(ref peer_sig_pubkey) => {
+                                    self.peer_auth_policy.authenticate(peer_sig_pubkey)
+                                }
+                                None => Err(Error::InternalError("peer sig pubkey is unknown")),
+                            };
+                            if let Err(e) = auth_result {
+                                return Err(self.reject_handshake(e, incoming_data, true));
+                            }
+                            self.unauthenticated_peer_sig_pubkey = false;
+                        }
+                    }
+                    Some(false) => {
+                        return Err(self.reject_handshake(
+                            IncomingMsgErr::InvalidHashOrSig.into(),
+                            incoming_data,
+                            true,
+                        ));
+                    }
+                    None if self.ack_msg_recv_too_early.is_none() => {
+                        self.reserve_memory(incoming_data.len())?;
+                        self.ack_msg_recv_too_early = Some(incoming_data.to_vec());
+                        return Ok(None);
+                    }
+                    None => {
+                        let e = self.fail(IncomingMsgErr::UnexpectedAckMsg.into());
+                        return Err(self.reject_handshake(e, incoming_data, false));
+                    }
+                }
+
+                // Validate custom data
+                if !self
+                    .validate_custom_data(&data[user_msg_begin..user_msg_end], &msg_type_headers)
+                {
+                    let e = self.fail(IncomingMsgErr::RejectedCustomData.into());
+                    return Err(self.reject_handshake(e, incoming_data, true));
                 }
 
                 // Update status
-                self.status.apply_action(Action::Receive(MsgType::Ack))?;
+                if let Err(e) = self.apply_action(Action::Receive(MsgType::Ack)) {
+                    return Err(self.reject_handshake(e, incoming_data, true));
+                }
+                self.transcript.record(
+                    TranscriptMsg::PeerAck,
+                    &data[..user_msg_end],
+                    &data[user_msg_end..],
+                );
+                // The peer sends its ACK message after its CONNECT message,
+                // which is no longer retransmitted
+                self.peer_connect_msg.take();
             }
-            MsgTypeHeaders::UserMsg { nonce } => {
+            MsgTypeHeaders::UserMsg {
+                nonce,
+                expiry,
+                keepalive,
+                cover,
+            } => {
                 // Verify nonce
                 if nonce < self.next_nonce_expected || self.orphan_nonce_list.contains(&nonce) {
                     return Err(IncomingMsgErr::InvalidNonce.into());
                 }
 
                 // Verify status
-                if let Some(ActionSideEffects::PushUserMsgIntoTmpStack) = self
-                    .status
-                    .apply_action(Action::Receive(MsgType::UserMsg))?
+                if let Some(ActionSideEffects::PushUserMsgIntoTmpStack) =
+                    self.apply_action(Action::Receive(MsgType::UserMsg))?
                 {
-                    self.tmp_stack_user_msgs.push(data);
+                    self.reserve_memory(data.len())?;
+                    self.tmp_stack_user_msgs
+                        .push((data, self.config.clock.now()));
                     return Ok(None);
                 }
 
-                // Verify hash
+                // Verify hash (omitted by the peer if both peers have disabled it)
                 let data_hashed = &data[..user_msg_end];
-                let hash = &data[user_msg_end..];
-                if hash != sha256(data_hashed).as_ref() {
+                let hash = reader::user_msg_footer(
+                    &data,
+                    user_msg_end,
+                    self.peer_capabilities
+                        .map(|capabilities| capabilities.contains(Capability::FramePadding))
+                        == Some(true),
+                )?;
+                if (!self.negotiated(Capability::OmitUserMsgHash) || !hash.is_empty())
+                    && hash != self.config.digest.sha256(data_hashed)
+                {
                     return Err(IncomingMsgErr::InvalidHashOrSig.into());
                 }
 
@@ -269,100 +1497,102 @@ impl MinimalSecureLayer {
                     }
                 } else {
                     if self.orphan_nonce_list.len() >= MAX_ORPHAN_NONCES {
-                        self.status = SecureLayerStatus::Fail;
-                        return Err(Error::TooManyUnorderedMsgs);
+                        return Err(self.fail(Error::TooManyUnorderedMsgs));
                     }
 
+                    self.reserve_memory(ORPHAN_NONCE_SIZE)?;
                     self.orphan_nonce_list.insert(nonce);
                 }
-            }
-        }
-
-        // Get message
-        let message = Message::from_bytes(
-            data.drain(user_msg_begin..user_msg_end).collect(),
-            msg_type_headers,
-        )?;
-
-        Ok(Some(message))
-    }
-    /// Encrypt and write message on a writer
-    #[inline]
-    fn encrypt_and_write<W: Write>(
-        &mut self,
-        encapsuled_message: &EncapsuledMessage,
-        writer: &mut BufWriter<W>,
-    ) -> Result<()> {
-        let encrypt_algo_with_secret =
-            if let Some(ref encrypt_algo_with_secret) = self.encrypt_algo_with_secret {
-                encrypt_algo_with_secret
-            } else {
-                panic!("Dev error: try to get encrypt_algo_with_secret before it's computed !")
-            };
 
-        let mut data_will_encrypted = BufWriter::new(Vec::with_capacity(
-            encapsuled_message.as_ref().len() + HASH_SIZE,
-        ));
+                // Cover message is dropped
+                if cover {
+                    return Ok(None);
+                }
 
-        // Write encapsuled message
-        data_will_encrypted
-            .write(encapsuled_message.as_ref())
-            .map_err(Error::WriteError)?;
-        // Write encapsuled message hash
-        data_will_encrypted
-            .write(sha256(encapsuled_message.as_ref()).as_ref())
-            .map_err(Error::WriteError)?;
+                // Keepalive message is not delivered, only its payload is given to the hook
+                if keepalive {
+                    if let Some(HeartbeatHook(ref mut hook)) = self.heartbeat_hook {
+                        hook(&data[user_msg_begin..user_msg_end]);
+                    }
+                    return Ok(None);
+                }
 
-        // Flush data_will_encrypted buffer
-        let data_will_encrypted = data_will_encrypted
-            .into_inner()
-            .map_err(|_| Error::BufferFlushError)?;
+                // Drop expired message
+                if let Some(expiry) = expiry {
+                    if unix_timestamp_ms(self.config.clock.now()) > expiry {
+                        self.expired_msgs_count += 1;
+                        return Ok(None);
+                    }
+                }
 
-        // Encrypt
-        encrypt(
-            &mut BufReader::new(&data_will_encrypted[..]),
-            encrypt_algo_with_secret,
-            writer,
-        )?;
+                self.traffic_counters.user_msgs_received += 1;
+                self.traffic_counters.user_bytes_received += (user_msg_end - user_msg_begin) as u64;
+                self.traffic_counters
+                    .received_sizes
+                    .record(user_msg_end - user_msg_begin);
+            }
+        }
 
-        Ok(())
+        Ok(Some(DecryptedIncomingData {
+            data,
+            user_msg_begin,
+            user_msg_end,
+            msg_type_headers,
+        }))
     }
     #[inline]
-    /// Create connect message
+    /// Create connect message (signature algorithm Ed25519)
     pub fn create_connect_message(
         &mut self,
         public_key: &[u8],
         custom_data: Option<&[u8]>,
+    ) -> Result<Vec<u8>> {
+        self.create_connect_message_with_sig_algo(SigAlgo::Ed25519, public_key, custom_data)
+    }
+    /// Create connect message with a specific signature algorithm
+    pub fn create_connect_message_with_sig_algo(
+        &mut self,
+        sig_algo: SigAlgo,
+        public_key: &[u8],
+        custom_data: Option<&[u8]>,
     ) -> Result<Vec<u8>> {
         // Update status
-        self.status.apply_action(Action::Create(MsgType::Connect))?;
+        self.apply_action(Action::Create(MsgType::Connect))?;
+        self.local_capabilities = self.config.advertised_capabilities(self.local_capabilities);
 
         // Create message and update status
         match self.encapsulate_message(&MessageRef::Connect {
-            sig_algo: SIG_ALGO_ED25519_ARRAY,
+            sig_algo: sig_algo.id(),
             sig_pubkey: public_key.to_vec(),
+            capabilities: self.local_capabilities,
             custom_data,
         }) {
-            Ok(encapsuled_msg) => Ok(encapsuled_msg.data),
-            Err(e) => {
-                self.status = SecureLayerStatus::Fail;
-                Err(e)
+            Ok(encapsuled_msg) => {
+                self.transcript
+                    .record(TranscriptMsg::LocalConnect, &encapsuled_msg.data, &[]);
+                Ok(encapsuled_msg.data)
             }
+            Err(e) => Err(self.fail(e)),
         }
     }
     #[inline]
     /// Create ack message
     pub fn create_ack_message(&mut self, custom_data: Option<&[u8]>) -> Result<Vec<u8>> {
         // Update status
-        self.status.apply_action(Action::Create(MsgType::Ack))?;
+        self.apply_action(Action::Create(MsgType::Ack))?;
 
         // Create message and update status
-        match self.encapsulate_message(&MessageRef::Ack { custom_data }) {
-            Ok(encapsuled_msg) => Ok(encapsuled_msg.data),
-            Err(e) => {
-                self.status = SecureLayerStatus::Fail;
-                Err(e)
+        match self.encapsulate_message(&MessageRef::Ack {
+            custom_data,
+            ack_only_sig: self.config.local_sig_requirement == SigRequirement::AckOnly,
+        }) {
+            Ok(encapsuled_msg) => {
+                self.last_sent_at = Some(self.config.clock.now());
+                self.transcript
+                    .record(TranscriptMsg::LocalAck, &encapsuled_msg.data, &[]);
+                Ok(encapsuled_msg.data)
             }
+            Err(e) => Err(self.fail(e)),
         }
     }
     #[inline]
@@ -371,40 +1601,258 @@ impl MinimalSecureLayer {
         &mut self,
         data: &[u8],
         writer: &mut BufWriter<W>,
+    ) -> Result<()> {
+        self.write_message_inner(data, None, OutgoingMsgKind::User, writer)
+    }
+    #[inline]
+    /// Write message that the peer must drop if it reads it after `expiry`
+    pub fn write_message_with_expiry<W: Write>(
+        &mut self,
+        data: &[u8],
+        expiry: SystemTime,
+        writer: &mut BufWriter<W>,
+    ) -> Result<()> {
+        self.require_peer(Capability::ExpiringMsgs)?;
+        self.write_message_inner(
+            data,
+            Some(unix_timestamp_ms(expiry)),
+            OutgoingMsgKind::User,
+            writer,
+        )
+    }
+    #[inline]
+    /// Write keepalive message with the heartbeat payload,
+    /// the peer does not deliver it to its application but to its heartbeat hook
+    pub fn write_keepalive<W: Write>(&mut self, writer: &mut BufWriter<W>) -> Result<()> {
+        self.require_peer(Capability::Keepalive)?;
+        let heartbeat_payload = self.heartbeat_payload.clone();
+        self.write_message_inner(&heartbeat_payload, None, OutgoingMsgKind::Keepalive, writer)
+    }
+    /// Get the time at which `handle_timeout` must be called (none if nothing is scheduled).
+    /// Event loops should wait for incoming data until this time.
+    pub fn poll_timeout(&self) -> Option<SystemTime> {
+        match (self.keepalive_at(), self.cover_at()) {
+            (Some(keepalive_at), Some(cover_at)) => Some(keepalive_at.min(cover_at)),
+            (keepalive_at, cover_at) => keepalive_at.or(cover_at),
+        }
+    }
+    /// Run the tasks due at the current time: write a cover frame if it is time to, and
+    /// a keepalive message if nothing has been sent for the keepalive interval.
+    /// Returns `true` if something has been written.
+    pub fn handle_timeout<W: Write>(&mut self, writer: &mut BufWriter<W>) -> Result<bool> {
+        let now = self.config.clock.now();
+        let mut written = false;
+        if let (Some(cover_at), Some(cover_traffic)) = (self.cover_at(), self.config.cover_traffic)
+        {
+            if cover_at <= now {
+                self.write_message_inner(&[], None, OutgoingMsgKind::Cover, writer)?;
+                self.next_cover_at = Some(now + cover_traffic.next_interval());
+                written = true;
+            }
+        }
+        match self.keepalive_at() {
+            Some(keepalive_at) if keepalive_at <= now => {
+                self.write_keepalive(writer)?;
+                Ok(true)
+            }
+            _ => Ok(written),
+        }
+    }
+    /// Time of the next keepalive message (none if the peer does not support them)
+    fn keepalive_at(&self) -> Option<SystemTime> {
+        match (self.status(), self.config.keepalive_interval) {
+            (SecureLayerStatus::Established, Some(keepalive_interval))
+                if self.peer_supports(Capability::Keepalive) =>
+            {
+                self.last_sent_at
+                    .map(|last_sent_at| last_sent_at + keepalive_interval)
+            }
+            _ => None,
+        }
+    }
+    /// Time of the next cover frame (none if the peer does not support them)
+    fn cover_at(&self) -> Option<SystemTime> {
+        match (self.status(), self.config.cover_traffic) {
+            (SecureLayerStatus::Established, Some(_))
+                if self.peer_supports(Capability::CoverTraffic) =>
+            {
+                self.next_cover_at
+            }
+            _ => None,
+        }
+    }
+    fn write_message_inner<W: Write>(
+        &mut self,
+        data: &[u8],
+        expiry: Option<u64>,
+        kind: OutgoingMsgKind,
+        writer: &mut BufWriter<W>,
     ) -> Result<()> {
         // Update status
-        self.status.apply_action(Action::Create(MsgType::UserMsg))?;
+        self.apply_action(Action::Create(MsgType::UserMsg))?;
 
-        match self.encapsulate_and_encrypt_and_write_message(data, writer) {
+        let message = MessageRef::Message {
+            nonce: self.next_nonce_sent,
+            custom_data: Some(data),
+            expiry,
+            keepalive: kind == OutgoingMsgKind::Keepalive,
+            cover: kind == OutgoingMsgKind::Cover,
+        };
+
+        // Encapsulate a small message in a stack buffer, without allocation
+        let small_msg = self.config.is_small_msg(data.len());
+        let mut stack_buffer = [0u8; SMALL_MSG_BUFFER_SIZE];
+        let small_msg_len = if small_msg {
+            message.encapsulate_user_msg_into(&mut stack_buffer)
+        } else {
+            None
+        };
+        let heap_encapsuled_msg;
+        let encapsuled_msg = match small_msg_len {
+            Some(small_msg_len) => &stack_buffer[..small_msg_len],
+            None => {
+                heap_encapsuled_msg = match self.encapsulate_message(&message) {
+                    Ok(encapsuled_msg) => encapsuled_msg,
+                    Err(e) => return Err(self.fail(e)),
+                };
+                heap_encapsuled_msg.as_ref()
+            }
+        };
+
+        // Apply rate limit on the frame length, charged once written
+        // (keepalive and cover messages are not limited, to be sent on time)
+        let frame_config = self.frame_config();
+        let frame_len = data.len() + frame_config.frame_overhead();
+        let send_rate_limit = match kind {
+            OutgoingMsgKind::User => self.config.send_rate_limit,
+            OutgoingMsgKind::Keepalive | OutgoingMsgKind::Cover => None,
+        };
+        if let Some(send_rate_limit) = send_rate_limit {
+            if let Err(ready_at) =
+                self.send_token_bucket
+                    .check(send_rate_limit, frame_len, self.config.clock.now())
+            {
+                return Err(Error::RateLimited { ready_at });
+            }
+        }
+
+        let encrypt_algo_with_secret =
+            if let Some(ref encrypt_algo_with_secret) = self.encrypt_algo_with_secret {
+                encrypt_algo_with_secret
+            } else {
+                return Err(Error::InternalError(
+                    "try to get encrypt_algo_with_secret before it's computed",
+                ));
+            };
+        match encrypt_and_write(
+            &frame_config,
+            encrypt_algo_with_secret,
+            encapsuled_msg,
+            small_msg,
+            writer,
+        ) {
             Ok(()) => {
-                self.status = SecureLayerStatus::NegotiationSuccessful;
+                self.status = StatusMachine::NegotiationSuccessful;
+                if send_rate_limit.is_some() {
+                    self.send_token_bucket.consume(frame_len);
+                }
 
                 self.next_nonce_sent += 1;
+                match kind {
+                    OutgoingMsgKind::User => {
+                        self.last_sent_at = Some(self.config.clock.now());
+                        self.traffic_counters.user_msgs_sent += 1;
+                        self.traffic_counters.user_bytes_sent += data.len() as u64;
+                    }
+                    OutgoingMsgKind::Keepalive => {
+                        self.last_sent_at = Some(self.config.clock.now());
+                    }
+                    // A cover frame does not postpone the keepalive of the heartbeat hook
+                    OutgoingMsgKind::Cover => {}
+                }
                 Ok(())
             }
-            Err(e) => {
-                self.status = SecureLayerStatus::Fail;
-                Err(e)
-            }
+            Err(e) => Err(self.fail(e)),
         }
     }
+    /// Get number of expired messages dropped
     #[inline]
-    fn encapsulate_and_encrypt_and_write_message<W: Write>(
+    pub fn expired_msgs_count(&self) -> u64 {
+        self.expired_msgs_count
+    }
+    /// Get session statistics
+    pub fn stats(&self) -> SessionStats {
+        SessionStats {
+            status: self.status(),
+            user_msgs_sent: self.traffic_counters.user_msgs_sent,
+            user_bytes_sent: self.traffic_counters.user_bytes_sent,
+            user_msgs_received: self.traffic_counters.user_msgs_received,
+            user_bytes_received: self.traffic_counters.user_bytes_received,
+            expired_msgs: self.expired_msgs_count,
+            buffered_bytes: self.buffered_bytes(),
+            pending_orphans: self.orphan_nonce_list.len(),
+            received_sizes: self.traffic_counters.received_sizes,
+            max_read_interval: self.max_read_interval,
+        }
+    }
+    /// Get status
+    #[inline]
+    pub fn status(&self) -> SecureLayerStatus {
+        self.status.to_public()
+    }
+    /// Transcript of the handshake, available once the negotiation is successful, to be
+    /// persisted as evidence of the peer identity and of the negotiated parameters
+    #[inline]
+    pub fn handshake_transcript(&self) -> Option<HandshakeTranscript> {
+        self.transcript.transcript()
+    }
+    /// Get session fingerprint (Sha256 of both ephemeral public keys, the smallest first).
+    /// Available as soon as the peer CONNECT message has been received.
+    #[inline]
+    pub fn session_fingerprint(&self) -> Option<[u8; HASH_SIZE]> {
+        self.session_fingerprint
+    }
+    fn validate_custom_data(
         &mut self,
-        data: &[u8],
-        writer: &mut BufWriter<W>,
-    ) -> Result<()> {
-        let encapsuled_msg = self.encapsulate_message(&MessageRef::Message {
-            nonce: self.next_nonce_sent,
-            custom_data: Some(data),
-        })?;
-        self.encrypt_and_write(&encapsuled_msg, writer)
+        custom_data: &[u8],
+        msg_type_headers: &MsgTypeHeaders,
+    ) -> bool {
+        if let Some(CustomDataValidator(ref mut validator)) = self.custom_data_validator {
+            validator(
+                &MessageView::new(custom_data, msg_type_headers),
+                self.session_fingerprint.as_ref(),
+            )
+        } else {
+            true
+        }
+    }
+    /// Reject an invalid handshake frame and record its diagnostics.
+    /// With uniform handshake rejection, the secure layer fails and the error is replaced by
+    /// `Error::HandshakeRejected`, after a dummy signature verification of `frame`
+    /// if no signature has been verified yet.
+    fn reject_handshake(&mut self, error: Error, frame: &[u8], sig_verified: bool) -> Error {
+        if self.handshake_failure.is_none() {
+            self.handshake_failure = Some(HandshakeFailure::new(
+                &error,
+                frame,
+                self.peer_sig_pubkey.as_ref().map(|pubkey| &pubkey[..]),
+            ));
+        }
+        if self.config.uniform_handshake_rejection {
+            if !sig_verified {
+                self.peer_sig_algo.dummy_verify(frame);
+            }
+            self.fail(error);
+            Error::HandshakeRejected
+        } else {
+            error
+        }
     }
     #[inline]
     fn verify_sig(&self, data: &[u8], sig_pubkey: &[u8], user_msg_end: usize) -> bool {
         let data_signed = &data[..user_msg_end];
         let sig = &data[user_msg_end..];
-        signature::verify_sig(sig_pubkey, data_signed, sig)
+        self.peer_sig_algo.verify(sig_pubkey, data_signed, sig)
     }
 }
 
@@ -412,8 +1860,10 @@ impl MinimalSecureLayer {
 mod tests {
 
     use super::*;
+    use crate::digest::RING_DIGEST;
+    use crate::encryption::tests::peer_encrypt_algo_with_secret;
     use crate::encryption::EncryptAlgo;
-    use crate::signature::SIG_ALGO_ED25519;
+    use crate::signature::{SigAlgos, SIG_ALGO_ED25519};
     use crate::Seed32;
     use ring::signature::{Ed25519KeyPair, KeyPair};
 
@@ -438,12 +1888,29 @@ mod tests {
         incoming_data.append(&mut CURRENT_VERSION.to_vec());
         incoming_data.append(&mut 34u64.to_be_bytes().to_vec()); // Encapsuled message length
         incoming_data.append(&mut vec![0, 2]); // ACK type
-        incoming_data.append(&mut sha256(&remote_epk).as_ref().to_vec()); // Challenge
+        incoming_data.append(&mut RING_DIGEST.sha256(&remote_epk).to_vec()); // Challenge
         let sig = sig_kp.sign(&incoming_data);
         incoming_data.append(&mut sig.as_ref().to_vec()); // SIG
         Ok(incoming_data)
     }
 
+    /// Write user message as the peer would, a layer rejects its own reflected messages
+    fn write_peer_message(
+        msl: &mut MinimalSecureLayer,
+        data: &[u8],
+        writer: &mut BufWriter<Vec<u8>>,
+    ) -> Result<()> {
+        let encrypt_algo_with_secret = msl
+            .encrypt_algo_with_secret
+            .take()
+            .expect("shared secret must be computed");
+        msl.encrypt_algo_with_secret =
+            Some(peer_encrypt_algo_with_secret(&encrypt_algo_with_secret));
+        let result = msl.write_message(data, writer);
+        msl.encrypt_algo_with_secret = Some(encrypt_algo_with_secret);
+        result
+    }
+
     #[test]
     fn test_change_config() -> Result<()> {
         let mut msl = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
@@ -465,13 +1932,94 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_nonce_checkpoint() -> Result<()> {
+        let mut msl = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
+        msl.next_nonce_sent = 10;
+        msl.next_nonce_expected = 5;
+        msl.orphan_nonce_list.insert(7);
+        msl.orphan_nonce_list.insert(9);
+
+        let checkpoint = msl.nonce_checkpoint();
+        assert_eq!(
+            NonceCheckpoint {
+                next_nonce_sent: 10,
+                next_nonce_expected: 10,
+            },
+            checkpoint
+        );
+
+        let mut restored_msl = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
+        restored_msl.orphan_nonce_list.insert(12);
+        restored_msl.restore_nonce_checkpoint(checkpoint, DEFAULT_NONCE_CHECKPOINT_MARGIN)?;
+        assert_eq!(
+            10 + DEFAULT_NONCE_CHECKPOINT_MARGIN,
+            restored_msl.next_nonce_sent
+        );
+        assert_eq!(10, restored_msl.next_nonce_expected);
+        assert!(restored_msl.orphan_nonce_list.contains(&12));
+
+        // Counters never go backward
+        restored_msl.restore_nonce_checkpoint(
+            NonceCheckpoint {
+                next_nonce_sent: 0,
+                next_nonce_expected: 0,
+            },
+            0,
+        )?;
+        assert_eq!(
+            10 + DEFAULT_NONCE_CHECKPOINT_MARGIN,
+            restored_msl.next_nonce_sent
+        );
+        assert_eq!(10, restored_msl.next_nonce_expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_previous_version_connect_derives_no_key() -> Result<()> {
+        let mut msl1 = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
+        let mut msl2 = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
+        let mut connect_msg = msl2.create_connect_message(&[0u8; 32], None)?;
+        connect_msg[MAGIC_VALUE.len()..MAGIC_VALUE.len() + VERSION_SIZE]
+            .copy_from_slice(&[0, 0, 0, 1]);
+
+        if let Err(Error::RecvInvalidMsg(IncomingMsgErr::UnsupportedVersion)) =
+            msl1.read(&connect_msg)
+        {
+        } else {
+            panic!("Expected error UnsupportedVersion !")
+        }
+        assert!(msl1.encrypt_algo_with_secret.is_none());
+        assert_eq!(None, msl1.peer_epk);
+        Ok(())
+    }
+
+    #[test]
+    fn test_session_fingerprint() -> Result<()> {
+        let mut msl1 = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
+        let mut msl2 = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
+        assert_eq!(None, msl1.session_fingerprint());
+
+        msl1.compute_shared_secret(msl2.ephemeral_pubkey.clone().as_ref())?;
+        msl2.compute_shared_secret(msl1.ephemeral_pubkey.clone().as_ref())?;
+
+        assert!(msl1.session_fingerprint().is_some());
+        assert_eq!(msl1.session_fingerprint(), msl2.session_fingerprint());
+        Ok(())
+    }
+
     #[test]
     fn test_status_update_to_fail() -> Result<()> {
         let mut msl1 = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
         let fake_encrypted_incoming_data = &[0, 0, 0, 0];
         let result = msl1.read(fake_encrypted_incoming_data);
 
-        assert_eq!(SecureLayerStatus::Fail, msl1.status);
+        assert_eq!(
+            SecureLayerStatus::Failed {
+                reason: FailReason::InvalidIncomingMsg(IncomingMsgErr::UnexpectedMessage)
+            },
+            msl1.status()
+        );
 
         if let Err(Error::RecvInvalidMsg(e)) = result {
             assert_eq!(IncomingMsgErr::UnexpectedMessage, e);
@@ -539,7 +2087,7 @@ mod tests {
     #[test]
     fn test_connect_msg_twice() -> Result<()> {
         // Create sig keypair
-        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::random().as_ref())
+        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
             .map_err(|_| Error::FailtoGenSigKeyPair)?;
 
         // Create secure layer
@@ -590,10 +2138,45 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_recv_connect_msg_with_not_accepted_sig_algo() -> Result<()> {
+        // Create sig keypair
+        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+            .map_err(|_| Error::FailtoGenSigKeyPair)?;
+
+        // Create connect msg bytes
+        let ephemeral_kp = EphemeralKeyPair::generate()?;
+        let incoming_data =
+            create_connect_msg_bytes(ephemeral_kp.public_key().as_ref().to_vec(), &sig_kp)?;
+
+        // Create secure layer that accept no signature algorithm
+        let mut msl1 = MinimalSecureLayer::create(
+            SecureLayerConfig {
+                accepted_sig_algos: SigAlgos::empty(),
+                ..SecureLayerConfig::default()
+            },
+            None,
+        )?;
+
+        let result = msl1.read(&incoming_data[..]);
+        if let Err(Error::RecvInvalidMsg(IncomingMsgErr::UnsupportedSigAlgo)) = result {
+            assert_eq!(
+                SecureLayerStatus::Failed {
+                    reason: FailReason::InvalidIncomingMsg(IncomingMsgErr::UnsupportedSigAlgo)
+                },
+                msl1.status()
+            );
+            Ok(())
+        } else {
+            println!("unexpected result={:?}", result);
+            panic!();
+        }
+    }
+
     #[test]
     fn test_recv_connect_msg_twice() -> Result<()> {
         // Create sig keypair
-        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::random().as_ref())
+        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
             .map_err(|_| Error::FailtoGenSigKeyPair)?;
 
         // Create EKP
@@ -604,12 +2187,21 @@ mod tests {
             create_connect_msg_bytes(ephemeral_kp.public_key().as_ref().to_vec(), &sig_kp)?;
 
         // Create secure layer
-        let mut msl1 = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
+        let mut msl1 = MinimalSecureLayer::create(
+            SecureLayerConfig::default(),
+            Some(sig_kp.public_key().as_ref().to_vec()),
+        )?;
 
         // Read connect message
         let _ = msl1.read(&incoming_data[..])?;
 
-        // Reread same connect message
+        // Reread same connect message must be ignored
+        assert_eq!(None, msl1.read(&incoming_data[..])?);
+
+        // Read another connect message
+        let ephemeral_kp = EphemeralKeyPair::generate()?;
+        let incoming_data =
+            create_connect_msg_bytes(ephemeral_kp.public_key().as_ref().to_vec(), &sig_kp)?;
         let result = msl1.read(&incoming_data[..]);
         if let Err(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedConnectMsg)) = result {
             Ok(())
@@ -622,15 +2214,14 @@ mod tests {
     #[test]
     fn test_recv_ack_msg_early_twice() -> Result<()> {
         // Create sig keypair
-        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::random().as_ref())
+        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
             .map_err(|_| Error::FailtoGenSigKeyPair)?;
 
         // Create secure layer
         let mut msl1 = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
 
         // Create ack msg bytes
-        let incoming_data =
-            create_ack_msg_bytes(msl1.ephemeral_pubkey.as_ref().to_vec(), &sig_kp)?;
+        let incoming_data = create_ack_msg_bytes(msl1.ephemeral_pubkey.as_ref().to_vec(), &sig_kp)?;
 
         // Read ack message received too early
         let _ = msl1.read(&incoming_data[..]);
@@ -655,7 +2246,7 @@ mod tests {
         incoming_data.append(&mut CURRENT_VERSION.to_vec());
         incoming_data.append(&mut 2u64.to_be_bytes().to_vec()); // Encapsuled message length
         incoming_data.append(&mut vec![0, 0]); // USER_MSG_TYPE
-        incoming_data.append(&mut sha256(&incoming_data).as_ref().to_vec()); // Hash
+        incoming_data.append(&mut RING_DIGEST.sha256(&incoming_data).to_vec()); // Hash
 
         // Read user message received before_nego
         let result = msl1.read(&incoming_data[..]);
@@ -670,7 +2261,7 @@ mod tests {
     #[test]
     fn test_recv_twice_same_user_msg() -> Result<()> {
         // Create sig keypair
-        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::random().as_ref())
+        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
             .map_err(|_| Error::FailtoGenSigKeyPair)?;
 
         // Create EKP
@@ -681,7 +2272,10 @@ mod tests {
             create_connect_msg_bytes(ephemeral_kp.public_key().as_ref().to_vec(), &sig_kp)?;
 
         // Create secure layer
-        let mut msl1 = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
+        let mut msl1 = MinimalSecureLayer::create(
+            SecureLayerConfig::default(),
+            Some(sig_kp.public_key().as_ref().to_vec()),
+        )?;
 
         // Read connect message
         let _ = msl1.read(&incoming_data[..])?;
@@ -693,19 +2287,18 @@ mod tests {
         let _ = msl1.create_ack_message(None)?;
 
         // Create ack msg bytes
-        let incoming_data =
-            create_ack_msg_bytes(msl1.ephemeral_pubkey.as_ref().to_vec(), &sig_kp)?;
+        let incoming_data = create_ack_msg_bytes(msl1.ephemeral_pubkey.as_ref().to_vec(), &sig_kp)?;
 
         // Read ack message
         let _ = msl1.read(&incoming_data[..])?;
 
         // Create and read different user messages
         let mut incoming_data = BufWriter::new(Vec::new());
-        msl1.write_message(&[1, 2, 3, 4], &mut incoming_data)?;
+        write_peer_message(&mut msl1, &[1, 2, 3, 4], &mut incoming_data)?;
         let _ = msl1.read(incoming_data.buffer())?;
 
         incoming_data = BufWriter::new(Vec::new());
-        msl1.write_message(&[1, 2, 3, 4], &mut incoming_data)?;
+        write_peer_message(&mut msl1, &[1, 2, 3, 4], &mut incoming_data)?;
         let _ = msl1.read(incoming_data.buffer())?;
 
         // Reread same user message
@@ -721,7 +2314,7 @@ mod tests {
     #[test]
     fn test_recv_unordered_user_msgs() -> Result<()> {
         // Create sig keypair
-        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::random().as_ref())
+        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
             .map_err(|_| Error::FailtoGenSigKeyPair)?;
 
         // Create EKP
@@ -732,7 +2325,10 @@ mod tests {
             create_connect_msg_bytes(ephemeral_kp.public_key().as_ref().to_vec(), &sig_kp)?;
 
         // Create secure layer
-        let mut msl1 = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
+        let mut msl1 = MinimalSecureLayer::create(
+            SecureLayerConfig::default(),
+            Some(sig_kp.public_key().as_ref().to_vec()),
+        )?;
 
         // Read connect message
         let _ = msl1.read(&incoming_data[..])?;
@@ -744,21 +2340,20 @@ mod tests {
         let _ = msl1.create_ack_message(None)?;
 
         // Create ack msg bytes
-        let incoming_data =
-            create_ack_msg_bytes(msl1.ephemeral_pubkey.as_ref().to_vec(), &sig_kp)?;
+        let incoming_data = create_ack_msg_bytes(msl1.ephemeral_pubkey.as_ref().to_vec(), &sig_kp)?;
 
         // Read ack message
         let _ = msl1.read(&incoming_data[..])?;
 
         // Create and read unordered user messages
         let mut incoming_data0 = BufWriter::new(Vec::new());
-        msl1.write_message(&[1, 2, 3, 4], &mut incoming_data0)?;
+        write_peer_message(&mut msl1, &[1, 2, 3, 4], &mut incoming_data0)?;
         let mut incoming_data1 = BufWriter::new(Vec::new());
-        msl1.write_message(&[1, 2, 3, 4], &mut incoming_data1)?;
+        write_peer_message(&mut msl1, &[1, 2, 3, 4], &mut incoming_data1)?;
         let mut incoming_data2 = BufWriter::new(Vec::new());
-        msl1.write_message(&[1, 2, 3, 4], &mut incoming_data2)?;
+        write_peer_message(&mut msl1, &[1, 2, 3, 4], &mut incoming_data2)?;
         let mut incoming_data3 = BufWriter::new(Vec::new());
-        msl1.write_message(&[1, 2, 3, 4], &mut incoming_data3)?;
+        write_peer_message(&mut msl1, &[1, 2, 3, 4], &mut incoming_data3)?;
 
         let _ = msl1.read(incoming_data0.buffer())?;
         let _ = msl1.read(incoming_data2.buffer())?;
@@ -772,7 +2367,7 @@ mod tests {
     #[ignore]
     fn test_recv_too_many_unordered_messages() -> Result<()> {
         // Create sig keypair
-        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::random().as_ref())
+        let sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
             .map_err(|_| Error::FailtoGenSigKeyPair)?;
 
         // Create EKP
@@ -795,26 +2390,25 @@ mod tests {
         let _ = msl1.create_ack_message(None)?;
 
         // Create ack msg bytes
-        let incoming_data =
-            create_ack_msg_bytes(msl1.ephemeral_pubkey.as_ref().to_vec(), &sig_kp)?;
+        let incoming_data = create_ack_msg_bytes(msl1.ephemeral_pubkey.as_ref().to_vec(), &sig_kp)?;
 
         // Read ack message
         let _ = msl1.read(&incoming_data[..])?;
 
         // Create a first msg without reading it
         let mut incoming_data = BufWriter::new(Vec::new());
-        msl1.write_message(&[], &mut incoming_data)?;
+        write_peer_message(&mut msl1, &[], &mut incoming_data)?;
 
         // Read MAX_ORPHAN_NONCES messages
         let _i: usize;
         for _i in 0..MAX_ORPHAN_NONCES {
             incoming_data = BufWriter::new(Vec::new());
-            msl1.write_message(&[], &mut incoming_data)?;
+            write_peer_message(&mut msl1, &[], &mut incoming_data)?;
             let _ = msl1.read(incoming_data.buffer())?;
         }
 
         incoming_data = BufWriter::new(Vec::new());
-        msl1.write_message(&[], &mut incoming_data)?;
+        write_peer_message(&mut msl1, &[], &mut incoming_data)?;
         let result = msl1.read(incoming_data.buffer());
         if let Err(Error::TooManyUnorderedMsgs) = result {
             Ok(())
@@ -823,4 +2417,56 @@ mod tests {
             panic!();
         }
     }
+
+    #[test]
+    fn test_small_msg_fast_path() -> Result<()> {
+        let encrypt_algo_with_secret =
+            crate::encryption::tests::gen_random_encrypt_algo_with_secret();
+
+        for user_msg_hash in &[true, false] {
+            let config = SecureLayerConfig {
+                user_msg_hash: *user_msg_hash,
+                ..SecureLayerConfig::default()
+            };
+            let message = MessageRef::Message {
+                nonce: 7,
+                custom_data: Some(&[1, 2, 3]),
+                expiry: Some(42),
+                keepalive: false,
+                cover: false,
+            };
+            let mut stack_buffer = [0u8; SMALL_MSG_BUFFER_SIZE];
+            let len = message
+                .encapsulate_user_msg_into(&mut stack_buffer)
+                .expect("buffer must be long enough");
+
+            // The stack buffers produce the same frame as the heap ones
+            let mut frames = Vec::new();
+            for small_msg in &[true, false] {
+                let mut writer = BufWriter::new(Vec::new());
+                encrypt_and_write(
+                    &config,
+                    &encrypt_algo_with_secret,
+                    &stack_buffer[..len],
+                    *small_msg,
+                    &mut writer,
+                )?;
+                frames.push(writer.into_inner().map_err(|_| Error::BufferFlushError)?);
+            }
+            assert_eq!(frames[0], frames[1]);
+            assert_eq!(config.frame_overhead() + EXPIRY_SIZE + 3, frames[0].len());
+        }
+
+        // Messages too long for the stack buffers use the heap
+        let config = SecureLayerConfig {
+            small_msg_max_len: 10_000,
+            ..SecureLayerConfig::default()
+        };
+        assert!(config.is_small_msg(512));
+        assert!(config.is_small_msg(SMALL_MSG_BUFFER_SIZE - config.frame_overhead() - 8));
+        assert!(!config.is_small_msg(SMALL_MSG_BUFFER_SIZE - config.frame_overhead() - 7));
+        assert!(!SecureLayerConfig::default().is_small_msg(513));
+
+        Ok(())
+    }
 }
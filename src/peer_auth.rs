@@ -0,0 +1,163 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Authenticate peers whose signature public key is not expected at creation.
+
+use crate::session_store::SessionStore;
+use crate::{Error, Result};
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// Hook called with each signature public key accepted without authentication
+pub type PeerAuthAudit = Box<dyn FnMut(&[u8]) + Send>;
+
+/// Authentication policy of a peer whose signature public key is not expected at creation.
+///
+/// Anonymous peers (`SigRequirement::Anonymous`) are not concerned: their signature public key
+/// is never authenticated.
+pub enum PeerAuthPolicy {
+    /// Reject the peer, its signature public key must be expected at creation
+    RequirePinned,
+    /// Pin the signature public key of an unknown peer in the store, and reject a known peer
+    /// whose signature public key differs from the pinned one
+    TrustOnFirstUse {
+        /// Store of the pinned signature public keys
+        store: Arc<dyn SessionStore>,
+        /// Application-defined identifier of the peer
+        peer_id: String,
+    },
+    /// Accept any signature public key, without authentication (default, without audit)
+    AcceptAny {
+        /// Hook called with each accepted signature public key
+        audit: PeerAuthAudit,
+    },
+}
+
+impl Default for PeerAuthPolicy {
+    fn default() -> Self {
+        PeerAuthPolicy::accept_any(|_| ())
+    }
+}
+
+impl PeerAuthPolicy {
+    /// Trust the peer on first use, see `PeerAuthPolicy::TrustOnFirstUse`
+    pub fn trust_on_first_use(store: Arc<dyn SessionStore>, peer_id: &str) -> Self {
+        PeerAuthPolicy::TrustOnFirstUse {
+            store,
+            peer_id: peer_id.to_owned(),
+        }
+    }
+    /// Accept any peer, see `PeerAuthPolicy::AcceptAny`
+    pub fn accept_any<F>(audit: F) -> Self
+    where
+        F: FnMut(&[u8]) + Send + 'static,
+    {
+        PeerAuthPolicy::AcceptAny {
+            audit: Box::new(audit),
+        }
+    }
+    /// Authenticate the signature public key of the peer, its signatures being verified
+    pub(crate) fn authenticate(&mut self, sig_pubkey: &[u8]) -> Result<()> {
+        match self {
+            PeerAuthPolicy::RequirePinned => Err(Error::UnexpectedRemoteSigPubKey),
+            PeerAuthPolicy::TrustOnFirstUse { store, peer_id } => {
+                if store.trust_on_first_use(peer_id, sig_pubkey)? {
+                    Ok(())
+                } else {
+                    Err(Error::UnexpectedRemoteSigPubKey)
+                }
+            }
+            PeerAuthPolicy::AcceptAny { audit } => {
+                audit(sig_pubkey);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Debug for PeerAuthPolicy {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            PeerAuthPolicy::RequirePinned => write!(f, "RequirePinned"),
+            PeerAuthPolicy::TrustOnFirstUse { store, peer_id } => f
+                .debug_struct("TrustOnFirstUse")
+                .field("store", store)
+                .field("peer_id", peer_id)
+                .finish(),
+            PeerAuthPolicy::AcceptAny { .. } => write!(f, "AcceptAny"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::session_store::MemorySessionStore;
+    use std::sync::Mutex;
+
+    #[test]
+    fn require_pinned() {
+        let mut policy = PeerAuthPolicy::RequirePinned;
+        if let Err(Error::UnexpectedRemoteSigPubKey) = policy.authenticate(&[1u8; 32]) {
+        } else {
+            panic!("Expected error UnexpectedRemoteSigPubKey !")
+        }
+    }
+
+    #[test]
+    fn default_accepts_any() -> Result<()> {
+        let mut policy = PeerAuthPolicy::default();
+        policy.authenticate(&[1u8; 32])?;
+        assert_eq!("AcceptAny", format!("{:?}", policy));
+        Ok(())
+    }
+
+    #[test]
+    fn trust_on_first_use() -> Result<()> {
+        let store = Arc::new(MemorySessionStore::new());
+        let mut policy = PeerAuthPolicy::trust_on_first_use(store.clone(), "peer");
+
+        policy.authenticate(&[1u8; 32])?;
+        assert_eq!(Some(vec![1u8; 32]), store.pinned_peer("peer")?);
+        policy.authenticate(&[1u8; 32])?;
+        if let Err(Error::UnexpectedRemoteSigPubKey) = policy.authenticate(&[2u8; 32]) {
+        } else {
+            panic!("Expected error UnexpectedRemoteSigPubKey !")
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn accept_any() -> Result<()> {
+        let audited = Arc::new(Mutex::new(Vec::new()));
+        let audited_clone = audited.clone();
+        let mut policy = PeerAuthPolicy::accept_any(move |sig_pubkey| {
+            audited_clone
+                .lock()
+                .expect("poisoned lock")
+                .push(sig_pubkey.to_vec())
+        });
+
+        policy.authenticate(&[1u8; 32])?;
+        policy.authenticate(&[2u8; 32])?;
+        assert_eq!(
+            vec![vec![1u8; 32], vec![2u8; 32]],
+            *audited.lock().expect("poisoned lock")
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,120 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage rate limiting of outgoing messages.
+
+use std::time::{Duration, SystemTime};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+/// Token bucket rate limit on outgoing messages
+pub struct SendRateLimit {
+    /// Sustained rate in bytes per second (at least 1)
+    pub bytes_per_sec: u64,
+    /// Maximum number of bytes that can be sent in a burst
+    pub burst: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+/// Token bucket state
+pub(crate) struct TokenBucket {
+    /// Available bytes, negative when a message larger than burst has been sent
+    tokens: f64,
+    last_refill: Option<SystemTime>,
+}
+
+impl TokenBucket {
+    pub(crate) fn new() -> Self {
+        TokenBucket {
+            tokens: 0.0,
+            last_refill: None,
+        }
+    }
+    /// Check that `amount` bytes can be consumed at `now`, or return the time at which
+    /// it will be possible. A message larger than burst is accepted when the bucket is full.
+    pub(crate) fn check(
+        &mut self,
+        limit: SendRateLimit,
+        amount: usize,
+        now: SystemTime,
+    ) -> Result<(), SystemTime> {
+        let burst = limit.burst as f64;
+        let rate = limit.bytes_per_sec.max(1) as f64;
+
+        // Refill
+        self.tokens = match self.last_refill {
+            Some(last_refill) => {
+                let elapsed = now
+                    .duration_since(last_refill)
+                    .unwrap_or_else(|_| Duration::from_secs(0));
+                let elapsed_secs =
+                    elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+                (self.tokens + elapsed_secs * rate).min(burst)
+            }
+            None => burst,
+        };
+        self.last_refill = Some(now);
+
+        let required = (amount as f64).min(burst);
+        if self.tokens >= required {
+            Ok(())
+        } else {
+            let missing_nanos = ((required - self.tokens) * 1e9 / rate).ceil();
+            Err(now + Duration::from_nanos(missing_nanos as u64))
+        }
+    }
+    /// Consume `amount` bytes, once they have been sent
+    pub(crate) fn consume(&mut self, amount: usize) {
+        self.tokens -= amount as f64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limit = SendRateLimit {
+            bytes_per_sec: 1_000,
+            burst: 2_000,
+        };
+        let t0 = SystemTime::now();
+        let mut bucket = TokenBucket::new();
+        let mut try_consume = |amount, now| {
+            bucket
+                .check(limit, amount, now)
+                .map(|()| bucket.consume(amount))
+        };
+
+        // Burst is available at start
+        assert_eq!(Ok(()), try_consume(1_500, t0));
+        assert_eq!(Err(t0 + Duration::from_millis(500)), try_consume(1_000, t0));
+
+        // Refill at bytes_per_sec
+        let t1 = t0 + Duration::from_millis(500);
+        assert_eq!(Ok(()), try_consume(1_000, t1));
+
+        // Message larger than burst is accepted once the bucket is full
+        let t2 = t1 + Duration::from_secs(1);
+        assert_eq!(Err(t1 + Duration::from_secs(2)), try_consume(5_000, t2));
+        let t3 = t1 + Duration::from_secs(2);
+        assert_eq!(Ok(()), try_consume(5_000, t3));
+        assert_eq!(
+            Err(t3 + Duration::from_secs(3) + Duration::from_millis(1)),
+            try_consume(1, t3)
+        );
+    }
+}
@@ -15,18 +15,24 @@
 
 //! Define PKSTL reader.
 
+use crate::capabilities::Capabilities;
 use crate::constants::*;
 use crate::encryption::{decrypt, EncryptAlgoWithSecretKey};
 use crate::errors::IncomingMsgErr;
-use crate::message::MsgTypeHeaders;
-use crate::signature::SIG_ALGO_ED25519;
+use crate::frame_allocator::{FrameAllocator, GLOBAL_FRAME_ALLOCATOR};
+use crate::message::{EncapsuledMessage, Message, MessageView, MsgTypeHeaders, UserMsgMeta};
+use crate::signature::SigAlgo;
 use crate::{Error, Result};
-use std::io::{BufWriter, Write};
+use std::convert::TryFrom;
+use std::time::SystemTime;
 
 const MAGIC_VALUE_END: usize = 4;
 const VERSION_END: usize = 8;
 pub(crate) const ENCAPSULED_MSG_BEGIN: usize = 16;
 const NONCE_SIZE: usize = 8;
+pub(crate) const USER_MSG_MIN_LEN: usize = ENCAPSULED_MSG_BEGIN + MSG_TYPE_LEN + NONCE_SIZE;
+const VERSION_REJECT_MIN_VERSION_BEGIN: usize = ENCAPSULED_MSG_BEGIN + MSG_TYPE_LEN;
+const VERSION_REJECT_MSG_LEN: usize = VERSION_REJECT_MIN_VERSION_BEGIN + 2 * VERSION_SIZE;
 
 #[derive(Debug, PartialEq)]
 pub(crate) struct DecryptedIncomingData {
@@ -36,106 +42,348 @@ pub(crate) struct DecryptedIncomingData {
     pub(crate) msg_type_headers: MsgTypeHeaders,
 }
 
+impl DecryptedIncomingData {
+    /// Convert into an owned message, reusing the decrypted data buffer
+    pub(crate) fn into_message(self) -> Result<Message> {
+        let DecryptedIncomingData {
+            mut data,
+            user_msg_begin,
+            user_msg_end,
+            msg_type_headers,
+        } = self;
+        data.truncate(user_msg_end);
+        data.drain(..user_msg_begin);
+        Message::from_bytes(data, msg_type_headers)
+    }
+    /// Metadata of the user message (none for handshake messages)
+    pub(crate) fn user_msg_meta(&self, received_at: SystemTime) -> Option<UserMsgMeta> {
+        if let MsgTypeHeaders::UserMsg { nonce, .. } = self.msg_type_headers {
+            Some(UserMsgMeta {
+                nonce,
+                received_at,
+                size: self.user_msg_end - self.user_msg_begin,
+            })
+        } else {
+            None
+        }
+    }
+    /// View message borrowing the decrypted data
+    pub(crate) fn view(&self) -> MessageView<'_> {
+        MessageView::new(
+            &self.data[self.user_msg_begin..self.user_msg_end],
+            &self.msg_type_headers,
+        )
+    }
+}
+
 /// Read incoming data
+#[inline]
 pub(crate) fn read(
     encrypt_algo_with_secret_opt: Option<&EncryptAlgoWithSecretKey>,
     incoming_data: &[u8],
     check_encrypt_state: bool,
 ) -> std::result::Result<DecryptedIncomingData, Error> {
+    read_with_allocator(
+        &GLOBAL_FRAME_ALLOCATOR,
+        encrypt_algo_with_secret_opt,
+        incoming_data,
+        check_encrypt_state,
+    )
+}
+
+/// Read incoming data, decrypting it in a buffer of `frame_allocator`.
+/// The buffer is released if the data is rejected.
+pub(crate) fn read_with_allocator(
+    frame_allocator: &dyn FrameAllocator,
+    encrypt_algo_with_secret_opt: Option<&EncryptAlgoWithSecretKey>,
+    incoming_data: &[u8],
+    check_encrypt_state: bool,
+) -> std::result::Result<DecryptedIncomingData, Error> {
+    let mut decrypted_data = frame_allocator.alloc(incoming_data.len());
+    match decrypt_and_parse(
+        encrypt_algo_with_secret_opt,
+        incoming_data,
+        check_encrypt_state,
+        &mut decrypted_data,
+    ) {
+        Ok((user_msg_begin, user_msg_end, msg_type_headers)) => Ok(DecryptedIncomingData {
+            data: decrypted_data,
+            user_msg_begin,
+            user_msg_end,
+            msg_type_headers,
+        }),
+        Err(e) => {
+            frame_allocator.release(decrypted_data);
+            Err(e)
+        }
+    }
+}
+
+/// Decrypt incoming data in `decrypted_data`, and parse it.
+/// Returns the bounds of the user message and the type headers.
+fn decrypt_and_parse(
+    encrypt_algo_with_secret_opt: Option<&EncryptAlgoWithSecretKey>,
+    incoming_data: &[u8],
+    check_encrypt_state: bool,
+    decrypted_data: &mut Vec<u8>,
+) -> Result<(usize, usize, MsgTypeHeaders)> {
     // Decrypt data
     let data_encrypted;
-    let mut buffer = BufWriter::new(Vec::with_capacity(incoming_data.len()));
-    if incoming_data[..MAGIC_VALUE_END] == MAGIC_VALUE {
+    if incoming_data.get(..MAGIC_VALUE_END) == Some(&MAGIC_VALUE[..]) {
         // Data are not encrypted
         data_encrypted = false;
-        buffer.write(incoming_data).map_err(Error::WriteError)?;
+        decrypted_data.extend_from_slice(incoming_data);
     } else {
         // Data are encrypted
         data_encrypted = true;
         if let Some(encrypt_algo_with_secret) = encrypt_algo_with_secret_opt {
-            decrypt(incoming_data, encrypt_algo_with_secret, &mut buffer)?;
+            // Reject truncated frames before any crypto work
+            let min_len = encrypt_algo_with_secret.tag_len() + USER_MSG_MIN_LEN;
+            if incoming_data.len() < min_len {
+                return Err(Error::FrameTruncated {
+                    expected: min_len,
+                    got: incoming_data.len(),
+                });
+            }
+            decrypt(incoming_data, encrypt_algo_with_secret, decrypted_data)?;
         } else {
             return Err(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedMessage));
         }
     }
-    let decrypted_data = buffer.into_inner().map_err(|_| Error::BufferFlushError)?;
+    let decrypted_data = &decrypted_data[..];
 
     // Check magic value
-    if decrypted_data[..MAGIC_VALUE_END] != MAGIC_VALUE {
+    if decrypted_data.get(..MAGIC_VALUE_END) != Some(&MAGIC_VALUE[..]) {
         return Err(IncomingMsgErr::InvalidMagicValue.into());
     }
 
+    // Decrypted data are at least USER_MSG_MIN_LEN long, so only clear frames can be truncated here
+    let frame_truncated = |expected: usize| Error::FrameTruncated {
+        expected,
+        got: incoming_data.len(),
+    };
+
+    // A VERSION REJECT frame is readable whatever its version, until the shared secret is known
+    if encrypt_algo_with_secret_opt.is_none()
+        && decrypted_data.get(ENCAPSULED_MSG_BEGIN..VERSION_REJECT_MIN_VERSION_BEGIN)
+            == Some(VERSION_REJECT_MSG_TYPE)
+    {
+        return Err(read_version_reject(decrypted_data)
+            .unwrap_or_else(|| frame_truncated(VERSION_REJECT_MSG_LEN)));
+    }
+
     // Check version
-    if decrypted_data[MAGIC_VALUE_END..VERSION_END] != CURRENT_VERSION {
-        return Err(IncomingMsgErr::UnsupportedVersion.into());
+    match decrypted_data.get(MAGIC_VALUE_END..VERSION_END) {
+        Some(version) if version == CURRENT_VERSION => {}
+        Some(_) => return Err(IncomingMsgErr::UnsupportedVersion.into()),
+        None => return Err(frame_truncated(ENCAPSULED_MSG_BEGIN)),
     }
 
     // Read ENCAPSULED_MSG_SIZE
     let mut buffer_8_bytes: [u8; 8] = <[u8; 8]>::default();
-    buffer_8_bytes.copy_from_slice(&decrypted_data[VERSION_END..ENCAPSULED_MSG_BEGIN]);
-    let encapsuled_msg_size = u64::from_be_bytes(buffer_8_bytes) as usize;
-    let user_msg_end = ENCAPSULED_MSG_BEGIN + encapsuled_msg_size;
+    buffer_8_bytes.copy_from_slice(
+        decrypted_data
+            .get(VERSION_END..ENCAPSULED_MSG_BEGIN)
+            .ok_or_else(|| frame_truncated(ENCAPSULED_MSG_BEGIN))?,
+    );
+    let encapsuled_msg_size = u64::from_be_bytes(buffer_8_bytes);
+    let available_size = decrypted_data.len() - ENCAPSULED_MSG_BEGIN;
+    if encapsuled_msg_size > available_size as u64 {
+        return Err(if data_encrypted {
+            // Authenticated by the peer: the frame is malformed, not truncated
+            IncomingMsgErr::MessageTooShort.into()
+        } else {
+            // Saturate if the announced length does not fit in usize
+            let expected = encapsuled_msg_size.saturating_add(ENCAPSULED_MSG_BEGIN as u64);
+            frame_truncated(usize::try_from(expected).unwrap_or(!0))
+        });
+    }
+    let user_msg_end = ENCAPSULED_MSG_BEGIN + encapsuled_msg_size as usize;
 
     // Read type headers
     let (msg_type_headers, type_headers_len) =
         read_type_headers(&decrypted_data[ENCAPSULED_MSG_BEGIN..])?;
+    let user_msg_begin = ENCAPSULED_MSG_BEGIN + type_headers_len;
 
-    if check_encrypt_state && data_encrypted != msg_type_headers.must_be_encrypted() {
+    // An encrypted frame never carries a handshake message, whatever the state checks
+    if (check_encrypt_state || data_encrypted)
+        && data_encrypted != msg_type_headers.must_be_encrypted()
+    {
         Err(Error::RecvInvalidMsg(
             IncomingMsgErr::UnexpectedEncryptionState,
         ))
+    } else if user_msg_begin > user_msg_end {
+        Err(IncomingMsgErr::MessageTooShort.into())
     } else {
-        Ok(DecryptedIncomingData {
-            data: decrypted_data,
-            user_msg_begin: ENCAPSULED_MSG_BEGIN + type_headers_len,
-            user_msg_end,
-            msg_type_headers,
-        })
+        Ok((user_msg_begin, user_msg_end, msg_type_headers))
+    }
+}
+
+/// Footer of a decrypted user message (its hash, possibly empty),
+/// without the padding and the padding length of a padded frame
+pub(crate) fn user_msg_footer(data: &[u8], user_msg_end: usize, padded: bool) -> Result<&[u8]> {
+    if !padded {
+        return Ok(&data[user_msg_end..]);
     }
+    let padding_len_begin = data
+        .len()
+        .checked_sub(PADDING_LEN_SIZE)
+        .filter(|begin| *begin >= user_msg_end)
+        .ok_or(IncomingMsgErr::MessageTooShort)?;
+    let mut padding_len = [0u8; PADDING_LEN_SIZE];
+    padding_len.copy_from_slice(&data[padding_len_begin..]);
+    let footer_end = padding_len_begin
+        .checked_sub(u16::from_be_bytes(padding_len) as usize)
+        .filter(|end| *end >= user_msg_end)
+        .ok_or(IncomingMsgErr::MessageTooShort)?;
+    Ok(&data[user_msg_end..footer_end])
+}
+
+/// Read the versions supported by the peer in a VERSION REJECT frame
+fn read_version_reject(frame: &[u8]) -> Option<Error> {
+    let read_version = |begin: usize| {
+        let mut version = [0u8; VERSION_SIZE];
+        version.copy_from_slice(frame.get(begin..begin + VERSION_SIZE)?);
+        Some(u32::from_be_bytes(version))
+    };
+    Some(Error::VersionRejected {
+        min_version: read_version(VERSION_REJECT_MIN_VERSION_BEGIN)?,
+        max_version: read_version(VERSION_REJECT_MIN_VERSION_BEGIN + VERSION_SIZE)?,
+    })
+}
+
+/// Create a VERSION REJECT frame, telling the peer which versions we support
+pub(crate) fn create_version_reject_msg() -> Result<Vec<u8>> {
+    version_reject_frame(PROTOCOL_VERSION, PROTOCOL_VERSION)
+}
+
+/// Read the protocol version of a clear frame (handshake or VERSION REJECT), without parsing
+/// the rest of it.
+///
+/// All versions keep the magic value and the VERSION field at the beginning of clear frames:
+/// a process linking several implementations of the protocol (during a network upgrade) can
+/// route each session to the implementation speaking the version of its peer first frame.
+pub fn peek_frame_version(frame: &[u8]) -> Option<u32> {
+    if frame.get(..MAGIC_VALUE_END) != Some(&MAGIC_VALUE[..]) {
+        return None;
+    }
+    let mut version = [0u8; VERSION_SIZE];
+    version.copy_from_slice(frame.get(MAGIC_VALUE_END..VERSION_END)?);
+    Some(u32::from_be_bytes(version))
+}
+
+/// Create a VERSION REJECT frame advertising the versions from `min_version` to `max_version`,
+/// for example all the versions spoken by the implementations linked in the process.
+pub fn version_reject_frame(min_version: u32, max_version: u32) -> Result<Vec<u8>> {
+    let mut type_msg_headers = Vec::with_capacity(MSG_TYPE_LEN + 2 * VERSION_SIZE);
+    type_msg_headers.extend_from_slice(VERSION_REJECT_MSG_TYPE);
+    type_msg_headers.extend_from_slice(&min_version.to_be_bytes());
+    type_msg_headers.extend_from_slice(&max_version.to_be_bytes());
+    Ok(EncapsuledMessage::new(&type_msg_headers, None)?.data)
+}
+
+/// Parse an untrusted clear frame (CONNECT or ACK) without verifying its signature.
+///
+/// Never panics, whatever the input: malformed frames are reported as errors.
+/// Intended for fuzzing and for inspecting captured handshake frames.
+pub fn parse_untrusted(frame: &[u8]) -> Result<Message> {
+    read(None, frame, true)?.into_message()
+}
+
+#[inline]
+fn get_slice(type_headers: &[u8], begin: usize, end: usize) -> Result<&[u8]> {
+    type_headers
+        .get(begin..end)
+        .ok_or_else(|| IncomingMsgErr::MessageTooShort.into())
 }
 
 fn read_type_headers(type_headers: &[u8]) -> Result<(MsgTypeHeaders, usize)> {
     // Match message type
-    match &type_headers[..MSG_TYPE_LEN] {
-        USER_MSG_TYPE => {
+    let msg_type = get_slice(type_headers, 0, MSG_TYPE_LEN)?;
+    match msg_type {
+        USER_MSG_TYPE | KEEPALIVE_MSG_TYPE | COVER_MSG_TYPE => {
             let mut nonce = [0u8; NONCE_SIZE];
-            nonce.copy_from_slice(&type_headers[MSG_TYPE_LEN..MSG_TYPE_LEN + NONCE_SIZE]);
+            nonce.copy_from_slice(get_slice(
+                type_headers,
+                MSG_TYPE_LEN,
+                MSG_TYPE_LEN + NONCE_SIZE,
+            )?);
             Ok((
                 MsgTypeHeaders::UserMsg {
                     nonce: u64::from_be_bytes(nonce),
+                    expiry: None,
+                    keepalive: msg_type == KEEPALIVE_MSG_TYPE,
+                    cover: msg_type == COVER_MSG_TYPE,
                 },
                 MSG_TYPE_LEN + NONCE_SIZE,
             ))
         }
+        EXPIRING_USER_MSG_TYPE => {
+            let mut nonce = [0u8; NONCE_SIZE];
+            nonce.copy_from_slice(get_slice(
+                type_headers,
+                MSG_TYPE_LEN,
+                MSG_TYPE_LEN + NONCE_SIZE,
+            )?);
+            let expiry_begin = MSG_TYPE_LEN + NONCE_SIZE;
+            let mut expiry = [0u8; EXPIRY_SIZE];
+            expiry.copy_from_slice(get_slice(
+                type_headers,
+                expiry_begin,
+                expiry_begin + EXPIRY_SIZE,
+            )?);
+            Ok((
+                MsgTypeHeaders::UserMsg {
+                    nonce: u64::from_be_bytes(nonce),
+                    expiry: Some(u64::from_be_bytes(expiry)),
+                    keepalive: false,
+                    cover: false,
+                },
+                expiry_begin + EXPIRY_SIZE,
+            ))
+        }
         CONNECT_MSG_TYPE => {
             // Read PEER_EPHEMERAL_PUBKEY
             let mut peer_ephemeral_pk = [0u8; EPK_SIZE];
-            peer_ephemeral_pk.copy_from_slice(&type_headers[MSG_TYPE_LEN..MSG_TYPE_LEN + EPK_SIZE]);
+            peer_ephemeral_pk.copy_from_slice(get_slice(
+                type_headers,
+                MSG_TYPE_LEN,
+                MSG_TYPE_LEN + EPK_SIZE,
+            )?);
             // Read SIG_ALGO and SIG_PUBKEY
-            match &type_headers[(MSG_TYPE_LEN + EPK_SIZE)..(SIG_PUBKEY_BEGIN)] {
-                SIG_ALGO_ED25519 => {
-                    let mut sig_algo = [0u8; SIG_ALGO_LEN];
-                    sig_algo.copy_from_slice(
-                        &type_headers
-                            [(MSG_TYPE_LEN + EPK_SIZE)..(MSG_TYPE_LEN + EPK_SIZE + SIG_ALGO_LEN)],
-                    );
-                    Ok((
-                        MsgTypeHeaders::Connect {
-                            peer_ephemeral_pk,
-                            sig_algo,
-                            sig_pubkey: type_headers[SIG_PUBKEY_BEGIN..(SIG_PUBKEY_BEGIN + 32)]
-                                .to_vec(),
-                        },
-                        SIG_PUBKEY_BEGIN + 32,
-                    ))
-                }
-                _ => Err(IncomingMsgErr::UnsupportedSigAlgo.into()),
+            let sig_algo_bytes =
+                get_slice(type_headers, MSG_TYPE_LEN + EPK_SIZE, SIG_PUBKEY_BEGIN)?;
+            if let Some(sig_algo) = SigAlgo::from_id(sig_algo_bytes) {
+                let sig_pubkey_end = SIG_PUBKEY_BEGIN + sig_algo.pubkey_len();
+                // Read CAPABILITIES
+                let capabilities_end = sig_pubkey_end + CAPABILITIES_SIZE;
+                let mut capabilities = [0u8; CAPABILITIES_SIZE];
+                capabilities.copy_from_slice(get_slice(
+                    type_headers,
+                    sig_pubkey_end,
+                    capabilities_end,
+                )?);
+                Ok((
+                    MsgTypeHeaders::Connect {
+                        peer_ephemeral_pk,
+                        sig_algo,
+                        sig_pubkey: get_slice(type_headers, SIG_PUBKEY_BEGIN, sig_pubkey_end)?
+                            .to_vec(),
+                        capabilities: Capabilities::from_bits(u32::from_be_bytes(capabilities)),
+                    },
+                    capabilities_end,
+                ))
+            } else {
+                Err(IncomingMsgErr::UnsupportedSigAlgo.into())
             }
         }
         ACK_MSG_TYPE => {
             let mut challenge = [0u8; CHALLENGE_SIZE];
-            challenge
-                .copy_from_slice(&&type_headers[MSG_TYPE_LEN..(MSG_TYPE_LEN + CHALLENGE_SIZE)]);
+            challenge.copy_from_slice(get_slice(
+                type_headers,
+                MSG_TYPE_LEN,
+                MSG_TYPE_LEN + CHALLENGE_SIZE,
+            )?);
             Ok((
                 MsgTypeHeaders::Ack { challenge },
                 MSG_TYPE_LEN + CHALLENGE_SIZE,
@@ -149,11 +397,14 @@ fn read_type_headers(type_headers: &[u8]) -> Result<(MsgTypeHeaders, usize)> {
 mod tests {
 
     use super::*;
-    use crate::digest::sha256;
-    use crate::encryption::{encrypt, tests::gen_random_encrypt_algo_with_secret};
-    use crate::signature::{SIG_ALGO_ED25519, SIG_ALGO_ED25519_ARRAY};
+    use crate::digest::{Digest, RING_DIGEST};
+    use crate::encryption::encrypt;
+    use crate::encryption::tests::{
+        gen_random_encrypt_algo_with_secret, peer_encrypt_algo_with_secret,
+    };
+    use crate::signature::SIG_ALGO_ED25519;
     use pretty_assertions::assert_eq;
-    use std::io::BufReader;
+    use std::io::{BufReader, BufWriter};
 
     #[test]
     fn test_unexpected_user_msg() {
@@ -168,14 +419,17 @@ mod tests {
 
     #[test]
     fn test_msg_with_unsupported_version() {
-        let mut fake_incoming_data = MAGIC_VALUE.to_vec();
-        fake_incoming_data.append(&mut vec![0, 0, 0, 2]);
-
-        let result = read(None, &fake_incoming_data, true);
-        if let Err(Error::RecvInvalidMsg(e)) = result {
-            assert_eq!(IncomingMsgErr::UnsupportedVersion, e);
-        } else {
-            panic!("unexpected result")
+        // Previous and next versions
+        for version in &[[0, 0, 0, 1], [0, 0, 0, 3]] {
+            let mut fake_incoming_data = MAGIC_VALUE.to_vec();
+            fake_incoming_data.extend_from_slice(version);
+
+            let result = read(None, &fake_incoming_data, true);
+            if let Err(Error::RecvInvalidMsg(e)) = result {
+                assert_eq!(IncomingMsgErr::UnsupportedVersion, e);
+            } else {
+                panic!("unexpected result")
+            }
         }
     }
 
@@ -197,7 +451,7 @@ mod tests {
 
     #[test]
     fn test_user_msg_with_wrong_magiv_value() -> Result<()> {
-        let wrong_magic_value = vec![0, 0, 0, 0];
+        let wrong_magic_value = vec![0; USER_MSG_MIN_LEN];
         let encrypt_algo_with_secret = gen_random_encrypt_algo_with_secret();
         let mut encrypted_data = BufWriter::new(Vec::new());
 
@@ -209,7 +463,7 @@ mod tests {
         let encrypted_incoming_data = encrypted_data.into_inner().expect("buffer flush error");
 
         let result = read(
-            Some(&encrypt_algo_with_secret),
+            Some(&peer_encrypt_algo_with_secret(&encrypt_algo_with_secret)),
             &encrypted_incoming_data[..],
             true,
         );
@@ -221,6 +475,54 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_encrypted_msg_types() -> Result<()> {
+        let mut user_msg = Vec::with_capacity(USER_MSG_MIN_LEN);
+        user_msg.append(&mut MAGIC_VALUE.to_vec());
+        user_msg.append(&mut CURRENT_VERSION.to_vec());
+        user_msg.append(&mut 10u64.to_be_bytes().to_vec()); // Encapsuled message length
+        user_msg.append(&mut vec![0, 0]); // USER type
+        user_msg.append(&mut 3u64.to_be_bytes().to_vec()); // NONCE
+        let mut ack_msg = Vec::with_capacity(50);
+        ack_msg.append(&mut MAGIC_VALUE.to_vec());
+        ack_msg.append(&mut CURRENT_VERSION.to_vec());
+        ack_msg.append(&mut 34u64.to_be_bytes().to_vec()); // Encapsuled message length
+        ack_msg.append(&mut vec![0, 2]); // ACK type
+        ack_msg.append(&mut [0u8; 32].to_vec()); // ACK challenge
+        let encrypt_algo_with_secret = gen_random_encrypt_algo_with_secret();
+        let peer_encrypt_algo_with_secret =
+            peer_encrypt_algo_with_secret(&encrypt_algo_with_secret);
+
+        for msg in &[&user_msg, &ack_msg] {
+            let mut encrypted_data = BufWriter::new(Vec::new());
+            encrypt(
+                &mut BufReader::new(&msg[..]),
+                &encrypt_algo_with_secret,
+                &mut encrypted_data,
+            )?;
+            let encrypted_incoming_data = encrypted_data.into_inner().expect("buffer flush error");
+
+            // An encrypted handshake message is rejected, even without the state checks
+            for check_encrypt_state in &[true, false] {
+                let result = read(
+                    Some(&peer_encrypt_algo_with_secret),
+                    &encrypted_incoming_data[..],
+                    *check_encrypt_state,
+                );
+                match result {
+                    Ok(DecryptedIncomingData { data, .. }) => {
+                        assert_eq!(user_msg, data);
+                    }
+                    Err(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedEncryptionState)) => {
+                        assert_eq!(&ack_msg, *msg)
+                    }
+                    Err(e) => panic!("unexpected error: {:?}", e),
+                }
+            }
+        }
+        Ok(())
+    }
+
     #[test]
     fn test_read() -> Result<()> {
         // Create fake keys
@@ -230,7 +532,7 @@ mod tests {
 
         // Create fake challenge
         let mut fake_challenge = [0u8; 32];
-        fake_challenge.copy_from_slice(sha256(fake_ephem_pk).as_ref());
+        fake_challenge.copy_from_slice(&RING_DIGEST.sha256(fake_ephem_pk));
 
         // Create encrypt_algo_with_secret
         let encrypt_algo_with_secret = gen_random_encrypt_algo_with_secret();
@@ -242,22 +544,24 @@ mod tests {
         let mut incoming_data = Vec::with_capacity(100);
         incoming_data.append(&mut MAGIC_VALUE.to_vec());
         incoming_data.append(&mut CURRENT_VERSION.to_vec());
-        incoming_data.append(&mut 74u64.to_be_bytes().to_vec()); // Encapsuled message length
+        incoming_data.append(&mut 78u64.to_be_bytes().to_vec()); // Encapsuled message length
         incoming_data.append(&mut vec![0, 1]); // CONNECT type
         incoming_data.append(&mut fake_ephem_pk.to_vec()); // EPK
         incoming_data.append(&mut SIG_ALGO_ED25519.to_vec()); // SIG_ALGO
         incoming_data.append(&mut fake_sig_pk.clone()); // SIG_PK
+        incoming_data.append(&mut vec![0, 0, 0, 14]); // CAPABILITIES
         incoming_data.append(&mut vec![5, 4, 4, 5]); // User custom data
         incoming_data.append(&mut [0u8; 32].to_vec()); // fake sig
         assert_eq!(
             DecryptedIncomingData {
                 data: incoming_data.clone(),
-                user_msg_begin: 86,
-                user_msg_end: 90,
+                user_msg_begin: 90,
+                user_msg_end: 94,
                 msg_type_headers: MsgTypeHeaders::Connect {
                     peer_ephemeral_pk: [0u8; EPK_SIZE],
-                    sig_algo: SIG_ALGO_ED25519_ARRAY,
+                    sig_algo: SigAlgo::Ed25519,
                     sig_pubkey: fake_sig_pk,
+                    capabilities: Capabilities::minimal(),
                 }
             },
             read(Some(&encrypt_algo_with_secret), &incoming_data[..], true)?,
@@ -297,13 +601,149 @@ mod tests {
             0, 0, 0, 0, 0, 1, 226, 64, // NONCE
         ];
 
-        let expected = (MsgTypeHeaders::UserMsg { nonce: 123_456 }, 10);
+        let expected = (
+            MsgTypeHeaders::UserMsg {
+                nonce: 123_456,
+                expiry: None,
+                keepalive: false,
+                cover: false,
+            },
+            10,
+        );
+
+        assert_eq!(expected, read_type_headers(&type_headers[..])?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_keepalive_type_headers() -> Result<()> {
+        let type_headers = vec![
+            0, 4, // KEEPALIVE_MSG_TYPE
+            0, 0, 0, 0, 0, 1, 226, 64, // NONCE
+        ];
+
+        let expected = (
+            MsgTypeHeaders::UserMsg {
+                nonce: 123_456,
+                expiry: None,
+                keepalive: true,
+                cover: false,
+            },
+            10,
+        );
 
         assert_eq!(expected, read_type_headers(&type_headers[..])?);
 
         Ok(())
     }
 
+    #[test]
+    fn test_read_expiring_user_type_headers() -> Result<()> {
+        let type_headers = vec![
+            0, 3, // EXPIRING_USER_MSG_TYPE
+            0, 0, 0, 0, 0, 1, 226, 64, // NONCE
+            0, 0, 1, 110, 190, 193, 196, 0, // EXPIRY
+        ];
+
+        let expected = (
+            MsgTypeHeaders::UserMsg {
+                nonce: 123_456,
+                expiry: Some(1_575_158_400_000),
+                keepalive: false,
+                cover: false,
+            },
+            18,
+        );
+
+        assert_eq!(expected, read_type_headers(&type_headers[..])?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_msg_footer() -> Result<()> {
+        // Message of 4 bytes, hash of 2 bytes, 3 padding bytes
+        let data = [1, 1, 1, 1, 9, 9, 0, 0, 0, 0, 3];
+        assert_eq!(
+            &[9, 9, 0, 0, 0, 0, 3][..],
+            user_msg_footer(&data, 4, false)?
+        );
+        assert_eq!(&[9, 9][..], user_msg_footer(&data, 4, true)?);
+
+        // Padding length beyond the message
+        for (data, user_msg_end) in &[(&data[..], 7), (&data[..], 10), (&data[..5], 4)] {
+            if let Err(Error::RecvInvalidMsg(IncomingMsgErr::MessageTooShort)) =
+                user_msg_footer(data, *user_msg_end, true)
+            {
+            } else {
+                panic!("Expected error MessageTooShort !")
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_peek_frame_version() -> Result<()> {
+        assert_eq!(CURRENT_VERSION, PROTOCOL_VERSION.to_be_bytes());
+
+        let mut version_reject_msg = version_reject_frame(1, 2)?;
+        assert_eq!(
+            Some(PROTOCOL_VERSION),
+            peek_frame_version(&version_reject_msg)
+        );
+        version_reject_msg[MAGIC_VALUE_END..VERSION_END].copy_from_slice(&[0, 0, 1, 0]);
+        assert_eq!(Some(256), peek_frame_version(&version_reject_msg));
+
+        // Truncated or encrypted frames
+        assert_eq!(
+            None,
+            peek_frame_version(&version_reject_msg[..VERSION_END - 1])
+        );
+        version_reject_msg[0] ^= 1;
+        assert_eq!(None, peek_frame_version(&version_reject_msg));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_version_reject_msg() -> Result<()> {
+        let mut version_reject_msg = create_version_reject_msg()?;
+        assert_eq!(VERSION_REJECT_MSG_LEN, version_reject_msg.len());
+
+        // Readable whatever its version
+        for version in &[CURRENT_VERSION, [0, 0, 0, 7]] {
+            version_reject_msg[MAGIC_VALUE_END..VERSION_END].copy_from_slice(version);
+            if let Err(Error::VersionRejected {
+                min_version,
+                max_version,
+            }) = read(None, &version_reject_msg, true)
+            {
+                assert_eq!(
+                    (PROTOCOL_VERSION, PROTOCOL_VERSION),
+                    (min_version, max_version)
+                );
+            } else {
+                panic!("unexpected result");
+            }
+        }
+
+        // Truncated
+        let result = read(
+            None,
+            &version_reject_msg[..VERSION_REJECT_MSG_LEN - 1],
+            true,
+        );
+        if let Err(Error::FrameTruncated { expected, .. }) = result {
+            assert_eq!(VERSION_REJECT_MSG_LEN, expected);
+        } else {
+            panic!("unexpected result");
+        }
+
+        Ok(())
+    }
+
     #[test]
     fn test_read_unknown_type_headers() {
         let type_headers = vec![1, 0]; // Unknown type
@@ -345,6 +785,7 @@ mod tests {
             0, 0, 0, 0, // SIG_ALGO_ED25519
             0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8,
             9, 0, 1, // Sig pubkey (32 bytes)
+            0, 0, 0, 15, // CAPABILITIES
         ];
 
         let expected = (
@@ -353,10 +794,11 @@ mod tests {
                     0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 0, 1, 2, 3, 4, 5,
                     6, 7, 8, 9, 0, 1,
                 ],
-                sig_algo: [0, 0, 0, 0],
-                sig_pubkey: type_headers[38..].to_vec(),
+                sig_algo: SigAlgo::Ed25519,
+                sig_pubkey: type_headers[38..70].to_vec(),
+                capabilities: Capabilities::complete(),
             },
-            70,
+            74,
         );
 
         assert_eq!(expected, read_type_headers(&type_headers[..])?);
@@ -384,4 +826,107 @@ mod tests {
             panic!("Unexpected result");
         }
     }
+
+    fn valid_connect_frame() -> Vec<u8> {
+        let mut frame = Vec::with_capacity(126);
+        frame.append(&mut MAGIC_VALUE.to_vec());
+        frame.append(&mut CURRENT_VERSION.to_vec());
+        frame.append(&mut 78u64.to_be_bytes().to_vec()); // Encapsuled message length
+        frame.append(&mut vec![0, 1]); // CONNECT type
+        frame.append(&mut [0u8; 32].to_vec()); // EPK
+        frame.append(&mut SIG_ALGO_ED25519.to_vec()); // SIG_ALGO
+        frame.append(&mut [0u8; 32].to_vec()); // SIG_PK
+        frame.append(&mut vec![0, 0, 0, 14]); // CAPABILITIES
+        frame.append(&mut vec![5, 4, 4, 5]); // User custom data
+        frame.append(&mut [0u8; 32].to_vec()); // fake sig
+        frame
+    }
+
+    #[test]
+    fn test_parse_untrusted() -> Result<()> {
+        assert_eq!(
+            Message::Connect {
+                sig_algo: SigAlgo::Ed25519.id(),
+                sig_pubkey: vec![0u8; 32],
+                custom_data: Some(vec![5, 4, 4, 5]),
+            },
+            parse_untrusted(&valid_connect_frame())?,
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_untrusted_truncated_frames() {
+        let frame = valid_connect_frame();
+        for len in 0..94 {
+            assert!(parse_untrusted(&frame[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_read_never_panics() {
+        let encrypt_algo_with_secret = gen_random_encrypt_algo_with_secret();
+        let mut state = 0x2545_F491_4F6C_DD1Du64;
+        for i in 0..2_000 {
+            let mut frame = valid_connect_frame();
+            if i % 2 == 0 {
+                frame.truncate(i % frame.len());
+            }
+            for byte in frame.iter_mut() {
+                // xorshift64
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                if state % 8 == 0 {
+                    *byte = state as u8;
+                }
+            }
+            let _ = parse_untrusted(&frame);
+            let _ = read(Some(&encrypt_algo_with_secret), &frame, true);
+            let _ = read(Some(&encrypt_algo_with_secret), &frame, false);
+        }
+    }
+
+    #[test]
+    fn test_read_encapsuled_msg_len_overflow() {
+        let mut frame = valid_connect_frame();
+        frame[8..16].copy_from_slice(&u64::max_value().to_be_bytes());
+        if let Err(Error::FrameTruncated { expected, got }) = read(None, &frame, true) {
+            assert_eq!(usize::max_value(), expected);
+            assert_eq!(126, got);
+        } else {
+            panic!("unexpected result")
+        }
+    }
+
+    #[test]
+    fn test_read_truncated_frames() {
+        // Truncated header
+        let frame = valid_connect_frame();
+        if let Err(Error::FrameTruncated { expected, got }) = read(None, &frame[..12], true) {
+            assert_eq!(ENCAPSULED_MSG_BEGIN, expected);
+            assert_eq!(12, got);
+        } else {
+            panic!("unexpected result")
+        }
+
+        // Truncated encapsuled message
+        if let Err(Error::FrameTruncated { expected, got }) = read(None, &frame[..60], true) {
+            assert_eq!(94, expected);
+            assert_eq!(60, got);
+        } else {
+            panic!("unexpected result")
+        }
+
+        // Encrypted frame shorter than the smallest user message
+        let encrypt_algo_with_secret = gen_random_encrypt_algo_with_secret();
+        if let Err(Error::FrameTruncated { expected, got }) =
+            read(Some(&encrypt_algo_with_secret), &[0u8; 20], true)
+        {
+            assert_eq!(42, expected);
+            assert_eq!(20, got);
+        } else {
+            panic!("unexpected result")
+        }
+    }
 }
@@ -15,6 +15,7 @@
 
 //! Provide wrappers around cryptographic seeds.
 
+use crate::{Error, Result};
 use zeroize::Zeroize;
 
 /// Store a 32 bytes seed
@@ -41,15 +42,25 @@ impl Seed32 {
         Seed32(seed_bytes)
     }
     #[inline]
-    /// Generate random seed
+    /// Generate random seed.
+    /// Panics if the system random generator fails, see `try_random`.
+    /// Not available with the `no-panic` feature.
+    #[cfg(not(feature = "no-panic"))]
+    #[allow(clippy::panic)]
     pub fn random() -> Seed32 {
-        if let Ok(random_bytes) = ring::rand::generate::<[u8; 32]>(&ring::rand::SystemRandom::new())
-        {
-            Seed32::new(random_bytes.expose())
+        if let Ok(seed) = Self::try_random() {
+            seed
         } else {
             panic!("System error: fail to generate random seed !")
         }
     }
+    #[inline]
+    /// Generate random seed, fails if the system random generator fails
+    pub fn try_random() -> Result<Seed32> {
+        ring::rand::generate::<[u8; 32]>(&ring::rand::SystemRandom::new())
+            .map(|random_bytes| Seed32::new(random_bytes.expose()))
+            .map_err(|_| Error::FailToGenRandomSeed)
+    }
 }
 
 /// Store a 48 bytes seed
@@ -101,6 +112,12 @@ impl Default for InnerSeed64 {
     }
 }
 
+impl AsRef<[u8]> for Seed64 {
+    fn as_ref(&self) -> &[u8] {
+        &(self.0).0
+    }
+}
+
 impl AsMut<[u8]> for Seed64 {
     fn as_mut(&mut self) -> &mut [u8] {
         &mut (self.0).0
@@ -124,12 +141,15 @@ pub mod tests {
     }
 
     #[test]
-    fn tests_seed32() {
+    fn tests_seed32() -> Result<()> {
+        #[cfg(not(feature = "no-panic"))]
         assert_ne!(Seed32::random(), Seed32::random());
+        assert_ne!(Seed32::try_random()?, Seed32::try_random()?);
 
         let mut seed = Seed32::new([3u8; 32]);
 
         assert_eq!(&[3u8; 32], seed.as_ref());
         assert_eq!(&mut [3u8; 32], seed.as_mut());
+        Ok(())
     }
 }
@@ -0,0 +1,187 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Check the cryptographic algorithms with known-answer tests.
+
+use crate::constants::HASH_SIZE;
+use crate::digest::{Digest, RING_DIGEST};
+use crate::encryption::EncryptAlgo;
+use crate::signature::SigAlgo;
+use ring::hmac;
+
+/// Sha256 of "abc" (FIPS 180-2 appendix B.1)
+const SHA256_KAT_DIGEST: [u8; HASH_SIZE] = [
+    0xba, 0x78, 0x16, 0xbf, 0x8f, 0x01, 0xcf, 0xea, 0x41, 0x41, 0x40, 0xde, 0x5d, 0xae, 0x22, 0x23,
+    0xb0, 0x03, 0x61, 0xa3, 0x96, 0x17, 0x7a, 0x9c, 0xb4, 0x10, 0xff, 0x61, 0xf2, 0x00, 0x15, 0xad,
+];
+
+/// HMAC-Sha256 of "what do ya want for nothing?" with key "Jefe" (RFC 4231 test case 2)
+const HMAC_SHA256_KAT_TAG: [u8; HASH_SIZE] = [
+    0x5b, 0xdc, 0xc1, 0x46, 0xbf, 0x60, 0x75, 0x4e, 0x6a, 0x04, 0x24, 0x26, 0x08, 0x95, 0x75, 0xc7,
+    0x5a, 0x00, 0x3f, 0x08, 0x9d, 0x27, 0x39, 0x83, 0x9d, 0xec, 0x58, 0xb9, 0x64, 0xec, 0x38, 0x43,
+];
+
+/// Algorithm checked by `self_test`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SelfTestAlgo {
+    /// Encryption algorithm
+    Encrypt(EncryptAlgo),
+    /// Sha256 of the digest backend (user message hashes, challenges, fingerprints)
+    Sha256,
+    /// HMAC-Sha256 (derivation of the encryption keys)
+    HmacSha256,
+    /// Signature algorithm
+    Sig(SigAlgo),
+}
+
+/// Result of the known-answer test of an algorithm
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SelfTestResult {
+    /// Algorithm checked
+    pub algo: SelfTestAlgo,
+    /// Failed check, none if the algorithm passed its known-answer test
+    pub failure: Option<&'static str>,
+}
+
+impl SelfTestResult {
+    /// Whether the algorithm passed its known-answer test
+    #[inline]
+    pub fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+/// Report of `self_test`: the result of each algorithm
+#[derive(Clone, Debug, PartialEq)]
+pub struct SelfTestReport {
+    /// Results, in the order of the tests
+    pub results: Vec<SelfTestResult>,
+}
+
+impl SelfTestReport {
+    /// Whether all algorithms passed their known-answer test
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(SelfTestResult::passed)
+    }
+    /// Results of the algorithms that failed their known-answer test
+    pub fn failures(&self) -> Vec<SelfTestResult> {
+        self.results
+            .iter()
+            .filter(|result| !result.passed())
+            .copied()
+            .collect()
+    }
+}
+
+/// Run the known-answer tests of all supported encryption, hash and signature algorithms,
+/// with the default digest backend.
+///
+/// Intended to be called at startup, to verify the cryptographic stack on the target hardware
+/// before opening connections.
+#[inline]
+pub fn self_test() -> SelfTestReport {
+    self_test_with_digest(&RING_DIGEST)
+}
+
+/// Run the known-answer tests of all supported encryption, hash and signature algorithms,
+/// the Sha256 test checking `digest` (for example a hardware SHA engine), see `self_test`.
+pub fn self_test_with_digest(digest: &dyn Digest) -> SelfTestReport {
+    let mut results =
+        Vec::with_capacity(EncryptAlgo::SUPPORTED.len() + SigAlgo::SUPPORTED.len() + 2);
+    for encrypt_algo in EncryptAlgo::SUPPORTED {
+        results.push(SelfTestResult {
+            algo: SelfTestAlgo::Encrypt(*encrypt_algo),
+            failure: encrypt_algo.known_answer_test().err(),
+        });
+    }
+    results.push(SelfTestResult {
+        algo: SelfTestAlgo::Sha256,
+        failure: if digest.sha256(b"abc") == SHA256_KAT_DIGEST {
+            None
+        } else {
+            Some("unexpected digest")
+        },
+    });
+    results.push(SelfTestResult {
+        algo: SelfTestAlgo::HmacSha256,
+        failure: hmac_sha256_known_answer_test().err(),
+    });
+    for sig_algo in SigAlgo::SUPPORTED {
+        results.push(SelfTestResult {
+            algo: SelfTestAlgo::Sig(*sig_algo),
+            failure: sig_algo.known_answer_test().err(),
+        });
+    }
+    SelfTestReport { results }
+}
+
+fn hmac_sha256_known_answer_test() -> Result<(), &'static str> {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, b"Jefe");
+    if hmac::sign(&key, b"what do ya want for nothing?").as_ref() != &HMAC_SHA256_KAT_TAG[..] {
+        return Err("unexpected tag");
+    }
+    if hmac::verify(&key, b"what do ya want for nothing!", &HMAC_SHA256_KAT_TAG).is_ok() {
+        return Err("tampered message accepted");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_self_test() {
+        let report = self_test();
+        assert!(report.passed(), "{:?}", report);
+        assert_eq!(
+            vec![
+                SelfTestAlgo::Encrypt(EncryptAlgo::Chacha20Poly1305Aead),
+                SelfTestAlgo::Sha256,
+                SelfTestAlgo::HmacSha256,
+                SelfTestAlgo::Sig(SigAlgo::Ed25519),
+            ],
+            report
+                .results
+                .iter()
+                .map(|result| result.algo)
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_self_test_with_faulty_digest() {
+        #[derive(Debug)]
+        struct FaultyDigest;
+        impl Digest for FaultyDigest {
+            fn sha256(&self, datas: &[u8]) -> [u8; HASH_SIZE] {
+                let mut hash = RING_DIGEST.sha256(datas);
+                hash[0] ^= 1;
+                hash
+            }
+        }
+
+        let report = self_test_with_digest(&FaultyDigest);
+        assert!(!report.passed());
+        assert_eq!(
+            vec![SelfTestResult {
+                algo: SelfTestAlgo::Sha256,
+                failure: Some("unexpected digest"),
+            }],
+            report.failures()
+        );
+    }
+}
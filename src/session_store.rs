@@ -0,0 +1,146 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage persistence of session data (resumption tickets, prekeys and pinned peers).
+
+use crate::{Error, Result};
+use ring::constant_time::verify_slices_are_equal;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::sync::{Mutex, MutexGuard};
+
+/// Storage of session data, to be backed by the application database.
+///
+/// Peers are identified by an application-defined identifier (host name, address, etc).
+/// Implementations must be safe to share between sessions.
+pub trait SessionStore: Debug + Send + Sync {
+    /// Persist the resumption ticket of a peer, replacing the previous one
+    fn save_ticket(&self, peer_id: &str, ticket: &[u8]) -> Result<()>;
+    /// Load the resumption ticket of a peer
+    fn load_ticket(&self, peer_id: &str) -> Result<Option<Vec<u8>>>;
+    /// Remove the resumption ticket of a peer
+    fn remove_ticket(&self, peer_id: &str) -> Result<()>;
+    /// Persist a prekey
+    fn save_prekey(&self, prekey_id: u64, prekey: &[u8]) -> Result<()>;
+    /// Load and remove a prekey, each prekey must be used only once
+    fn take_prekey(&self, prekey_id: u64) -> Result<Option<Vec<u8>>>;
+    /// Pin the signature public key of a peer, replacing the previous one
+    fn pin_peer(&self, peer_id: &str, sig_pubkey: &[u8]) -> Result<()>;
+    /// Get the pinned signature public key of a peer
+    fn pinned_peer(&self, peer_id: &str) -> Result<Option<Vec<u8>>>;
+    /// Trust on first use: pin the signature public key of an unknown peer,
+    /// and check it against the pinned one for known peers
+    fn trust_on_first_use(&self, peer_id: &str, sig_pubkey: &[u8]) -> Result<bool> {
+        if let Some(pinned_sig_pubkey) = self.pinned_peer(peer_id)? {
+            Ok(verify_slices_are_equal(&pinned_sig_pubkey, sig_pubkey).is_ok())
+        } else {
+            self.pin_peer(peer_id, sig_pubkey)?;
+            Ok(true)
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+/// Session store in process memory, data is lost when the process exits
+pub struct MemorySessionStore {
+    pinned_peers: Mutex<HashMap<String, Vec<u8>>>,
+    prekeys: Mutex<HashMap<u64, Vec<u8>>>,
+    tickets: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemorySessionStore {
+    /// Create empty session store
+    pub fn new() -> Self {
+        MemorySessionStore::default()
+    }
+}
+
+#[inline]
+fn lock<T>(mutex: &Mutex<T>) -> Result<MutexGuard<'_, T>> {
+    // Poisoned by a panic of another thread
+    mutex
+        .lock()
+        .map_err(|_| Error::StoreError(std::io::ErrorKind::Other.into()))
+}
+
+impl SessionStore for MemorySessionStore {
+    fn save_ticket(&self, peer_id: &str, ticket: &[u8]) -> Result<()> {
+        lock(&self.tickets)?.insert(peer_id.to_owned(), ticket.to_vec());
+        Ok(())
+    }
+    fn load_ticket(&self, peer_id: &str) -> Result<Option<Vec<u8>>> {
+        Ok(lock(&self.tickets)?.get(peer_id).cloned())
+    }
+    fn remove_ticket(&self, peer_id: &str) -> Result<()> {
+        lock(&self.tickets)?.remove(peer_id);
+        Ok(())
+    }
+    fn save_prekey(&self, prekey_id: u64, prekey: &[u8]) -> Result<()> {
+        lock(&self.prekeys)?.insert(prekey_id, prekey.to_vec());
+        Ok(())
+    }
+    fn take_prekey(&self, prekey_id: u64) -> Result<Option<Vec<u8>>> {
+        Ok(lock(&self.prekeys)?.remove(&prekey_id))
+    }
+    fn pin_peer(&self, peer_id: &str, sig_pubkey: &[u8]) -> Result<()> {
+        lock(&self.pinned_peers)?.insert(peer_id.to_owned(), sig_pubkey.to_vec());
+        Ok(())
+    }
+    fn pinned_peer(&self, peer_id: &str) -> Result<Option<Vec<u8>>> {
+        Ok(lock(&self.pinned_peers)?.get(peer_id).cloned())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_memory_session_store() -> Result<()> {
+        let store = MemorySessionStore::new();
+
+        // Tickets
+        assert_eq!(None, store.load_ticket("peer")?);
+        store.save_ticket("peer", &[1, 2])?;
+        store.save_ticket("peer", &[3, 4])?;
+        assert_eq!(Some(vec![3, 4]), store.load_ticket("peer")?);
+        store.remove_ticket("peer")?;
+        assert_eq!(None, store.load_ticket("peer")?);
+
+        // Prekeys are used only once
+        store.save_prekey(7, &[5, 6])?;
+        assert_eq!(Some(vec![5, 6]), store.take_prekey(7)?);
+        assert_eq!(None, store.take_prekey(7)?);
+
+        // Pinned peers
+        assert_eq!(None, store.pinned_peer("peer")?);
+        store.pin_peer("peer", &[8])?;
+        assert_eq!(Some(vec![8]), store.pinned_peer("peer")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_trust_on_first_use() -> Result<()> {
+        let store: Box<dyn SessionStore> = Box::new(MemorySessionStore::new());
+
+        assert!(store.trust_on_first_use("peer", &[1; 32])?);
+        assert!(store.trust_on_first_use("peer", &[1; 32])?);
+        assert!(!store.trust_on_first_use("peer", &[2; 32])?);
+        assert_eq!(Some(vec![1; 32]), store.pinned_peer("peer")?);
+        assert!(store.trust_on_first_use("other peer", &[2; 32])?);
+        Ok(())
+    }
+}
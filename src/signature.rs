@@ -15,7 +15,10 @@
 
 //! Define PKSTL Signature.
 
-use ring::signature::UnparsedPublicKey;
+use crate::constants::SIG_ALGO_LEN;
+use crate::errors::IncomingMsgErr;
+use crate::Result;
+use ring::signature::{Ed25519KeyPair, UnparsedPublicKey};
 
 /// Signature algorithm Ed25519
 pub const SIG_ALGO_ED25519: &[u8] = &[0, 0, 0, 0];
@@ -23,8 +26,326 @@ pub const SIG_ALGO_ED25519: &[u8] = &[0, 0, 0, 0];
 /// Signature algorithm Ed25519 array
 pub const SIG_ALGO_ED25519_ARRAY: [u8; 4] = [0, 0, 0, 0];
 
-pub(crate) fn verify_sig(pubkey: &[u8], message: &[u8], sig: &[u8]) -> bool {
-    UnparsedPublicKey::new(&ring::signature::ED25519, pubkey)
-        .verify(message, sig)
-        .is_ok()
+/// Valid Ed25519 public key (RFC 8032 test vector 1), used for dummy verifications
+const ED25519_DUMMY_PUBKEY: [u8; 32] = [
+    0xd7, 0x5a, 0x98, 0x01, 0x82, 0xb1, 0x0a, 0xb7, 0xd5, 0x4b, 0xfe, 0xd3, 0xc9, 0x64, 0x07, 0x3a,
+    0x0e, 0xe1, 0x72, 0xf3, 0xda, 0xa6, 0x23, 0x25, 0xaf, 0x02, 0x1a, 0x68, 0xf7, 0x07, 0x51, 0x1a,
+];
+
+/// Seed of `ED25519_DUMMY_PUBKEY`, for the known-answer test
+const ED25519_KAT_SEED: [u8; 32] = [
+    0x9d, 0x61, 0xb1, 0x9d, 0xef, 0xfd, 0x5a, 0x60, 0xba, 0x84, 0x4a, 0xf4, 0x92, 0xec, 0x2c, 0xc4,
+    0x44, 0x49, 0xc5, 0x69, 0x7b, 0x32, 0x69, 0x19, 0x70, 0x3b, 0xac, 0x03, 0x1c, 0xae, 0x7f, 0x60,
+];
+
+/// Signature of the empty message by `ED25519_DUMMY_PUBKEY`, for the known-answer test
+const ED25519_KAT_SIG: [u8; 64] = [
+    0xe5, 0x56, 0x43, 0x00, 0xc3, 0x60, 0xac, 0x72, 0x90, 0x86, 0xe2, 0xcc, 0x80, 0x6e, 0x82, 0x8a,
+    0x84, 0x87, 0x7f, 0x1e, 0xb8, 0xe5, 0xd9, 0x74, 0xd8, 0x73, 0xe0, 0x65, 0x22, 0x49, 0x01, 0x55,
+    0x5f, 0xb8, 0x82, 0x15, 0x90, 0xa3, 0x3b, 0xac, 0xc6, 0x1e, 0x39, 0x70, 0x1c, 0xf9, 0xb4, 0x6b,
+    0xd2, 0x5b, 0xf5, 0xf0, 0x59, 0x5b, 0xbe, 0x24, 0x65, 0x51, 0x41, 0x43, 0x8e, 0x7a, 0x10, 0x0b,
+];
+
+/// Signature algorithm
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SigAlgo {
+    /// Ed25519 (see https://tools.ietf.org/html/rfc8032)
+    Ed25519,
+}
+
+impl SigAlgo {
+    /// All signature algorithms supported by this implementation
+    pub const SUPPORTED: &'static [SigAlgo] = &[SigAlgo::Ed25519];
+
+    /// Get signature algorithm from its identifier
+    pub fn from_id(id: &[u8]) -> Option<Self> {
+        match id {
+            SIG_ALGO_ED25519 => Some(Self::Ed25519),
+            _ => None,
+        }
+    }
+    /// Signature algorithm identifier (SIG_ALGO field of CONNECT message)
+    pub fn id(self) -> [u8; SIG_ALGO_LEN] {
+        match self {
+            Self::Ed25519 => SIG_ALGO_ED25519_ARRAY,
+        }
+    }
+    /// Public key length in bytes
+    pub fn pubkey_len(self) -> usize {
+        match self {
+            Self::Ed25519 => 32,
+        }
+    }
+    /// Signature length in bytes
+    pub fn sig_len(self) -> usize {
+        match self {
+            Self::Ed25519 => 64,
+        }
+    }
+    #[inline]
+    fn flag(self) -> u32 {
+        match self {
+            Self::Ed25519 => 1,
+        }
+    }
+    pub(crate) fn verify(self, pubkey: &[u8], message: &[u8], sig: &[u8]) -> bool {
+        match self {
+            Self::Ed25519 => UnparsedPublicKey::new(&ring::signature::ED25519, pubkey)
+                .verify(message, sig)
+                .is_ok(),
+        }
+    }
+    /// Known-answer test of the algorithm (RFC 8032 test vector 1): sign and verify,
+    /// and reject a tampered signature. Returns the failed check.
+    pub(crate) fn known_answer_test(self) -> std::result::Result<(), &'static str> {
+        match self {
+            Self::Ed25519 => {
+                let sig = Ed25519KeyPair::from_seed_and_public_key(
+                    &ED25519_KAT_SEED,
+                    &ED25519_DUMMY_PUBKEY,
+                )
+                .map_err(|_| "invalid key pair")?
+                .sign(&[]);
+                if sig.as_ref() != &ED25519_KAT_SIG[..] {
+                    return Err("unexpected signature");
+                }
+                if !self.verify(&ED25519_DUMMY_PUBKEY, &[], &ED25519_KAT_SIG) {
+                    return Err("valid signature rejected");
+                }
+                let mut tampered_sig = ED25519_KAT_SIG;
+                tampered_sig[0] ^= 1;
+                if self.verify(&ED25519_DUMMY_PUBKEY, &[], &tampered_sig) {
+                    return Err("tampered signature accepted");
+                }
+                Ok(())
+            }
+        }
+    }
+    /// Spend the time of a full signature verification of `message`, whose result is irrelevant
+    pub(crate) fn dummy_verify(self, message: &[u8]) {
+        match self {
+            Self::Ed25519 => {
+                self.verify(&ED25519_DUMMY_PUBKEY, message, &[0u8; 64]);
+            }
+        }
+    }
+}
+
+/// Set of signature algorithms
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SigAlgos(u32);
+
+impl Default for SigAlgos {
+    fn default() -> Self {
+        Self::supported()
+    }
+}
+
+impl SigAlgos {
+    /// Empty set
+    pub fn empty() -> Self {
+        SigAlgos(0)
+    }
+    /// All signature algorithms supported by this implementation
+    pub fn supported() -> Self {
+        SigAlgo::SUPPORTED
+            .iter()
+            .fold(Self::empty(), |algos, algo| algos.with(*algo))
+    }
+    /// Add a signature algorithm to the set
+    pub fn with(self, sig_algo: SigAlgo) -> Self {
+        SigAlgos(self.0 | sig_algo.flag())
+    }
+    /// Remove a signature algorithm from the set
+    pub fn without(self, sig_algo: SigAlgo) -> Self {
+        SigAlgos(self.0 & !sig_algo.flag())
+    }
+    /// Check if the set contains a signature algorithm
+    pub fn contains(self, sig_algo: SigAlgo) -> bool {
+        self.0 & sig_algo.flag() != 0
+    }
+    /// Select the first algorithm of `preferences` that is in the set
+    pub fn select(self, preferences: &[SigAlgo]) -> Option<SigAlgo> {
+        preferences
+            .iter()
+            .find(|algo| self.contains(**algo))
+            .copied()
+    }
+}
+
+/// Handshake messages signed by a peer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SigRequirement {
+    /// CONNECT and ACK messages
+    ConnectAndAck,
+    /// ACK message only, whose challenge then also covers the ephemeral public key of the signer
+    AckOnly,
+    /// None, the peer is anonymous
+    Anonymous,
+}
+
+/// Policy deciding the validity of the co-signatures of peer CONNECT message
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConnectSigPolicy {
+    /// Public keys of authorized co-signers (same algorithm as the peer signature)
+    pub co_signers: Vec<Vec<u8>>,
+    /// Minimum number of distinct co-signers whose signature must be valid
+    pub threshold: usize,
+}
+
+impl ConnectSigPolicy {
+    /// Verify co-signatures (concatenated after the peer signature)
+    pub(crate) fn verify(&self, sig_algo: SigAlgo, message: &[u8], co_sigs: &[u8]) -> Result<()> {
+        let co_sigs = co_sigs.chunks_exact(sig_algo.sig_len());
+        if !co_sigs.remainder().is_empty() {
+            return Err(IncomingMsgErr::InvalidHashOrSig.into());
+        }
+        if co_sigs.len() > self.co_signers.len() {
+            return Err(IncomingMsgErr::TooManyCoSignatures.into());
+        }
+
+        let valid_co_signers_count = self
+            .co_signers
+            .iter()
+            .filter(|co_signer| {
+                co_sigs
+                    .clone()
+                    .any(|co_sig| sig_algo.verify(&co_signer[..], message, co_sig))
+            })
+            .count();
+
+        if valid_co_signers_count < self.threshold {
+            Err(IncomingMsgErr::NotEnoughCoSignatures.into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Signature to verify in a batch
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SigToVerify<'a> {
+    /// Signature algorithm
+    pub sig_algo: SigAlgo,
+    /// Signer public key
+    pub pubkey: &'a [u8],
+    /// Signed message
+    pub message: &'a [u8],
+    /// Signature
+    pub sig: &'a [u8],
+}
+
+/// Verify a batch of signatures (for example the CONNECT messages of pending handshakes),
+/// returns the validity of each signature in the same order.
+/// The signature backend does not provide batch verification, so each distinct signature is
+/// verified once: duplicates in the batch (retransmissions) cost nothing more.
+pub fn verify_batch(sigs: &[SigToVerify]) -> Vec<bool> {
+    let mut results: Vec<bool> = Vec::with_capacity(sigs.len());
+    for (i, sig_to_verify) in sigs.iter().enumerate() {
+        let valid = match sigs[..i].iter().position(|sig| sig == sig_to_verify) {
+            Some(first_index) => results[first_index],
+            None => sig_to_verify.sig_algo.verify(
+                sig_to_verify.pubkey,
+                sig_to_verify.message,
+                sig_to_verify.sig,
+            ),
+        };
+        results.push(valid);
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{Error, Seed32};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    #[test]
+    fn test_sig_algo_ids() {
+        for sig_algo in SigAlgo::SUPPORTED {
+            assert_eq!(Some(*sig_algo), SigAlgo::from_id(&sig_algo.id()));
+        }
+        assert_eq!(None, SigAlgo::from_id(&[0, 0, 0, 1]));
+    }
+
+    #[test]
+    fn test_sig_algos_set() {
+        let algos = SigAlgos::default();
+        assert!(algos.contains(SigAlgo::Ed25519));
+        assert_eq!(Some(SigAlgo::Ed25519), algos.select(SigAlgo::SUPPORTED));
+
+        let algos = algos.without(SigAlgo::Ed25519);
+        assert_eq!(SigAlgos::empty(), algos);
+        assert_eq!(None, algos.select(SigAlgo::SUPPORTED));
+    }
+
+    #[test]
+    fn test_connect_sig_policy() -> Result<()> {
+        let key_pairs = (0..3)
+            .map(|_| {
+                Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+                    .map_err(|_| Error::FailtoGenSigKeyPair)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let policy = ConnectSigPolicy {
+            co_signers: key_pairs
+                .iter()
+                .map(|kp| kp.public_key().as_ref().to_vec())
+                .collect(),
+            threshold: 2,
+        };
+        let message = b"connect";
+
+        // 2-of-3 co-signatures (in any order)
+        let mut co_sigs = key_pairs[2].sign(message).as_ref().to_vec();
+        co_sigs.extend_from_slice(key_pairs[0].sign(message).as_ref());
+        policy.verify(SigAlgo::Ed25519, message, &co_sigs)?;
+
+        // The same co-signer twice does not count twice
+        let mut co_sigs = key_pairs[1].sign(message).as_ref().to_vec();
+        co_sigs.extend_from_slice(key_pairs[1].sign(message).as_ref());
+        if let Err(Error::RecvInvalidMsg(IncomingMsgErr::NotEnoughCoSignatures)) =
+            policy.verify(SigAlgo::Ed25519, message, &co_sigs)
+        {
+        } else {
+            panic!("unexpected result")
+        }
+
+        // Truncated co-signature
+        if let Err(Error::RecvInvalidMsg(IncomingMsgErr::InvalidHashOrSig)) =
+            policy.verify(SigAlgo::Ed25519, message, &co_sigs[..100])
+        {
+            Ok(())
+        } else {
+            panic!("unexpected result")
+        }
+    }
+
+    #[test]
+    fn test_verify_batch() -> Result<()> {
+        let key_pair = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+            .map_err(|_| Error::FailtoGenSigKeyPair)?;
+        let sig1 = key_pair.sign(b"connect1");
+        let sig2 = key_pair.sign(b"connect2");
+        let batch = [
+            (&b"connect1"[..], sig1.as_ref()),
+            (&b"connect1"[..], sig2.as_ref()),
+            (&b"connect2"[..], sig2.as_ref()),
+            (&b"connect1"[..], sig1.as_ref()),
+        ]
+        .iter()
+        .map(|(message, sig)| SigToVerify {
+            sig_algo: SigAlgo::Ed25519,
+            pubkey: key_pair.public_key().as_ref(),
+            message,
+            sig,
+        })
+        .collect::<Vec<_>>();
+
+        assert_eq!(vec![true, false, true, true], verify_batch(&batch));
+        assert_eq!(Vec::<bool>::new(), verify_batch(&[]));
+        Ok(())
+    }
 }
@@ -0,0 +1,212 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage connections through a SOCKS5 proxy (e.g. Tor), see https://tools.ietf.org/html/rfc1928.
+
+use crate::complete::SecureLayer;
+use crate::transport::{StreamTransport, TcpTransportDriver, TransportDriver};
+use crate::{Error, Result};
+use std::convert::TryFrom;
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTH_METHOD: u8 = 0;
+const CONNECT_CMD: u8 = 1;
+const RESERVED: u8 = 0;
+const ATYP_IPV4: u8 = 1;
+const ATYP_DOMAIN_NAME: u8 = 3;
+const ATYP_IPV6: u8 = 4;
+const SUCCEEDED: u8 = 0;
+
+/// Open a TCP stream to `host:port` through the SOCKS5 proxy at `proxy_addr`.
+/// The host name is resolved by the proxy, so `.onion` addresses can be reached through Tor.
+pub fn connect_socks5<A: ToSocketAddrs>(
+    proxy_addr: A,
+    host: &str,
+    port: u16,
+) -> std::io::Result<TcpStream> {
+    let host_len = u8::try_from(host.len())
+        .map_err(|_| IoError::new(ErrorKind::InvalidInput, "host name too long"))?;
+    let mut stream = TcpStream::connect(proxy_addr)?;
+
+    // Method selection
+    stream.write_all(&[SOCKS_VERSION, 1, NO_AUTH_METHOD])?;
+    let mut method_reply = [0u8; 2];
+    stream.read_exact(&mut method_reply)?;
+    if method_reply != [SOCKS_VERSION, NO_AUTH_METHOD] {
+        return Err(IoError::new(
+            ErrorKind::PermissionDenied,
+            "SOCKS5 proxy requires an unsupported authentication method",
+        ));
+    }
+
+    // Connect request
+    let mut request = Vec::with_capacity(7 + host.len());
+    request.extend_from_slice(&[
+        SOCKS_VERSION,
+        CONNECT_CMD,
+        RESERVED,
+        ATYP_DOMAIN_NAME,
+        host_len,
+    ]);
+    request.extend_from_slice(host.as_bytes());
+    request.extend_from_slice(&port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    // Connect reply
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[0] != SOCKS_VERSION {
+        return Err(IoError::new(ErrorKind::InvalidData, "invalid SOCKS5 reply"));
+    }
+    if reply[1] != SUCCEEDED {
+        return Err(IoError::new(
+            ErrorKind::ConnectionRefused,
+            format!("SOCKS5 proxy fail to connect (reply code {})", reply[1]),
+        ));
+    }
+    // Skip bound address and port
+    let bound_addr_len = match reply[3] {
+        ATYP_IPV4 => 4,
+        ATYP_IPV6 => 16,
+        ATYP_DOMAIN_NAME => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len)?;
+            usize::from(len[0])
+        }
+        _ => return Err(IoError::new(ErrorKind::InvalidData, "invalid SOCKS5 reply")),
+    };
+    let mut bound_addr_and_port = vec![0u8; bound_addr_len + 2];
+    stream.read_exact(&mut bound_addr_and_port)?;
+
+    Ok(stream)
+}
+
+/// Connect to `host:port` through the SOCKS5 proxy at `proxy_addr`, then run the negotiation.
+/// Return the driver of the established session and the custom data of the peer CONNECT message.
+pub fn handshake_over_socks5<A: ToSocketAddrs>(
+    secure_layer: SecureLayer,
+    proxy_addr: A,
+    host: &str,
+    port: u16,
+    max_frame_len: usize,
+    connect_custom_data: Option<&[u8]>,
+) -> Result<(TcpTransportDriver, Option<Vec<u8>>)> {
+    let stream = connect_socks5(proxy_addr, host, port).map_err(Error::TransportError)?;
+    let mut driver =
+        TransportDriver::new(secure_layer, StreamTransport::new(stream, max_frame_len));
+    let peer_connect_custom_data = driver.handshake(connect_custom_data, None)?;
+    Ok((driver, peer_connect_custom_data))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{SecureLayerConfig, SecureLayerStatus};
+    use std::net::TcpListener;
+
+    /// Fake SOCKS5 proxy: check the connect request and reply with `reply_code`
+    fn accept_socks5(
+        listener: &TcpListener,
+        expected_host: &str,
+        expected_port: u16,
+        reply_code: u8,
+    ) -> std::io::Result<TcpStream> {
+        let (mut stream, _) = listener.accept()?;
+        let mut greeting = [0u8; 3];
+        stream.read_exact(&mut greeting)?;
+        assert_eq!([SOCKS_VERSION, 1, NO_AUTH_METHOD], greeting);
+        stream.write_all(&[SOCKS_VERSION, NO_AUTH_METHOD])?;
+
+        let mut request = vec![0u8; 7 + expected_host.len()];
+        stream.read_exact(&mut request)?;
+        assert_eq!(
+            [SOCKS_VERSION, CONNECT_CMD, RESERVED, ATYP_DOMAIN_NAME],
+            request[..4]
+        );
+        assert_eq!(
+            expected_host.as_bytes(),
+            &request[5..5 + expected_host.len()]
+        );
+        assert_eq!(
+            expected_port.to_be_bytes(),
+            request[5 + expected_host.len()..]
+        );
+        stream.write_all(&[
+            SOCKS_VERSION,
+            reply_code,
+            RESERVED,
+            ATYP_IPV4,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ])?;
+        Ok(stream)
+    }
+
+    #[test]
+    fn test_handshake_over_socks5() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").map_err(Error::TransportError)?;
+        let proxy_addr = listener.local_addr().map_err(Error::TransportError)?;
+
+        // The fake proxy is also the peer
+        let server_thread = std::thread::spawn(move || -> Result<Option<Vec<u8>>> {
+            let stream = accept_socks5(&listener, "peer.onion", 10901, SUCCEEDED)
+                .map_err(Error::TransportError)?;
+            let server_sl = SecureLayer::create(SecureLayerConfig::default(), None, None)?;
+            let mut server = TransportDriver::new(server_sl, StreamTransport::new(stream, 1_024));
+            server.handshake(None, None)?;
+            server.recv()
+        });
+
+        let client_sl = SecureLayer::create(SecureLayerConfig::default(), None, None)?;
+        let (mut client, _) =
+            handshake_over_socks5(client_sl, proxy_addr, "peer.onion", 10901, 1_024, None)?;
+        assert_eq!(
+            SecureLayerStatus::Established,
+            client.secure_layer().status()
+        );
+        client.send(&[1, 2, 3])?;
+
+        assert_eq!(
+            Some(vec![1, 2, 3]),
+            server_thread.join().expect("server thread panicked")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_socks5_connect_refused() -> std::io::Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let proxy_addr = listener.local_addr()?;
+
+        let proxy_thread =
+            std::thread::spawn(move || accept_socks5(&listener, "peer.onion", 10901, 5));
+
+        let result = connect_socks5(proxy_addr, "peer.onion", 10901);
+        proxy_thread.join().expect("proxy thread panicked")?;
+        if let Err(e) = result {
+            assert_eq!(ErrorKind::ConnectionRefused, e.kind());
+        } else {
+            panic!("unexpected result");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,290 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage session statistics.
+
+use crate::status::SecureLayerStatus;
+use std::fmt::{Debug, Formatter};
+use std::time::Duration;
+
+/// Number of buckets of a size histogram
+pub const SIZE_HISTOGRAM_BUCKETS: usize = 16;
+
+/// Upper bound of the first bucket of a size histogram, doubled for each next bucket
+const SIZE_HISTOGRAM_FIRST_BOUND: usize = 64;
+
+/// Histogram of message sizes, with power-of-two buckets
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SizeHistogram {
+    counts: [u64; SIZE_HISTOGRAM_BUCKETS],
+}
+
+impl SizeHistogram {
+    /// Upper bound (included) of the sizes counted in bucket `index`, none for the last bucket
+    pub fn upper_bound(index: usize) -> Option<usize> {
+        if index + 1 < SIZE_HISTOGRAM_BUCKETS {
+            Some(SIZE_HISTOGRAM_FIRST_BOUND << index)
+        } else {
+            None
+        }
+    }
+    /// Number of messages of each bucket
+    pub fn counts(&self) -> &[u64; SIZE_HISTOGRAM_BUCKETS] {
+        &self.counts
+    }
+    pub(crate) fn record(&mut self, size: usize) {
+        let index = (0..SIZE_HISTOGRAM_BUCKETS - 1)
+            .find(|index| size <= SIZE_HISTOGRAM_FIRST_BOUND << index)
+            .unwrap_or(SIZE_HISTOGRAM_BUCKETS - 1);
+        self.counts[index] += 1;
+    }
+}
+
+/// User traffic counters
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub(crate) struct TrafficCounters {
+    pub(crate) user_msgs_sent: u64,
+    pub(crate) user_bytes_sent: u64,
+    pub(crate) user_msgs_received: u64,
+    pub(crate) user_bytes_received: u64,
+    pub(crate) received_sizes: SizeHistogram,
+}
+
+/// Warning given to the slow consumer hook
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SlowConsumerWarning {
+    /// Bytes buffered by the session
+    pub buffered_bytes: usize,
+    /// Time since the previous read (none for the first read)
+    pub read_interval: Option<Duration>,
+}
+
+type SlowConsumerHookFn = dyn FnMut(SlowConsumerWarning) + Send;
+
+/// Hook called when the buffered bytes exceed a threshold
+pub(crate) struct SlowConsumerHook {
+    pub(crate) threshold: usize,
+    /// The buffered bytes exceed the threshold, the hook is not called again until they fall below
+    pub(crate) lagging: bool,
+    pub(crate) hook: Box<SlowConsumerHookFn>,
+}
+
+impl Debug for SlowConsumerHook {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "SlowConsumerHook")
+    }
+}
+
+/// Session statistics
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SessionStats {
+    /// Session status
+    pub status: SecureLayerStatus,
+    /// Number of user messages sent
+    pub user_msgs_sent: u64,
+    /// Bytes of user messages sent (after compression)
+    pub user_bytes_sent: u64,
+    /// Number of user messages received
+    pub user_msgs_received: u64,
+    /// Bytes of user messages received (before decompression)
+    pub user_bytes_received: u64,
+    /// Number of expired messages dropped
+    pub expired_msgs: u64,
+    /// Bytes buffered by the session
+    pub buffered_bytes: usize,
+    /// Number of nonces received ahead of the expected one
+    pub pending_orphans: usize,
+    /// Sizes of user messages received (before decompression)
+    pub received_sizes: SizeHistogram,
+    /// Longest time between two reads
+    pub max_read_interval: Option<Duration>,
+}
+
+#[cfg(feature = "prometheus")]
+/// Format statistics of sessions in Prometheus text exposition format.
+///
+/// Each session is labelled with `session="<label>"`, labels should be unique.
+pub fn format_prometheus(sessions: &[(&str, SessionStats)]) -> String {
+    type Metric = (
+        &'static str,
+        &'static str,
+        &'static str,
+        fn(&SessionStats) -> u64,
+    );
+    let metrics: [Metric; 9] = [
+        (
+            "pkstl_user_messages_sent_total",
+            "counter",
+            "Number of user messages sent",
+            |stats| stats.user_msgs_sent,
+        ),
+        (
+            "pkstl_user_bytes_sent_total",
+            "counter",
+            "Bytes of user messages sent",
+            |stats| stats.user_bytes_sent,
+        ),
+        (
+            "pkstl_user_messages_received_total",
+            "counter",
+            "Number of user messages received",
+            |stats| stats.user_msgs_received,
+        ),
+        (
+            "pkstl_user_bytes_received_total",
+            "counter",
+            "Bytes of user messages received",
+            |stats| stats.user_bytes_received,
+        ),
+        (
+            "pkstl_expired_messages_total",
+            "counter",
+            "Number of expired messages dropped",
+            |stats| stats.expired_msgs,
+        ),
+        (
+            "pkstl_buffered_bytes",
+            "gauge",
+            "Bytes buffered by the session",
+            |stats| stats.buffered_bytes as u64,
+        ),
+        (
+            "pkstl_pending_orphans",
+            "gauge",
+            "Number of nonces received ahead of the expected one",
+            |stats| stats.pending_orphans as u64,
+        ),
+        (
+            "pkstl_session_established",
+            "gauge",
+            "Whether the session is established",
+            |stats| u64::from(stats.status == SecureLayerStatus::Established),
+        ),
+        (
+            "pkstl_session_failed",
+            "gauge",
+            "Whether the session has failed",
+            |stats| {
+                if let SecureLayerStatus::Failed { .. } = stats.status {
+                    1
+                } else {
+                    0
+                }
+            },
+        ),
+    ];
+
+    let mut text = String::new();
+    for (name, metric_type, help, value) in metrics.iter() {
+        text.push_str(&format!("# HELP {} {}\n", name, help));
+        text.push_str(&format!("# TYPE {} {}\n", name, metric_type));
+        for (label, stats) in sessions {
+            text.push_str(&format!(
+                "{}{{session=\"{}\"}} {}\n",
+                name,
+                escape_label_value(label),
+                value(stats)
+            ));
+        }
+    }
+
+    // Sizes of user messages received
+    let name = "pkstl_user_message_bytes_received";
+    text.push_str(&format!(
+        "# HELP {} Sizes of user messages received\n# TYPE {} histogram\n",
+        name, name
+    ));
+    for (label, stats) in sessions {
+        let label = escape_label_value(label);
+        let mut cumulative_count = 0;
+        for (index, count) in stats.received_sizes.counts().iter().enumerate() {
+            cumulative_count += count;
+            let le = SizeHistogram::upper_bound(index)
+                .map_or_else(|| "+Inf".to_owned(), |bound| bound.to_string());
+            text.push_str(&format!(
+                "{}_bucket{{session=\"{}\",le=\"{}\"}} {}\n",
+                name, label, le, cumulative_count
+            ));
+        }
+        text.push_str(&format!(
+            "{}_sum{{session=\"{}\"}} {}\n",
+            name, label, stats.user_bytes_received
+        ));
+        text.push_str(&format!(
+            "{}_count{{session=\"{}\"}} {}\n",
+            name, label, stats.user_msgs_received
+        ));
+    }
+    text
+}
+
+#[cfg(feature = "prometheus")]
+fn escape_label_value(label: &str) -> String {
+    let mut escaped = String::with_capacity(label.len());
+    for c in label.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(all(test, feature = "prometheus"))]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_format_prometheus() {
+        let mut received_sizes = SizeHistogram::default();
+        received_sizes.record(30);
+        received_sizes.record(50);
+        let stats = SessionStats {
+            status: SecureLayerStatus::Established,
+            user_msgs_sent: 3,
+            user_bytes_sent: 120,
+            user_msgs_received: 2,
+            user_bytes_received: 80,
+            expired_msgs: 1,
+            buffered_bytes: 42,
+            pending_orphans: 0,
+            received_sizes,
+            max_read_interval: None,
+        };
+        let text = format_prometheus(&[("peer \"a\"", stats), ("peer b", stats)]);
+
+        assert!(text.contains(
+            "# HELP pkstl_user_messages_sent_total Number of user messages sent\n\
+             # TYPE pkstl_user_messages_sent_total counter\n\
+             pkstl_user_messages_sent_total{session=\"peer \\\"a\\\"\"} 3\n\
+             pkstl_user_messages_sent_total{session=\"peer b\"} 3\n"
+        ));
+        assert!(text.contains("pkstl_buffered_bytes{session=\"peer b\"} 42\n"));
+        assert!(text.contains("pkstl_session_established{session=\"peer b\"} 1\n"));
+        assert!(text.contains("pkstl_session_failed{session=\"peer b\"} 0\n"));
+        assert!(text.contains(
+            "pkstl_user_message_bytes_received_bucket{session=\"peer b\",le=\"64\"} 2\n"
+        ));
+        assert!(text.contains(
+            "pkstl_user_message_bytes_received_bucket{session=\"peer b\",le=\"+Inf\"} 2\n"
+        ));
+        assert!(text.contains("pkstl_user_message_bytes_received_sum{session=\"peer b\"} 80\n"));
+        assert!(text.contains("pkstl_user_message_bytes_received_count{session=\"peer b\"} 2\n"));
+        assert_eq!(9 * 4 + 2 + 2 * 18, text.lines().count());
+    }
+}
@@ -17,12 +17,86 @@
 
 use crate::errors::IncomingMsgErr;
 use crate::{Action, ActionSideEffects, Error, LocalNegoThread, MsgType, RemoteNegoThread, Result};
+use std::fmt::{Debug, Formatter};
 
 /// Secure layer status
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SecureLayerStatus {
+    /// Waiting for the peer CONNECT message
+    AwaitingConnect,
+    /// Peer CONNECT message received, waiting for the ACK messages exchange to finish
+    AwaitingAck,
+    /// Negotiation successful, user messages can be exchanged
+    Established,
+    /// Closed locally, in-flight user messages can still be read
+    Closing,
+    /// An error has occurred or one peer message is wrong
+    Failed {
+        /// Failure reason
+        reason: FailReason,
+    },
+}
+
+/// Failure reason of a secure layer
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum FailReason {
+    /// Fail to decrypt incoming data
+    FailToDecryptData,
+    /// Receive invalid message
+    InvalidIncomingMsg(IncomingMsgErr),
+    /// Try to write a user message before the negotiation is successful
+    NegoMustHaveBeenSuccessful,
+    /// Received too many unordered messages; possibly due to an attack
+    TooManyUnorderedMsgs,
+    /// Unexpected remote signature public key
+    UnexpectedRemoteSigPubKey,
+    /// The peer does not support our protocol version
+    VersionRejected {
+        /// Minimal version supported by the peer
+        min_version: u32,
+        /// Maximal version supported by the peer
+        max_version: u32,
+    },
+    /// Fail to write or buffer a message
+    WriteError,
+}
+
+impl From<&Error> for FailReason {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::FailToDecryptData(_) => Self::FailToDecryptData,
+            Error::RecvInvalidMsg(e) => Self::InvalidIncomingMsg(*e),
+            Error::NegoMustHaveBeenSuccessful => Self::NegoMustHaveBeenSuccessful,
+            Error::TooManyUnorderedMsgs => Self::TooManyUnorderedMsgs,
+            Error::UnexpectedRemoteSigPubKey => Self::UnexpectedRemoteSigPubKey,
+            Error::VersionRejected {
+                min_version,
+                max_version,
+            } => Self::VersionRejected {
+                min_version: *min_version,
+                max_version: *max_version,
+            },
+            _ => Self::WriteError,
+        }
+    }
+}
+
+/// Hook called on each status change, with the old and the new status
+pub(crate) struct StateChangeHook(
+    pub(crate) Box<dyn FnMut(SecureLayerStatus, SecureLayerStatus) + Send>,
+);
+
+impl Debug for StateChangeHook {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "StateChangeHook")
+    }
+}
+
+/// Status machine
 #[derive(Clone, Copy, Debug, PartialEq)]
-pub(crate) enum SecureLayerStatus {
+pub(crate) enum StatusMachine {
     /// An error has occurred or one peer message is wrong
-    Fail,
+    Fail(FailReason),
     /// Negotiation in progress
     OngoingNegotiation {
         local: LocalNegoThread,
@@ -30,18 +104,20 @@ pub(crate) enum SecureLayerStatus {
     },
     /// Equivalent to "AckMsgWrittenAndPeerAckMsgOk"
     NegotiationSuccessful,
+    /// Closed locally
+    Closing,
 }
 
-impl SecureLayerStatus {
+impl StatusMachine {
     pub(crate) fn init() -> Self {
-        SecureLayerStatus::OngoingNegotiation {
+        StatusMachine::OngoingNegotiation {
             local: LocalNegoThread::Created,
             remote: RemoteNegoThread::WaitConnectMsg,
         }
     }
     pub(crate) fn apply_action(&mut self, action: Action) -> Result<Option<ActionSideEffects>> {
         match self {
-            Self::Fail => Err(Error::ConnectionHadFail),
+            Self::Fail(_) => Err(Error::ConnectionHadFail),
             Self::OngoingNegotiation { local, remote } => match action {
                 Action::Create(msg_type) => match msg_type {
                     MsgType::Connect => {
@@ -64,10 +140,7 @@ impl SecureLayerStatus {
                             Err(Error::ForbidWriteAckMsgNow)
                         }
                     }
-                    MsgType::UserMsg => {
-                        *self = Self::Fail;
-                        Err(Error::NegoMustHaveBeenSuccessful)
-                    }
+                    MsgType::UserMsg => self.fail(Error::NegoMustHaveBeenSuccessful),
                 },
                 Action::Receive(msg_type) => match msg_type {
                     MsgType::Connect => {
@@ -75,8 +148,7 @@ impl SecureLayerStatus {
                             *remote = RemoteNegoThread::ValidConnectMsgReceived;
                             Ok(None)
                         } else {
-                            *self = Self::Fail;
-                            Err(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedConnectMsg))
+                            self.fail(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedConnectMsg))
                         }
                     }
                     MsgType::Ack => {
@@ -88,8 +160,7 @@ impl SecureLayerStatus {
                             }
                             Ok(None)
                         } else {
-                            *self = Self::Fail;
-                            Err(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedAckMsg))
+                            self.fail(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedAckMsg))
                         }
                     }
                     MsgType::UserMsg => {
@@ -98,8 +169,7 @@ impl SecureLayerStatus {
                         {
                             Ok(Some(ActionSideEffects::PushUserMsgIntoTmpStack))
                         } else {
-                            *self = Self::Fail;
-                            Err(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedMessage))
+                            self.fail(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedMessage))
                         }
                     }
                 },
@@ -112,16 +182,43 @@ impl SecureLayerStatus {
                 },
                 Action::Receive(msg_type) => match msg_type {
                     MsgType::Connect => {
-                        *self = Self::Fail;
-                        Err(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedConnectMsg))
+                        self.fail(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedConnectMsg))
                     }
                     MsgType::Ack => {
-                        *self = Self::Fail;
-                        Err(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedAckMsg))
+                        self.fail(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedAckMsg))
                     }
                     MsgType::UserMsg => Ok(None),
                 },
             },
+            Self::Closing => match action {
+                Action::Create(_) => Err(Error::ForbidWriteAfterClose),
+                Action::Receive(msg_type) => match msg_type {
+                    MsgType::Connect => {
+                        self.fail(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedConnectMsg))
+                    }
+                    MsgType::Ack => {
+                        self.fail(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedAckMsg))
+                    }
+                    MsgType::UserMsg => Ok(None),
+                },
+            },
+        }
+    }
+    #[inline]
+    fn fail(&mut self, error: Error) -> Result<Option<ActionSideEffects>> {
+        *self = Self::Fail(FailReason::from(&error));
+        Err(error)
+    }
+    pub(crate) fn to_public(self) -> SecureLayerStatus {
+        match self {
+            Self::Fail(reason) => SecureLayerStatus::Failed { reason },
+            Self::OngoingNegotiation {
+                remote: RemoteNegoThread::WaitConnectMsg,
+                ..
+            } => SecureLayerStatus::AwaitingConnect,
+            Self::OngoingNegotiation { .. } => SecureLayerStatus::AwaitingAck,
+            Self::NegotiationSuccessful => SecureLayerStatus::Established,
+            Self::Closing => SecureLayerStatus::Closing,
         }
     }
 }
@@ -0,0 +1,210 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage test utilities: simulated network link with impairments.
+
+use std::time::{Duration, SystemTime};
+
+/// Impairments injected by a simulated link.
+/// Rates are probabilities between 0.0 and 1.0 applied to each frame.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinkImpairments {
+    /// Fixed delivery latency
+    pub latency: Duration,
+    /// Maximum random delay added to latency
+    pub jitter: Duration,
+    /// Probability to drop a frame
+    pub drop_rate: f64,
+    /// Probability to deliver a frame twice
+    pub duplicate_rate: f64,
+    /// Probability to flip one bit of a frame
+    pub corrupt_rate: f64,
+    /// Probability to hold a frame back so that it's delivered after the next one
+    pub reorder_rate: f64,
+}
+
+impl Default for LinkImpairments {
+    fn default() -> Self {
+        LinkImpairments {
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            drop_rate: 0.0,
+            duplicate_rate: 0.0,
+            corrupt_rate: 0.0,
+            reorder_rate: 0.0,
+        }
+    }
+}
+
+/// Simulated unidirectional link, deterministic for a given seed
+#[derive(Clone, Debug)]
+pub struct SimulatedLink {
+    impairments: LinkImpairments,
+    in_flight: Vec<(SystemTime, u64, Vec<u8>)>,
+    held_back: Option<Vec<u8>>,
+    rng_state: u64,
+    sequence: u64,
+}
+
+impl SimulatedLink {
+    /// Create simulated link
+    pub fn new(impairments: LinkImpairments, seed: u64) -> Self {
+        SimulatedLink {
+            impairments,
+            in_flight: Vec::new(),
+            held_back: None,
+            // xorshift state must not be zero
+            rng_state: seed | 1,
+            sequence: 0,
+        }
+    }
+    /// Send a frame at `now`
+    pub fn send(&mut self, frame: &[u8], now: SystemTime) {
+        if self.random_event(self.impairments.drop_rate) {
+            return;
+        }
+
+        let mut frame = frame.to_vec();
+        if !frame.is_empty() && self.random_event(self.impairments.corrupt_rate) {
+            let bit = self.next_u64() % (frame.len() as u64 * 8);
+            frame[(bit / 8) as usize] ^= 1 << (bit % 8);
+        }
+
+        if self.random_event(self.impairments.reorder_rate) {
+            if let Some(previous_held_back) = self.held_back.replace(frame) {
+                self.schedule(previous_held_back, now);
+            }
+            return;
+        }
+
+        if self.random_event(self.impairments.duplicate_rate) {
+            self.schedule(frame.clone(), now);
+        }
+        self.schedule(frame, now);
+        if let Some(held_back) = self.held_back.take() {
+            self.schedule(held_back, now);
+        }
+    }
+    /// Release the held back frame, if any
+    pub fn flush(&mut self, now: SystemTime) {
+        if let Some(held_back) = self.held_back.take() {
+            self.schedule(held_back, now);
+        }
+    }
+    /// Receive frames delivered at or before `now`, in delivery order
+    pub fn recv(&mut self, now: SystemTime) -> Vec<Vec<u8>> {
+        self.in_flight
+            .sort_by(|(time1, seq1, _), (time2, seq2, _)| (time1, seq1).cmp(&(time2, seq2)));
+        let delivered_count = self
+            .in_flight
+            .iter()
+            .take_while(|(delivery_time, _, _)| *delivery_time <= now)
+            .count();
+        self.in_flight
+            .drain(..delivered_count)
+            .map(|(_, _, frame)| frame)
+            .collect()
+    }
+    /// Number of frames not yet delivered (including held back frame)
+    pub fn pending_frames(&self) -> usize {
+        self.in_flight.len() + self.held_back.iter().count()
+    }
+    fn schedule(&mut self, frame: Vec<u8>, now: SystemTime) {
+        let jitter_nanos = self.impairments.jitter.as_nanos() as u64;
+        let jitter = if jitter_nanos > 0 {
+            Duration::from_nanos(self.next_u64() % (jitter_nanos + 1))
+        } else {
+            Duration::from_nanos(0)
+        };
+        let delivery_time = now + self.impairments.latency + jitter;
+        self.sequence += 1;
+        self.in_flight.push((delivery_time, self.sequence, frame));
+    }
+    #[inline]
+    fn random_event(&mut self, probability: f64) -> bool {
+        probability > 0.0 && ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < probability
+    }
+    #[inline]
+    fn next_u64(&mut self) -> u64 {
+        // xorshift64
+        self.rng_state ^= self.rng_state << 13;
+        self.rng_state ^= self.rng_state >> 7;
+        self.rng_state ^= self.rng_state << 17;
+        self.rng_state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn transmit(impairments: LinkImpairments, seed: u64) -> Vec<Vec<u8>> {
+        let t0 = SystemTime::now();
+        let mut link = SimulatedLink::new(impairments, seed);
+        for i in 0..100u8 {
+            link.send(&[i; 4], t0 + Duration::from_millis(u64::from(i)));
+        }
+        link.flush(t0 + Duration::from_millis(100));
+        link.recv(t0 + Duration::from_secs(60))
+    }
+
+    #[test]
+    fn test_perfect_link() {
+        let t0 = SystemTime::now();
+        let mut link = SimulatedLink::new(
+            LinkImpairments {
+                latency: Duration::from_millis(10),
+                ..LinkImpairments::default()
+            },
+            42,
+        );
+        link.send(&[1, 2, 3], t0);
+        link.send(&[4, 5, 6], t0);
+        assert_eq!(Vec::<Vec<u8>>::new(), link.recv(t0));
+        assert_eq!(2, link.pending_frames());
+        assert_eq!(
+            vec![vec![1, 2, 3], vec![4, 5, 6]],
+            link.recv(t0 + Duration::from_millis(10))
+        );
+        assert_eq!(0, link.pending_frames());
+    }
+
+    #[test]
+    fn test_impaired_link() {
+        let impairments = LinkImpairments {
+            latency: Duration::from_millis(10),
+            jitter: Duration::from_millis(50),
+            drop_rate: 0.1,
+            duplicate_rate: 0.1,
+            corrupt_rate: 0.1,
+            reorder_rate: 0.1,
+        };
+
+        // Same seed, same impairments
+        let delivered = transmit(impairments, 7);
+        assert_eq!(delivered, transmit(impairments, 7));
+        assert_ne!(delivered, transmit(impairments, 8));
+
+        // Some frames must have been dropped, duplicated, corrupted and reordered
+        let sent = (0..100u8).map(|i| vec![i; 4]).collect::<Vec<_>>();
+        assert!(sent.iter().any(|frame| !delivered.contains(frame)));
+        assert!(sent
+            .iter()
+            .any(|frame| delivered.iter().filter(|f| *f == frame).count() > 1));
+        assert!(delivered.iter().any(|frame| !sent.contains(frame)));
+        assert!(delivered.windows(2).any(|w| w[0][0] > w[1][0]));
+    }
+}
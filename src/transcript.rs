@@ -0,0 +1,141 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage handshake transcripts, to be persisted as evidence of a negotiated session.
+
+/// Handshake message of a transcript
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SignedHandshakeMsg {
+    /// Signed bytes, exactly as sent
+    pub data: Vec<u8>,
+    /// Signature and co-signatures of `data`, empty if the message is not signed.
+    /// In minimal mode our own messages are signed by the caller, their signatures are unknown.
+    pub sigs: Vec<u8>,
+}
+
+/// Transcript of a successful handshake: the CONNECT and ACK messages of both peers
+/// (signature public keys, signature algorithms, capabilities, ephemeral public keys,
+/// challenges and custom data) with their signatures
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HandshakeTranscript {
+    /// Our CONNECT message
+    pub local_connect: SignedHandshakeMsg,
+    /// Our ACK message
+    pub local_ack: SignedHandshakeMsg,
+    /// Peer CONNECT message
+    pub peer_connect: SignedHandshakeMsg,
+    /// Peer ACK message
+    pub peer_ack: SignedHandshakeMsg,
+}
+
+/// Handshake message recorded in a transcript
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum TranscriptMsg {
+    LocalConnect,
+    LocalAck,
+    PeerConnect,
+    PeerAck,
+}
+
+/// Handshake messages recorded during the negotiation
+#[derive(Clone, Debug, Default)]
+pub(crate) struct TranscriptRecorder {
+    local_connect: Option<SignedHandshakeMsg>,
+    local_ack: Option<SignedHandshakeMsg>,
+    peer_connect: Option<SignedHandshakeMsg>,
+    peer_ack: Option<SignedHandshakeMsg>,
+}
+
+impl TranscriptRecorder {
+    #[inline]
+    fn msg_mut(&mut self, msg: TranscriptMsg) -> &mut Option<SignedHandshakeMsg> {
+        match msg {
+            TranscriptMsg::LocalConnect => &mut self.local_connect,
+            TranscriptMsg::LocalAck => &mut self.local_ack,
+            TranscriptMsg::PeerConnect => &mut self.peer_connect,
+            TranscriptMsg::PeerAck => &mut self.peer_ack,
+        }
+    }
+    /// Record a handshake message
+    pub(crate) fn record(&mut self, msg: TranscriptMsg, data: &[u8], sigs: &[u8]) {
+        *self.msg_mut(msg) = Some(SignedHandshakeMsg {
+            data: data.to_vec(),
+            sigs: sigs.to_vec(),
+        });
+    }
+    /// Record the signatures of a handshake message signed after its recording
+    pub(crate) fn record_sigs(&mut self, msg: TranscriptMsg, sigs: &[u8]) {
+        if let Some(signed_msg) = self.msg_mut(msg) {
+            signed_msg.sigs = sigs.to_vec();
+        }
+    }
+    /// Transcript, once all handshake messages are recorded
+    pub(crate) fn transcript(&self) -> Option<HandshakeTranscript> {
+        match (
+            &self.local_connect,
+            &self.local_ack,
+            &self.peer_connect,
+            &self.peer_ack,
+        ) {
+            (Some(local_connect), Some(local_ack), Some(peer_connect), Some(peer_ack)) => {
+                Some(HandshakeTranscript {
+                    local_connect: local_connect.clone(),
+                    local_ack: local_ack.clone(),
+                    peer_connect: peer_connect.clone(),
+                    peer_ack: peer_ack.clone(),
+                })
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transcript_recorder() {
+        let mut recorder = TranscriptRecorder::default();
+        recorder.record(TranscriptMsg::LocalConnect, &[1], &[]);
+        recorder.record(TranscriptMsg::PeerConnect, &[2], &[20]);
+        recorder.record(TranscriptMsg::LocalAck, &[3], &[]);
+        assert_eq!(None, recorder.transcript());
+
+        recorder.record_sigs(TranscriptMsg::LocalAck, &[30]);
+        recorder.record(TranscriptMsg::PeerAck, &[4], &[40]);
+        assert_eq!(
+            Some(HandshakeTranscript {
+                local_connect: SignedHandshakeMsg {
+                    data: vec![1],
+                    sigs: vec![],
+                },
+                local_ack: SignedHandshakeMsg {
+                    data: vec![3],
+                    sigs: vec![30],
+                },
+                peer_connect: SignedHandshakeMsg {
+                    data: vec![2],
+                    sigs: vec![20],
+                },
+                peer_ack: SignedHandshakeMsg {
+                    data: vec![4],
+                    sigs: vec![40],
+                },
+            }),
+            recorder.transcript()
+        );
+    }
+}
@@ -0,0 +1,444 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage secure sessions over generic frame carriers.
+
+use crate::complete::message::IncomingBinaryMessage;
+use crate::complete::SecureLayer;
+use crate::status::SecureLayerStatus;
+use crate::{Error, Result};
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io::{BufWriter, Read, Write};
+use std::net::TcpStream;
+
+/// Driver of a session over TCP
+pub type TcpTransportDriver = TransportDriver<StreamTransport<TcpStream>>;
+
+/// Carrier of whole frames (serial link, message queue, stream with its own framing, etc)
+pub trait Transport {
+    /// Send a frame
+    fn send_frame(&mut self, frame: &[u8]) -> std::io::Result<()>;
+    /// Receive the next frame, blocking until one is available
+    fn recv_frame(&mut self) -> std::io::Result<Vec<u8>>;
+}
+
+#[derive(Debug)]
+/// Transport over a byte stream (TCP, Unix socket, etc), each frame is prefixed by its length
+/// on 4 bytes (big endian)
+pub struct StreamTransport<S: Read + Write> {
+    stream: S,
+    max_frame_len: usize,
+}
+
+impl<S: Read + Write> StreamTransport<S> {
+    /// Create stream transport, frames longer than `max_frame_len` are refused
+    pub fn new(stream: S, max_frame_len: usize) -> Self {
+        StreamTransport {
+            stream,
+            max_frame_len,
+        }
+    }
+    /// Get stream
+    #[inline]
+    pub fn stream(&self) -> &S {
+        &self.stream
+    }
+    /// Get back stream
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: Read + Write> Transport for StreamTransport<S> {
+    fn send_frame(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        let len = match u32::try_from(frame.len()) {
+            Ok(len) if frame.len() <= self.max_frame_len => len,
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "frame too large",
+                ))
+            }
+        };
+        self.stream.write_all(&len.to_be_bytes())?;
+        self.stream.write_all(frame)?;
+        self.stream.flush()
+    }
+    fn recv_frame(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut len = [0u8; 4];
+        self.stream.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > self.max_frame_len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "frame too large",
+            ));
+        }
+        let mut frame = vec![0u8; len];
+        self.stream.read_exact(&mut frame)?;
+        Ok(frame)
+    }
+}
+
+/// Size of frame checksum
+const CRC32_SIZE: usize = 4;
+
+/// Compute CRC-32 (IEEE 802.3)
+fn crc32(datas: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in datas {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[derive(Debug)]
+/// Transport adding a CRC-32 to each frame, for carriers without their own integrity
+/// (raw serial link, radio, etc). Corrupted frames are dropped before any decryption attempt.
+pub struct ChecksummedTransport<T: Transport> {
+    corrupted_frames_count: u64,
+    transport: T,
+}
+
+impl<T: Transport> ChecksummedTransport<T> {
+    /// Create checksummed transport over an inner transport
+    pub fn new(transport: T) -> Self {
+        ChecksummedTransport {
+            corrupted_frames_count: 0,
+            transport,
+        }
+    }
+    /// Get number of corrupted frames dropped
+    #[inline]
+    pub fn corrupted_frames_count(&self) -> u64 {
+        self.corrupted_frames_count
+    }
+    /// Get back inner transport
+    pub fn into_inner(self) -> T {
+        self.transport
+    }
+}
+
+impl<T: Transport> Transport for ChecksummedTransport<T> {
+    fn send_frame(&mut self, frame: &[u8]) -> std::io::Result<()> {
+        let mut checksummed_frame = Vec::with_capacity(frame.len() + CRC32_SIZE);
+        checksummed_frame.extend_from_slice(frame);
+        checksummed_frame.extend_from_slice(&crc32(frame).to_be_bytes());
+        self.transport.send_frame(&checksummed_frame)
+    }
+    fn recv_frame(&mut self) -> std::io::Result<Vec<u8>> {
+        loop {
+            let mut frame = self.transport.recv_frame()?;
+            if frame.len() >= CRC32_SIZE {
+                let frame_len = frame.len() - CRC32_SIZE;
+                let mut crc = [0u8; CRC32_SIZE];
+                crc.copy_from_slice(&frame[frame_len..]);
+                if u32::from_be_bytes(crc) == crc32(&frame[..frame_len]) {
+                    frame.truncate(frame_len);
+                    return Ok(frame);
+                }
+            }
+            self.corrupted_frames_count += 1;
+        }
+    }
+}
+
+#[derive(Debug)]
+/// Drive the handshake and the session of a secure layer over a transport
+pub struct TransportDriver<T: Transport> {
+    secure_layer: SecureLayer,
+    transport: T,
+    pending_msgs: VecDeque<Option<Vec<u8>>>,
+}
+
+impl<T: Transport> TransportDriver<T> {
+    /// Create a driver from a secure layer whose negotiation has not started
+    pub fn new(secure_layer: SecureLayer, transport: T) -> Self {
+        TransportDriver {
+            secure_layer,
+            transport,
+            pending_msgs: VecDeque::new(),
+        }
+    }
+    /// Get secure layer
+    #[inline]
+    pub fn secure_layer(&self) -> &SecureLayer {
+        &self.secure_layer
+    }
+    /// Get transport
+    #[inline]
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+    /// Get back secure layer and transport
+    pub fn into_inner(self) -> (SecureLayer, T) {
+        (self.secure_layer, self.transport)
+    }
+    /// Run the negotiation until the session is established.
+    /// Return the custom data of the peer CONNECT message.
+    pub fn handshake(
+        &mut self,
+        connect_custom_data: Option<&[u8]>,
+        ack_custom_data: Option<&[u8]>,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut buffer = BufWriter::new(Vec::new());
+        self.secure_layer
+            .write_connect_msg_bin(connect_custom_data, &mut buffer)?;
+        self.send_buffer(buffer)?;
+
+        let mut peer_connect_custom_data = None;
+        while self.secure_layer.status() != SecureLayerStatus::Established {
+            let msgs = match self.recv_msgs() {
+                Ok(msgs) => msgs,
+                Err(e) => {
+                    // Tell an outdated peer which versions we support
+                    if let Some(version_reject_msg) =
+                        self.secure_layer.take_version_reject_message()
+                    {
+                        let _ = self.transport.send_frame(&version_reject_msg);
+                    }
+                    return Err(e);
+                }
+            };
+            for msg in msgs {
+                match msg {
+                    IncomingBinaryMessage::Connect { custom_data, .. } => {
+                        peer_connect_custom_data = custom_data;
+                        let mut buffer = BufWriter::new(Vec::new());
+                        self.secure_layer
+                            .write_ack_msg_bin(ack_custom_data, &mut buffer)?;
+                        self.send_buffer(buffer)?;
+                    }
+                    IncomingBinaryMessage::Ack { .. } => {}
+                    IncomingBinaryMessage::Message { data, .. } => {
+                        self.pending_msgs.push_back(data)
+                    }
+                }
+            }
+        }
+        Ok(peer_connect_custom_data)
+    }
+    /// Send user message
+    pub fn send(&mut self, data: &[u8]) -> Result<()> {
+        let mut buffer = BufWriter::new(Vec::new());
+        self.secure_layer.write_bin(data, &mut buffer)?;
+        self.send_buffer(buffer)
+    }
+    /// Receive next user message (`None` for an empty message)
+    pub fn recv(&mut self) -> Result<Option<Vec<u8>>> {
+        while self.pending_msgs.is_empty() {
+            for msg in self.recv_msgs()? {
+                if let IncomingBinaryMessage::Message { data, .. } = msg {
+                    self.pending_msgs.push_back(data);
+                }
+            }
+        }
+        Ok(self.pending_msgs.pop_front().unwrap_or(None))
+    }
+    fn recv_msgs(&mut self) -> Result<Vec<IncomingBinaryMessage>> {
+        let frame = self.transport.recv_frame().map_err(Error::TransportError)?;
+        self.secure_layer.read_bin(&frame)
+    }
+    fn send_buffer(&mut self, buffer: BufWriter<Vec<u8>>) -> Result<()> {
+        let frame = buffer.into_inner().map_err(|_| Error::BufferFlushError)?;
+        self.transport
+            .send_frame(&frame)
+            .map_err(Error::TransportError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+    use crate::{SecureLayerConfig, Seed32};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+
+    struct ChannelTransport {
+        sender: Sender<Vec<u8>>,
+        receiver: Receiver<Vec<u8>>,
+    }
+
+    impl Transport for ChannelTransport {
+        fn send_frame(&mut self, frame: &[u8]) -> std::io::Result<()> {
+            self.sender
+                .send(frame.to_vec())
+                .map_err(|_| std::io::ErrorKind::BrokenPipe.into())
+        }
+        fn recv_frame(&mut self) -> std::io::Result<Vec<u8>> {
+            self.receiver
+                .recv()
+                .map_err(|_| std::io::ErrorKind::UnexpectedEof.into())
+        }
+    }
+
+    fn transports() -> (ChannelTransport, ChannelTransport) {
+        let (client_sender, server_receiver) = channel();
+        let (server_sender, client_receiver) = channel();
+        (
+            ChannelTransport {
+                sender: client_sender,
+                receiver: client_receiver,
+            },
+            ChannelTransport {
+                sender: server_sender,
+                receiver: server_receiver,
+            },
+        )
+    }
+
+    #[test]
+    fn test_transport_driver() -> Result<()> {
+        let server_seed = Seed32::try_random()?;
+        let server_sig_pubkey = Ed25519KeyPair::from_seed_unchecked(server_seed.as_ref())
+            .map_err(|_| Error::FailtoGenSigKeyPair)?
+            .public_key()
+            .as_ref()
+            .to_vec();
+        let server_sl = SecureLayer::create(SecureLayerConfig::default(), Some(server_seed), None)?;
+        let client_sl =
+            SecureLayer::create(SecureLayerConfig::default(), None, Some(server_sig_pubkey))?;
+        let (client_transport, server_transport) = transports();
+
+        let server_thread = std::thread::spawn(move || -> Result<Option<Vec<u8>>> {
+            let mut server = TransportDriver::new(server_sl, server_transport);
+            let client_custom_data = server.handshake(None, None)?;
+            let msg = server.recv()?;
+            server.send(&[4, 5, 6])?;
+            assert_eq!(Some(vec![1, 2, 3]), msg);
+            Ok(client_custom_data)
+        });
+
+        let mut client = TransportDriver::new(client_sl, client_transport);
+        assert_eq!(None, client.handshake(Some(&[7, 8]), None)?);
+        client.send(&[1, 2, 3])?;
+        assert_eq!(Some(vec![4, 5, 6]), client.recv()?);
+        assert_eq!(
+            SecureLayerStatus::Established,
+            client.secure_layer().status()
+        );
+
+        let client_custom_data = server_thread.join().expect("server thread panicked")?;
+        assert_eq!(Some(vec![7, 8]), client_custom_data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_stream_transport() -> std::io::Result<()> {
+        let mut transport = StreamTransport::new(std::io::Cursor::new(Vec::new()), 4);
+        transport.send_frame(&[1, 2, 3])?;
+        assert_eq!(
+            std::io::ErrorKind::InvalidInput,
+            transport
+                .send_frame(&[1, 2, 3, 4, 5])
+                .expect_err("frame must be refused")
+                .kind()
+        );
+        assert_eq!(&[0, 0, 0, 3, 1, 2, 3], &transport.stream().get_ref()[..]);
+
+        let mut stream = transport.into_inner();
+        stream.set_position(0);
+        let mut transport = StreamTransport::new(stream, 2);
+        assert_eq!(
+            std::io::ErrorKind::InvalidData,
+            transport
+                .recv_frame()
+                .expect_err("frame must be refused")
+                .kind()
+        );
+
+        let mut stream = transport.into_inner();
+        stream.set_position(0);
+        let mut transport = StreamTransport::new(stream, 4);
+        assert_eq!(vec![1, 2, 3], transport.recv_frame()?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(0, crc32(b""));
+        assert_eq!(0xCBF4_3926, crc32(b"123456789"));
+    }
+
+    #[test]
+    fn test_checksummed_transport() -> Result<()> {
+        let (client_transport, server_transport) = transports();
+        let mut client_transport = ChecksummedTransport::new(client_transport);
+        let mut server_transport = ChecksummedTransport::new(server_transport);
+
+        // Corrupted and too short frames are dropped
+        let mut corrupted_frame = vec![1, 2, 3];
+        corrupted_frame.extend_from_slice(&crc32(&[1, 2, 3]).to_be_bytes());
+        corrupted_frame[1] ^= 0x10;
+        let raw_client_transport = &mut client_transport.transport;
+        raw_client_transport
+            .send_frame(&corrupted_frame)
+            .map_err(Error::TransportError)?;
+        raw_client_transport
+            .send_frame(&[1, 2])
+            .map_err(Error::TransportError)?;
+        client_transport
+            .send_frame(&[4, 5, 6])
+            .map_err(Error::TransportError)?;
+        assert_eq!(
+            vec![4, 5, 6],
+            server_transport
+                .recv_frame()
+                .map_err(Error::TransportError)?
+        );
+        assert_eq!(2, server_transport.corrupted_frames_count());
+
+        // A session can run over it
+        let server_sl = SecureLayer::create(SecureLayerConfig::default(), None, None)?;
+        let client_sl = SecureLayer::create(SecureLayerConfig::default(), None, None)?;
+        let server_thread = std::thread::spawn(move || -> Result<Option<Vec<u8>>> {
+            let mut server = TransportDriver::new(server_sl, server_transport);
+            server.handshake(None, None)?;
+            server.recv()
+        });
+        let mut client = TransportDriver::new(client_sl, client_transport);
+        client.handshake(None, None)?;
+        client.send(&[7, 8, 9])?;
+        assert_eq!(
+            Some(vec![7, 8, 9]),
+            server_thread.join().expect("server thread panicked")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_transport_error() -> Result<()> {
+        let client_sl = SecureLayer::create(SecureLayerConfig::default(), None, None)?;
+        let (client_transport, server_transport) = transports();
+        drop(server_transport);
+
+        let mut client = TransportDriver::new(client_sl, client_transport);
+        if let Err(Error::TransportError(e)) = client.handshake(None, None) {
+            assert_eq!(std::io::ErrorKind::BrokenPipe, e.kind());
+        } else {
+            panic!("unexpected result");
+        }
+        Ok(())
+    }
+}
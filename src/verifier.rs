@@ -0,0 +1,320 @@
+//  Copyright (C) 2019  Eloïs SANCHEZ.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! Manage offline verification of captured frames, for audits of persisted messages.
+//!
+//! Given the session secret (see key log) and the frames captured by one peer, the verifier
+//! checks again signatures, challenges, hashes and nonces, and reports the validity of each frame.
+
+use crate::agreement::SharedSecret;
+use crate::capabilities::Capability;
+use crate::config::SecureLayerConfig;
+use crate::constants::*;
+use crate::digest::Digest;
+use crate::encoding::from_base16;
+use crate::encryption::{Direction, EncryptAlgoWithSecretKey};
+use crate::errors::IncomingMsgErr;
+use crate::message::{ack_challenge, MsgTypeHeaders};
+use crate::minimal::MinimalSecureLayer;
+use crate::reader::{self, DecryptedIncomingData};
+use crate::signature::{SigAlgo, SigRequirement};
+use crate::status::FailReason;
+use crate::{Error, Result};
+use std::collections::BTreeSet;
+
+/// Side of a captured frame
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CaptureSide {
+    /// Frame sent by the capturing peer
+    Local,
+    /// Frame received by the capturing peer
+    Remote,
+}
+
+/// Frame successfully verified
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum VerifiedFrame {
+    /// CONNECT message, with a valid signature if its sender signs it
+    Connect {
+        /// Signature public key of the sender
+        sig_pubkey: Vec<u8>,
+    },
+    /// ACK message with a valid challenge, and a valid signature if its sender signs it
+    Ack,
+    /// USER, KEEPALIVE or COVER message with a valid hash and a fresh nonce
+    UserMsg {
+        /// Nonce
+        nonce: u64,
+        /// Keepalive message
+        keepalive: bool,
+        /// Cover message
+        cover: bool,
+        /// Received after a message of greater nonce
+        reordered: bool,
+    },
+}
+
+/// Verification result of a captured frame
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FrameReport {
+    /// Side of the frame
+    pub side: CaptureSide,
+    /// Verified frame or failure reason
+    pub result: std::result::Result<VerifiedFrame, FailReason>,
+}
+
+/// Verification report of captured frames
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VerificationReport {
+    /// Reports of each frame, in capture order
+    pub frames: Vec<FrameReport>,
+    /// Whether the session fingerprint computed from the CONNECT messages matches the expected one
+    /// (none if it is not expected or if a CONNECT message is missing)
+    pub fingerprint_matches: Option<bool>,
+}
+
+impl VerificationReport {
+    /// Check that all frames are valid and that the session fingerprint does not mismatch
+    pub fn is_valid(&self) -> bool {
+        self.fingerprint_matches != Some(false)
+            && self.frames.iter().all(|frame| frame.result.is_ok())
+    }
+}
+
+#[derive(Debug)]
+struct SideState {
+    sig_requirement: SigRequirement,
+    epk: Option<[u8; EPK_SIZE]>,
+    signer: Option<(SigAlgo, Vec<u8>)>,
+    highest_nonce: Option<u64>,
+    nonces: BTreeSet<u64>,
+    /// Frames padded, as advertised in the CONNECT message
+    frame_padding: bool,
+}
+
+impl SideState {
+    fn new(sig_requirement: SigRequirement) -> Self {
+        SideState {
+            sig_requirement,
+            epk: None,
+            signer: None,
+            highest_nonce: None,
+            nonces: BTreeSet::new(),
+            frame_padding: false,
+        }
+    }
+}
+
+/// Offline verifier of captured frames
+#[derive(Debug)]
+pub struct OfflineVerifier {
+    digest: &'static dyn Digest,
+    encrypt_algo_with_secret: EncryptAlgoWithSecretKey,
+    expected_fingerprint: Option<[u8; HASH_SIZE]>,
+    local_sig_requirement: SigRequirement,
+    peer_sig_requirement: SigRequirement,
+}
+
+impl OfflineVerifier {
+    /// Create verifier from the session shared secret
+    pub fn new(config: SecureLayerConfig, shared_secret: &[u8]) -> Result<Self> {
+        let mut secret = SharedSecret::new(config.encrypt_algo.shared_secret_len());
+        if secret.as_ref().len() != shared_secret.len() {
+            return Err(Error::InvalidSessionSecret);
+        }
+        secret.as_mut().copy_from_slice(shared_secret);
+
+        Ok(OfflineVerifier {
+            digest: config.digest,
+            // The direction is set for each frame, once both ephemeral public keys are known
+            encrypt_algo_with_secret: EncryptAlgoWithSecretKey::build(
+                config.encrypt_algo,
+                secret,
+                Direction::FromLowestEpk,
+                config.key_context.as_bytes(),
+            )?,
+            expected_fingerprint: None,
+            local_sig_requirement: config.local_sig_requirement,
+            peer_sig_requirement: config.peer_sig_requirement,
+        })
+    }
+    /// Create verifier from a key log line
+    /// (`PKSTL_SESSION_SECRET <SESSION_FINGERPRINT> <SHARED_SECRET>`),
+    /// the session fingerprint is checked against the captured CONNECT messages
+    pub fn from_key_log_line(config: SecureLayerConfig, line: &str) -> Result<Self> {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 || fields[0] != SESSION_SECRET_LABEL {
+            return Err(Error::InvalidSessionSecret);
+        }
+        let fingerprint = from_base16(fields[1])?;
+        if fingerprint.len() != HASH_SIZE {
+            return Err(Error::InvalidSessionSecret);
+        }
+        let mut expected_fingerprint = [0u8; HASH_SIZE];
+        expected_fingerprint.copy_from_slice(&fingerprint);
+
+        let mut verifier = Self::new(config, &from_base16(fields[2])?)?;
+        verifier.expected_fingerprint = Some(expected_fingerprint);
+        Ok(verifier)
+    }
+    /// Verify captured frames, in capture order
+    pub fn verify(&self, frames: &[(CaptureSide, &[u8])]) -> VerificationReport {
+        let mut local = SideState::new(self.local_sig_requirement);
+        let mut remote = SideState::new(self.peer_sig_requirement);
+
+        let frames = frames
+            .iter()
+            .map(|(side, frame)| {
+                let (sender, receiver) = match side {
+                    CaptureSide::Local => (&mut local, &remote),
+                    CaptureSide::Remote => (&mut remote, &local),
+                };
+                FrameReport {
+                    side: *side,
+                    result: self
+                        .verify_frame(frame, sender, receiver)
+                        .map_err(|e| FailReason::from(&e)),
+                }
+            })
+            .collect();
+
+        let fingerprint_matches = match (self.expected_fingerprint, local.epk, remote.epk) {
+            (Some(expected_fingerprint), Some(local_epk), Some(remote_epk)) => Some(
+                MinimalSecureLayer::compute_session_fingerprint(
+                    self.digest,
+                    &local_epk,
+                    &remote_epk,
+                ) == expected_fingerprint,
+            ),
+            _ => None,
+        };
+
+        VerificationReport {
+            frames,
+            fingerprint_matches,
+        }
+    }
+    /// Secret key of the receiver of an encrypted frame
+    fn receiver_encrypt_algo_with_secret(
+        &self,
+        sender: &SideState,
+        receiver: &SideState,
+    ) -> Option<EncryptAlgoWithSecretKey> {
+        match (sender.epk, receiver.epk) {
+            (Some(sender_epk), Some(receiver_epk)) => {
+                let mut encrypt_algo_with_secret = self.encrypt_algo_with_secret.clone();
+                encrypt_algo_with_secret.outgoing_direction =
+                    Direction::new(&receiver_epk, &sender_epk);
+                Some(encrypt_algo_with_secret)
+            }
+            _ => None,
+        }
+    }
+    fn verify_frame(
+        &self,
+        frame: &[u8],
+        sender: &mut SideState,
+        receiver: &SideState,
+    ) -> Result<VerifiedFrame> {
+        let DecryptedIncomingData {
+            data,
+            user_msg_end,
+            msg_type_headers,
+            ..
+        } = reader::read(
+            self.receiver_encrypt_algo_with_secret(sender, receiver)
+                .as_ref(),
+            frame,
+            true,
+        )?;
+        let (data_signed, footer) = data.split_at(user_msg_end);
+
+        match msg_type_headers {
+            MsgTypeHeaders::Connect {
+                peer_ephemeral_pk,
+                sig_algo,
+                sig_pubkey,
+                capabilities,
+                ..
+            } => {
+                if sender.sig_requirement == SigRequirement::ConnectAndAck {
+                    // Co-signatures may follow the signature
+                    let sig = footer
+                        .get(..sig_algo.sig_len())
+                        .ok_or(IncomingMsgErr::InvalidHashOrSig)?;
+                    if !sig_algo.verify(&sig_pubkey, data_signed, sig) {
+                        return Err(IncomingMsgErr::InvalidHashOrSig.into());
+                    }
+                }
+                sender.epk = Some(peer_ephemeral_pk);
+                sender.frame_padding = capabilities.contains(Capability::FramePadding);
+                sender.signer = Some((sig_algo, sig_pubkey.clone()));
+                Ok(VerifiedFrame::Connect { sig_pubkey })
+            }
+            MsgTypeHeaders::Ack { challenge } => {
+                // Signed with the key of the sender CONNECT message
+                let ((sig_algo, sig_pubkey), sender_epk, receiver_epk) =
+                    match (&sender.signer, sender.epk, receiver.epk) {
+                        (Some(signer), Some(sender_epk), Some(receiver_epk)) => {
+                            (signer, sender_epk, receiver_epk)
+                        }
+                        _ => return Err(IncomingMsgErr::UnexpectedAckMsg.into()),
+                    };
+                let signer_epk = if sender.sig_requirement == SigRequirement::AckOnly {
+                    Some(&sender_epk[..])
+                } else {
+                    None
+                };
+                if challenge != ack_challenge(self.digest, &receiver_epk, signer_epk) {
+                    return Err(IncomingMsgErr::InvalidChallenge.into());
+                }
+                if sender.sig_requirement != SigRequirement::Anonymous
+                    && !sig_algo.verify(sig_pubkey, data_signed, footer)
+                {
+                    return Err(IncomingMsgErr::InvalidHashOrSig.into());
+                }
+                Ok(VerifiedFrame::Ack)
+            }
+            MsgTypeHeaders::UserMsg {
+                nonce,
+                keepalive,
+                cover,
+                ..
+            } => {
+                // Hash may be omitted
+                let footer = reader::user_msg_footer(&data, user_msg_end, sender.frame_padding)?;
+                if !footer.is_empty() && footer != self.digest.sha256(data_signed) {
+                    return Err(IncomingMsgErr::InvalidHashOrSig.into());
+                }
+                if !sender.nonces.insert(nonce) {
+                    return Err(IncomingMsgErr::InvalidNonce.into());
+                }
+                let reordered = sender
+                    .highest_nonce
+                    .map(|highest_nonce| nonce < highest_nonce)
+                    .unwrap_or(false);
+                if !reordered {
+                    sender.highest_nonce = Some(nonce);
+                }
+                Ok(VerifiedFrame::UserMsg {
+                    nonce,
+                    keepalive,
+                    cover,
+                    reordered,
+                })
+            }
+        }
+    }
+}
@@ -18,8 +18,9 @@
 #[cfg(feature = "zip-sign")]
 mod tests {
     use pkstl::*;
-    use ring::signature::{Ed25519KeyPair, KeyPair};
+    use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
     use std::io::BufWriter;
+    use std::time::SystemTime;
 
     trait AsOptRef {
         fn as_opt_ref(&self) -> Option<&[u8]>;
@@ -36,7 +37,7 @@ mod tests {
 
     fn server_infos() -> Result<(SecureLayer, Vec<u8>)> {
         // Create server sig keypair seed
-        let seed = Seed32::random();
+        let seed = Seed32::try_random()?;
 
         // Create server secure layer
         let server_msl =
@@ -126,6 +127,7 @@ mod tests {
         let msg_received = receiver_msl.read_bin(&channel[..])?;
         if let IncomingBinaryMessage::Message {
             data: data_received,
+            ..
         } = msg_received.get(0).expect("Must receive a message")
         {
             assert_eq!(&Some(data), data_received);
@@ -253,6 +255,17 @@ mod tests {
 
         send_ack_msg(&mut client_msl, &mut server_msl, Some(vec![5, 0, 0, 5]))?;
 
+        // Both peers must share the same session fingerprint
+        assert!(client_msl.session_fingerprint().is_some());
+        assert_eq!(
+            client_msl.session_fingerprint(),
+            server_msl.session_fingerprint()
+        );
+        assert_eq!(
+            Some(to_base58(&server_sig_pk)),
+            client_msl.peer_sig_public_key().map(to_base58)
+        );
+
         // Negociation must be successfull, so we can clone secure layer
         client_msl.try_clone()?;
         server_msl.try_clone()?;
@@ -280,4 +293,258 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn connect_co_signatures_threshold() -> Result<()> {
+        // 2-of-3 operator keys
+        let operators_seeds = vec![
+            Seed32::try_random()?,
+            Seed32::try_random()?,
+            Seed32::try_random()?,
+        ];
+        let co_signers = operators_seeds
+            .iter()
+            .map(|seed| {
+                Ok(Ed25519KeyPair::from_seed_unchecked(seed.as_ref())
+                    .map_err(|_| Error::FailtoGenSigKeyPair)?
+                    .public_key()
+                    .as_ref()
+                    .to_vec())
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let policy: &'static ConnectSigPolicy = Box::leak(Box::new(ConnectSigPolicy {
+            co_signers,
+            threshold: 2,
+        }));
+        let client_config = SecureLayerConfig {
+            connect_sig_policy: Some(policy),
+            ..SecureLayerConfig::default()
+        };
+
+        // Server CONNECT message co-signed by 2 operators must be accepted
+        let (mut server_msl, server_sig_pk) = server_infos()?;
+        server_msl.add_connect_co_signer(operators_seeds[0].clone())?;
+        server_msl.add_connect_co_signer(operators_seeds[2].clone())?;
+        let mut client_msl = SecureLayer::create(client_config, None, Some(server_sig_pk))?;
+        send_connect_msg(&mut server_msl, &mut client_msl, None)?;
+
+        // Server CONNECT message co-signed by only 1 operator must be rejected
+        let (mut server_msl, server_sig_pk) = server_infos()?;
+        server_msl.add_connect_co_signer(operators_seeds[1].clone())?;
+        let mut client_msl = SecureLayer::create(client_config, None, Some(server_sig_pk))?;
+        let result = send_connect_msg(&mut server_msl, &mut client_msl, None);
+        if let Err(Error::RecvInvalidMsg(IncomingMsgErr::NotEnoughCoSignatures)) = result {
+            Ok(())
+        } else {
+            println!("unexpected result={:?}", result);
+            panic!();
+        }
+    }
+
+    #[test]
+    fn connect_co_signatures_without_connect_sig() -> Result<()> {
+        let policy: &'static ConnectSigPolicy = Box::leak(Box::new(ConnectSigPolicy {
+            co_signers: vec![vec![0u8; 32]],
+            threshold: 1,
+        }));
+
+        // Co-signatures of an unsigned CONNECT message can't be verified
+        for peer_sig_requirement in &[SigRequirement::AckOnly, SigRequirement::Anonymous] {
+            let config = SecureLayerConfig {
+                connect_sig_policy: Some(policy),
+                peer_sig_requirement: *peer_sig_requirement,
+                ..SecureLayerConfig::default()
+            };
+            let result = SecureLayer::create(config, None, None);
+            if let Err(Error::InvalidConfig(_)) = result {
+            } else {
+                panic!("Expected error InvalidConfig !");
+            }
+            let mut secure_layer = SecureLayer::create(SecureLayerConfig::default(), None, None)?;
+            if let Err(Error::InvalidConfig(_)) = secure_layer.change_config(config) {
+            } else {
+                panic!("Expected error InvalidConfig !");
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn custom_data_validator() -> Result<()> {
+        let (mut server_msl, server_sig_pk) = server_infos()?;
+        let mut client_msl = client_infos(Some(server_sig_pk))?;
+
+        // The validator sees uncompressed custom data
+        server_msl.set_custom_data_validator(|message, _| {
+            message.custom_data() == Some(&[5, 1, 1, 5][..])
+        });
+
+        send_connect_msg(&mut client_msl, &mut server_msl, Some(vec![5, 1, 1, 5]))?;
+        send_connect_msg(&mut server_msl, &mut client_msl, None)?;
+        send_ack_msg(&mut server_msl, &mut client_msl, None)?;
+
+        let result = send_ack_msg(&mut client_msl, &mut server_msl, Some(vec![7, 1, 1, 7]));
+        if let Err(Error::RecvInvalidMsg(IncomingMsgErr::RejectedCustomData)) = result {
+            Ok(())
+        } else {
+            println!("unexpected result={:?}", result);
+            panic!();
+        }
+    }
+
+    #[test]
+    fn prepared_connect() -> Result<()> {
+        let (mut server_msl, server_sig_pubkey) = server_infos()?;
+        let client_seed = Seed32::try_random()?;
+
+        // First attempt fails after writing the CONNECT message
+        let mut client_msl = SecureLayer::create(
+            SecureLayerConfig::default(),
+            Some(client_seed.clone()),
+            Some(server_sig_pubkey.clone()),
+        )?;
+        let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+        client_msl.write_connect_msg_bin(Some(&[7]), &mut channel)?;
+        let frame = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+        let prepared_connect = client_msl
+            .into_prepared_connect()
+            .expect("ephemeral key pair not used");
+        assert_eq!(&frame[..], prepared_connect.frame());
+
+        // Second attempt re-emits the same frame, whatever the custom data
+        let mut client_msl = SecureLayer::create_with_prepared_connect(
+            prepared_connect,
+            client_seed,
+            Some(server_sig_pubkey),
+        )?;
+        let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+        client_msl.write_connect_msg_bin(None, &mut channel)?;
+        assert_eq!(
+            frame,
+            channel.into_inner().map_err(|_| Error::BufferFlushError)?
+        );
+
+        // Negotiation
+        let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+        client_msl.write_connect_msg_bin(None, &mut channel)?;
+        let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+        assert_eq!(1, server_msl.read_bin(&channel)?.len());
+        send_connect_msg(&mut server_msl, &mut client_msl, None)?;
+        send_ack_msg(&mut server_msl, &mut client_msl, None)?;
+        send_ack_msg(&mut client_msl, &mut server_msl, None)?;
+        send_user_msg(&mut client_msl, &mut server_msl, vec![1, 2, 3])?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn minimal_peer() -> Result<()> {
+        let (mut server_msl, _) = server_infos()?;
+        let mut client_msl = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
+        let client_sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+            .map_err(|_| Error::FailtoGenSigKeyPair)?;
+        assert_eq!(None, server_msl.peer_capabilities());
+
+        // Exchange CONNECT messages
+        let mut channel =
+            client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+        channel.extend_from_slice(client_sig_kp.sign(&channel).as_ref());
+        assert_eq!(1, server_msl.read_bin(&channel)?.len());
+        let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+        server_msl.write_connect_msg_bin(None, &mut channel)?;
+        let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+        assert!(client_msl.read(&channel)?.is_some());
+        assert_eq!(
+            Some(Capabilities::complete()),
+            client_msl.peer_capabilities()
+        );
+        assert_eq!(
+            Some(Capabilities::minimal()),
+            server_msl.peer_capabilities()
+        );
+
+        // The minimal peer does not read compressed user data
+        let mut channel = BufWriter::new(Vec::new());
+        if let Err(Error::PeerUnsupported(Capability::Compression)) =
+            server_msl.write_bin(&[1, 2, 3], &mut channel)
+        {
+        } else {
+            panic!("Expected error PeerUnsupported !")
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn handshake_transcript() -> Result<()> {
+        let (mut server_msl, server_sig_pk) = server_infos()?;
+        let mut client_msl = client_infos(Some(server_sig_pk.clone()))?;
+        send_connect_msg(&mut client_msl, &mut server_msl, Some(vec![1, 2]))?;
+        send_connect_msg(&mut server_msl, &mut client_msl, None)?;
+        send_ack_msg(&mut server_msl, &mut client_msl, None)?;
+        assert_eq!(None, server_msl.handshake_transcript());
+        send_ack_msg(&mut client_msl, &mut server_msl, Some(vec![3, 4]))?;
+
+        let server_transcript = server_msl
+            .handshake_transcript()
+            .expect("Must have a transcript");
+        let client_transcript = client_msl
+            .handshake_transcript()
+            .expect("Must have a transcript");
+        assert_eq!(
+            server_transcript.local_connect,
+            client_transcript.peer_connect
+        );
+        assert_eq!(server_transcript.local_ack, client_transcript.peer_ack);
+        assert_eq!(
+            server_transcript.peer_connect,
+            client_transcript.local_connect
+        );
+        assert_eq!(server_transcript.peer_ack, client_transcript.local_ack);
+
+        // The transcript is evidence of the server identity
+        let server_sig_pk = UnparsedPublicKey::new(&ED25519, &server_sig_pk);
+        for msg in &[client_transcript.peer_connect, client_transcript.peer_ack] {
+            assert!(server_sig_pk.verify(&msg.data, &msg.sigs).is_ok());
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn user_msg_metadata() -> Result<()> {
+        let (mut server_msl, server_sig_pk) = server_infos()?;
+        let mut client_msl = client_infos(Some(server_sig_pk))?;
+        send_connect_msg(&mut client_msl, &mut server_msl, None)?;
+        send_connect_msg(&mut server_msl, &mut client_msl, None)?;
+        send_ack_msg(&mut server_msl, &mut client_msl, None)?;
+        send_ack_msg(&mut client_msl, &mut server_msl, None)?;
+
+        let frame_overhead = SecureLayerConfig::default().frame_overhead();
+        for expected_nonce in 0..3 {
+            let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+            client_msl.write_bin(&[7u8; 100], &mut channel)?;
+            let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+
+            let before_read = SystemTime::now();
+            let msgs = server_msl.read_bin(&channel)?;
+            if let Some(IncomingBinaryMessage::Message {
+                data,
+                nonce,
+                received_at,
+                size,
+            }) = msgs.get(0)
+            {
+                assert_eq!(&Some(vec![7u8; 100]), data);
+                assert_eq!(expected_nonce, *nonce);
+                assert!(*received_at >= before_read && *received_at <= SystemTime::now());
+                // Size of the data written by the compressor
+                assert_eq!(channel.len() - frame_overhead, *size);
+            } else {
+                panic!("Unexpected incoming messages={:?}", msgs);
+            }
+        }
+
+        Ok(())
+    }
 }
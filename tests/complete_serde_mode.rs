@@ -26,7 +26,7 @@ mod tests {
 
     fn server_infos(format: MessageFormat) -> Result<(SecureLayer, Vec<u8>)> {
         // Create server sig keypair seed
-        let seed = Seed32::random();
+        let seed = Seed32::try_random()?;
 
         // Create server secure layer
         let mut conf = SecureLayerConfig::default();
@@ -118,6 +118,7 @@ mod tests {
         let msg_received = receiver_msl.read(&channel[..])?;
         if let IncomingMessage::Message {
             data: data_received,
+            ..
         } = msg_received.get(0).expect("Must receive a message")
         {
             assert_eq!(&Some(data), data_received);
@@ -161,6 +162,96 @@ mod tests {
         )
     }
 
+    #[cfg(feature = "json")]
+    #[test]
+    fn pre_serialized_message() -> Result<()> {
+        let (mut server_msl, server_sig_pk) = server_infos(MessageFormat::Utf8Json)?;
+        let mut client_msl = client_infos(Some(server_sig_pk), MessageFormat::Utf8Json)?;
+        send_connect_msg::<String>(&mut client_msl, &mut server_msl, None)?;
+        send_connect_msg::<String>(&mut server_msl, &mut client_msl, None)?;
+        send_ack_msg::<String>(&mut server_msl, &mut client_msl, None)?;
+        send_ack_msg::<String>(&mut client_msl, &mut server_msl, None)?;
+
+        // Payload encoded by another subsystem is not serialized again
+        let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+        client_msl.write_serialized(MessageFormat::Utf8Json, b"\"abc\"", &mut channel)?;
+        let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+        let msg_received = server_msl.read::<String>(&channel[..])?;
+        if let Some(IncomingMessage::Message { data, .. }) = msg_received.get(0) {
+            assert_eq!(&Some("abc".to_owned()), data);
+        } else {
+            panic!("Unexpected incoming message={:?}", msg_received);
+        }
+
+        // Raw binary payloads are written with write_bin
+        let mut channel = BufWriter::new(Vec::new());
+        if let Err(Error::SerdeError(_)) =
+            client_msl.write_serialized(MessageFormat::RawBinary, &[1, 2, 3], &mut channel)
+        {
+        } else {
+            panic!("Expected error UseSuffixedBinFunctions !")
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "json")]
+    #[test]
+    fn broadcast() -> Result<()> {
+        let mut sessions = Vec::new();
+        for _ in 0..3 {
+            let (mut server_msl, server_sig_pk) = server_infos(MessageFormat::Utf8Json)?;
+            let mut client_msl = client_infos(Some(server_sig_pk), MessageFormat::Utf8Json)?;
+            send_connect_msg::<String>(&mut client_msl, &mut server_msl, None)?;
+            send_connect_msg::<String>(&mut server_msl, &mut client_msl, None)?;
+            send_ack_msg::<String>(&mut server_msl, &mut client_msl, None)?;
+            send_ack_msg::<String>(&mut client_msl, &mut server_msl, None)?;
+            sessions.push((server_msl, client_msl));
+        }
+        // A session whose negotiation is not finished
+        let (server_msl, server_sig_pk) = server_infos(MessageFormat::Utf8Json)?;
+        sessions.push((
+            server_msl,
+            client_infos(Some(server_sig_pk), MessageFormat::Utf8Json)?,
+        ));
+
+        // Gossip to all peers
+        let mut channels: Vec<_> = sessions
+            .iter()
+            .map(|_| BufWriter::new(Vec::with_capacity(1_000)))
+            .collect();
+        let results = SecureLayer::broadcast(
+            &"gossip".to_owned(),
+            sessions
+                .iter_mut()
+                .map(|(server_msl, _)| server_msl)
+                .zip(channels.iter_mut()),
+        );
+        assert_eq!(4, results.len());
+        if let Err(Error::NegoMustHaveBeenSuccessful) = results[3] {
+        } else {
+            panic!("Expected error NegoMustHaveBeenSuccessful !")
+        }
+
+        for ((result, (_, client_msl)), channel) in results
+            .into_iter()
+            .zip(sessions.iter_mut())
+            .zip(channels)
+            .take(3)
+        {
+            result?;
+            let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+            let msg_received = client_msl.read::<String>(&channel[..])?;
+            if let Some(IncomingMessage::Message { data, .. }) = msg_received.get(0) {
+                assert_eq!(&Some("gossip".to_owned()), data);
+            } else {
+                panic!("Unexpected incoming message={:?}", msg_received);
+            }
+        }
+
+        Ok(())
+    }
+
     fn test_ordered_passing_case<D: Clone + Debug + PartialEq + Serialize + DeserializeOwned>(
         message_format: MessageFormat,
         connect_msg_custom_data: Option<D>,
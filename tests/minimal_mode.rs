@@ -17,7 +17,11 @@
 
 use pkstl::*;
 use ring::signature::{Ed25519KeyPair, KeyPair};
+use std::collections::BTreeSet;
 use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 trait AsOptRef {
     fn as_opt_ref(&self) -> Option<&[u8]>;
@@ -34,7 +38,7 @@ impl AsOptRef for Option<Vec<u8>> {
 
 fn client_infos(server_sig_kp: &[u8]) -> Result<(MinimalSecureLayer, Ed25519KeyPair)> {
     // Create client sig keypair
-    let client_sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::random().as_ref())
+    let client_sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
         .map_err(|_| Error::FailtoGenSigKeyPair)?;
 
     // Create client secure layer
@@ -49,7 +53,7 @@ fn server_infos() -> Result<(MinimalSecureLayer, Ed25519KeyPair)> {
     let server_msl = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
 
     // Create server sig keypair
-    let server_sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::random().as_ref())
+    let server_sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
         .map_err(|_| Error::FailtoGenSigKeyPair)?;
 
     Ok((server_msl, server_sig_kp))
@@ -89,13 +93,9 @@ fn send_connect_msg(
     receiver_msl: &mut MinimalSecureLayer,
     custom_data: Option<Vec<u8>>,
 ) -> Result<()> {
-    let connect_msg_received = send_connect_msg_inner(
-        sender_msl,
-        sender_sig_kp,
-        receiver_msl,
-        custom_data.clone(),
-    )?
-    .expect("Must receive a message");
+    let connect_msg_received =
+        send_connect_msg_inner(sender_msl, sender_sig_kp, receiver_msl, custom_data.clone())?
+            .expect("Must receive a message");
     assert_eq!(
         Message::Connect {
             sig_algo: SIG_ALGO_ED25519_ARRAY,
@@ -483,3 +483,2145 @@ fn ordered_passing_case() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn user_msg_without_hash() -> Result<()> {
+    let config_without_hash = SecureLayerConfig {
+        user_msg_hash: false,
+        ..SecureLayerConfig::default()
+    };
+
+    for server_user_msg_hash in &[true, false] {
+        let (mut server_msl, server_sig_kp) = server_infos()?;
+        let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+        server_msl.change_config(SecureLayerConfig {
+            user_msg_hash: *server_user_msg_hash,
+            ..SecureLayerConfig::default()
+        })?;
+        client_msl.change_config(config_without_hash)?;
+
+        // Negotiation
+        send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+        send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+        send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+        send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+        assert_eq!(
+            Some(!*server_user_msg_hash),
+            client_msl
+                .peer_capabilities()
+                .map(|caps| caps.contains(Capability::OmitUserMsgHash))
+        );
+
+        // The hash is omitted only if both peers have disabled it
+        let expected_overhead = if *server_user_msg_hash {
+            SecureLayerConfig::default().frame_overhead()
+        } else {
+            config_without_hash.frame_overhead()
+        };
+        let mut channel = BufWriter::new(Vec::with_capacity(100));
+        client_msl.write_message(&[1, 2, 3], &mut channel)?;
+        let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+        assert_eq!(3 + expected_overhead, channel.len());
+        assert_eq!(
+            Some(Message::Message {
+                custom_data: Some(vec![1, 2, 3]),
+            }),
+            server_msl.read(&channel)?
+        );
+        send_user_msg(&mut server_msl, &mut client_msl, vec![3, 2, 1])?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn status_changes() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    let status_changes = Arc::new(Mutex::new(Vec::new()));
+    let status_changes_clone = status_changes.clone();
+    client_msl.on_state_change(move |old_status, new_status| {
+        status_changes_clone
+            .lock()
+            .expect("poisoned lock")
+            .push((old_status, new_status))
+    });
+    assert_eq!(SecureLayerStatus::AwaitingConnect, client_msl.status());
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    assert_eq!(SecureLayerStatus::AwaitingAck, client_msl.status());
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    assert_eq!(SecureLayerStatus::Established, client_msl.status());
+
+    // After close, client can't write but can still read in-flight messages
+    client_msl.close()?;
+    let result = send_user_msg(&mut client_msl, &mut server_msl, vec![1, 2, 3]);
+    if let Err(Error::ForbidWriteAfterClose) = result {
+    } else {
+        println!("unexpected result={:?}", result);
+        panic!();
+    }
+    send_user_msg(&mut server_msl, &mut client_msl, vec![3, 2, 1])?;
+
+    // A new connect message make the client fail
+    let (mut other_server_msl, _) = server_infos()?;
+    let result = send_connect_msg(&mut other_server_msl, &server_sig_kp, &mut client_msl, None);
+    if let Err(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedConnectMsg)) = result {
+    } else {
+        println!("unexpected result={:?}", result);
+        panic!();
+    }
+    let failed_status = SecureLayerStatus::Failed {
+        reason: FailReason::InvalidIncomingMsg(IncomingMsgErr::UnexpectedConnectMsg),
+    };
+    assert_eq!(failed_status, client_msl.status());
+
+    assert_eq!(
+        vec![
+            (
+                SecureLayerStatus::AwaitingConnect,
+                SecureLayerStatus::AwaitingAck
+            ),
+            (
+                SecureLayerStatus::AwaitingAck,
+                SecureLayerStatus::Established
+            ),
+            (SecureLayerStatus::Established, SecureLayerStatus::Closing),
+            (SecureLayerStatus::Closing, failed_status),
+        ],
+        *status_changes.lock().expect("poisoned lock")
+    );
+    Ok(())
+}
+
+#[test]
+fn expired_user_msg() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+    // Message not yet expired must be received
+    let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+    let expiry = SystemTime::now() + Duration::from_secs(60);
+    client_msl.write_message_with_expiry(&[1, 2, 3], expiry, &mut channel)?;
+    let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+    assert_eq!(
+        Some(Message::Message {
+            custom_data: Some(vec![1, 2, 3]),
+        }),
+        server_msl.read(&channel[..])?,
+    );
+    assert_eq!(0, server_msl.expired_msgs_count());
+
+    // Expired message must be dropped
+    let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+    let expiry = SystemTime::now() - Duration::from_secs(60);
+    client_msl.write_message_with_expiry(&[3, 2, 1], expiry, &mut channel)?;
+    let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+    assert_eq!(None, server_msl.read(&channel[..])?);
+    assert_eq!(1, server_msl.expired_msgs_count());
+
+    // Next messages are still received
+    send_user_msg(&mut client_msl, &mut server_msl, vec![4, 5, 6])
+}
+
+#[test]
+fn send_rate_limit() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+    // Burst of one frame of 100 bytes of data
+    let frame_overhead = SecureLayerConfig::default().frame_overhead();
+    client_msl.change_config(SecureLayerConfig {
+        send_rate_limit: Some(SendRateLimit {
+            bytes_per_sec: 1_000,
+            burst: (100 + frame_overhead) as u64,
+        }),
+        ..SecureLayerConfig::default()
+    })?;
+
+    // Burst is available
+    send_user_msg(&mut client_msl, &mut server_msl, vec![1; 100])?;
+
+    // Next message exceed burst, messages are charged with their frame length
+    let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+    let ready_at = match client_msl.write_message(&[2], &mut channel) {
+        Err(Error::RateLimited { ready_at }) => ready_at,
+        result => {
+            println!("unexpected result={:?}", result);
+            panic!();
+        }
+    };
+    assert!(ready_at > SystemTime::now());
+    assert_eq!(SecureLayerStatus::Established, client_msl.status());
+
+    // Message can be written from ready_at
+    std::thread::sleep(
+        ready_at
+            .duration_since(SystemTime::now())
+            .unwrap_or_else(|_| Duration::from_millis(0)),
+    );
+    send_user_msg(&mut client_msl, &mut server_msl, vec![2])
+}
+
+#[test]
+fn reordering_state() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    assert_eq!(None, server_msl.highest_nonce_received());
+
+    // Client write messages with nonces 0 to 3
+    let mut channels = Vec::new();
+    for i in 0..4 {
+        let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+        client_msl.write_message(&[i], &mut channel)?;
+        channels.push(channel.into_inner().map_err(|_| Error::BufferFlushError)?);
+    }
+
+    // Server receive nonces 0, 3 and 2
+    for i in &[0, 3, 2] {
+        server_msl.read(&channels[*i][..])?;
+    }
+    assert_eq!(
+        &[2, 3].iter().copied().collect::<BTreeSet<u64>>(),
+        server_msl.pending_orphans()
+    );
+    assert_eq!(Some(3), server_msl.highest_nonce_received());
+
+    // Server receive nonce 1
+    server_msl.read(&channels[1][..])?;
+    assert!(server_msl.pending_orphans().is_empty());
+    assert_eq!(Some(3), server_msl.highest_nonce_received());
+
+    Ok(())
+}
+
+#[test]
+fn read_all_releases_early_user_msgs() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    // Negotiation until server wait client ACK message
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+
+    // Client write its ACK message, but server receive a user message first
+    let ack_msg = client_msl.create_ack_message(None)?;
+    let sig = client_sig_kp.sign(&ack_msg);
+    assert_eq!(
+        None,
+        send_user_msg_inner(&mut client_msl, &mut server_msl, vec![1, 2, 3])?
+    );
+
+    // Reading the ACK message must also release the user message
+    let mut channel = ack_msg;
+    channel.extend_from_slice(sig.as_ref());
+    assert_eq!(
+        vec![
+            Message::Ack { custom_data: None },
+            Message::Message {
+                custom_data: Some(vec![1, 2, 3]),
+            },
+        ],
+        server_msl.read_all(&channel)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn retransmitted_connect_msg() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    // Client connect message is received twice
+    let mut connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+    let sig = client_sig_kp.sign(&connect_msg);
+    connect_msg.extend_from_slice(sig.as_ref());
+    assert!(server_msl.read(&connect_msg)?.is_some());
+    assert_eq!(None, server_msl.read(&connect_msg)?);
+
+    // Retransmission until the peer ACK message is still ignored
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    assert_eq!(None, server_msl.read(&connect_msg)?);
+
+    // The connect message is dropped once the peer ACK message is received
+    let buffered_bytes = server_msl.buffered_bytes();
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    assert_eq!(
+        buffered_bytes - connect_msg.len(),
+        server_msl.buffered_bytes()
+    );
+    send_user_msg(&mut client_msl, &mut server_msl, vec![1, 2, 3])?;
+    if let Err(Error::RecvInvalidMsg(IncomingMsgErr::UnexpectedConnectMsg)) =
+        server_msl.read(&connect_msg)
+    {
+        Ok(())
+    } else {
+        panic!("Expected error UnexpectedConnectMsg !")
+    }
+}
+
+#[test]
+fn reflected_connect_msg() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+
+    // Server connect message is reflected back to the server
+    let mut connect_msg =
+        server_msl.create_connect_message(server_sig_kp.public_key().as_ref(), None)?;
+    let sig = server_sig_kp.sign(&connect_msg);
+    connect_msg.extend_from_slice(sig.as_ref());
+    if let Err(Error::RecvInvalidMsg(IncomingMsgErr::ReflectedConnectMsg)) =
+        server_msl.read(&connect_msg)
+    {
+        assert_eq!(
+            SecureLayerStatus::Failed {
+                reason: FailReason::InvalidIncomingMsg(IncomingMsgErr::ReflectedConnectMsg),
+            },
+            server_msl.status()
+        );
+        Ok(())
+    } else {
+        panic!("Expected error ReflectedConnectMsg !")
+    }
+}
+
+#[test]
+fn truncated_frames_never_panic() -> Result<()> {
+    let (_, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    let mut connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+    let sig = client_sig_kp.sign(&connect_msg);
+    connect_msg.extend_from_slice(sig.as_ref());
+
+    // The signature is not part of the encapsuled message
+    let encapsuled_msg_end = connect_msg.len() - sig.as_ref().len();
+    for len in 0..connect_msg.len() {
+        let (mut server_msl, _) = server_infos()?;
+        assert_eq!(
+            len >= encapsuled_msg_end,
+            parse_untrusted(&connect_msg[..len]).is_ok()
+        );
+        assert!(server_msl.read(&connect_msg[..len]).is_err());
+    }
+    assert!(parse_untrusted(&connect_msg).is_ok());
+
+    Ok(())
+}
+
+#[test]
+fn truncated_and_oversized_frames() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+    server_msl.change_config(SecureLayerConfig {
+        max_frame_len: Some(200),
+        ..SecureLayerConfig::default()
+    })?;
+
+    // Truncated frame is rejected without breaking the session
+    let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+    client_msl.write_message(&[1, 2, 3], &mut channel)?;
+    let frame = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+    if let Err(Error::FrameTruncated { got, .. }) = server_msl.read(&frame[..10]) {
+        assert_eq!(10, got);
+    } else {
+        panic!("unexpected result");
+    }
+    assert_eq!(SecureLayerStatus::Established, server_msl.status());
+    assert_eq!(
+        Some(Message::Message {
+            custom_data: Some(vec![1, 2, 3]),
+        }),
+        server_msl.read(&frame)?
+    );
+
+    // Oversized frame is rejected without breaking the session
+    let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+    client_msl.write_message(&[4; 300], &mut channel)?;
+    let frame = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+    if let Err(Error::FrameTooLarge { max, got }) = server_msl.read(&frame) {
+        assert_eq!(200, max);
+        assert_eq!(frame.len(), got);
+    } else {
+        panic!("unexpected result");
+    }
+    assert_eq!(SecureLayerStatus::Established, server_msl.status());
+    send_user_msg(&mut client_msl, &mut server_msl, vec![5; 10])
+}
+
+#[test]
+fn uniform_handshake_rejection() -> Result<()> {
+    let uniform_config = SecureLayerConfig {
+        uniform_handshake_rejection: true,
+        ..SecureLayerConfig::default()
+    };
+    let (_, server_sig_kp) = server_infos()?;
+    let (mut middle_msl, middle_sig_kp) = server_infos()?;
+
+    // Unexpected identity
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    client_msl.change_config(uniform_config)?;
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut middle_msl, None)?;
+    let result = send_connect_msg_inner(&mut middle_msl, &middle_sig_kp, &mut client_msl, None);
+    if let Err(Error::HandshakeRejected) = result {
+        assert_eq!(
+            SecureLayerStatus::Failed {
+                reason: FailReason::UnexpectedRemoteSigPubKey,
+            },
+            client_msl.status()
+        );
+    } else {
+        println!("unexpected result={:?}", result);
+        panic!();
+    }
+
+    // Invalid signature
+    let (mut server_msl, _) = server_infos()?;
+    server_msl.change_config(uniform_config)?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    let mut connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+    connect_msg.extend_from_slice(&[0u8; 64]);
+    if let Err(Error::HandshakeRejected) = server_msl.read(&connect_msg) {
+        assert_eq!(
+            SecureLayerStatus::Failed {
+                reason: FailReason::InvalidIncomingMsg(IncomingMsgErr::InvalidHashOrSig),
+            },
+            server_msl.status()
+        );
+    } else {
+        panic!("unexpected result");
+    }
+
+    // Invalid magic value
+    let (mut server_msl, _) = server_infos()?;
+    server_msl.change_config(uniform_config)?;
+    connect_msg[0] ^= 1;
+    if let Err(Error::HandshakeRejected) = server_msl.read(&connect_msg) {
+        assert_eq!(
+            SecureLayerStatus::Failed {
+                reason: FailReason::InvalidIncomingMsg(IncomingMsgErr::UnexpectedMessage),
+            },
+            server_msl.status()
+        );
+    } else {
+        panic!("unexpected result");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn memory_budget() -> Result<()> {
+    let budget = MemoryBudget::new(400);
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    server_msl.set_memory_budget(budget.clone());
+
+    // Negotiation until server wait client ACK message
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+
+    // The accepted CONNECT message is kept to detect its retransmissions
+    let connect_msg_len = server_msl.buffered_bytes();
+    assert!(connect_msg_len > 0);
+    assert_eq!(connect_msg_len, budget.used());
+
+    // User messages received too early are buffered within the budget
+    let ack_msg = client_msl.create_ack_message(None)?;
+    let sig = client_sig_kp.sign(&ack_msg);
+    assert_eq!(
+        None,
+        send_user_msg_inner(&mut client_msl, &mut server_msl, vec![1; 100])?
+    );
+    assert!(budget.used() > connect_msg_len + 100);
+    assert_eq!(server_msl.buffered_bytes(), budget.used());
+    if let Err(Error::MemoryBudgetExceeded) =
+        send_user_msg_inner(&mut client_msl, &mut server_msl, vec![2; 200])
+    {
+        assert_eq!(server_msl.buffered_bytes(), budget.used());
+    } else {
+        panic!("unexpected result");
+    }
+    assert_eq!(SecureLayerStatus::AwaitingAck, server_msl.status());
+
+    // Reading the ACK message releases buffered user message and the CONNECT message
+    let mut channel = ack_msg;
+    channel.extend_from_slice(sig.as_ref());
+    assert_eq!(2, server_msl.read_all(&channel)?.len());
+    assert_eq!(0, budget.used());
+
+    // Memory is released on drop
+    drop(server_msl);
+    assert_eq!(0, budget.used());
+
+    Ok(())
+}
+
+#[test]
+fn read_message_view() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    // CONNECT message view
+    let connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), Some(&[1, 2, 3]))?;
+    let mut channel = connect_msg;
+    channel.extend_from_slice(client_sig_kp.sign(&channel).as_ref());
+    let view = server_msl
+        .read_view(&channel)?
+        .expect("Must receive a message");
+    assert_eq!(
+        MessageView::Connect {
+            sig_algo: SIG_ALGO_ED25519_ARRAY,
+            sig_pubkey: client_sig_kp.public_key().as_ref(),
+            custom_data: Some(&[1, 2, 3]),
+        },
+        view
+    );
+    assert_eq!(
+        Message::Connect {
+            sig_algo: SIG_ALGO_ED25519_ARRAY,
+            sig_pubkey: client_sig_kp.public_key().as_ref().to_vec(),
+            custom_data: Some(vec![1, 2, 3]),
+        },
+        view.to_message()
+    );
+
+    // Negotiation
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+    // User message views
+    for data in &[vec![4, 5, 6], vec![7; 1_000]] {
+        let mut channel = BufWriter::new(Vec::with_capacity(1_100));
+        client_msl.write_message(data, &mut channel)?;
+        let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+        assert_eq!(
+            Some(MessageView::Message {
+                custom_data: Some(&data[..])
+            }),
+            server_msl.read_view(&channel)?
+        );
+    }
+
+    // Replayed message is still rejected
+    let mut channel = BufWriter::new(Vec::with_capacity(100));
+    client_msl.write_message(&[8], &mut channel)?;
+    let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+    server_msl.read_view(&channel)?;
+    if let Err(Error::RecvInvalidMsg(IncomingMsgErr::InvalidNonce)) = server_msl.read_view(&channel)
+    {
+        Ok(())
+    } else {
+        panic!("unexpected result");
+    }
+}
+
+#[test]
+fn session_stats() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+    // Handshake messages are not counted
+    let stats = server_msl.stats();
+    assert_eq!(SecureLayerStatus::Established, stats.status);
+    assert_eq!(0, stats.user_msgs_sent);
+    assert_eq!(0, stats.user_msgs_received);
+
+    send_user_msg(&mut client_msl, &mut server_msl, vec![1; 10])?;
+    send_user_msg(&mut client_msl, &mut server_msl, vec![2; 20])?;
+    send_user_msg(&mut server_msl, &mut client_msl, vec![3; 5])?;
+
+    let stats = server_msl.stats();
+    assert_eq!(1, stats.user_msgs_sent);
+    assert_eq!(5, stats.user_bytes_sent);
+    assert_eq!(2, stats.user_msgs_received);
+    assert_eq!(30, stats.user_bytes_received);
+    assert_eq!(0, stats.pending_orphans);
+
+    let stats = client_msl.stats();
+    assert_eq!(2, stats.user_msgs_sent);
+    assert_eq!(30, stats.user_bytes_sent);
+    assert_eq!(1, stats.user_msgs_received);
+    assert_eq!(5, stats.user_bytes_received);
+    Ok(())
+}
+
+#[test]
+fn custom_clock() -> Result<()> {
+    #[derive(Debug)]
+    struct ManualClock(AtomicU64);
+    impl Clock for ManualClock {
+        fn now(&self) -> SystemTime {
+            UNIX_EPOCH + Duration::from_secs(self.0.load(Ordering::SeqCst))
+        }
+    }
+    static CLOCK: ManualClock = ManualClock(AtomicU64::new(1_000));
+    let config = SecureLayerConfig {
+        clock: &CLOCK,
+        send_rate_limit: Some(SendRateLimit {
+            bytes_per_sec: 100,
+            burst: 100,
+        }),
+        ..SecureLayerConfig::default()
+    };
+
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    server_msl.change_config(config)?;
+    client_msl.change_config(config)?;
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+    // Rate limit follows the clock
+    let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+    let expiry = CLOCK.now() + Duration::from_secs(10);
+    client_msl.write_message_with_expiry(&[1; 50], expiry, &mut channel)?;
+    let ready_at = match client_msl.write_message(&[2; 50], &mut BufWriter::new(Vec::new())) {
+        Err(Error::RateLimited { ready_at }) => ready_at,
+        result => {
+            println!("unexpected result={:?}", result);
+            panic!();
+        }
+    };
+    assert!(ready_at > CLOCK.now());
+    assert!(ready_at <= CLOCK.now() + Duration::from_secs(2));
+
+    // Expiry follows the clock
+    CLOCK.0.fetch_add(60, Ordering::SeqCst);
+    let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+    assert_eq!(None, server_msl.read(&channel)?);
+    assert_eq!(1, server_msl.expired_msgs_count());
+    send_user_msg(&mut client_msl, &mut server_msl, vec![3; 50])
+}
+
+#[test]
+fn keepalive() -> Result<()> {
+    #[derive(Debug)]
+    struct ManualClock(AtomicU64);
+    impl Clock for ManualClock {
+        fn now(&self) -> SystemTime {
+            UNIX_EPOCH + Duration::from_secs(self.0.load(Ordering::SeqCst))
+        }
+    }
+    static CLOCK: ManualClock = ManualClock(AtomicU64::new(1_000));
+    let config = SecureLayerConfig {
+        clock: &CLOCK,
+        keepalive_interval: Some(Duration::from_secs(30)),
+        ..SecureLayerConfig::default()
+    };
+
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    server_msl.change_config(config)?;
+    client_msl.change_config(config)?;
+    assert_eq!(None, client_msl.poll_timeout());
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    let timeout = CLOCK.now() + Duration::from_secs(30);
+    assert_eq!(Some(timeout), client_msl.poll_timeout());
+
+    // Nothing to do before the timeout
+    let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+    assert!(!client_msl.handle_timeout(&mut channel)?);
+    assert!(channel.buffer().is_empty());
+
+    // Sending a user message postpones the keepalive
+    CLOCK.0.fetch_add(20, Ordering::SeqCst);
+    send_user_msg(&mut client_msl, &mut server_msl, vec![1, 2, 3])?;
+    assert_eq!(
+        Some(CLOCK.now() + Duration::from_secs(30)),
+        client_msl.poll_timeout()
+    );
+
+    // Keepalive is sent on timeout and not delivered to the peer application
+    CLOCK.0.fetch_add(30, Ordering::SeqCst);
+    assert!(client_msl.handle_timeout(&mut channel)?);
+    let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+    assert_eq!(None, server_msl.read(&channel)?);
+    assert_eq!(1, client_msl.stats().user_msgs_sent);
+    assert_eq!(1, server_msl.stats().user_msgs_received);
+    assert_eq!(
+        Some(CLOCK.now() + Duration::from_secs(30)),
+        client_msl.poll_timeout()
+    );
+
+    // Keepalive consumes a nonce, it cannot be replayed
+    if let Err(Error::RecvInvalidMsg(IncomingMsgErr::InvalidNonce)) = server_msl.read(&channel) {
+    } else {
+        panic!("unexpected result");
+    }
+    send_user_msg(&mut client_msl, &mut server_msl, vec![4])?;
+
+    // No keepalive once closed
+    client_msl.close()?;
+    assert_eq!(None, client_msl.poll_timeout());
+    Ok(())
+}
+
+#[test]
+fn frame_padding() -> Result<()> {
+    for user_msg_hash in &[true, false] {
+        let config = SecureLayerConfig {
+            user_msg_hash: *user_msg_hash,
+            frame_padding: Some(256),
+            ..SecureLayerConfig::default()
+        };
+
+        let (mut server_msl, server_sig_kp) = server_infos()?;
+        let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+        server_msl.change_config(config)?;
+        client_msl.change_config(config)?;
+
+        // Negotiation
+        send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+        send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+        send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+        send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+        // Frame lengths are multiples of the block length, on stack and heap paths
+        let max_len = config.max_plaintext_for_frame(512);
+        for len in &[1, 100, max_len, max_len + 1, 2_000] {
+            let data = vec![7u8; *len];
+            let mut channel = BufWriter::new(Vec::with_capacity(3_000));
+            client_msl.write_message(&data, &mut channel)?;
+            let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+            assert_eq!(0, channel.len() % 256);
+            if *len == max_len {
+                assert_eq!(512, channel.len());
+            }
+            assert_eq!(
+                Some(Message::Message {
+                    custom_data: Some(data),
+                }),
+                server_msl.read(&channel)?
+            );
+        }
+        send_user_msg(&mut server_msl, &mut client_msl, vec![1, 2, 3])?;
+    }
+    Ok(())
+}
+
+#[test]
+fn frame_padding_one_side() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    client_msl.change_config(SecureLayerConfig {
+        frame_padding: Some(256),
+        ..SecureLayerConfig::default()
+    })?;
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+    // Only client frames are padded, each peer reads the frames of the other
+    for (client_writes, expected_len) in &[
+        (true, 256),
+        (false, 3 + SecureLayerConfig::default().frame_overhead()),
+    ] {
+        let mut channel = BufWriter::new(Vec::with_capacity(300));
+        if *client_writes {
+            client_msl.write_message(&[1, 2, 3], &mut channel)?;
+        } else {
+            server_msl.write_message(&[1, 2, 3], &mut channel)?;
+        }
+        let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+        assert_eq!(*expected_len, channel.len());
+        let msg = if *client_writes {
+            server_msl.read(&channel)?
+        } else {
+            client_msl.read(&channel)?
+        };
+        assert_eq!(
+            Some(Message::Message {
+                custom_data: Some(vec![1, 2, 3]),
+            }),
+            msg
+        );
+    }
+    Ok(())
+}
+
+#[test]
+fn key_context() -> Result<()> {
+    for (client_context, server_context) in &[("api:ws2p-v2", "api:ws2p-v2"), ("api:ws2p-v2", "")] {
+        let (mut server_msl, server_sig_kp) = server_infos()?;
+        let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+        server_msl.change_config(SecureLayerConfig {
+            key_context: server_context,
+            ..SecureLayerConfig::default()
+        })?;
+        client_msl.change_config(SecureLayerConfig {
+            key_context: client_context,
+            ..SecureLayerConfig::default()
+        })?;
+
+        // Negotiation does not depend on the context
+        send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+        send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+        send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+        send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+        // Encrypted messages require the same context
+        if client_context == server_context {
+            send_user_msg(&mut client_msl, &mut server_msl, vec![1, 2, 3])?;
+        } else if let Err(Error::FailToDecryptData(_)) =
+            send_user_msg_inner(&mut client_msl, &mut server_msl, vec![1, 2, 3])
+        {
+        } else {
+            panic!("Expected error FailToDecryptData !")
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn cover_traffic() -> Result<()> {
+    #[derive(Debug)]
+    struct ManualClock(AtomicU64);
+    impl Clock for ManualClock {
+        fn now(&self) -> SystemTime {
+            UNIX_EPOCH + Duration::from_secs(self.0.load(Ordering::SeqCst))
+        }
+    }
+    static CLOCK: ManualClock = ManualClock(AtomicU64::new(1_000));
+    let config = SecureLayerConfig {
+        clock: &CLOCK,
+        keepalive_interval: Some(Duration::from_secs(30)),
+        frame_padding: Some(128),
+        cover_traffic: Some(CoverTraffic {
+            min_interval: Duration::from_secs(10),
+            max_interval: Duration::from_secs(10),
+        }),
+        ..SecureLayerConfig::default()
+    };
+
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    server_msl.change_config(config)?;
+    client_msl.change_config(config)?;
+    assert_eq!(None, client_msl.poll_timeout());
+
+    // Negotiation schedules the first cover frame
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    assert_eq!(
+        Some(CLOCK.now() + Duration::from_secs(10)),
+        client_msl.poll_timeout()
+    );
+
+    // Cover frame is sent on timeout, padded like user messages, and dropped by the peer
+    let mut user_frame = BufWriter::new(Vec::with_capacity(1_000));
+    client_msl.write_message(&[1, 2, 3], &mut user_frame)?;
+    let user_frame = user_frame
+        .into_inner()
+        .map_err(|_| Error::BufferFlushError)?;
+    CLOCK.0.fetch_add(10, Ordering::SeqCst);
+    let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+    assert!(client_msl.handle_timeout(&mut channel)?);
+    let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+    assert_eq!(user_frame.len(), channel.len());
+    assert_eq!(None, server_msl.read(&channel)?);
+    assert!(server_msl.read(&user_frame)?.is_some());
+    assert_eq!(1, client_msl.stats().user_msgs_sent);
+    assert_eq!(1, server_msl.stats().user_msgs_received);
+
+    // Next cover frame is rescheduled, it does not postpone the keepalive
+    assert_eq!(
+        Some(CLOCK.now() + Duration::from_secs(10)),
+        client_msl.poll_timeout()
+    );
+    CLOCK.0.fetch_add(20, Ordering::SeqCst);
+    let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+    assert!(client_msl.handle_timeout(&mut channel)?);
+    let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+    assert_eq!(2 * user_frame.len(), channel.len());
+
+    // Cover frame consumes a nonce, it cannot be replayed
+    assert_eq!(None, server_msl.read(&channel[..user_frame.len()])?);
+    if let Err(Error::RecvInvalidMsg(IncomingMsgErr::InvalidNonce)) =
+        server_msl.read(&channel[..user_frame.len()])
+    {
+    } else {
+        panic!("unexpected result");
+    }
+    send_user_msg(&mut client_msl, &mut server_msl, vec![4])?;
+
+    // No cover frame once closed
+    client_msl.close()?;
+    assert_eq!(None, client_msl.poll_timeout());
+    Ok(())
+}
+
+#[test]
+fn slow_consumer() -> Result<()> {
+    #[derive(Debug)]
+    struct ManualClock(AtomicU64);
+    impl Clock for ManualClock {
+        fn now(&self) -> SystemTime {
+            UNIX_EPOCH + Duration::from_secs(self.0.load(Ordering::SeqCst))
+        }
+    }
+    static CLOCK: ManualClock = ManualClock(AtomicU64::new(1_000));
+    let config = SecureLayerConfig {
+        clock: &CLOCK,
+        ..SecureLayerConfig::default()
+    };
+
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    server_msl.change_config(config)?;
+    client_msl.change_config(config)?;
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+    // The accepted CONNECT message remains buffered
+    let connect_msg_len = server_msl.buffered_bytes();
+    let warnings = Arc::new(Mutex::new(Vec::new()));
+    let warnings_clone = warnings.clone();
+    server_msl.on_slow_consumer(connect_msg_len + 16, move |warning| {
+        warnings_clone
+            .lock()
+            .expect("warnings mutex poisoned")
+            .push(warning)
+    });
+
+    // Messages read out of order are buffered as orphan nonces (8 bytes each)
+    let mut frames = Vec::new();
+    for size in &[10usize, 100, 1_000, 5_000] {
+        let mut channel = BufWriter::new(Vec::with_capacity(6_000));
+        client_msl.write_message(&vec![1u8; *size], &mut channel)?;
+        frames.push(channel.into_inner().map_err(|_| Error::BufferFlushError)?);
+    }
+    for frame in frames[1..].iter().rev() {
+        CLOCK.0.fetch_add(5, Ordering::SeqCst);
+        assert!(server_msl.read(frame)?.is_some());
+    }
+
+    // Warned once, when the buffered bytes exceed the threshold
+    assert_eq!(
+        vec![SlowConsumerWarning {
+            buffered_bytes: connect_msg_len + 24,
+            read_interval: Some(Duration::from_secs(5)),
+        }],
+        *warnings.lock().expect("warnings mutex poisoned")
+    );
+
+    // Warned again once the buffered bytes have fallen below the threshold
+    CLOCK.0.fetch_add(20, Ordering::SeqCst);
+    assert!(server_msl.read(&frames[0])?.is_some());
+    for _ in 0..4 {
+        let mut channel = BufWriter::new(Vec::with_capacity(100));
+        client_msl.write_message(&[2u8; 5], &mut channel)?;
+        frames.push(channel.into_inner().map_err(|_| Error::BufferFlushError)?);
+    }
+    for frame in frames[5..].iter().rev() {
+        assert!(server_msl.read(frame)?.is_some());
+    }
+    assert_eq!(2, warnings.lock().expect("warnings mutex poisoned").len());
+    assert!(server_msl.read(&frames[4])?.is_some());
+
+    // Sizes of received messages and longest time between reads
+    let stats = server_msl.stats();
+    let counts = stats.received_sizes.counts();
+    assert_eq!(SIZE_HISTOGRAM_BUCKETS, counts.len());
+    assert_eq!(Some(64), SizeHistogram::upper_bound(0));
+    assert_eq!(None, SizeHistogram::upper_bound(SIZE_HISTOGRAM_BUCKETS - 1));
+    assert_eq!([5, 1, 0, 0, 1, 0, 0, 1], counts[..8]);
+    assert_eq!(stats.user_msgs_received, counts.iter().sum::<u64>());
+    assert_eq!(Some(Duration::from_secs(20)), stats.max_read_interval);
+    Ok(())
+}
+
+#[test]
+fn version_reject() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    assert_eq!(None, server_msl.take_version_reject_message());
+
+    // The client speaks an unsupported version
+    let mut connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+    connect_msg[4..8].copy_from_slice(&[0, 0, 0, 3]);
+    let sig = client_sig_kp.sign(&connect_msg);
+    connect_msg.extend_from_slice(sig.as_ref());
+    if let Err(Error::RecvInvalidMsg(IncomingMsgErr::UnsupportedVersion)) =
+        server_msl.read(&connect_msg)
+    {
+    } else {
+        panic!("unexpected result");
+    }
+
+    // The server tells the client which versions it speaks
+    let version_reject_msg = server_msl
+        .take_version_reject_message()
+        .expect("version reject message must be available");
+    assert_eq!(None, server_msl.take_version_reject_message());
+    if let Err(Error::VersionRejected {
+        min_version,
+        max_version,
+    }) = client_msl.read(&version_reject_msg)
+    {
+        assert_eq!((2, 2), (min_version, max_version));
+    } else {
+        panic!("unexpected result");
+    }
+    assert_eq!(
+        SecureLayerStatus::Failed {
+            reason: FailReason::VersionRejected {
+                min_version: 2,
+                max_version: 2,
+            }
+        },
+        client_msl.status()
+    );
+    Ok(())
+}
+
+#[test]
+fn custom_digest_backend() -> Result<()> {
+    #[derive(Debug)]
+    struct CountingDigest(AtomicUsize);
+    impl Digest for CountingDigest {
+        fn sha256(&self, datas: &[u8]) -> [u8; 32] {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            RING_DIGEST.sha256(datas)
+        }
+    }
+    static COUNTING_DIGEST: CountingDigest = CountingDigest(AtomicUsize::new(0));
+
+    let config = SecureLayerConfig {
+        digest: &COUNTING_DIGEST,
+        ..SecureLayerConfig::default()
+    };
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    server_msl.change_config(config)?;
+    client_msl.change_config(config)?;
+
+    // Negotiation and user messages
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_user_msg(&mut client_msl, &mut server_msl, vec![1, 2, 3])?;
+
+    assert!(COUNTING_DIGEST.0.load(Ordering::SeqCst) > 0);
+    Ok(())
+}
+
+#[cfg(feature = "keylog")]
+#[test]
+fn key_log() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    let lines = Arc::new(Mutex::new(Vec::new()));
+    let lines_clone = lines.clone();
+    client_msl.set_key_log_sink(move |line| {
+        lines_clone
+            .lock()
+            .expect("poisoned lock")
+            .push(line.to_owned())
+    });
+    let lines_clone = lines.clone();
+    server_msl.set_key_log_sink(move |line| {
+        lines_clone
+            .lock()
+            .expect("poisoned lock")
+            .push(line.to_owned())
+    });
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+
+    // Both peers must log the same session secret
+    let lines = lines.lock().expect("poisoned lock");
+    assert_eq!(2, lines.len());
+    assert_eq!(lines[0], lines[1]);
+    let fields = lines[0].split(' ').collect::<Vec<_>>();
+    assert_eq!("PKSTL_SESSION_SECRET", fields[0]);
+    assert_eq!(
+        to_multibase(
+            &client_msl
+                .session_fingerprint()
+                .expect("fingerprint must be computed"),
+            MultibaseEncoding::Base16
+        ),
+        format!("f{}", fields[1])
+    );
+    assert_eq!(96, fields[2].len());
+
+    Ok(())
+}
+
+#[cfg(feature = "keylog")]
+#[test]
+fn offline_verification() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    let line = Arc::new(Mutex::new(String::new()));
+    let line_clone = line.clone();
+    client_msl.set_key_log_sink(move |l| *line_clone.lock().expect("poisoned lock") = l.to_owned());
+
+    // Capture frames on client side
+    let mut frames = Vec::new();
+    let mut client_connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+    client_connect_msg.extend_from_slice(client_sig_kp.sign(&client_connect_msg).as_ref());
+    server_msl.read(&client_connect_msg)?;
+    frames.push((CaptureSide::Local, client_connect_msg));
+    let mut server_connect_msg =
+        server_msl.create_connect_message(server_sig_kp.public_key().as_ref(), None)?;
+    server_connect_msg.extend_from_slice(server_sig_kp.sign(&server_connect_msg).as_ref());
+    client_msl.read(&server_connect_msg)?;
+    frames.push((CaptureSide::Remote, server_connect_msg));
+    let mut server_ack_msg = server_msl.create_ack_message(None)?;
+    server_ack_msg.extend_from_slice(server_sig_kp.sign(&server_ack_msg).as_ref());
+    client_msl.read(&server_ack_msg)?;
+    frames.push((CaptureSide::Remote, server_ack_msg));
+    let mut client_ack_msg = client_msl.create_ack_message(None)?;
+    client_ack_msg.extend_from_slice(client_sig_kp.sign(&client_ack_msg).as_ref());
+    server_msl.read(&client_ack_msg)?;
+    frames.push((CaptureSide::Local, client_ack_msg));
+    for data in &[&b"hello"[..], &b"world"[..]] {
+        let mut channel = BufWriter::new(Vec::new());
+        server_msl.write_message(data, &mut channel)?;
+        let user_msg = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+        frames.push((CaptureSide::Remote, user_msg));
+    }
+
+    let verifier = OfflineVerifier::from_key_log_line(
+        SecureLayerConfig::default(),
+        &line.lock().expect("poisoned lock"),
+    )?;
+    let captured = frames
+        .iter()
+        .map(|(side, frame)| (*side, &frame[..]))
+        .collect::<Vec<_>>();
+
+    // All captured frames are valid
+    let report = verifier.verify(&captured);
+    assert!(report.is_valid());
+    assert_eq!(Some(true), report.fingerprint_matches);
+    assert_eq!(
+        Ok(VerifiedFrame::Connect {
+            sig_pubkey: server_sig_kp.public_key().as_ref().to_vec(),
+        }),
+        report.frames[1].result
+    );
+    assert_eq!(Ok(VerifiedFrame::Ack), report.frames[2].result);
+
+    // Replayed user message is detected
+    let mut replayed = captured.clone();
+    replayed.push(captured[4]);
+    let report = verifier.verify(&replayed);
+    assert!(!report.is_valid());
+    assert_eq!(
+        Err(FailReason::InvalidIncomingMsg(IncomingMsgErr::InvalidNonce)),
+        report.frames[6].result
+    );
+
+    // Tampered user message is detected
+    let mut tampered_frame = frames[5].1.clone();
+    let last = tampered_frame.len() - 1;
+    tampered_frame[last] ^= 1;
+    let mut tampered = captured.clone();
+    tampered[5] = (CaptureSide::Remote, &tampered_frame[..]);
+    let report = verifier.verify(&tampered);
+    assert!(!report.is_valid());
+    assert!(report.frames[5].result.is_err());
+
+    // A wrong session secret fails all user messages, handshake messages are in clear
+    let verifier = OfflineVerifier::new(SecureLayerConfig::default(), &[0u8; 48])?;
+    let report = verifier.verify(&captured);
+    assert_eq!(None, report.fingerprint_matches);
+    assert!(report.frames[..4].iter().all(|frame| frame.result.is_ok()));
+    assert_eq!(Err(FailReason::FailToDecryptData), report.frames[4].result);
+
+    Ok(())
+}
+
+#[test]
+fn sig_requirements() -> Result<()> {
+    let server_sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+        .map_err(|_| Error::FailtoGenSigKeyPair)?;
+    let client_sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+        .map_err(|_| Error::FailtoGenSigKeyPair)?;
+
+    for client_sig_requirement in &[SigRequirement::Anonymous, SigRequirement::AckOnly] {
+        let mut server_msl = MinimalSecureLayer::create(
+            SecureLayerConfig {
+                peer_sig_requirement: *client_sig_requirement,
+                ..SecureLayerConfig::default()
+            },
+            None,
+        )?;
+        let mut client_msl = MinimalSecureLayer::create(
+            SecureLayerConfig {
+                local_sig_requirement: *client_sig_requirement,
+                ..SecureLayerConfig::default()
+            },
+            Some(server_sig_kp.public_key().as_ref().to_vec()),
+        )?;
+
+        // Client CONNECT message is not signed
+        let client_connect_msg =
+            client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+        assert!(server_msl.read(&client_connect_msg)?.is_some());
+        send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+        send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+
+        // Client ACK message is signed only if required
+        let mut client_ack_msg = client_msl.create_ack_message(None)?;
+        if *client_sig_requirement == SigRequirement::AckOnly {
+            client_ack_msg.extend_from_slice(client_sig_kp.sign(&client_ack_msg).as_ref());
+        }
+        assert!(server_msl.read(&client_ack_msg)?.is_some());
+        assert_eq!(SecureLayerStatus::Established, server_msl.status());
+        assert_eq!(SecureLayerStatus::Established, client_msl.status());
+
+        // The public key of an anonymous client is not authenticated
+        assert_eq!(
+            *client_sig_requirement == SigRequirement::AckOnly,
+            server_msl.peer_sig_public_key().is_some()
+        );
+
+        send_user_msg(&mut client_msl, &mut server_msl, vec![1, 2, 3])?;
+        send_user_msg(&mut server_msl, &mut client_msl, vec![4, 5, 6])?;
+    }
+
+    // A peer signing its ACK message alone must cover its ephemeral public key in the challenge
+    let mut server_msl = MinimalSecureLayer::create(
+        SecureLayerConfig {
+            peer_sig_requirement: SigRequirement::AckOnly,
+            ..SecureLayerConfig::default()
+        },
+        None,
+    )?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    let result = send_ack_msg_inner(&mut client_msl, &client_sig_kp, &mut server_msl, None);
+    if let Err(Error::RecvInvalidMsg(e)) = result {
+        assert_eq!(IncomingMsgErr::InvalidChallenge, e);
+    } else {
+        panic!("Expected error RecvInvalidMsg(InvalidChallenge)");
+    }
+
+    // A peer required to sign both handshake messages cannot send an unsigned CONNECT message
+    let (mut server_msl, _) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    let client_connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+    if let Err(Error::RecvInvalidMsg(e)) = server_msl.read(&client_connect_msg) {
+        assert_eq!(IncomingMsgErr::InvalidHashOrSig, e);
+    } else {
+        panic!("Expected error RecvInvalidMsg(InvalidHashOrSig)");
+    }
+
+    Ok(())
+}
+
+#[test]
+fn custom_data_validator() -> Result<()> {
+    const TOKEN: &[u8] = b"api-token";
+
+    // The response of the client, bound to the session
+    fn response(session_fingerprint: &[u8; 32]) -> [u8; 32] {
+        RING_DIGEST.sha256(&[TOKEN, &session_fingerprint[..]].concat())
+    }
+
+    for valid_response in &[true, false] {
+        let (mut server_msl, server_sig_kp) = server_infos()?;
+        let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+        server_msl.set_custom_data_validator(|message, session_fingerprint| match message {
+            MessageView::Connect { custom_data, .. } => custom_data.is_none(),
+            MessageView::Ack { custom_data } => match session_fingerprint {
+                Some(session_fingerprint) => {
+                    *custom_data == Some(&response(session_fingerprint)[..])
+                }
+                None => false,
+            },
+            MessageView::Message { .. } => false,
+        });
+
+        // Negotiation
+        send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+        send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+        send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+        let mut client_response = response(
+            &client_msl
+                .session_fingerprint()
+                .expect("fingerprint must be computed"),
+        );
+        if !valid_response {
+            client_response[0] ^= 1;
+        }
+        let result = send_ack_msg_inner(
+            &mut client_msl,
+            &client_sig_kp,
+            &mut server_msl,
+            Some(&client_response),
+        );
+
+        if *valid_response {
+            assert!(result?.is_some());
+            assert_eq!(SecureLayerStatus::Established, server_msl.status());
+        } else {
+            if let Err(Error::RecvInvalidMsg(e)) = result {
+                assert_eq!(IncomingMsgErr::RejectedCustomData, e);
+            } else {
+                panic!("Expected error RecvInvalidMsg(RejectedCustomData)");
+            }
+            assert_eq!(
+                SecureLayerStatus::Failed {
+                    reason: FailReason::InvalidIncomingMsg(IncomingMsgErr::RejectedCustomData)
+                },
+                server_msl.status()
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[test]
+fn heartbeat_payload() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    let heartbeats = Arc::new(Mutex::new(Vec::new()));
+    let heartbeats_clone = heartbeats.clone();
+    server_msl.on_heartbeat(move |payload| {
+        heartbeats_clone
+            .lock()
+            .expect("poisoned lock")
+            .push(payload.to_vec())
+    });
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+    // Keepalive messages carry the current payload, given to the hook instead of delivered
+    for payload in &[None, Some(42u64.to_be_bytes())] {
+        if let Some(payload) = payload {
+            client_msl.set_heartbeat_payload(payload);
+        }
+        let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+        client_msl.write_keepalive(&mut channel)?;
+        let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+        assert_eq!(None, server_msl.read(&channel)?);
+    }
+    assert_eq!(
+        vec![vec![], 42u64.to_be_bytes().to_vec()],
+        *heartbeats.lock().expect("poisoned lock")
+    );
+    assert_eq!(0, server_msl.stats().user_msgs_received);
+
+    Ok(())
+}
+
+#[test]
+fn frame_overhead() -> Result<()> {
+    for user_msg_hash in &[true, false] {
+        let config = SecureLayerConfig {
+            user_msg_hash: *user_msg_hash,
+            ..SecureLayerConfig::default()
+        };
+        let (mut server_msl, server_sig_kp) = server_infos()?;
+        let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+        server_msl.change_config(config)?;
+        client_msl.change_config(config)?;
+
+        // Negotiation
+        send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+        send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+        send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+        send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+        // Exact frame length
+        let frame_len = 1_000;
+        let data = vec![7u8; config.max_plaintext_for_frame(frame_len)];
+        let mut channel = BufWriter::new(Vec::with_capacity(frame_len));
+        client_msl.write_message(&data, &mut channel)?;
+        let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+        assert_eq!(frame_len, channel.len());
+        assert_eq!(data.len() + config.frame_overhead(), channel.len());
+        assert_eq!(
+            Some(Message::Message {
+                custom_data: Some(data)
+            }),
+            server_msl.read(&channel)?
+        );
+    }
+    assert_eq!(0, SecureLayerConfig::default().max_plaintext_for_frame(10));
+
+    Ok(())
+}
+
+#[test]
+fn reserved_nonces() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    // Nonces can only be reserved once the negotiation is successful
+    if let Err(Error::NegoMustHaveBeenSuccessful) = client_msl.reserve_nonces(3) {
+    } else {
+        panic!("Expected error NegoMustHaveBeenSuccessful !")
+    }
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+    // Each writer thread encrypts its message with its own reserved nonce
+    let handles = (0..3u8)
+        .map(|i| {
+            let mut reserved = client_msl.reserve_nonces(1)?;
+            Ok(std::thread::spawn(move || -> Result<(u64, Vec<u8>)> {
+                let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+                let nonce = reserved.write_message(&[i], &mut channel)?;
+                if let Err(Error::NoncesExhausted) = reserved.write_message(&[i], &mut channel) {
+                } else {
+                    panic!("Expected error NoncesExhausted !")
+                }
+                Ok((
+                    nonce,
+                    channel.into_inner().map_err(|_| Error::BufferFlushError)?,
+                ))
+            }))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let frames = handles
+        .into_iter()
+        .map(|handle| handle.join().expect("writer thread panicked"))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        vec![0, 1, 2],
+        frames.iter().map(|(nonce, _)| *nonce).collect::<Vec<_>>()
+    );
+
+    // The secure layer continues after the reserved nonces
+    let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+    client_msl.write_message(&[3], &mut channel)?;
+    let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+    assert_eq!(4, client_msl.nonce_checkpoint().next_nonce_sent);
+
+    // Server receive messages in any order
+    for (i, frame) in [&channel, &frames[2].1, &frames[0].1, &frames[1].1]
+        .iter()
+        .enumerate()
+    {
+        assert_eq!(
+            Some(Message::Message {
+                custom_data: Some(vec![[3, 2, 0, 1][i]]),
+            }),
+            server_msl.read(frame)?
+        );
+    }
+    assert!(server_msl.pending_orphans().is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn reflected_user_msg() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+    // Client user message reflected back to the client is rejected by its AAD
+    let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+    client_msl.write_message(&[1, 2, 3], &mut channel)?;
+    let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+    if let Err(Error::FailToDecryptData(_)) = client_msl.read(&channel) {
+    } else {
+        panic!("Expected error FailToDecryptData !")
+    }
+
+    // The server accepts it
+    assert_eq!(
+        Some(Message::Message {
+            custom_data: Some(vec![1, 2, 3]),
+        }),
+        server_msl.read(&channel)?
+    );
+
+    Ok(())
+}
+
+#[test]
+fn prepared_connect() -> Result<()> {
+    let (_, server_sig_kp) = server_infos()?;
+    let client_sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+        .map_err(|_| Error::FailtoGenSigKeyPair)?;
+    let server_sig_pubkey = server_sig_kp.public_key().as_ref().to_vec();
+
+    // Client signs its CONNECT frame once
+    let prepared_connect = PreparedConnect::new(
+        SecureLayerConfig::default(),
+        SigAlgo::Ed25519,
+        client_sig_kp.public_key().as_ref(),
+        Capabilities::minimal(),
+        Some(&[7]),
+        |connect_msg| client_sig_kp.sign(connect_msg).as_ref().to_vec(),
+    )?;
+    let frame = prepared_connect.frame().to_vec();
+    let prepared_at = prepared_connect.prepared_at();
+
+    // First attempt fails after the server received the CONNECT frame
+    let client_msl = MinimalSecureLayer::create_with_prepared_connect(
+        prepared_connect,
+        Some(server_sig_pubkey.clone()),
+    )?;
+    let (mut server_msl, _) = server_infos()?;
+    assert_eq!(
+        Some(Message::Connect {
+            sig_algo: SIG_ALGO_ED25519_ARRAY,
+            sig_pubkey: client_sig_kp.public_key().as_ref().to_vec(),
+            custom_data: Some(vec![7]),
+        }),
+        server_msl.read(client_msl.prepared_connect_frame().expect("prepared"))?
+    );
+    let prepared_connect = client_msl
+        .into_prepared_connect()
+        .expect("ephemeral key pair not used");
+    assert_eq!(&frame[..], prepared_connect.frame());
+    assert_eq!(prepared_at, prepared_connect.prepared_at());
+
+    // Second attempt re-emits the same frame
+    let mut client_msl = MinimalSecureLayer::create_with_prepared_connect(
+        prepared_connect,
+        Some(server_sig_pubkey),
+    )?;
+    let (mut server_msl, _) = server_infos()?;
+    assert!(server_msl.read(&frame)?.is_some());
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_user_msg(&mut client_msl, &mut server_msl, vec![1, 2, 3])?;
+
+    // The transcript holds the signed prepared frame, our ACK message is signed by the caller
+    let client_transcript = client_msl
+        .handshake_transcript()
+        .expect("Must have a transcript");
+    assert_eq!(
+        frame,
+        [
+            &client_transcript.local_connect.data[..],
+            &client_transcript.local_connect.sigs[..]
+        ]
+        .concat()
+    );
+    assert!(client_transcript.local_ack.sigs.is_empty());
+    assert_eq!(
+        server_msl.handshake_transcript().map(|t| t.peer_ack.data),
+        Some(client_transcript.local_ack.data)
+    );
+
+    // The ephemeral key pair is used
+    assert!(client_msl.into_prepared_connect().is_none());
+
+    Ok(())
+}
+
+#[test]
+fn peer_capabilities() -> Result<()> {
+    let (_, server_sig_kp) = server_infos()?;
+    let client_sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+        .map_err(|_| Error::FailtoGenSigKeyPair)?;
+    let client_capabilities = Capabilities::minimal()
+        .without(Capability::Keepalive)
+        .without(Capability::ExpiringMsgs);
+
+    // Client does not read keepalive and expiring messages
+    let prepared_connect = PreparedConnect::new(
+        SecureLayerConfig::default(),
+        SigAlgo::Ed25519,
+        client_sig_kp.public_key().as_ref(),
+        client_capabilities,
+        None,
+        |connect_msg| client_sig_kp.sign(connect_msg).as_ref().to_vec(),
+    )?;
+    let mut client_msl = MinimalSecureLayer::create_with_prepared_connect(
+        prepared_connect,
+        Some(server_sig_kp.public_key().as_ref().to_vec()),
+    )?;
+    let (mut server_msl, _) = server_infos()?;
+    server_msl.change_config(SecureLayerConfig {
+        keepalive_interval: Some(Duration::from_secs(30)),
+        ..SecureLayerConfig::default()
+    })?;
+    assert_eq!(None, server_msl.peer_capabilities());
+
+    // Negotiation
+    assert!(server_msl
+        .read(client_msl.prepared_connect_frame().expect("prepared"))?
+        .is_some());
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    assert_eq!(Some(client_capabilities), server_msl.peer_capabilities());
+    assert_eq!(
+        Some(Capabilities::minimal()),
+        client_msl.peer_capabilities()
+    );
+
+    // Server skips scheduled keepalive messages and rejects explicit ones
+    assert_eq!(None, server_msl.poll_timeout());
+    let mut channel = BufWriter::new(Vec::new());
+    if let Err(Error::PeerUnsupported(Capability::Keepalive)) =
+        server_msl.write_keepalive(&mut channel)
+    {
+    } else {
+        panic!("Expected error PeerUnsupported !")
+    }
+    if let Err(Error::PeerUnsupported(Capability::ExpiringMsgs)) =
+        server_msl.write_message_with_expiry(&[1], SystemTime::now(), &mut channel)
+    {
+    } else {
+        panic!("Expected error PeerUnsupported !")
+    }
+    assert!(channel.buffer().is_empty());
+
+    // Other messages are still sent in both directions
+    send_user_msg(&mut server_msl, &mut client_msl, vec![1, 2, 3])?;
+    client_msl.write_keepalive(&mut channel)?;
+
+    Ok(())
+}
+
+#[test]
+fn deferred_connect_verification() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    server_msl.set_deferred_connect_verification(true);
+
+    // Server CONNECT message, then client CONNECT and ACK messages
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    assert_eq!(
+        None,
+        send_connect_msg_inner(&mut client_msl, &client_sig_kp, &mut server_msl, None)?
+    );
+    assert_eq!(
+        None,
+        send_ack_msg_inner(&mut client_msl, &client_sig_kp, &mut server_msl, None)?
+    );
+    assert_eq!(SecureLayerStatus::AwaitingConnect, server_msl.status());
+
+    // Verify the client CONNECT message in another thread
+    let pending = server_msl
+        .take_pending_connect_verification()
+        .expect("pending verification");
+    assert!(server_msl.take_pending_connect_verification().is_none());
+    let verified = std::thread::spawn(move || pending.verify())
+        .join()
+        .expect("verifier thread panicked");
+    assert!(verified.is_valid());
+
+    // The handshake completes, releasing the ACK message received too early
+    assert_eq!(
+        vec![
+            Message::Connect {
+                sig_algo: SIG_ALGO_ED25519_ARRAY,
+                sig_pubkey: client_sig_kp.public_key().as_ref().to_vec(),
+                custom_data: None,
+            },
+            Message::Ack { custom_data: None },
+        ],
+        server_msl.complete_connect_verification(verified)?
+    );
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_user_msg(&mut client_msl, &mut server_msl, vec![1, 2, 3])?;
+
+    // A CONNECT message with an invalid signature is rejected once verified
+    let (mut server_msl, _) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    server_msl.set_deferred_connect_verification(true);
+    let mut connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+    let sig = client_sig_kp.sign(b"other message");
+    connect_msg.extend_from_slice(sig.as_ref());
+    assert_eq!(None, server_msl.read(&connect_msg)?);
+    let verified = server_msl
+        .take_pending_connect_verification()
+        .expect("pending verification")
+        .verify();
+    assert!(!verified.is_valid());
+    if let Err(Error::RecvInvalidMsg(IncomingMsgErr::InvalidHashOrSig)) =
+        server_msl.complete_connect_verification(verified)
+    {
+    } else {
+        panic!("Expected error InvalidHashOrSig !")
+    }
+
+    Ok(())
+}
+
+#[test]
+fn handshake_failure_diagnostics() -> Result<()> {
+    let (_, server_sig_kp) = server_infos()?;
+    let (mut middle_msl, middle_sig_kp) = server_infos()?;
+    assert_eq!(None, middle_msl.handshake_failure());
+
+    // Unexpected identity
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut middle_msl, None)?;
+    let mut connect_msg =
+        middle_msl.create_connect_message(middle_sig_kp.public_key().as_ref(), None)?;
+    let sig = middle_sig_kp.sign(&connect_msg);
+    connect_msg.extend_from_slice(sig.as_ref());
+    if let Err(Error::UnexpectedRemoteSigPubKey) = client_msl.read(&connect_msg) {
+    } else {
+        panic!("Expected error UnexpectedRemoteSigPubKey !")
+    }
+    assert_eq!(
+        Some(&HandshakeFailure {
+            phase: HandshakePhase::Connect,
+            field: Some(HandshakeField::SigPubkey),
+            reason: FailReason::UnexpectedRemoteSigPubKey,
+            peer_sig_pubkey: Some(middle_sig_kp.public_key().as_ref().to_vec()),
+            header: connect_msg[..18].to_vec(),
+        }),
+        client_msl.handshake_failure()
+    );
+
+    // Invalid challenge, the identity comes from the peer CONNECT message
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    let mut connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+    let sig = client_sig_kp.sign(&connect_msg);
+    connect_msg.extend_from_slice(sig.as_ref());
+    server_msl.read(&connect_msg)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    let mut ack_msg = server_msl.create_ack_message(None)?;
+    ack_msg[18] ^= 1;
+    let sig = server_sig_kp.sign(&ack_msg);
+    ack_msg.extend_from_slice(sig.as_ref());
+    if let Err(Error::RecvInvalidMsg(IncomingMsgErr::InvalidChallenge)) = client_msl.read(&ack_msg)
+    {
+    } else {
+        panic!("Expected error InvalidChallenge !")
+    }
+    let failure = client_msl
+        .handshake_failure()
+        .expect("handshake failure must be recorded");
+    assert_eq!(HandshakePhase::Ack, failure.phase);
+    assert_eq!(Some(HandshakeField::Challenge), failure.field);
+    assert_eq!(
+        Some(server_sig_kp.public_key().as_ref()),
+        failure.peer_sig_pubkey.as_ref().map(|pubkey| &pubkey[..])
+    );
+
+    // Unsupported version
+    let (mut server_msl, _) = server_infos()?;
+    connect_msg[4..8].copy_from_slice(&[0, 0, 0, 3]);
+    server_msl.read(&connect_msg).ok();
+    let failure = server_msl
+        .handshake_failure()
+        .expect("handshake failure must be recorded");
+    assert_eq!(HandshakePhase::Connect, failure.phase);
+    assert_eq!(Some(HandshakeField::Version), failure.field);
+    assert_eq!(&connect_msg[..18], &failure.header[..]);
+
+    // Invalid magic value, the frame is not readable
+    let (mut server_msl, _) = server_infos()?;
+    connect_msg[0] ^= 1;
+    server_msl.read(&connect_msg).ok();
+    let failure = server_msl
+        .handshake_failure()
+        .expect("handshake failure must be recorded");
+    assert_eq!(HandshakePhase::Unknown, failure.phase);
+    assert_eq!(Some(HandshakeField::Magic), failure.field);
+    assert_eq!(None, failure.peer_sig_pubkey);
+
+    // The diagnostics are recorded even with uniform handshake rejection
+    let (mut server_msl, _) = server_infos()?;
+    server_msl.change_config(SecureLayerConfig {
+        uniform_handshake_rejection: true,
+        ..SecureLayerConfig::default()
+    })?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    let mut connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+    connect_msg.extend_from_slice(&[0u8; 64]);
+    if let Err(Error::HandshakeRejected) = server_msl.read(&connect_msg) {
+    } else {
+        panic!("Expected error HandshakeRejected !")
+    }
+    let failure = server_msl
+        .handshake_failure()
+        .expect("handshake failure must be recorded");
+    assert_eq!(Some(HandshakeField::Sig), failure.field);
+    assert_eq!(
+        Some(client_sig_kp.public_key().as_ref()),
+        failure.peer_sig_pubkey.as_ref().map(|pubkey| &pubkey[..])
+    );
+
+    Ok(())
+}
+
+#[test]
+fn loss_report() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    assert_eq!(LossReport::default(), server_msl.loss_report());
+
+    // Client write messages with nonces 0 to 7, server receive nonces 0, 2 and 5
+    let mut channels = Vec::new();
+    for i in 0..8 {
+        let mut channel = BufWriter::new(Vec::with_capacity(1_000));
+        client_msl.write_message(&[i], &mut channel)?;
+        channels.push(channel.into_inner().map_err(|_| Error::BufferFlushError)?);
+    }
+    for i in &[0, 2, 5] {
+        server_msl.read(&channels[*i][..])?;
+    }
+    client_msl.close()?;
+    server_msl.close()?;
+
+    let client_report = client_msl.loss_report();
+    let server_report = server_msl.loss_report();
+    assert_eq!(
+        LossReport {
+            nonces_sent: 0,
+            missing_nonces: vec![1..2, 3..5],
+            received_end: 6,
+        },
+        server_report
+    );
+    assert_eq!(8, client_report.nonces_sent);
+    assert_eq!(
+        vec![1..2, 3..5, 6..8],
+        client_report.unacknowledged(&server_report)
+    );
+    assert!(server_report.unacknowledged(&client_report).is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn previous_version_peer() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+
+    // Version 1 CONNECT message: no CAPABILITIES field after the 32 bytes signature public key
+    let mut connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+    connect_msg[4..8].copy_from_slice(&[0, 0, 0, 1]);
+    connect_msg.drain(86..90);
+    let encapsuled_msg_len = connect_msg.len() as u64 - 16;
+    connect_msg[8..16].copy_from_slice(&encapsuled_msg_len.to_be_bytes());
+    let sig = client_sig_kp.sign(&connect_msg);
+    connect_msg.extend_from_slice(sig.as_ref());
+
+    // Reported as a version mismatch, before any key derivation or decryption
+    if let Err(Error::RecvInvalidMsg(IncomingMsgErr::UnsupportedVersion)) =
+        server_msl.read(&connect_msg)
+    {
+    } else {
+        panic!("Expected error UnsupportedVersion !")
+    }
+    assert!(server_msl.take_version_reject_message().is_some());
+    assert_eq!(
+        SecureLayerStatus::Failed {
+            reason: FailReason::InvalidIncomingMsg(IncomingMsgErr::UnsupportedVersion),
+        },
+        server_msl.status()
+    );
+
+    Ok(())
+}
+
+#[test]
+fn version_routing() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    let mut connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+    let sig = client_sig_kp.sign(&connect_msg);
+    connect_msg.extend_from_slice(sig.as_ref());
+
+    // A peer of our version is routed to this implementation
+    assert_eq!(Some(PROTOCOL_VERSION), peek_frame_version(&connect_msg));
+    server_msl.read(&connect_msg)?;
+
+    // A peer of a version spoken by none of the implementations linked in the process
+    // is told all the versions of the process
+    connect_msg[4..8].copy_from_slice(&[0, 0, 0, 3]);
+    assert_eq!(Some(3), peek_frame_version(&connect_msg));
+    let (mut client_msl, _) = client_infos(server_sig_kp.public_key().as_ref())?;
+    if let Err(Error::VersionRejected {
+        min_version,
+        max_version,
+    }) = client_msl.read(&version_reject_frame(1, PROTOCOL_VERSION)?)
+    {
+        assert_eq!((1, 2), (min_version, max_version));
+    } else {
+        panic!("Expected error VersionRejected !")
+    }
+
+    Ok(())
+}
+
+#[test]
+fn small_msg_fast_path() -> Result<()> {
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    client_msl.change_config(SecureLayerConfig {
+        small_msg_max_len: 1_000,
+        ..SecureLayerConfig::default()
+    })?;
+
+    // Negotiation
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+
+    // Messages on both sides of the stack buffers limit
+    let overhead = SecureLayerConfig::default().frame_overhead();
+    for len in &[1, 512, SMALL_MSG_BUFFER_SIZE - overhead, 2_000] {
+        send_user_msg(&mut client_msl, &mut server_msl, vec![7; *len])?;
+        let mut channel = BufWriter::new(Vec::new());
+        let expiry = SystemTime::now() + Duration::from_secs(60);
+        client_msl.write_message_with_expiry(&vec![8; *len], expiry, &mut channel)?;
+        let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+        assert_eq!(
+            Some(Message::Message {
+                custom_data: Some(vec![8; *len]),
+            }),
+            server_msl.read(&channel)?
+        );
+    }
+
+    Ok(())
+}
+
+#[test]
+fn peer_auth_policies() -> Result<()> {
+    let server_sig_kp = Ed25519KeyPair::from_seed_unchecked(Seed32::try_random()?.as_ref())
+        .map_err(|_| Error::FailtoGenSigKeyPair)?;
+    let server_sig_pubkey = server_sig_kp.public_key().as_ref().to_vec();
+    let (_, client_sig_kp) = client_infos(&server_sig_pubkey)?;
+    let client_sig_pubkey = client_sig_kp.public_key().as_ref().to_vec();
+
+    // An unexpected peer is rejected if it must be pinned
+    let mut server_msl = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
+    server_msl.set_peer_auth_policy(PeerAuthPolicy::RequirePinned);
+    let mut client_msl = MinimalSecureLayer::create(
+        SecureLayerConfig::default(),
+        Some(server_sig_pubkey.clone()),
+    )?;
+    let result = send_connect_msg_inner(&mut client_msl, &client_sig_kp, &mut server_msl, None);
+    if let Err(Error::UnexpectedRemoteSigPubKey) = result {
+    } else {
+        panic!("Expected error UnexpectedRemoteSigPubKey !")
+    }
+
+    // Trust on first use pins the peer, then rejects another one
+    let store = Arc::new(MemorySessionStore::new());
+    for _ in 0..2 {
+        let (mut server_msl, _) = server_infos()?;
+        server_msl
+            .set_peer_auth_policy(PeerAuthPolicy::trust_on_first_use(store.clone(), "client"));
+        let mut client_msl = MinimalSecureLayer::create(
+            SecureLayerConfig::default(),
+            Some(server_sig_pubkey.clone()),
+        )?;
+        send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    }
+    assert_eq!(
+        Some(client_sig_pubkey.clone()),
+        store.pinned_peer("client")?
+    );
+    let (mut server_msl, _) = server_infos()?;
+    server_msl.set_peer_auth_policy(PeerAuthPolicy::trust_on_first_use(store.clone(), "client"));
+    let (mut other_client_msl, other_client_sig_kp) = client_infos(&server_sig_pubkey)?;
+    let result = send_connect_msg_inner(
+        &mut other_client_msl,
+        &other_client_sig_kp,
+        &mut server_msl,
+        None,
+    );
+    if let Err(Error::UnexpectedRemoteSigPubKey) = result {
+    } else {
+        panic!("Expected error UnexpectedRemoteSigPubKey !")
+    }
+
+    // A peer signing its ACK message alone is pinned once its ACK signature is verified
+    let ack_only_store = Arc::new(MemorySessionStore::new());
+    let mut server_msl = MinimalSecureLayer::create(
+        SecureLayerConfig {
+            peer_sig_requirement: SigRequirement::AckOnly,
+            ..SecureLayerConfig::default()
+        },
+        None,
+    )?;
+    server_msl.set_peer_auth_policy(PeerAuthPolicy::trust_on_first_use(
+        ack_only_store.clone(),
+        "client",
+    ));
+    let mut client_msl = MinimalSecureLayer::create(
+        SecureLayerConfig {
+            local_sig_requirement: SigRequirement::AckOnly,
+            ..SecureLayerConfig::default()
+        },
+        Some(server_sig_pubkey.clone()),
+    )?;
+    let client_connect_msg =
+        client_msl.create_connect_message(client_sig_kp.public_key().as_ref(), None)?;
+    assert!(server_msl.read(&client_connect_msg)?.is_some());
+    assert_eq!(None, ack_only_store.pinned_peer("client")?);
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    assert_eq!(
+        Some(client_sig_pubkey.clone()),
+        ack_only_store.pinned_peer("client")?
+    );
+
+    // Any peer is accepted and audited
+    let audited = Arc::new(Mutex::new(Vec::new()));
+    let audited_clone = audited.clone();
+    let mut server_msl = MinimalSecureLayer::create(SecureLayerConfig::default(), None)?;
+    server_msl.set_peer_auth_policy(PeerAuthPolicy::accept_any(move |sig_pubkey| {
+        audited_clone
+            .lock()
+            .expect("poisoned lock")
+            .push(sig_pubkey.to_vec())
+    }));
+    let mut client_msl =
+        MinimalSecureLayer::create(SecureLayerConfig::default(), Some(server_sig_pubkey))?;
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    assert_eq!(SecureLayerStatus::Established, server_msl.status());
+    assert_eq!(
+        vec![client_sig_pubkey],
+        *audited.lock().expect("poisoned lock")
+    );
+
+    Ok(())
+}
+
+#[cfg(feature = "frame-allocator")]
+#[test]
+fn frame_buffer_pool() -> Result<()> {
+    let pool: &'static FrameBufferPool = Box::leak(Box::new(FrameBufferPool::new(8, 4_096)));
+    let config = SecureLayerConfig {
+        frame_allocator: pool,
+        small_msg_max_len: 0,
+        ..SecureLayerConfig::default()
+    };
+    let (mut server_msl, server_sig_kp) = server_infos()?;
+    let (mut client_msl, client_sig_kp) = client_infos(server_sig_kp.public_key().as_ref())?;
+    server_msl.change_config(config)?;
+    client_msl.change_config(config)?;
+
+    // Negotiation, the decrypted handshake messages keep their buffer
+    send_connect_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    send_connect_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut server_msl, &server_sig_kp, &mut client_msl, None)?;
+    send_ack_msg(&mut client_msl, &client_sig_kp, &mut server_msl, None)?;
+    let available = pool.available();
+    assert_eq!(4, available);
+
+    // Encryption buffers are released once the frame is written,
+    // the buffer of a message view is released by the next read
+    for data in &[vec![1; 1_000], vec![2; 2_000], vec![3; 3_000]] {
+        let mut channel = BufWriter::new(Vec::with_capacity(3_100));
+        let available_before_write = pool.available();
+        client_msl.write_message(data, &mut channel)?;
+        assert_eq!(available_before_write, pool.available());
+        let channel = channel.into_inner().map_err(|_| Error::BufferFlushError)?;
+        assert_eq!(
+            Some(MessageView::Message {
+                custom_data: Some(&data[..])
+            }),
+            server_msl.read_view(&channel)?
+        );
+        assert_eq!(available - 1, pool.available());
+    }
+
+    Ok(())
+}